@@ -2,14 +2,13 @@
 //!
 //! Run with: cargo run --example aec_test
 //!
-//! This tool plays a 440Hz test tone through speakers while recording from the
-//! microphone with AEC enabled. If AEC is working correctly, the recording should
-//! contain your voice but NOT the test tone.
+//! This tool plays a 440Hz test tone through the capture engine's own output
+//! while recording from the microphone with AEC enabled. `playback_sync`
+//! keeps the tone in step with the capture, so the canceller always has it
+//! as its reference and the measured echo return loss gives a pass or fail.
 
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use hound::{SampleFormat, WavSpec, WavWriter};
 use std::f32::consts::PI;
-use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use sys_voice::{AecConfig, CaptureHandle, Channels};
 
@@ -17,6 +16,9 @@ const SAMPLE_RATE: u32 = 48000;
 const DURATION_SECS: u64 = 10;
 const TONE_FREQ: f32 = 440.0;
 const TONE_VOLUME: f32 = 0.3;
+/// Echo return loss that counts as a pass. Speaker-to-microphone loss alone
+/// rarely reaches it; active AEC typically gets 30 dB or more.
+const PASS_ERLE_DB: f32 = 20.0;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -40,47 +42,24 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config = AecConfig {
         sample_rate: SAMPLE_RATE,
         channels: Channels::Mono,
+        playback_sync: true,
+        ..Default::default()
     };
 
     let handle = CaptureHandle::new(config)?;
     let mut recorded_samples: Vec<f32> = Vec::new();
 
-    let host = cpal::default_host();
-    let output_device = host
-        .default_output_device()
-        .ok_or("No output device available")?;
-
-    let output_config = output_device.default_output_config()?;
-    let output_sample_rate = output_config.sample_rate().0 as f32;
-    let output_channels = output_config.channels() as usize;
-
-    let phase_increment = TONE_FREQ / output_sample_rate;
-    let phase = Arc::new(Mutex::new(0.0f32));
-    let phase_clone = phase.clone();
-
-    let output_stream = output_device.build_output_stream(
-        &output_config.into(),
-        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-            let Ok(mut current_phase) = phase_clone.lock() else {
-                return;
-            };
-            for frame in data.chunks_mut(output_channels) {
-                let sample = (*current_phase * 2.0 * PI).sin() * TONE_VOLUME;
-                *current_phase = (*current_phase + phase_increment) % 1.0;
-                for channel_sample in frame.iter_mut() {
-                    *channel_sample = sample;
-                }
-            }
-        },
-        |err| eprintln!("Output stream error: {err:?}"),
-        None,
-    )?;
-
-    output_stream.play()?;
+    // The whole tone can be queued at once: playback_sync releases it as
+    // the capture advances
+    let tone: Vec<f32> = (0..SAMPLE_RATE as u64 * DURATION_SECS)
+        .map(|i| (2.0 * PI * TONE_FREQ * i as f32 / SAMPLE_RATE as f32).sin() * TONE_VOLUME)
+        .collect();
+    handle.play_audio(tone, SAMPLE_RATE)?;
 
     println!("Recording... speak now!");
     println!();
 
+    let mut erle_db = None;
     let start = std::time::Instant::now();
     while start.elapsed() < Duration::from_secs(DURATION_SECS) {
         while let Some(result) = handle.try_recv() {
@@ -89,6 +68,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 Err(e) => eprintln!("Audio error: {e}"),
             }
         }
+        // Read while the tone is still playing, before the window empties
+        erle_db = handle.measure_erle().or(erle_db);
         std::thread::sleep(Duration::from_millis(10));
     }
     // Drain remaining samples
@@ -98,8 +79,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             Err(e) => eprintln!("Audio error: {e}"),
         }
     }
-
-    drop(output_stream);
+    let aec_active = handle.stats().aec_active;
 
     println!("Recording complete!");
     println!();
@@ -126,10 +106,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         samples.len() as f32 / SAMPLE_RATE as f32
     );
     println!();
-    println!("To verify AEC is working:");
-    println!("- Play aec_recording.wav");
-    println!("- You should hear your voice but NOT the 440Hz test tone");
-    println!("- If you hear the tone clearly, AEC may not be active on your system");
 
-    Ok(())
+    let Some(erle_db) = erle_db else {
+        println!("FAIL: not enough of the tone was captured to measure the echo");
+        std::process::exit(1);
+    };
+    println!("Echo return loss: {erle_db:.1} dB (AEC reported active: {aec_active})");
+    if erle_db >= PASS_ERLE_DB {
+        println!("PASS: the tone was cancelled from the recording");
+        Ok(())
+    } else {
+        println!("FAIL: the tone came back less than {PASS_ERLE_DB} dB down");
+        println!("- Play aec_recording.wav to hear the echo that got through");
+        std::process::exit(1);
+    }
 }
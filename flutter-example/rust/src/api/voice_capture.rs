@@ -2,6 +2,7 @@
 //!
 //! This module exposes sys-voice functionality to Flutter via flutter_rust_bridge.
 
+use crate::frb_generated::StreamSink;
 use sys_voice::{AecConfig, CaptureHandle, Channels};
 
 /// Result from polling audio.
@@ -9,6 +10,34 @@ pub struct AudioPollResult {
     pub samples: Vec<f32>,
 }
 
+/// Capture status events pushed to Dart.
+pub enum CaptureStatus {
+    /// The microphone was disconnected; no more audio will arrive.
+    DeviceLost,
+    /// The microphone was lost and is being reopened.
+    Reconnecting { attempt: u32 },
+    /// The microphone reopened and audio continues.
+    Reconnected,
+    /// The OS suspended capture (e.g. a phone call).
+    Interrupted,
+    /// Capture continues after an interruption.
+    Resumed,
+}
+
+impl From<sys_voice::CaptureStatus> for CaptureStatus {
+    fn from(status: sys_voice::CaptureStatus) -> Self {
+        match status {
+            sys_voice::CaptureStatus::DeviceLost => CaptureStatus::DeviceLost,
+            sys_voice::CaptureStatus::Reconnecting { attempt } => {
+                CaptureStatus::Reconnecting { attempt }
+            }
+            sys_voice::CaptureStatus::Reconnected => CaptureStatus::Reconnected,
+            sys_voice::CaptureStatus::Interrupted => CaptureStatus::Interrupted,
+            sys_voice::CaptureStatus::Resumed => CaptureStatus::Resumed,
+        }
+    }
+}
+
 /// Opaque wrapper for sys-voice CaptureHandle with its tokio runtime.
 #[flutter_rust_bridge::frb(opaque)]
 pub struct VoiceCaptureHandle {
@@ -26,13 +55,10 @@ pub fn start_capture(sample_rate: u32) -> Result<VoiceCaptureHandle, String> {
     let config = AecConfig {
         sample_rate,
         channels: Channels::Mono,
+        ..Default::default()
     };
 
-    // Use block_on to ensure the runtime is fully started and worker threads are running.
-    // CaptureHandle::new() calls tokio::spawn() internally which requires an active runtime.
-    // Using enter() alone may not start the worker threads - block_on ensures they're running.
-    let handle = runtime
-        .block_on(async { CaptureHandle::new(config) })
+    let handle = CaptureHandle::new_on(config, runtime.handle().clone())
         .map_err(|e| format!("Failed to start capture: {e:?}"))?;
 
     Ok(VoiceCaptureHandle { handle, runtime })
@@ -46,12 +72,29 @@ impl VoiceCaptureHandle {
     #[flutter_rust_bridge::frb(sync)]
     pub fn poll_audio(&self) -> Result<Option<AudioPollResult>, String> {
         match self.handle.try_recv() {
-            Some(Ok(samples)) => Ok(Some(AudioPollResult { samples })),
+            Some(Ok(frame)) => Ok(Some(AudioPollResult {
+                samples: frame.into_vec(),
+            })),
             Some(Err(e)) => Err(format!("Failed to receive audio: {e:?}")),
             None => Ok(None),
         }
     }
 
+    /// Stream capture status changes to Dart, e.g. to show a
+    /// "microphone disconnected" banner on `DeviceLost`.
+    ///
+    /// The stream ends when capture stops.
+    pub fn status_stream(&self, sink: StreamSink<CaptureStatus>) {
+        let status = self.handle.status();
+        self.runtime.spawn(async move {
+            while let Some(event) = status.recv().await {
+                if sink.add(event.into()).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
     /// Get the native sample rate of the capture device.
     #[flutter_rust_bridge::frb(sync)]
     pub fn sample_rate(&self) -> u32 {
@@ -159,6 +159,55 @@ fn wire__crate__api__voice_capture__VoiceCaptureHandle_sample_rate_impl(
         },
     )
 }
+fn wire__crate__api__voice_capture__VoiceCaptureHandle_status_stream_impl(
+    port_: flutter_rust_bridge::for_generated::MessagePort,
+    that: impl CstDecode<
+        RustOpaqueNom<flutter_rust_bridge::for_generated::RustAutoOpaqueInner<VoiceCaptureHandle>>,
+    >,
+    sink: impl CstDecode<
+        StreamSink<
+            crate::api::voice_capture::CaptureStatus,
+            flutter_rust_bridge::for_generated::DcoCodec,
+        >,
+    >,
+) {
+    FLUTTER_RUST_BRIDGE_HANDLER.wrap_normal::<flutter_rust_bridge::for_generated::DcoCodec, _, _>(
+        flutter_rust_bridge::for_generated::TaskInfo {
+            debug_name: "VoiceCaptureHandle_status_stream",
+            port: Some(port_),
+            mode: flutter_rust_bridge::for_generated::FfiCallMode::Normal,
+        },
+        move || {
+            let api_that = that.cst_decode();
+            let api_sink = sink.cst_decode();
+            move |context| {
+                transform_result_dco::<_, _, ()>((move || {
+                    let mut api_that_guard = None;
+                    let decode_indices_ =
+                        flutter_rust_bridge::for_generated::lockable_compute_decode_order(vec![
+                            flutter_rust_bridge::for_generated::LockableOrderInfo::new(
+                                &api_that, 0, false,
+                            ),
+                        ]);
+                    for i in decode_indices_ {
+                        match i {
+                            0 => api_that_guard = Some(api_that.lockable_decode_sync_ref()),
+                            _ => unreachable!(),
+                        }
+                    }
+                    let api_that_guard = api_that_guard.unwrap();
+                    let output_ok = Result::<_, ()>::Ok({
+                        crate::api::voice_capture::VoiceCaptureHandle::status_stream(
+                            &*api_that_guard,
+                            api_sink,
+                        );
+                    })?;
+                    Ok(output_ok)
+                })())
+            }
+        },
+    )
+}
 fn wire__crate__api__voice_capture__greet_impl(
     name: impl CstDecode<String>,
 ) -> flutter_rust_bridge::for_generated::WireSyncRust2DartDco {
@@ -266,6 +315,19 @@ impl SseDecode
     }
 }
 
+impl SseDecode
+    for StreamSink<
+        crate::api::voice_capture::CaptureStatus,
+        flutter_rust_bridge::for_generated::DcoCodec,
+    >
+{
+    // Codec=Sse (Serialization based), see doc to use other codecs
+    fn sse_decode(deserializer: &mut flutter_rust_bridge::for_generated::SseDeserializer) -> Self {
+        let mut inner = <String>::sse_decode(deserializer);
+        return StreamSink::deserialize(inner);
+    }
+}
+
 impl SseDecode for String {
     // Codec=Sse (Serialization based), see doc to use other codecs
     fn sse_decode(deserializer: &mut flutter_rust_bridge::for_generated::SseDeserializer) -> Self {
@@ -284,6 +346,36 @@ impl SseDecode for crate::api::voice_capture::AudioPollResult {
     }
 }
 
+impl SseDecode for crate::api::voice_capture::CaptureStatus {
+    // Codec=Sse (Serialization based), see doc to use other codecs
+    fn sse_decode(deserializer: &mut flutter_rust_bridge::for_generated::SseDeserializer) -> Self {
+        let mut tag_ = <i32>::sse_decode(deserializer);
+        match tag_ {
+            0 => {
+                return crate::api::voice_capture::CaptureStatus::DeviceLost;
+            }
+            1 => {
+                let mut var_attempt = <u32>::sse_decode(deserializer);
+                return crate::api::voice_capture::CaptureStatus::Reconnecting {
+                    attempt: var_attempt,
+                };
+            }
+            2 => {
+                return crate::api::voice_capture::CaptureStatus::Reconnected;
+            }
+            3 => {
+                return crate::api::voice_capture::CaptureStatus::Interrupted;
+            }
+            4 => {
+                return crate::api::voice_capture::CaptureStatus::Resumed;
+            }
+            _ => {
+                unimplemented!("");
+            }
+        }
+    }
+}
+
 impl SseDecode for f32 {
     // Codec=Sse (Serialization based), see doc to use other codecs
     fn sse_decode(deserializer: &mut flutter_rust_bridge::for_generated::SseDeserializer) -> Self {
@@ -430,6 +522,34 @@ impl flutter_rust_bridge::IntoIntoDart<crate::api::voice_capture::AudioPollResul
         self
     }
 }
+// Codec=Dco (DartCObject based), see doc to use other codecs
+impl flutter_rust_bridge::IntoDart for crate::api::voice_capture::CaptureStatus {
+    fn into_dart(self) -> flutter_rust_bridge::for_generated::DartAbi {
+        match self {
+            crate::api::voice_capture::CaptureStatus::DeviceLost => [0.into_dart()].into_dart(),
+            crate::api::voice_capture::CaptureStatus::Reconnecting { attempt } => {
+                [1.into_dart(), attempt.into_into_dart().into_dart()].into_dart()
+            }
+            crate::api::voice_capture::CaptureStatus::Reconnected => [2.into_dart()].into_dart(),
+            crate::api::voice_capture::CaptureStatus::Interrupted => [3.into_dart()].into_dart(),
+            crate::api::voice_capture::CaptureStatus::Resumed => [4.into_dart()].into_dart(),
+            _ => {
+                unimplemented!("");
+            }
+        }
+    }
+}
+impl flutter_rust_bridge::for_generated::IntoDartExceptPrimitive
+    for crate::api::voice_capture::CaptureStatus
+{
+}
+impl flutter_rust_bridge::IntoIntoDart<crate::api::voice_capture::CaptureStatus>
+    for crate::api::voice_capture::CaptureStatus
+{
+    fn into_into_dart(self) -> crate::api::voice_capture::CaptureStatus {
+        self
+    }
+}
 
 impl SseEncode for VoiceCaptureHandle {
     // Codec=Sse (Serialization based), see doc to use other codecs
@@ -449,6 +569,18 @@ impl SseEncode
     }
 }
 
+impl SseEncode
+    for StreamSink<
+        crate::api::voice_capture::CaptureStatus,
+        flutter_rust_bridge::for_generated::DcoCodec,
+    >
+{
+    // Codec=Sse (Serialization based), see doc to use other codecs
+    fn sse_encode(self, serializer: &mut flutter_rust_bridge::for_generated::SseSerializer) {
+        unimplemented!("")
+    }
+}
+
 impl SseEncode for String {
     // Codec=Sse (Serialization based), see doc to use other codecs
     fn sse_encode(self, serializer: &mut flutter_rust_bridge::for_generated::SseSerializer) {
@@ -463,6 +595,33 @@ impl SseEncode for crate::api::voice_capture::AudioPollResult {
     }
 }
 
+impl SseEncode for crate::api::voice_capture::CaptureStatus {
+    // Codec=Sse (Serialization based), see doc to use other codecs
+    fn sse_encode(self, serializer: &mut flutter_rust_bridge::for_generated::SseSerializer) {
+        match self {
+            crate::api::voice_capture::CaptureStatus::DeviceLost => {
+                <i32>::sse_encode(0, serializer);
+            }
+            crate::api::voice_capture::CaptureStatus::Reconnecting { attempt } => {
+                <i32>::sse_encode(1, serializer);
+                <u32>::sse_encode(attempt, serializer);
+            }
+            crate::api::voice_capture::CaptureStatus::Reconnected => {
+                <i32>::sse_encode(2, serializer);
+            }
+            crate::api::voice_capture::CaptureStatus::Interrupted => {
+                <i32>::sse_encode(3, serializer);
+            }
+            crate::api::voice_capture::CaptureStatus::Resumed => {
+                <i32>::sse_encode(4, serializer);
+            }
+            _ => {
+                unimplemented!("");
+            }
+        }
+    }
+}
+
 impl SseEncode for f32 {
     // Codec=Sse (Serialization based), see doc to use other codecs
     fn sse_encode(self, serializer: &mut flutter_rust_bridge::for_generated::SseSerializer) {
@@ -592,6 +751,25 @@ mod io {
             unsafe { decode_rust_opaque_nom(self as _) }
         }
     }
+    impl
+        CstDecode<
+            StreamSink<
+                crate::api::voice_capture::CaptureStatus,
+                flutter_rust_bridge::for_generated::DcoCodec,
+            >,
+        > for *mut wire_cst_list_prim_u_8_strict
+    {
+        // Codec=Cst (C-struct based), see doc to use other codecs
+        fn cst_decode(
+            self,
+        ) -> StreamSink<
+            crate::api::voice_capture::CaptureStatus,
+            flutter_rust_bridge::for_generated::DcoCodec,
+        > {
+            let raw: String = self.cst_decode();
+            StreamSink::deserialize(raw)
+        }
+    }
     impl CstDecode<String> for *mut wire_cst_list_prim_u_8_strict {
         // Codec=Cst (C-struct based), see doc to use other codecs
         fn cst_decode(self) -> String {
@@ -681,6 +859,15 @@ mod io {
         wire__crate__api__voice_capture__VoiceCaptureHandle_sample_rate_impl(that)
     }
 
+    #[unsafe(no_mangle)]
+    pub extern "C" fn frbgen_voice_capture_example_wire__crate__api__voice_capture__VoiceCaptureHandle_status_stream(
+        port_: i64,
+        that: usize,
+        sink: *mut wire_cst_list_prim_u_8_strict,
+    ) {
+        wire__crate__api__voice_capture__VoiceCaptureHandle_status_stream_impl(port_, that, sink)
+    }
+
     #[unsafe(no_mangle)]
     pub extern "C" fn frbgen_voice_capture_example_wire__crate__api__voice_capture__greet(
         name: *mut wire_cst_list_prim_u_8_strict,
@@ -813,6 +1000,24 @@ mod web {
 
     // Section: dart2rust
 
+    impl
+        CstDecode<
+            StreamSink<
+                crate::api::voice_capture::CaptureStatus,
+                flutter_rust_bridge::for_generated::DcoCodec,
+            >,
+        > for String
+    {
+        // Codec=Cst (C-struct based), see doc to use other codecs
+        fn cst_decode(
+            self,
+        ) -> StreamSink<
+            crate::api::voice_capture::CaptureStatus,
+            flutter_rust_bridge::for_generated::DcoCodec,
+        > {
+            StreamSink::deserialize(self)
+        }
+    }
     impl CstDecode<String> for String {
         // Codec=Cst (C-struct based), see doc to use other codecs
         fn cst_decode(self) -> String {
@@ -956,6 +1161,15 @@ mod web {
         wire__crate__api__voice_capture__VoiceCaptureHandle_sample_rate_impl(that)
     }
 
+    #[wasm_bindgen]
+    pub fn wire__crate__api__voice_capture__VoiceCaptureHandle_status_stream(
+        port_: flutter_rust_bridge::for_generated::MessagePort,
+        that: flutter_rust_bridge::for_generated::wasm_bindgen::JsValue,
+        sink: String,
+    ) {
+        wire__crate__api__voice_capture__VoiceCaptureHandle_status_stream_impl(port_, that, sink)
+    }
+
     #[wasm_bindgen]
     pub fn wire__crate__api__voice_capture__greet(
         name: String,
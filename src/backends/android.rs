@@ -2,15 +2,28 @@ use std::sync::{Arc, Mutex};
 
 use oboe::{
     AudioInputCallback, AudioInputStreamSafe, AudioOutputCallback, AudioOutputStreamSafe,
-    AudioStream, AudioStreamBase, AudioStreamBuilder, AudioStreamSafe, DataCallbackResult, Input,
-    InputPreset, Mono, Output, PerformanceMode, SampleRateConversionQuality, SharingMode, Usage,
+    AudioStream, AudioStreamAsync, AudioStreamBase, AudioStreamBuilder, AudioStreamSafe,
+    AudioStreamSync, DataCallbackResult, DefaultStreamValues, Input, InputPreset, Mono, Output,
+    PerformanceMode, SampleRateConversionQuality, SharingMode, Usage,
 };
 
-use crate::backends::PlaybackRequest;
-use crate::AecError;
+use crate::backends::bluetooth_sco::ScoLink;
+use crate::backends::{
+    keeps_channels, thread_name, Chunk, ChunkSender, MuteRamp, PlaybackLevel, PlaybackMarkers,
+    PlaybackMute, PlaybackRequest, StreamInfo,
+};
+use crate::latency::LatencyPlan;
+use crate::{
+    AecConfig, AecError, DownmixMode, Processing, RenderFn, RenderSource, ResamplerQuality,
+};
+
+/// Reported by `CaptureHandle::backend_name()`
+pub const BACKEND_NAME: &str = "oboe";
 
 struct InputHandler {
-    sender: flume::Sender<Vec<f32>>,
+    /// Taken when the stream closes on error so the audio thread sees
+    /// the channel disconnect
+    sender: Option<ChunkSender>,
 }
 
 impl AudioInputCallback for InputHandler {
@@ -21,13 +34,31 @@ impl AudioInputCallback for InputHandler {
         _stream: &mut dyn AudioInputStreamSafe,
         frames: &[f32],
     ) -> DataCallbackResult {
-        let _ = self.sender.try_send(frames.to_vec());
+        if let Some(sender) = &self.sender {
+            sender.send(frames.to_vec());
+        }
         DataCallbackResult::Continue
     }
+
+    fn on_error_after_close(&mut self, _stream: &mut dyn AudioInputStreamSafe, error: oboe::Error) {
+        tracing::warn!("Oboe input stream closed: {error:?}");
+        self.sender = None;
+    }
+}
+
+/// Samples waiting for the output callback
+struct PlaybackBuffer {
+    samples: Vec<f32>,
+    markers: PlaybackMarkers,
 }
 
 struct OutputHandler {
-    playback_buffer: Arc<Mutex<Vec<f32>>>,
+    playback_buffer: Arc<Mutex<PlaybackBuffer>>,
+    playback_level: Arc<PlaybackLevel>,
+    ramp: MuteRamp,
+    /// Taken into `render` on the first callback, so only that one locks
+    render_source: Option<RenderSource>,
+    render: Option<RenderFn>,
 }
 
 impl AudioOutputCallback for OutputHandler {
@@ -38,6 +69,21 @@ impl AudioOutputCallback for OutputHandler {
         _stream: &mut dyn AudioOutputStreamSafe,
         frames: &mut [f32],
     ) -> DataCallbackResult {
+        if let Some(source) = self.render_source.take() {
+            self.render = source.take();
+        }
+        // Muted, the render callback isn't called and the buffer isn't
+        // drained, so both resume where they stopped
+        let audible = self.ramp.audible(frames.len());
+        if let Some(render) = &mut self.render {
+            frames.fill(0.0);
+            if audible > 0 {
+                render(&mut frames[..audible]);
+                self.ramp.apply(&mut frames[..audible]);
+            }
+            return DataCallbackResult::Continue;
+        }
+
         let mut buffer = match self.playback_buffer.lock() {
             Ok(b) => b,
             Err(_) => {
@@ -45,10 +91,14 @@ impl AudioOutputCallback for OutputHandler {
                 return DataCallbackResult::Continue;
             }
         };
-        let available = buffer.len().min(frames.len());
-        frames[..available].copy_from_slice(&buffer[..available]);
-        buffer.drain(..available);
+        let available = buffer.samples.len().min(audible);
+        frames[..available].copy_from_slice(&buffer.samples[..available]);
+        buffer.samples.drain(..available);
+        buffer.markers.consume(available);
+        self.playback_level
+            .store(buffer.samples.len(), STREAM_SAMPLE_RATE as u32);
         frames[available..].fill(0.0);
+        self.ramp.apply(&mut frames[..available]);
         DataCallbackResult::Continue
     }
 }
@@ -76,110 +126,320 @@ fn resample_linear(input: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
     output
 }
 
-/// Create Android Oboe capture backend with hardware AEC.
-/// Spawns a dedicated OS thread that owns the audio stream lifecycle.
-/// Returns (sample_rate, buffer_size).
+/// Oboe's setting for `AecConfig::device_resampler_quality`
+fn conversion_quality(quality: ResamplerQuality) -> SampleRateConversionQuality {
+    match quality {
+        ResamplerQuality::Fastest => SampleRateConversionQuality::Fastest,
+        ResamplerQuality::Low => SampleRateConversionQuality::Low,
+        ResamplerQuality::Medium => SampleRateConversionQuality::Medium,
+        ResamplerQuality::High => SampleRateConversionQuality::High,
+        ResamplerQuality::Best => SampleRateConversionQuality::Best,
+    }
+}
+
+/// Open the VoiceCommunication input stream in the requested sharing mode,
+/// on `device_id` or the default input for None. Exclusive is retried in
+/// shared mode when the device refuses it (common on mid-range phones).
+fn open_input_stream(
+    sender: &ChunkSender,
+    requested: SharingMode,
+    processing: Processing,
+    device_id: Option<i32>,
+    quality: SampleRateConversionQuality,
+) -> oboe::Result<AudioStreamAsync<Input, InputHandler>> {
+    let open = |sharing_mode| {
+        AudioStreamBuilder::default()
+            .set_direction::<Input>()
+            .set_device_id(device_id.unwrap_or(UNSPECIFIED_DEVICE))
+            .set_usage(usage(processing))
+            .set_input_preset(match processing {
+                Processing::Voice => InputPreset::VoiceCommunication,
+                // No AEC, noise suppression or AGC, and supported everywhere
+                // unlike Unprocessed
+                Processing::Raw => InputPreset::VoiceRecognition,
+            })
+            .set_performance_mode(PerformanceMode::LowLatency)
+            .set_sharing_mode(sharing_mode)
+            .set_sample_rate(STREAM_SAMPLE_RATE)
+            .set_sample_rate_conversion_quality(quality)
+            .set_format::<f32>()
+            .set_mono()
+            .set_callback(InputHandler {
+                sender: Some(sender.clone()),
+            })
+            .open_stream()
+    };
+
+    match requested {
+        SharingMode::Exclusive => open(SharingMode::Exclusive).or_else(|e| {
+            tracing::warn!("Oboe exclusive input open failed ({e:?}), retrying shared");
+            open(SharingMode::Shared)
+        }),
+        SharingMode::Shared => open(SharingMode::Shared),
+    }
+}
+
+/// Open the playback stream that feeds the AEC reference, with the same
+/// sharing mode and fallback as the input.
+#[allow(clippy::too_many_arguments)]
+fn open_output_stream(
+    playback_buffer: &Arc<Mutex<PlaybackBuffer>>,
+    playback_level: &Arc<PlaybackLevel>,
+    playback_mute: &Arc<PlaybackMute>,
+    render_source: Option<&RenderSource>,
+    requested: SharingMode,
+    processing: Processing,
+    device_id: Option<i32>,
+    quality: SampleRateConversionQuality,
+) -> oboe::Result<AudioStreamAsync<Output, OutputHandler>> {
+    let open = |sharing_mode| {
+        AudioStreamBuilder::default()
+            .set_direction::<Output>()
+            .set_device_id(device_id.unwrap_or(UNSPECIFIED_DEVICE))
+            .set_usage(usage(processing))
+            .set_performance_mode(PerformanceMode::LowLatency)
+            .set_sharing_mode(sharing_mode)
+            .set_sample_rate(STREAM_SAMPLE_RATE)
+            .set_sample_rate_conversion_quality(quality)
+            .set_format::<f32>()
+            .set_mono()
+            .set_callback(OutputHandler {
+                playback_buffer: playback_buffer.clone(),
+                playback_level: playback_level.clone(),
+                ramp: MuteRamp::new(playback_mute.clone(), STREAM_SAMPLE_RATE as u32),
+                render_source: render_source.cloned(),
+                render: None,
+            })
+            .open_stream()
+    };
+
+    match requested {
+        SharingMode::Exclusive => open(SharingMode::Exclusive).or_else(|e| {
+            tracing::warn!("Oboe exclusive output open failed ({e:?}), retrying shared");
+            open(SharingMode::Shared)
+        }),
+        SharingMode::Shared => open(SharingMode::Shared),
+    }
+}
+
+/// The rate AAudio or OpenSL ES opens a VoiceCommunication input at when
+/// none is asked for, found by opening one without starting it. The
+/// per-device rate list is only reachable through Java.
+pub fn supported_sample_rates() -> Result<Vec<u32>, AecError> {
+    let stream = probe_input_stream()?;
+    Ok(vec![stream.get_sample_rate() as u32])
+}
+
+/// Frames per burst of a voice input stream, which capture delivers one
+/// of per callback. Oboe's default when no stream opens.
+pub fn default_buffer_frames() -> usize {
+    match probe_input_stream() {
+        Ok(mut stream) => stream.get_frames_per_burst() as usize,
+        Err(_) => DefaultStreamValues::get_frames_per_burst() as usize,
+    }
+}
+
+/// An unstarted input stream opened as capture would, without asking for
+/// a rate, to read what the device settles on.
+fn probe_input_stream() -> Result<AudioStreamSync<Input, (f32, Mono)>, AecError> {
+    AudioStreamBuilder::default()
+        .set_direction::<Input>()
+        .set_input_preset(InputPreset::VoiceCommunication)
+        .set_performance_mode(PerformanceMode::LowLatency)
+        .set_format::<f32>()
+        .set_mono()
+        .open_stream()
+        .map_err(|e| oboe_error("Oboe input stream open failed", e))
+}
+
+/// Keep the Oboe result code of a failed stream call alongside the context.
+fn oboe_error(context: &str, e: oboe::Error) -> AecError {
+    AecError::OsError {
+        message: format!("{context}: {e:?}"),
+        code: e as i64,
+    }
+}
+
+/// VoiceCommunication routes both streams through the AEC; raw capture
+/// plays as ordinary media.
+fn usage(processing: Processing) -> Usage {
+    match processing {
+        Processing::Voice => Usage::VoiceCommunication,
+        Processing::Raw => Usage::Media,
+    }
+}
+
+/// Rate both Oboe streams are opened at, so the AEC reference and the
+/// capture share one clock
 const STREAM_SAMPLE_RATE: i32 = 48000;
 
+/// oboe::kUnspecified, which leaves routing to the OS
+const UNSPECIFIED_DEVICE: i32 = 0;
+
 /// Create Android Oboe capture backend with hardware AEC.
 /// Spawns a dedicated OS thread that owns both input and output audio streams.
-/// Returns (sample_rate, buffer_size).
+/// Returns the mono stream format.
 pub fn create_backend(
-    public_sender: flume::Sender<Vec<f32>>,
+    config: &AecConfig,
+    public_sender: ChunkSender,
     playback_rx: flume::Receiver<PlaybackRequest>,
-) -> Result<(u32, usize), AecError> {
-    let playback_buffer: Arc<Mutex<Vec<f32>>> = Arc::new(Mutex::new(Vec::with_capacity(48000)));
+    playback_level: Arc<PlaybackLevel>,
+    playback_mute: Arc<PlaybackMute>,
+) -> Result<StreamInfo, AecError> {
+    if !keeps_channels(config.channels)
+        && matches!(config.downmix, DownmixMode::SelectChannel(index) if index > 0)
+    {
+        tracing::warn!("Oboe input is opened mono, ignoring SelectChannel");
+    }
+
+    let playback_buffer = Arc::new(Mutex::new(PlaybackBuffer {
+        samples: Vec::with_capacity(48000),
+        markers: PlaybackMarkers::default(),
+    }));
     let playback_buffer_for_thread = playback_buffer.clone();
+    let level_for_thread = playback_level.clone();
+    let max_queued = config
+        .playback_buffer_max
+        .map(|max| (max.as_secs_f64() * STREAM_SAMPLE_RATE as f64) as usize);
 
-    let (callback_tx, callback_rx) = flume::bounded::<Vec<f32>>(32);
-    let (meta_tx, meta_rx) = flume::bounded::<Result<(u32, usize), AecError>>(1);
+    let (callback_tx, callback_rx) = flume::bounded::<Chunk>(32);
+    let callback_tx = ChunkSender::new(callback_tx, &callback_rx, config.overflow_policy);
+    let (meta_tx, meta_rx) = flume::bounded::<Result<StreamInfo, AecError>>(1);
+    let requested = match config.sharing_mode {
+        crate::SharingMode::Exclusive => SharingMode::Exclusive,
+        crate::SharingMode::Shared => SharingMode::Shared,
+    };
+    let processing = config.processing;
+    let bluetooth_sco = config.bluetooth_sco;
+    let quality = conversion_quality(config.device_resampler_quality);
+    let render_source = config.render_source.clone();
+    let target_frames =
+        LatencyPlan::new(config.latency_target).device_buffer_frames(STREAM_SAMPLE_RATE as u32);
 
-    std::thread::Builder::new()
-        .name("android-playback".to_string())
-        .spawn(move || {
-            let target_rate = STREAM_SAMPLE_RATE as u32;
-            while let Ok(request) = playback_rx.recv() {
-                let samples = resample_linear(&request.samples, request.sample_rate, target_rate);
-                if let Ok(mut buffer) = playback_buffer_for_thread.lock() {
-                    buffer.extend(samples);
+    // The output pulls from the render callback instead, so dropping the
+    // receiver makes play_audio fail
+    let playback_rate = render_source.is_none().then_some(STREAM_SAMPLE_RATE as u32);
+    if render_source.is_some() {
+        drop(playback_rx);
+    } else {
+        std::thread::Builder::new()
+            .name(thread_name("android-playback", config))
+            .spawn(move || {
+                let target_rate = STREAM_SAMPLE_RATE as u32;
+                while let Ok(request) = playback_rx.recv() {
+                    let samples =
+                        resample_linear(&request.samples, request.sample_rate, target_rate);
+                    if let Ok(mut buffer) = playback_buffer_for_thread.lock() {
+                        buffer.markers.append(samples.len(), request.done);
+                        buffer.samples.extend(samples);
+                        // Drop the oldest audio so a caller outpacing the output
+                        // can't grow the buffer without bound
+                        if let Some(max) = max_queued {
+                            let excess = buffer.samples.len().saturating_sub(max);
+                            if excess > 0 {
+                                tracing::warn!("playback buffer full, dropped {excess} samples");
+                                buffer.samples.drain(..excess);
+                                buffer.markers.consume(excess);
+                            }
+                        }
+                        level_for_thread.store(buffer.samples.len(), target_rate);
+                    }
                 }
-            }
-        })
-        .map_err(|e| AecError::BackendError(format!("failed to spawn playback thread: {e:?}")))?;
+            })
+            .map_err(|e| {
+                AecError::BackendError(format!("failed to spawn playback thread: {e:?}"))
+            })?;
+    }
 
     std::thread::Builder::new()
-        .name("android-audio".to_string())
+        .name(thread_name("android-audio", config))
         .spawn(move || {
-            let input_handler = InputHandler {
-                sender: callback_tx,
+            // Held until the streams close, which drop before it
+            let sco = match bluetooth_sco.then(ScoLink::start).transpose() {
+                Ok(sco) => sco,
+                Err(e) => {
+                    let _ = meta_tx.send(Err(e));
+                    return;
+                }
             };
-            let output_handler = OutputHandler { playback_buffer };
-
-            let mut input_stream = match AudioStreamBuilder::default()
-                .set_direction::<Input>()
-                .set_usage(Usage::VoiceCommunication)
-                .set_input_preset(InputPreset::VoiceCommunication)
-                .set_performance_mode(PerformanceMode::LowLatency)
-                .set_sharing_mode(SharingMode::Exclusive)
-                .set_sample_rate(STREAM_SAMPLE_RATE)
-                .set_sample_rate_conversion_quality(SampleRateConversionQuality::Medium)
-                .set_format::<f32>()
-                .set_mono()
-                .set_callback(input_handler)
-                .open_stream()
-            {
+            let input_device = sco.as_ref().map(|sco| sco.input_id);
+            let output_device = sco.as_ref().and_then(|sco| sco.output_id);
+
+            let input_stream =
+                open_input_stream(&callback_tx, requested, processing, input_device, quality);
+            // The stream's callback now owns the only sender, so closing the
+            // stream disconnects `callback_rx`
+            drop(callback_tx);
+            let mut input_stream = match input_stream {
                 Ok(s) => s,
                 Err(e) => {
-                    let _ = meta_tx.send(Err(AecError::BackendError(format!(
-                        "Oboe input stream open failed: {e:?}"
-                    ))));
+                    let _ = meta_tx.send(Err(oboe_error("Oboe input stream open failed", e)));
                     return;
                 }
             };
 
-            let mut output_stream = match AudioStreamBuilder::default()
-                .set_direction::<Output>()
-                .set_usage(Usage::VoiceCommunication)
-                .set_performance_mode(PerformanceMode::LowLatency)
-                .set_sharing_mode(SharingMode::Exclusive)
-                .set_sample_rate(STREAM_SAMPLE_RATE)
-                .set_sample_rate_conversion_quality(SampleRateConversionQuality::Medium)
-                .set_format::<f32>()
-                .set_mono()
-                .set_callback(output_handler)
-                .open_stream()
-            {
+            let mut output_stream = match open_output_stream(
+                &playback_buffer,
+                &playback_level,
+                &playback_mute,
+                render_source.as_ref(),
+                requested,
+                processing,
+                output_device,
+                quality,
+            ) {
                 Ok(s) => s,
                 Err(e) => {
-                    let _ = meta_tx.send(Err(AecError::BackendError(format!(
-                        "Oboe output stream open failed: {e:?}"
-                    ))));
+                    let _ = meta_tx.send(Err(oboe_error("Oboe output stream open failed", e)));
                     return;
                 }
             };
 
+            let sharing_mode = match input_stream.get_sharing_mode() {
+                SharingMode::Exclusive => crate::SharingMode::Exclusive,
+                SharingMode::Shared => crate::SharingMode::Shared,
+            };
+            tracing::info!(
+                "Oboe streams open: input {:?}, output {:?}",
+                input_stream.get_sharing_mode(),
+                output_stream.get_sharing_mode()
+            );
+
+            // Input is delivered per burst; the output buffer sets how far
+            // the AEC reference runs ahead of what the speaker plays
+            if let Some(frames) = target_frames {
+                match output_stream.set_buffer_size_in_frames(frames as i32) {
+                    Ok(granted) => tracing::info!("Oboe output buffer {granted} frames"),
+                    Err(e) => tracing::warn!("Oboe output buffer size rejected ({e:?})"),
+                }
+            }
+
             let sample_rate = input_stream.get_sample_rate() as u32;
             let buffer_size = input_stream.get_frames_per_burst() as usize;
 
             if let Err(e) = input_stream.start() {
-                let _ = meta_tx.send(Err(AecError::BackendError(format!(
-                    "Oboe input stream start failed: {e:?}"
-                ))));
+                let _ = meta_tx.send(Err(oboe_error("Oboe input stream start failed", e)));
                 return;
             }
 
             if let Err(e) = output_stream.start() {
                 let _ = input_stream.stop();
-                let _ = meta_tx.send(Err(AecError::BackendError(format!(
-                    "Oboe output stream start failed: {e:?}"
-                ))));
+                let _ = meta_tx.send(Err(oboe_error("Oboe output stream start failed", e)));
                 return;
             }
 
-            let _ = meta_tx.send(Ok((sample_rate, buffer_size)));
+            let _ = meta_tx.send(Ok(StreamInfo {
+                sample_rate,
+                buffer_size,
+                channels: 1,
+                playback_rate,
+                sharing_mode: Some(sharing_mode),
+                backend: BACKEND_NAME,
+                aec: processing == Processing::Voice,
+            }));
 
-            while let Ok(samples) = callback_rx.recv() {
-                if public_sender.send(samples).is_err() {
+            while let Ok(chunk) = callback_rx.recv() {
+                if !public_sender.forward(chunk) {
                     break;
                 }
             }
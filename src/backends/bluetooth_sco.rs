@@ -0,0 +1,308 @@
+//! Bluetooth SCO routing for `AecConfig::bluetooth_sco`. A headset's
+//! microphone is only reachable over SCO, the hands-free voice link, which
+//! the Java AudioManager has to bring up before an Oboe stream can open on
+//! it. Android doesn't do that for native streams.
+
+use std::time::{Duration, Instant};
+
+use jni::objects::{GlobalRef, JObject, JValue};
+use jni::{JNIEnv, JavaVM};
+use oboe::{AudioDeviceDirection, AudioDeviceInfo, AudioDeviceType};
+
+use crate::AecError;
+
+/// How long a headset gets to bring the link up
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Interval the link state is checked at while waiting
+const CONNECT_POLL: Duration = Duration::from_millis(50);
+
+/// setCommunicationDevice replaced startBluetoothSco in Android 12
+const SDK_COMMUNICATION_DEVICE: i32 = 31;
+// AudioManager.MODE_IN_COMMUNICATION
+const MODE_IN_COMMUNICATION: i32 = 3;
+// AudioDeviceInfo.TYPE_BLUETOOTH_SCO
+const TYPE_BLUETOOTH_SCO: i32 = 7;
+// AudioManager.SCO_AUDIO_STATE_CONNECTED
+const SCO_AUDIO_STATE_CONNECTED: i32 = 1;
+const ACTION_SCO_AUDIO_STATE_UPDATED: &str = "android.media.ACTION_SCO_AUDIO_STATE_UPDATED";
+const EXTRA_SCO_AUDIO_STATE: &str = "android.media.extra.SCO_AUDIO_STATE";
+
+/// An SCO link held up for capture. Dropping it releases the link and
+/// restores the audio mode it replaced.
+pub(crate) struct ScoLink {
+    vm: JavaVM,
+    /// The app context, which the SCO state broadcast is read through
+    context: GlobalRef,
+    audio_manager: GlobalRef,
+    sdk: i32,
+    previous_mode: i32,
+    /// Oboe device ID of the headset microphone
+    pub input_id: i32,
+    /// Oboe device ID of the headset speaker, so the echo reference plays
+    /// where the microphone hears it. None if the OS doesn't list one.
+    pub output_id: Option<i32>,
+}
+
+impl ScoLink {
+    /// Bring the SCO link up and wait for it to connect. `DeviceUnavailable`
+    /// when no headset does within `CONNECT_TIMEOUT`; `PermissionDenied`
+    /// without MODIFY_AUDIO_SETTINGS.
+    pub fn start() -> Result<Self, AecError> {
+        let android = ndk_context::android_context();
+        let vm = unsafe { JavaVM::from_raw(android.vm().cast()) }
+            .map_err(|e| AecError::BackendError(format!("no Java VM for Bluetooth SCO: {e}")))?;
+        let (context, audio_manager, sdk, previous_mode) = with_env(&vm, |env| {
+            let context = unsafe { JObject::from_raw(android.context().cast()) };
+            let name = env.new_string("audio")?;
+            let audio_manager = env
+                .call_method(
+                    &context,
+                    "getSystemService",
+                    "(Ljava/lang/String;)Ljava/lang/Object;",
+                    &[JValue::Object(&name)],
+                )?
+                .l()?;
+            let sdk = env
+                .get_static_field("android/os/Build$VERSION", "SDK_INT", "I")?
+                .i()?;
+            let previous_mode = env
+                .call_method(&audio_manager, "getMode", "()I", &[])?
+                .i()?;
+            Ok((
+                env.new_global_ref(context)?,
+                env.new_global_ref(audio_manager)?,
+                sdk,
+                previous_mode,
+            ))
+        })?;
+
+        // Dropped on any failure below, which undoes whatever was started
+        let mut link = Self {
+            vm,
+            context,
+            audio_manager,
+            sdk,
+            previous_mode,
+            input_id: 0,
+            output_id: None,
+        };
+        link.request()?;
+
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        while !link.connected()? {
+            if Instant::now() >= deadline {
+                tracing::warn!("Bluetooth SCO did not connect within {CONNECT_TIMEOUT:?}");
+                return Err(AecError::DeviceUnavailable);
+            }
+            std::thread::sleep(CONNECT_POLL);
+        }
+
+        link.input_id =
+            sco_device(AudioDeviceDirection::Input)?.ok_or(AecError::DeviceUnavailable)?;
+        link.output_id = sco_device(AudioDeviceDirection::Output)?;
+        tracing::info!(
+            "Bluetooth SCO connected: input device {}, output device {:?}",
+            link.input_id,
+            link.output_id
+        );
+        Ok(link)
+    }
+
+    /// Switch to communication mode and ask for the headset. Android 12
+    /// picks it as the communication device; earlier versions start SCO.
+    fn request(&self) -> Result<(), AecError> {
+        let audio_manager = self.audio_manager.as_obj();
+        let sdk = self.sdk;
+        let requested = with_env(&self.vm, |env| {
+            env.call_method(
+                audio_manager,
+                "setMode",
+                "(I)V",
+                &[JValue::Int(MODE_IN_COMMUNICATION)],
+            )?;
+
+            if sdk < SDK_COMMUNICATION_DEVICE {
+                let available = env
+                    .call_method(audio_manager, "isBluetoothScoAvailableOffCall", "()Z", &[])?
+                    .z()?;
+                if available {
+                    env.call_method(audio_manager, "startBluetoothSco", "()V", &[])?;
+                    env.call_method(
+                        audio_manager,
+                        "setBluetoothScoOn",
+                        "(Z)V",
+                        &[JValue::Bool(1)],
+                    )?;
+                }
+                return Ok(available);
+            }
+
+            let devices = env
+                .call_method(
+                    audio_manager,
+                    "getAvailableCommunicationDevices",
+                    "()Ljava/util/List;",
+                    &[],
+                )?
+                .l()?;
+            let count = env.call_method(&devices, "size", "()I", &[])?.i()?;
+            for index in 0..count {
+                let device = env
+                    .call_method(
+                        &devices,
+                        "get",
+                        "(I)Ljava/lang/Object;",
+                        &[JValue::Int(index)],
+                    )?
+                    .l()?;
+                if env.call_method(&device, "getType", "()I", &[])?.i()? == TYPE_BLUETOOTH_SCO {
+                    return env
+                        .call_method(
+                            audio_manager,
+                            "setCommunicationDevice",
+                            "(Landroid/media/AudioDeviceInfo;)Z",
+                            &[JValue::Object(&device)],
+                        )?
+                        .z();
+                }
+            }
+            Ok(false)
+        })?;
+
+        if requested {
+            Ok(())
+        } else {
+            tracing::warn!("no Bluetooth SCO headset available");
+            Err(AecError::DeviceUnavailable)
+        }
+    }
+
+    /// Whether the link is up: the headset is the communication device, or
+    /// before Android 12, the sticky SCO state broadcast says connected.
+    fn connected(&self) -> Result<bool, AecError> {
+        let audio_manager = self.audio_manager.as_obj();
+        let context = self.context.as_obj();
+        let sdk = self.sdk;
+        with_env(&self.vm, |env| {
+            if sdk >= SDK_COMMUNICATION_DEVICE {
+                let device = env
+                    .call_method(
+                        audio_manager,
+                        "getCommunicationDevice",
+                        "()Landroid/media/AudioDeviceInfo;",
+                        &[],
+                    )?
+                    .l()?;
+                if device.is_null() {
+                    return Ok(false);
+                }
+                return Ok(
+                    env.call_method(&device, "getType", "()I", &[])?.i()? == TYPE_BLUETOOTH_SCO
+                );
+            }
+
+            // A null receiver only reads the last sticky broadcast, which
+            // stands in for the state callback a Java app would register
+            let action = env.new_string(ACTION_SCO_AUDIO_STATE_UPDATED)?;
+            let filter = env.new_object(
+                "android/content/IntentFilter",
+                "(Ljava/lang/String;)V",
+                &[JValue::Object(&action)],
+            )?;
+            let intent = env
+                .call_method(
+                    context,
+                    "registerReceiver",
+                    "(Landroid/content/BroadcastReceiver;Landroid/content/IntentFilter;)Landroid/content/Intent;",
+                    &[JValue::Object(&JObject::null()), JValue::Object(&filter)],
+                )?
+                .l()?;
+            if intent.is_null() {
+                return Ok(false);
+            }
+            let extra = env.new_string(EXTRA_SCO_AUDIO_STATE)?;
+            let state = env
+                .call_method(
+                    &intent,
+                    "getIntExtra",
+                    "(Ljava/lang/String;I)I",
+                    &[JValue::Object(&extra), JValue::Int(-1)],
+                )?
+                .i()?;
+            Ok(state == SCO_AUDIO_STATE_CONNECTED)
+        })
+    }
+}
+
+impl Drop for ScoLink {
+    fn drop(&mut self) {
+        let audio_manager = self.audio_manager.as_obj();
+        let (sdk, previous_mode) = (self.sdk, self.previous_mode);
+        let released = with_env(&self.vm, |env| {
+            if sdk >= SDK_COMMUNICATION_DEVICE {
+                env.call_method(audio_manager, "clearCommunicationDevice", "()V", &[])?;
+            } else {
+                env.call_method(
+                    audio_manager,
+                    "setBluetoothScoOn",
+                    "(Z)V",
+                    &[JValue::Bool(0)],
+                )?;
+                env.call_method(audio_manager, "stopBluetoothSco", "()V", &[])?;
+            }
+            env.call_method(
+                audio_manager,
+                "setMode",
+                "(I)V",
+                &[JValue::Int(previous_mode)],
+            )?;
+            Ok(())
+        });
+        if let Err(e) = released {
+            tracing::warn!("Bluetooth SCO not released: {e}");
+        }
+    }
+}
+
+/// Oboe ID of the SCO device facing `direction`, once the link is up.
+fn sco_device(direction: AudioDeviceDirection) -> Result<Option<i32>, AecError> {
+    let devices = AudioDeviceInfo::request(direction)
+        .map_err(|e| AecError::BackendError(format!("failed to list audio devices: {e}")))?;
+    Ok(devices
+        .iter()
+        .find(|device| device.device_type == AudioDeviceType::BluetoothSCO)
+        .map(|device| device.id))
+}
+
+/// Run `f` on this thread attached to the VM. A Java exception is cleared
+/// and mapped: `PermissionDenied` for a SecurityException, otherwise
+/// `BackendError`.
+fn with_env<R>(
+    vm: &JavaVM,
+    f: impl for<'local> FnOnce(&mut JNIEnv<'local>) -> jni::errors::Result<R>,
+) -> Result<R, AecError> {
+    let mut env = vm
+        .attach_current_thread()
+        .map_err(|e| AecError::BackendError(format!("failed to attach to the Java VM: {e}")))?;
+    let e = match f(&mut env) {
+        Ok(value) => return Ok(value),
+        Err(e) => e,
+    };
+
+    let mut denied = false;
+    if let Ok(exception) = env.exception_occurred() {
+        if !exception.is_null() {
+            let _ = env.exception_clear();
+            denied = env
+                .is_instance_of(&exception, "java/lang/SecurityException")
+                .unwrap_or(false);
+        }
+    }
+    if denied {
+        Err(AecError::PermissionDenied)
+    } else {
+        Err(AecError::BackendError(format!(
+            "Bluetooth SCO call failed: {e}"
+        )))
+    }
+}
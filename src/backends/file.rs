@@ -0,0 +1,110 @@
+use std::time::{Duration, Instant};
+
+use crate::backends::{
+    check_downmix, keeps_channels, thread_name, to_mono, ChunkSender, StreamInfo,
+};
+use crate::latency::LatencyPlan;
+use crate::{AecConfig, AecError, FileFault, FileSource};
+
+/// Reported by `CaptureHandle::backend_name()`
+pub const BACKEND_NAME: &str = "file";
+
+const CHUNK_MS: u32 = 10;
+
+/// Decode the WAV up front and spawn a thread that feeds it to the pipeline
+/// in 10ms chunks, or device-buffer-sized chunks under a latency target.
+/// The thread exits at end of file, at a `FileFault::Disconnect`, or when
+/// the receiver drops.
+pub fn create_backend(
+    source: &FileSource,
+    config: &AecConfig,
+    sender: ChunkSender,
+) -> Result<StreamInfo, AecError> {
+    let reader = hound::WavReader::open(&source.path).map_err(|e| {
+        AecError::BackendError(format!("failed to open {}: {e}", source.path.display()))
+    })?;
+    let spec = reader.spec();
+    if spec.channels == 0 || spec.sample_rate == 0 {
+        return Err(AecError::BackendError(format!(
+            "{} has no channels or a zero sample rate",
+            source.path.display()
+        )));
+    }
+
+    let samples = read_samples(reader)?;
+    let (samples, channels) = if keeps_channels(config.channels) {
+        (samples, spec.channels)
+    } else {
+        check_downmix(config.downmix, spec.channels as usize)?;
+        (to_mono(&samples, spec.channels as usize, config.downmix), 1)
+    };
+
+    let frames_per_chunk = LatencyPlan::new(config.latency_target)
+        .device_buffer_frames(spec.sample_rate)
+        .unwrap_or((spec.sample_rate * CHUNK_MS / 1000) as usize)
+        .max(1);
+    let chunk_len = frames_per_chunk * channels as usize;
+    let realtime = source.realtime;
+    let sample_rate = spec.sample_rate;
+    // Chunks delivered before the simulated unplug, counting the one that
+    // reaches it
+    let disconnect_after = match source.fault {
+        Some(FileFault::Disconnect(at)) => {
+            let frames = (at.as_secs_f64() * sample_rate as f64) as usize;
+            Some(frames.div_ceil(frames_per_chunk))
+        }
+        _ => None,
+    };
+
+    std::thread::Builder::new()
+        .name(thread_name("sys-voice-file", config))
+        .spawn(move || {
+            let chunk_duration =
+                Duration::from_secs_f64(frames_per_chunk as f64 / sample_rate as f64);
+            let start = Instant::now();
+
+            for (index, chunk) in samples.chunks(chunk_len).enumerate() {
+                if disconnect_after == Some(index) {
+                    tracing::info!("file input: simulating a disconnect");
+                    break;
+                }
+                if realtime {
+                    let due = start + chunk_duration * index as u32;
+                    if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                        std::thread::sleep(wait);
+                    }
+                }
+
+                if !sender.send(chunk.to_vec()) {
+                    break;
+                }
+            }
+        })
+        .map_err(|e| AecError::BackendError(format!("failed to spawn file thread: {e:?}")))?;
+
+    Ok(StreamInfo {
+        sample_rate: spec.sample_rate,
+        buffer_size: frames_per_chunk,
+        channels,
+        playback_rate: None,
+        sharing_mode: None,
+        backend: BACKEND_NAME,
+        aec: false,
+    })
+}
+
+/// Interleaved samples scaled to [-1.0, 1.0] regardless of the file's format.
+fn read_samples<R: std::io::Read>(reader: hound::WavReader<R>) -> Result<Vec<f32>, AecError> {
+    let spec = reader.spec();
+    let samples: Result<Vec<f32>, hound::Error> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.into_samples::<f32>().collect(),
+        hound::SampleFormat::Int => {
+            let scale = (1_i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .into_samples::<i32>()
+                .map(|s| s.map(|s| s as f32 / scale))
+                .collect()
+        }
+    };
+    samples.map_err(|e| AecError::BackendError(format!("failed to read WAV samples: {e}")))
+}
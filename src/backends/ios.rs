@@ -1,14 +1,23 @@
-use crate::backends::PlaybackRequest;
-use crate::AecError;
+use crate::backends::{
+    keeps_channels, thread_name, ChunkSender, DeviceClock, MuteRamp, NativeSlot, PlaybackMarkers,
+    PlaybackMute, PlaybackRequest, StreamInfo,
+};
+use crate::latency::LatencyPlan;
+use crate::raw_capture::{RawCapture, RawFormat};
+use crate::{
+    AecConfig, AecError, AecSupport, CaptureStatus, DownmixMode, Ducking, NativeUnit, Processing,
+};
+use block2::RcBlock;
 use flume::{Receiver, Sender};
 use objc2::rc::Retained;
-use objc2::runtime::AnyObject;
+use objc2::runtime::{AnyObject, Bool};
 use objc2::{class, msg_send};
 use objc2_foundation::{NSError, NSString};
 use std::ffi::c_void;
 use std::ptr;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 
 // ============================================================================
 // AudioToolbox FFI Types and Constants
@@ -81,9 +90,22 @@ struct AURenderCallbackStruct {
     input_proc_ref_con: *mut c_void,
 }
 
+/// AUVoiceIOOtherAudioDuckingConfiguration
+#[repr(C)]
+struct OtherAudioDuckingConfiguration {
+    enable_advanced_ducking: u8,
+    ducking_level: u32,
+}
+
+/// Reported by `CaptureHandle::backend_name()`
+pub const BACKEND_NAME: &str = "audiotoolbox-vpio";
+/// Reported instead of `BACKEND_NAME` for `Processing::Raw`
+pub const RAW_BACKEND_NAME: &str = "audiotoolbox-remoteio";
+
 // Audio Unit Types
 const K_AUDIO_UNIT_TYPE_OUTPUT: u32 = 0x61756f75; // 'auou'
 const K_AUDIO_UNIT_SUB_TYPE_VOICE_PROCESSING_IO: u32 = 0x7670696f; // 'vpio'
+const K_AUDIO_UNIT_SUB_TYPE_REMOTE_IO: u32 = 0x72696f63; // 'rioc'
 const K_AUDIO_UNIT_MANUFACTURER_APPLE: u32 = 0x6170706c; // 'appl'
 
 // Audio Unit Properties
@@ -92,6 +114,8 @@ const K_AUDIO_OUTPUT_UNIT_PROPERTY_SET_INPUT_CALLBACK: u32 = 2005;
 const K_AUDIO_UNIT_PROPERTY_STREAM_FORMAT: u32 = 8;
 const K_AUDIO_UNIT_PROPERTY_SET_RENDER_CALLBACK: u32 = 23;
 const K_AU_VOICE_IO_PROPERTY_BYPASS_VOICE_PROCESSING: u32 = 2100;
+// iOS 17+
+const K_AU_VOICE_IO_PROPERTY_OTHER_AUDIO_DUCKING_CONFIGURATION: u32 = 2108;
 
 // Audio Unit Scopes
 const K_AUDIO_UNIT_SCOPE_INPUT: u32 = 1;
@@ -101,12 +125,18 @@ const K_AUDIO_UNIT_SCOPE_GLOBAL: u32 = 0;
 // Audio Format
 const K_AUDIO_FORMAT_LINEAR_PCM: u32 = 0x6c70636d; // 'lpcm'
 const K_AUDIO_FORMAT_FLAG_IS_FLOAT: u32 = 1 << 0;
+const K_AUDIO_FORMAT_FLAG_IS_SIGNED_INTEGER: u32 = 1 << 2;
 const K_AUDIO_FORMAT_FLAG_IS_PACKED: u32 = 1 << 3;
 const K_AUDIO_FORMAT_FLAG_IS_NON_INTERLEAVED: u32 = 1 << 5;
 
+// AudioTimeStamp flags
+const K_AUDIO_TIME_STAMP_SAMPLE_TIME_VALID: u32 = 1 << 0;
+
 // Audio Session
 const AV_AUDIO_SESSION_CATEGORY_PLAY_AND_RECORD: &str = "AVAudioSessionCategoryPlayAndRecord";
 const AV_AUDIO_SESSION_MODE_VIDEO_CHAT: &str = "AVAudioSessionModeVideoChat";
+// Minimal system signal processing, for raw capture
+const AV_AUDIO_SESSION_MODE_MEASUREMENT: &str = "AVAudioSessionModeMeasurement";
 
 // Audio session options bitmask:
 // - 0x1 = AVAudioSessionCategoryOptionDefaultToSpeaker
@@ -114,7 +144,13 @@ const AV_AUDIO_SESSION_MODE_VIDEO_CHAT: &str = "AVAudioSessionModeVideoChat";
 // - 0x20 = AVAudioSessionCategoryOptionAllowBluetoothA2DP
 const AV_AUDIO_SESSION_OPTIONS: u64 = 0x1 | 0x4 | 0x20;
 
-const BUFFER_SIZE: u32 = 1024;
+// Interruption notification and its AVAudioSessionInterruptionType values
+const AV_AUDIO_SESSION_INTERRUPTION_NOTIFICATION: &str = "AVAudioSessionInterruptionNotification";
+const AV_AUDIO_SESSION_INTERRUPTION_TYPE_KEY: &str = "AVAudioSessionInterruptionTypeKey";
+const AV_AUDIO_SESSION_INTERRUPTION_TYPE_BEGAN: usize = 1;
+const AV_AUDIO_SESSION_INTERRUPTION_TYPE_ENDED: usize = 0;
+
+pub const BUFFER_SIZE: u32 = 1024;
 const SAMPLE_RATE: f64 = 48000.0;
 
 // ============================================================================
@@ -176,14 +212,26 @@ extern "C" {
 
 const MAX_FRAMES_PER_CALLBACK: usize = 4096;
 
+/// Samples waiting for the render callback
+struct PlaybackBuffer {
+    samples: Vec<f32>,
+    markers: PlaybackMarkers,
+    ramp: MuteRamp,
+}
+
 struct VPIOContext {
     audio_unit: AudioComponentInstance,
-    capture_sender: Sender<Vec<f32>>,
+    capture_sender: ChunkSender,
     playback_receiver: Arc<Mutex<Receiver<PlaybackRequest>>>,
-    playback_buffer: Arc<Mutex<Vec<f32>>>,
-    // Pre-allocated scratch buffer to avoid heap allocation in callback
+    playback_buffer: Arc<Mutex<PlaybackBuffer>>,
+    // Pre-allocated scratch buffer to avoid heap allocation in callback.
+    // Sized for f32 frames, which also fits the smaller i16 frames.
     input_scratch: std::sync::Mutex<Vec<f32>>,
+    /// Sample times of earlier input callbacks, to find input the OS lost
+    input_clock: std::sync::Mutex<DeviceClock>,
+    sample_format: PcmFormat,
     sample_rate: f64,
+    raw_capture: Option<RawCapture>,
 }
 
 unsafe impl Send for VPIOContext {}
@@ -193,50 +241,101 @@ unsafe impl Sync for VPIOContext {}
 // Public API
 // ============================================================================
 
-/// Create iOS VPIO (Voice Processing I/O) capture backend.
+/// Create iOS VPIO (Voice Processing I/O) capture backend, or a plain
+/// RemoteIO one for `Processing::Raw`.
 /// Uses low-level Audio Unit for reliable AEC.
-/// Returns (sample_rate, buffer_size).
+/// Returns the mono stream format.
 pub fn create_backend(
-    public_sender: Sender<Vec<f32>>,
+    config: &AecConfig,
+    public_sender: ChunkSender,
     playback_rx: Receiver<PlaybackRequest>,
-) -> Result<(u32, usize), AecError> {
+    playback_mute: Arc<PlaybackMute>,
+    status_tx: Sender<CaptureStatus>,
+    native: Arc<NativeSlot>,
+) -> Result<StreamInfo, AecError> {
+    let preferred_buffer = LatencyPlan::new(config.latency_target).device_buffer;
+
+    let raw = config.processing == Processing::Raw;
+    if !keeps_channels(config.channels)
+        && matches!(config.downmix, DownmixMode::SelectChannel(index) if index > 0)
+    {
+        eprintln!("[sys-voice] iOS capture is mono, ignoring SelectChannel");
+    }
+
     // Configure audio session first (on main thread context is fine)
-    configure_audio_session()?;
+    configure_audio_session(preferred_buffer, raw)?;
+    let buffer_size = match preferred_buffer {
+        Some(_) => io_buffer_frames(),
+        None => BUFFER_SIZE as usize,
+    };
 
-    // Create VPIO unit
-    let audio_unit = create_vpio_unit()?;
+    // Create VPIO unit, or plain RemoteIO for raw capture
+    let audio_unit = create_io_unit(if raw {
+        &REMOTE_IO_DESCRIPTION
+    } else {
+        &VPIO_DESCRIPTION
+    })?;
 
     // Enable I/O on both buses
     enable_io(audio_unit)?;
 
-    // Set audio format on both buses
-    let format = create_audio_format(SAMPLE_RATE, 1); // Mono
-    set_audio_format(audio_unit, &format)?;
+    // Set audio format on both buses, falling back to 16-bit integer when
+    // the device doesn't accept float
+    let sample_format = match set_audio_format(
+        audio_unit,
+        &create_audio_format(SAMPLE_RATE, 1, PcmFormat::F32),
+    ) {
+        Ok(()) => PcmFormat::F32,
+        Err(e) => {
+            eprintln!("[sys-voice] Float format rejected ({e}), trying 16-bit integer");
+            set_audio_format(
+                audio_unit,
+                &create_audio_format(SAMPLE_RATE, 1, PcmFormat::I16),
+            )?;
+            PcmFormat::I16
+        }
+    };
 
     // Disable voice processing bypass (ensure AEC is ON)
-    let bypass: u32 = 0;
-    let status = unsafe {
-        AudioUnitSetProperty(
-            audio_unit,
-            K_AU_VOICE_IO_PROPERTY_BYPASS_VOICE_PROCESSING,
-            K_AUDIO_UNIT_SCOPE_GLOBAL,
-            0,
-            &bypass as *const u32 as *const c_void,
-            std::mem::size_of::<u32>() as u32,
-        )
-    };
-    if status != 0 {
-        eprintln!("[sys-voice] Warning: Could not set voice processing bypass: {status}");
+    if !raw {
+        let bypass: u32 = 0;
+        let status = unsafe {
+            AudioUnitSetProperty(
+                audio_unit,
+                K_AU_VOICE_IO_PROPERTY_BYPASS_VOICE_PROCESSING,
+                K_AUDIO_UNIT_SCOPE_GLOBAL,
+                0,
+                &bypass as *const u32 as *const c_void,
+                std::mem::size_of::<u32>() as u32,
+            )
+        };
+        if status != 0 {
+            eprintln!("[sys-voice] Warning: Could not set voice processing bypass: {status}");
+        }
+
+        set_other_audio_ducking(audio_unit, config.other_audio_ducking);
     }
 
+    #[cfg(feature = "debug-capture")]
+    let raw_capture = config.raw_capture.clone();
+    #[cfg(not(feature = "debug-capture"))]
+    let raw_capture = None;
+
     // Create context for callbacks
     let context = Box::new(VPIOContext {
         audio_unit,
         capture_sender: public_sender,
         playback_receiver: Arc::new(Mutex::new(playback_rx)),
-        playback_buffer: Arc::new(Mutex::new(Vec::new())),
+        playback_buffer: Arc::new(Mutex::new(PlaybackBuffer {
+            samples: Vec::new(),
+            markers: PlaybackMarkers::default(),
+            ramp: MuteRamp::new(playback_mute, SAMPLE_RATE as u32),
+        })),
         input_scratch: std::sync::Mutex::new(vec![0.0f32; MAX_FRAMES_PER_CALLBACK]),
+        input_clock: std::sync::Mutex::new(DeviceClock::default()),
+        sample_format,
         sample_rate: SAMPLE_RATE,
+        raw_capture,
     });
     let context_ptr = Box::into_raw(context);
 
@@ -252,9 +351,7 @@ pub fn create_backend(
         unsafe {
             let _ = Box::from_raw(context_ptr);
         }
-        return Err(AecError::BackendError(format!(
-            "AudioUnitInitialize failed: {status}"
-        )));
+        return Err(os_error("AudioUnitInitialize failed", status));
     }
 
     // Start the audio unit
@@ -264,20 +361,22 @@ pub fn create_backend(
             AudioUnitUninitialize(audio_unit);
             let _ = Box::from_raw(context_ptr);
         }
-        return Err(AecError::BackendError(format!(
-            "AudioOutputUnitStart failed: {status}"
-        )));
+        return Err(os_error("AudioOutputUnitStart failed", status));
     }
 
-    eprintln!("[sys-voice] VPIO Audio Unit started successfully");
-    eprintln!("[sys-voice] Sample rate: {SAMPLE_RATE} Hz, Buffer size: {BUFFER_SIZE}");
+    eprintln!("[sys-voice] IO Audio Unit started successfully (raw: {raw})");
+    eprintln!("[sys-voice] Sample rate: {SAMPLE_RATE} Hz, Buffer size: {buffer_size}");
 
     // Spawn thread to handle playback requests
     let playback_buffer = unsafe { (*context_ptr).playback_buffer.clone() };
     let playback_receiver = unsafe { (*context_ptr).playback_receiver.clone() };
 
+    // Observer token is not Send; carry it to the playback thread as an address
+    let interruption_observer = observe_interruptions(status_tx) as usize;
+    let native_guard = native.publish(NativeUnit::AudioUnit(audio_unit as *mut c_void));
+
     std::thread::Builder::new()
-        .name("ios-playback".to_string())
+        .name(thread_name("ios-playback", config))
         .spawn(move || {
             let rx = playback_receiver.lock().unwrap();
             while let Ok(request) = rx.recv() {
@@ -285,24 +384,41 @@ pub fn create_backend(
                 let resampled =
                     resample_linear(&request.samples, request.sample_rate as f64, SAMPLE_RATE);
                 let mut buffer = playback_buffer.lock().unwrap();
-                buffer.extend(resampled);
+                buffer.markers.append(resampled.len(), request.done);
+                buffer.samples.extend(resampled);
             }
+
+            // Handle dropped: stop reporting interruptions
+            remove_observer(interruption_observer as *mut AnyObject);
+            drop(native_guard);
         })
         .map_err(|e| AecError::BackendError(format!("Failed to spawn playback thread: {e}")))?;
 
-    Ok((SAMPLE_RATE as u32, BUFFER_SIZE as usize))
+    Ok(StreamInfo {
+        sample_rate: SAMPLE_RATE as u32,
+        buffer_size,
+        channels: 1,
+        playback_rate: Some(SAMPLE_RATE as u32),
+        sharing_mode: None,
+        backend: if raw { RAW_BACKEND_NAME } else { BACKEND_NAME },
+        aec: !raw,
+    })
 }
 
 // ============================================================================
 // Audio Session Configuration
 // ============================================================================
 
-fn configure_audio_session() -> Result<(), AecError> {
+fn configure_audio_session(preferred_buffer: Option<Duration>, raw: bool) -> Result<(), AecError> {
     let session_class = class!(AVAudioSession);
     let session: Retained<AnyObject> = unsafe { msg_send![session_class, sharedInstance] };
 
     let category = NSString::from_str(AV_AUDIO_SESSION_CATEGORY_PLAY_AND_RECORD);
-    let mode = NSString::from_str(AV_AUDIO_SESSION_MODE_VIDEO_CHAT);
+    let mode = NSString::from_str(if raw {
+        AV_AUDIO_SESSION_MODE_MEASUREMENT
+    } else {
+        AV_AUDIO_SESSION_MODE_VIDEO_CHAT
+    });
 
     let mut error: *mut NSError = ptr::null_mut();
 
@@ -324,6 +440,22 @@ fn configure_audio_session() -> Result<(), AecError> {
         )));
     }
 
+    // Only a preference; the session picks the nearest duration it supports
+    if let Some(duration) = preferred_buffer {
+        let mut error: *mut NSError = ptr::null_mut();
+        let accepted: bool = unsafe {
+            msg_send![
+                &session,
+                setPreferredIOBufferDuration: duration.as_secs_f64(),
+                error: &mut error
+            ]
+        };
+        if !accepted {
+            let msg = extract_nserror_message(error);
+            eprintln!("[sys-voice] Warning: Could not set preferred IO buffer duration: {msg}");
+        }
+    }
+
     // Activate session
     let mut error: *mut NSError = ptr::null_mut();
     let activated: bool = unsafe { msg_send![&session, setActive: true, error: &mut error] };
@@ -335,39 +467,217 @@ fn configure_audio_session() -> Result<(), AecError> {
         )));
     }
 
-    eprintln!("[sys-voice] Audio session configured with VideoChat mode and speaker options");
+    eprintln!("[sys-voice] Audio session configured ({mode}) with speaker options");
+    Ok(())
+}
+
+/// Input gain of the audio session, 0.0 to 1.0.
+pub fn hardware_gain() -> Result<f32, AecError> {
+    let session_class = class!(AVAudioSession);
+    let session: Retained<AnyObject> = unsafe { msg_send![session_class, sharedInstance] };
+    let gain: f32 = unsafe { msg_send![&session, inputGain] };
+    Ok(gain)
+}
+
+/// Set the session's input gain, where the current input allows it.
+pub fn set_hardware_gain(gain: f32) -> Result<(), AecError> {
+    let session_class = class!(AVAudioSession);
+    let session: Retained<AnyObject> = unsafe { msg_send![session_class, sharedInstance] };
+    let settable: bool = unsafe { msg_send![&session, isInputGainSettable] };
+    if !settable {
+        return Err(AecError::AecNotSupported);
+    }
+
+    let mut error: *mut NSError = ptr::null_mut();
+    let accepted: bool = unsafe { msg_send![&session, setInputGain: gain, error: &mut error] };
+    if !accepted {
+        let msg = extract_nserror_message(error);
+        return Err(AecError::BackendError(format!(
+            "Failed to set input gain: {msg}"
+        )));
+    }
+    Ok(())
+}
+
+/// The hardware rate the audio session runs at. iOS doesn't list the rates
+/// a route could switch to, and picks between them itself.
+pub fn supported_sample_rates() -> Vec<u32> {
+    let session_class = class!(AVAudioSession);
+    let session: Retained<AnyObject> = unsafe { msg_send![session_class, sharedInstance] };
+    let rate: f64 = unsafe { msg_send![&session, sampleRate] };
+    if rate > 0.0 {
+        vec![rate.round() as u32]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Delay from rendered output to its echo at the input as the audio session
+/// reports it: input and output latency plus an IO buffer each way, which
+/// VoiceProcessingIO aligns its reference over.
+pub fn aec_reference_delay() -> Option<Duration> {
+    let session_class = class!(AVAudioSession);
+    let session: Retained<AnyObject> = unsafe { msg_send![session_class, sharedInstance] };
+    let input: f64 = unsafe { msg_send![&session, inputLatency] };
+    let output: f64 = unsafe { msg_send![&session, outputLatency] };
+    let buffer: f64 = unsafe { msg_send![&session, IOBufferDuration] };
+    let delay = input + output + 2.0 * buffer;
+    (delay > 0.0).then(|| Duration::from_secs_f64(delay))
+}
+
+/// Bypass voice processing on a running VoiceProcessingIO unit, or restore
+/// it. Capture continues either way.
+pub fn set_voice_processing_bypass(unit: *mut c_void, bypass: bool) -> Result<(), AecError> {
+    let value = u32::from(bypass);
+    let status = unsafe {
+        AudioUnitSetProperty(
+            unit as AudioComponentInstance,
+            K_AU_VOICE_IO_PROPERTY_BYPASS_VOICE_PROCESSING,
+            K_AUDIO_UNIT_SCOPE_GLOBAL,
+            0,
+            &value as *const u32 as *const c_void,
+            std::mem::size_of::<u32>() as u32,
+        )
+    };
+    if status != 0 {
+        return Err(os_error("failed to set voice processing bypass", status));
+    }
     Ok(())
 }
 
+/// IO buffer the active session actually granted, in frames.
+fn io_buffer_frames() -> usize {
+    let session_class = class!(AVAudioSession);
+    let session: Retained<AnyObject> = unsafe { msg_send![session_class, sharedInstance] };
+    let duration: f64 = unsafe { msg_send![&session, IOBufferDuration] };
+    (duration * SAMPLE_RATE).round() as usize
+}
+
+/// Forward AVAudioSession interruptions (phone calls, Siri, other apps taking
+/// the session) as `Interrupted`/`Resumed`. Returns the retained observer token.
+fn observe_interruptions(status_tx: Sender<CaptureStatus>) -> *mut AnyObject {
+    let handler = RcBlock::new(move |notification: *mut AnyObject| {
+        let key = NSString::from_str(AV_AUDIO_SESSION_INTERRUPTION_TYPE_KEY);
+        let interruption_type: usize = unsafe {
+            let info: *mut AnyObject = msg_send![notification, userInfo];
+            if info.is_null() {
+                return;
+            }
+            let value: *mut AnyObject = msg_send![info, objectForKey: &*key];
+            if value.is_null() {
+                return;
+            }
+            msg_send![value, unsignedIntegerValue]
+        };
+
+        let status = match interruption_type {
+            AV_AUDIO_SESSION_INTERRUPTION_TYPE_BEGAN => CaptureStatus::Interrupted,
+            AV_AUDIO_SESSION_INTERRUPTION_TYPE_ENDED => CaptureStatus::Resumed,
+            _ => return,
+        };
+        let _ = status_tx.send(status);
+    });
+
+    let name = NSString::from_str(AV_AUDIO_SESSION_INTERRUPTION_NOTIFICATION);
+    let center: Retained<AnyObject> =
+        unsafe { msg_send![class!(NSNotificationCenter), defaultCenter] };
+    let observer: Retained<AnyObject> = unsafe {
+        msg_send![
+            &center,
+            addObserverForName: &*name,
+            object: ptr::null::<AnyObject>(),
+            queue: ptr::null::<AnyObject>(),
+            usingBlock: &*handler
+        ]
+    };
+    Retained::into_raw(observer)
+}
+
+fn remove_observer(observer: *mut AnyObject) {
+    let Some(observer) = (unsafe { Retained::from_raw(observer) }) else {
+        return;
+    };
+    let center: Retained<AnyObject> =
+        unsafe { msg_send![class!(NSNotificationCenter), defaultCenter] };
+    let _: () = unsafe { msg_send![&center, removeObserver: &*observer] };
+}
+
+/// Show the record-permission prompt and resolve with the user's answer.
+/// Resolves immediately if the user has already decided.
+pub async fn request_microphone_permission() -> bool {
+    let (granted_tx, granted_rx) = tokio::sync::oneshot::channel::<bool>();
+
+    // Block and session are not Send, so keep them out of the await
+    {
+        let granted_tx = Mutex::new(Some(granted_tx));
+        let handler = RcBlock::new(move |granted: Bool| {
+            if let Some(tx) = granted_tx.lock().ok().and_then(|mut tx| tx.take()) {
+                let _ = tx.send(granted.as_bool());
+            }
+        });
+
+        let session_class = class!(AVAudioSession);
+        let session: Retained<AnyObject> = unsafe { msg_send![session_class, sharedInstance] };
+        let _: () = unsafe { msg_send![&session, requestRecordPermission: &*handler] };
+    }
+
+    granted_rx.await.unwrap_or(false)
+}
+
+/// Keep the OSStatus of a failed Audio Unit call alongside the context.
+fn os_error(context: &str, status: OSStatus) -> AecError {
+    AecError::OsError {
+        message: context.to_string(),
+        code: status as i64,
+    }
+}
+
 // ============================================================================
 // VPIO Unit Setup
 // ============================================================================
 
-fn create_vpio_unit() -> Result<AudioComponentInstance, AecError> {
-    let desc = AudioComponentDescription {
-        component_type: K_AUDIO_UNIT_TYPE_OUTPUT,
-        component_sub_type: K_AUDIO_UNIT_SUB_TYPE_VOICE_PROCESSING_IO,
-        component_manufacturer: K_AUDIO_UNIT_MANUFACTURER_APPLE,
-        component_flags: 0,
-        component_flags_mask: 0,
-    };
+const VPIO_DESCRIPTION: AudioComponentDescription = AudioComponentDescription {
+    component_type: K_AUDIO_UNIT_TYPE_OUTPUT,
+    component_sub_type: K_AUDIO_UNIT_SUB_TYPE_VOICE_PROCESSING_IO,
+    component_manufacturer: K_AUDIO_UNIT_MANUFACTURER_APPLE,
+    component_flags: 0,
+    component_flags_mask: 0,
+};
+
+const REMOTE_IO_DESCRIPTION: AudioComponentDescription = AudioComponentDescription {
+    component_type: K_AUDIO_UNIT_TYPE_OUTPUT,
+    component_sub_type: K_AUDIO_UNIT_SUB_TYPE_REMOTE_IO,
+    component_manufacturer: K_AUDIO_UNIT_MANUFACTURER_APPLE,
+    component_flags: 0,
+    component_flags_mask: 0,
+};
+
+/// Look up the VPIO component without instantiating it.
+pub fn aec_supported() -> AecSupport {
+    let component = unsafe { AudioComponentFindNext(ptr::null_mut(), &VPIO_DESCRIPTION) };
+    AecSupport {
+        hardware: !component.is_null(),
+        software: false,
+    }
+}
 
-    let component = unsafe { AudioComponentFindNext(ptr::null_mut(), &desc) };
+fn create_io_unit(
+    description: &AudioComponentDescription,
+) -> Result<AudioComponentInstance, AecError> {
+    let component = unsafe { AudioComponentFindNext(ptr::null_mut(), description) };
     if component.is_null() {
         return Err(AecError::BackendError(
-            "Could not find VPIO audio component".to_string(),
+            "Could not find IO audio component".to_string(),
         ));
     }
 
     let mut audio_unit: AudioComponentInstance = ptr::null_mut();
     let status = unsafe { AudioComponentInstanceNew(component, &mut audio_unit) };
     if status != 0 {
-        return Err(AecError::BackendError(format!(
-            "AudioComponentInstanceNew failed: {status}"
-        )));
+        return Err(os_error("AudioComponentInstanceNew failed", status));
     }
 
-    eprintln!("[sys-voice] Created VPIO Audio Unit");
+    eprintln!("[sys-voice] Created IO Audio Unit");
     Ok(audio_unit)
 }
 
@@ -387,9 +697,7 @@ fn enable_io(audio_unit: AudioComponentInstance) -> Result<(), AecError> {
         )
     };
     if status != 0 {
-        return Err(AecError::BackendError(format!(
-            "Failed to enable input on Bus 1: {status}"
-        )));
+        return Err(os_error("Failed to enable input on Bus 1", status));
     }
 
     // Enable output on Bus 0 (speaker)
@@ -404,29 +712,94 @@ fn enable_io(audio_unit: AudioComponentInstance) -> Result<(), AecError> {
         )
     };
     if status != 0 {
-        return Err(AecError::BackendError(format!(
-            "Failed to enable output on Bus 0: {status}"
-        )));
+        return Err(os_error("Failed to enable output on Bus 0", status));
     }
 
     eprintln!("[sys-voice] Enabled I/O on Bus 0 (output) and Bus 1 (input)");
     Ok(())
 }
 
-fn create_audio_format(sample_rate: f64, channels: u32) -> AudioStreamBasicDescription {
+/// Linear PCM sample types the VPIO unit is asked for, in order of preference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PcmFormat {
+    F32,
+    I16,
+}
+
+impl PcmFormat {
+    fn bytes_per_sample(self) -> u32 {
+        match self {
+            PcmFormat::F32 => 4,
+            PcmFormat::I16 => 2,
+        }
+    }
+
+    /// Mono frames in this format, as reported to `RawCapture`
+    fn raw_format(self, sample_rate: f64) -> RawFormat {
+        let bits = self.bytes_per_sample() as u16 * 8;
+        RawFormat {
+            sample_rate: sample_rate as u32,
+            channels: 1,
+            bits_per_sample: bits,
+            valid_bits: bits,
+            float: self == PcmFormat::F32,
+        }
+    }
+}
+
+fn create_audio_format(
+    sample_rate: f64,
+    channels: u32,
+    sample_format: PcmFormat,
+) -> AudioStreamBasicDescription {
+    let sample_flag = match sample_format {
+        PcmFormat::F32 => K_AUDIO_FORMAT_FLAG_IS_FLOAT,
+        PcmFormat::I16 => K_AUDIO_FORMAT_FLAG_IS_SIGNED_INTEGER,
+    };
+    let bytes = sample_format.bytes_per_sample();
+
     AudioStreamBasicDescription {
         sample_rate,
         format_id: K_AUDIO_FORMAT_LINEAR_PCM,
-        format_flags: K_AUDIO_FORMAT_FLAG_IS_FLOAT | K_AUDIO_FORMAT_FLAG_IS_PACKED,
-        bytes_per_packet: 4 * channels,
+        format_flags: sample_flag | K_AUDIO_FORMAT_FLAG_IS_PACKED,
+        bytes_per_packet: bytes * channels,
         frames_per_packet: 1,
-        bytes_per_frame: 4 * channels,
+        bytes_per_frame: bytes * channels,
         channels_per_frame: channels,
-        bits_per_channel: 32,
+        bits_per_channel: bytes * 8,
         reserved: 0,
     }
 }
 
+/// Apply a non-default ducking level; iOS before 17 rejects the property
+/// and keeps its default.
+fn set_other_audio_ducking(audio_unit: AudioComponentInstance, ducking: Ducking) {
+    // AUVoiceIOOtherAudioDuckingLevel values
+    let level = match ducking {
+        Ducking::Default => return,
+        Ducking::Minimum => 10,
+        Ducking::Medium => 20,
+        Ducking::Maximum => 30,
+    };
+    let config = OtherAudioDuckingConfiguration {
+        enable_advanced_ducking: 1,
+        ducking_level: level,
+    };
+    let status = unsafe {
+        AudioUnitSetProperty(
+            audio_unit,
+            K_AU_VOICE_IO_PROPERTY_OTHER_AUDIO_DUCKING_CONFIGURATION,
+            K_AUDIO_UNIT_SCOPE_GLOBAL,
+            0,
+            &config as *const OtherAudioDuckingConfiguration as *const c_void,
+            std::mem::size_of::<OtherAudioDuckingConfiguration>() as u32,
+        )
+    };
+    if status != 0 {
+        eprintln!("[sys-voice] Warning: Could not set other audio ducking: {status}");
+    }
+}
+
 fn set_audio_format(
     audio_unit: AudioComponentInstance,
     format: &AudioStreamBasicDescription,
@@ -443,9 +816,10 @@ fn set_audio_format(
         )
     };
     if status != 0 {
-        return Err(AecError::BackendError(format!(
-            "Failed to set format on Bus 1 output scope: {status}"
-        )));
+        return Err(os_error(
+            "Failed to set format on Bus 1 output scope",
+            status,
+        ));
     }
 
     // Set format for input scope of Bus 0 (what we send to speaker)
@@ -460,14 +834,22 @@ fn set_audio_format(
         )
     };
     if status != 0 {
-        return Err(AecError::BackendError(format!(
-            "Failed to set format on Bus 0 input scope: {status}"
-        )));
+        return Err(os_error(
+            "Failed to set format on Bus 0 input scope",
+            status,
+        ));
     }
 
     eprintln!(
-        "[sys-voice] Set audio format: {:.0} Hz, {} channels, 32-bit float",
-        format.sample_rate, format.channels_per_frame
+        "[sys-voice] Set audio format: {:.0} Hz, {} channels, {}-bit {}",
+        format.sample_rate,
+        format.channels_per_frame,
+        format.bits_per_channel,
+        if format.format_flags & K_AUDIO_FORMAT_FLAG_IS_FLOAT != 0 {
+            "float"
+        } else {
+            "integer"
+        }
     );
     Ok(())
 }
@@ -495,9 +877,7 @@ fn set_render_callback(
     };
 
     if status != 0 {
-        return Err(AecError::BackendError(format!(
-            "Failed to set render callback on Bus 0: {status}"
-        )));
+        return Err(os_error("Failed to set render callback on Bus 0", status));
     }
 
     eprintln!("[sys-voice] Set render callback on Bus 0 (output)");
@@ -527,9 +907,7 @@ fn set_input_callback(
     };
 
     if status != 0 {
-        return Err(AecError::BackendError(format!(
-            "Failed to set input callback: {status}"
-        )));
+        return Err(os_error("Failed to set input callback", status));
     }
 
     eprintln!("[sys-voice] Set input callback on Bus 1 (input)");
@@ -563,13 +941,34 @@ extern "C" fn render_callback(
     }
 
     let buffer = &mut buffer_list.buffers[0];
-    let data = buffer.data as *mut f32;
     let frame_count = in_number_frames as usize;
 
-    if data.is_null() {
+    if buffer.data.is_null() {
         return 0;
     }
 
+    if context.sample_format == PcmFormat::I16 {
+        let data = unsafe { std::slice::from_raw_parts_mut(buffer.data as *mut i16, frame_count) };
+        data.fill(0);
+        // Silence if the lock is contended, same as the float path
+        if let Ok(mut guard) = context.playback_buffer.try_lock() {
+            let playback_buffer = &mut *guard;
+            let audible = playback_buffer.ramp.audible(frame_count);
+            let available = playback_buffer.samples.len().min(audible);
+            for (out, sample) in data
+                .iter_mut()
+                .zip(playback_buffer.samples.drain(..available))
+            {
+                let sample = sample * playback_buffer.ramp.next_gain();
+                *out = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            }
+            playback_buffer.markers.consume(available);
+        }
+        return 0;
+    }
+
+    let data = buffer.data as *mut f32;
+
     // Try to get samples from playback buffer
     let mut playback_buffer = match context.playback_buffer.try_lock() {
         Ok(b) => b,
@@ -582,14 +981,20 @@ extern "C" fn render_callback(
         }
     };
 
-    // Copy available samples or pad with silence
-    let available = playback_buffer.len().min(frame_count);
+    // Copy available samples or pad with silence; none are taken once a
+    // mute has faded out
+    let audible = playback_buffer.ramp.audible(frame_count);
+    let available = playback_buffer.samples.len().min(audible);
     if available > 0 {
         unsafe {
-            ptr::copy_nonoverlapping(playback_buffer.as_ptr(), data, available);
+            ptr::copy_nonoverlapping(playback_buffer.samples.as_ptr(), data, available);
+            playback_buffer
+                .ramp
+                .apply(std::slice::from_raw_parts_mut(data, available));
         }
-        playback_buffer.drain(..available);
+        playback_buffer.samples.drain(..available);
     }
+    playback_buffer.markers.consume(available);
 
     // Fill remaining with silence
     if available < frame_count {
@@ -653,7 +1058,7 @@ extern "C" fn input_callback(
 
         let mut buffer = AudioBuffer {
             number_channels: 1,
-            data_byte_size: (frame_count * std::mem::size_of::<f32>()) as u32,
+            data_byte_size: frame_count as u32 * context.sample_format.bytes_per_sample(),
             data: scratch_guard.as_mut_ptr() as *mut c_void,
         };
 
@@ -681,21 +1086,48 @@ extern "C" fn input_callback(
             return status;
         }
 
+        if let Some(raw_capture) = &context.raw_capture {
+            // Render reports how many bytes it actually wrote
+            let written = (buffer_list.buffers[0].data_byte_size as usize)
+                .min(frame_count * std::mem::size_of::<f32>());
+            let bytes =
+                unsafe { std::slice::from_raw_parts(scratch_guard.as_ptr() as *const u8, written) };
+            raw_capture.tee(
+                &context.sample_format.raw_format(context.sample_rate),
+                bytes,
+            );
+        }
+
         // Copy to new vec for sending (allocation happens here, outside real-time critical path)
         // Note: This is still an allocation, but it's unavoidable with current channel design
         // A ring buffer would be better for production
-        let samples = scratch_guard[..frame_count].to_vec();
-        match context.capture_sender.try_send(samples) {
-            Ok(_) => {
-                if count < 5 {
-                    eprintln!("[sys-voice] Sent {} samples", frame_count);
-                }
+        let samples = match context.sample_format {
+            PcmFormat::F32 => scratch_guard[..frame_count].to_vec(),
+            PcmFormat::I16 => {
+                // i16 frames were rendered into the front of the f32 scratch
+                let pcm = unsafe {
+                    std::slice::from_raw_parts(scratch_guard.as_ptr() as *const i16, frame_count)
+                };
+                pcm.iter().map(|&s| s as f32 / 32768.0).collect()
             }
-            Err(e) => {
-                if count < 10 {
-                    eprintln!("[sys-voice] try_send failed: {:?}", e);
-                }
+        };
+        // Callbacks skipped for a busy scratch buffer, or lost by the OS,
+        // show up as a jump in the sample time
+        let time_stamp = unsafe { in_time_stamp.as_ref() };
+        if let Some(time_stamp) =
+            time_stamp.filter(|t| t.flags & K_AUDIO_TIME_STAMP_SAMPLE_TIME_VALID != 0)
+        {
+            if let Ok(mut clock) = context.input_clock.try_lock() {
+                let skipped = clock.gap(time_stamp.sample_time as u64, frame_count);
+                context.capture_sender.skip(skipped);
+            }
+        }
+        if context.capture_sender.send(samples) {
+            if count < 5 {
+                eprintln!("[sys-voice] Sent {} samples", frame_count);
             }
+        } else if count < 10 {
+            eprintln!("[sys-voice] send failed: receiver disconnected");
         }
 
         0
@@ -1,26 +1,60 @@
+use libpulse_binding::def::BufferAttr;
 use libpulse_binding::sample::{Format, Spec};
 use libpulse_binding::stream::Direction;
 use libpulse_simple_binding::Simple;
+use std::io::{BufRead, BufReader};
+use std::sync::Arc;
 
-use crate::backends::PlaybackRequest;
-use crate::resampler::Resampler;
-use crate::AecError;
+use crate::backends::{
+    ChunkSender, MuteRamp, PlaybackMarkers, PlaybackMute, PlaybackRequest, StreamInfo,
+    PLAYBACK_POLL,
+};
+use crate::latency::LatencyPlan;
+use crate::{AecConfig, AecError, AecSupport, LinuxAudioApi, StreamProperties};
+
+/// Reported by `CaptureHandle::backend_name()`
+pub const BACKEND_NAME: &str = "pulse-simple";
+/// Reported instead when capturing through a PipeWire server
+pub const PIPEWIRE_BACKEND_NAME: &str = "pipewire-pulse";
 
 const SAMPLE_RATE: u32 = 48000;
-const BUFFER_FRAMES: usize = 480; // 10ms at 48kHz
+pub const BUFFER_FRAMES: usize = 480; // 10ms at 48kHz
+/// PA_VOLUME_NORM, the raw volume reported as 100%
+const VOLUME_NORM: f32 = 65536.0;
 
-/// Create PulseAudio capture backend.
+/// Create PulseAudio capture backend, reading `input_device` when given.
 /// Spawns a blocking task that owns all PulseAudio resources.
-/// Returns (sample_rate, buffer_size).
+/// Returns the mono stream format.
 pub fn create_backend(
-    sender: flume::Sender<Vec<f32>>,
+    config: &AecConfig,
+    input_device: Option<&str>,
+    sender: ChunkSender,
     playback_rx: flume::Receiver<PlaybackRequest>,
-) -> Result<(u32, usize), AecError> {
+    playback_mute: Arc<PlaybackMute>,
+    runtime: &tokio::runtime::Handle,
+) -> Result<StreamInfo, AecError> {
+    let target_frames = LatencyPlan::new(config.latency_target).device_buffer_frames(SAMPLE_RATE);
+    let buffer_frames = target_frames.unwrap_or(BUFFER_FRAMES);
+    let mut route = Route::for_api(config.linux_api)?;
+    // A hardware source bypasses the echo canceller's virtual one
+    let aec = match input_device {
+        Some(source) => {
+            route.source = Some(source.to_string());
+            source.contains("echo-cancel")
+        }
+        None => route.source.is_some() || echo_cancel_loaded(),
+    };
+
     // Verify PulseAudio connection works before spawning task
-    let simple = create_simple_stream(Direction::Record, "AEC Capture")?;
+    let simple = create_simple_stream(
+        Direction::Record,
+        &config.stream_properties,
+        route.source.as_deref(),
+        target_frames,
+    )?;
 
-    tokio::task::spawn_blocking(move || {
-        let mut buffer = vec![0.0f32; BUFFER_FRAMES];
+    runtime.spawn_blocking(move || {
+        let mut buffer = vec![0.0f32; buffer_frames];
 
         loop {
             let byte_slice = unsafe {
@@ -35,46 +69,358 @@ pub fn create_backend(
             }
 
             // When receiver is dropped, send fails and we exit
-            if sender.send(buffer.clone()).is_err() {
+            if !sender.send(buffer.clone()) {
                 break;
             }
         }
     });
 
     // Spawn playback task
-    tokio::task::spawn_blocking(move || {
-        let _ = run_playback(playback_rx);
+    let properties = config.stream_properties.clone();
+    runtime.spawn_blocking(move || {
+        let _ = run_playback(
+            playback_rx,
+            playback_mute,
+            &properties,
+            route.sink,
+            target_frames,
+        );
     });
 
-    Ok((SAMPLE_RATE, BUFFER_FRAMES))
+    Ok(StreamInfo {
+        sample_rate: SAMPLE_RATE,
+        buffer_size: buffer_frames,
+        channels: 1,
+        playback_rate: Some(SAMPLE_RATE),
+        sharing_mode: None,
+        backend: route.backend,
+        // The echo-cancel module filters its own virtual source, which
+        // PulseAudio makes the default when it loads
+        aec,
+    })
+}
+
+/// Where a capture reads and plays, None meaning the server default.
+struct Route {
+    backend: &'static str,
+    source: Option<String>,
+    sink: Option<String>,
+}
+
+impl Route {
+    /// PipeWire only makes its echo-cancel nodes the default when
+    /// configured to, so they are picked by name.
+    fn for_api(api: LinuxAudioApi) -> Result<Self, AecError> {
+        let pipewire = match api {
+            LinuxAudioApi::Auto => server_is_pipewire(),
+            LinuxAudioApi::Pulse => false,
+            LinuxAudioApi::PipeWire if server_is_pipewire() => true,
+            // ALSA has no native backend; the cpal fallback captures it
+            LinuxAudioApi::PipeWire | LinuxAudioApi::Alsa => return Err(AecError::AecNotSupported),
+        };
+
+        if !pipewire {
+            return Ok(Self {
+                backend: BACKEND_NAME,
+                source: None,
+                sink: None,
+            });
+        }
+        Ok(Self {
+            backend: PIPEWIRE_BACKEND_NAME,
+            source: echo_cancel_node("sources"),
+            sink: echo_cancel_node("sinks"),
+        })
+    }
+}
+
+/// Sound systems reachable right now. PipeWire also serves the PulseAudio
+/// protocol, so it lists both.
+pub fn audio_apis() -> Vec<LinuxAudioApi> {
+    let mut apis = Vec::new();
+    if let Ok(info) = pactl(&["info"]) {
+        apis.push(LinuxAudioApi::Pulse);
+        if is_pipewire(&info) {
+            apis.push(LinuxAudioApi::PipeWire);
+        }
+    }
+    if cfg!(feature = "backend-cpal") && std::path::Path::new("/proc/asound/cards").exists() {
+        apis.push(LinuxAudioApi::Alsa);
+    }
+    apis
+}
+
+/// PulseAudio has no built-in AEC; report whether the echo-cancel module
+/// (or PipeWire's equivalent source) is present in the sound server.
+pub fn aec_supported() -> AecSupport {
+    AecSupport {
+        hardware: false,
+        software: echo_cancel_loaded(),
+    }
+}
+
+/// Volume of the default source, 1.0 at 100%. Above 1.0 when the source
+/// is boosted past its nominal level.
+pub fn hardware_gain() -> Result<f32, AecError> {
+    let output = pactl(&["get-source-volume", "@DEFAULT_SOURCE@"])?;
+    // "Volume: front-left: 39321 /  60% / -13.31 dB,   front-right: ..."
+    output
+        .split_whitespace()
+        .find_map(|token| token.parse::<u32>().ok())
+        .map(|raw| raw as f32 / VOLUME_NORM)
+        .ok_or_else(|| AecError::BackendError(format!("unexpected pactl output: {output}")))
+}
+
+/// Set every channel of the default source to `gain`, 1.0 being 100%.
+pub fn set_hardware_gain(gain: f32) -> Result<(), AecError> {
+    let raw = (gain * VOLUME_NORM).round() as u32;
+    pactl(&["set-source-volume", "@DEFAULT_SOURCE@", &raw.to_string()]).map(drop)
+}
+
+/// Run pactl, as the Simple API can't reach source volumes. A missing
+/// pactl means volume control isn't available.
+fn pactl(args: &[&str]) -> Result<String, AecError> {
+    let output = std::process::Command::new("pactl")
+        .args(args)
+        .output()
+        .map_err(|_| AecError::AecNotSupported)?;
+    if !output.status.success() {
+        return Err(AecError::BackendError(format!(
+            "pactl {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn server_is_pipewire() -> bool {
+    pactl(&["info"]).is_ok_and(|info| is_pipewire(&info))
+}
+
+/// pipewire-pulse reports "Server Name: PulseAudio (on PipeWire 1.0.5)"
+fn is_pipewire(info: &str) -> bool {
+    info.lines()
+        .any(|line| line.starts_with("Server Name:") && line.contains("PipeWire"))
+}
+
+/// Names of the sources capture can read, from `pactl list short`.
+pub fn input_devices() -> Vec<String> {
+    pactl(&["list", "short", "sources"])
+        .map(|list| source_names(&list))
+        .unwrap_or_default()
+}
+
+/// The rate source `name`, or the default source, runs at. The server
+/// resamples every stream to it, so it's the only rate without conversion.
+/// Empty when pactl isn't there to ask.
+pub fn supported_sample_rates(name: Option<&str>) -> Result<Vec<u32>, AecError> {
+    let Ok(list) = pactl(&["list", "short", "sources"]) else {
+        return Ok(Vec::new());
+    };
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => pactl(&["info"])
+            .ok()
+            .and_then(|info| default_source(&info))
+            .ok_or(AecError::DeviceUnavailable)?,
+    };
+    source_rate(&list, &name)
+        .map(|rate| vec![rate])
+        .ok_or(AecError::DeviceUnavailable)
+}
+
+fn default_source(info: &str) -> Option<String> {
+    info.lines()
+        .find_map(|line| line.strip_prefix("Default Source:"))
+        .map(|name| name.trim().to_string())
+}
+
+/// Rate from a short listing's sample spec, e.g. "s32le 2ch 48000Hz".
+fn source_rate(list: &str, name: &str) -> Option<u32> {
+    list.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|fields| fields.get(1) == Some(&name))?
+        .iter()
+        .find_map(|field| field.strip_suffix("Hz")?.parse().ok())
 }
 
-fn run_playback(playback_rx: flume::Receiver<PlaybackRequest>) -> Result<(), AecError> {
-    let playback_simple = create_simple_stream(Direction::Playback, "AEC Playback")?;
+/// A `pactl subscribe` process reporting source changes, killed on drop.
+pub struct DeviceNotifier {
+    subscribe: std::process::Child,
+}
+
+impl Drop for DeviceNotifier {
+    fn drop(&mut self) {
+        // The reader thread sees the pipe close and drops its sender
+        let _ = self.subscribe.kill();
+        let _ = self.subscribe.wait();
+    }
+}
 
-    while let Ok(request) = playback_rx.recv() {
-        let samples = if request.sample_rate == SAMPLE_RATE {
-            request.samples
+/// Signal `changed` whenever a source appears or goes away. The Simple API
+/// can't subscribe to server events, so pactl does it.
+pub fn notify_device_changes(changed: flume::Sender<()>) -> Result<DeviceNotifier, AecError> {
+    let mut subscribe = std::process::Command::new("pactl")
+        .arg("subscribe")
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .map_err(|_| AecError::AecNotSupported)?;
+    let Some(events) = subscribe.stdout.take() else {
+        return Err(AecError::BackendError(
+            "pactl subscribe has no output".into(),
+        ));
+    };
+    let notifier = DeviceNotifier { subscribe };
+
+    std::thread::Builder::new()
+        .name("sys-voice-pactl".to_string())
+        .spawn(move || {
+            for line in BufReader::new(events).lines() {
+                let Ok(line) = line else { break };
+                if is_source_list_event(&line) && changed.send(()).is_err() {
+                    break;
+                }
+            }
+        })
+        .map_err(|e| AecError::BackendError(format!("failed to spawn pactl reader: {e}")))?;
+    Ok(notifier)
+}
+
+/// Sources added or removed; `change` events, sent on every volume move,
+/// leave the list as it was.
+fn is_source_list_event(line: &str) -> bool {
+    (line.starts_with("Event 'new'") || line.starts_with("Event 'remove'"))
+        && line.contains(" on source #")
+}
+
+/// Every source in a short listing except sink monitors, which carry output.
+fn source_names(list: &str) -> Vec<String> {
+    list.lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .filter(|name| !name.ends_with(".monitor"))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Name of the echo canceller's source or sink, from `pactl list short`.
+fn echo_cancel_node(kind: &str) -> Option<String> {
+    let list = pactl(&["list", "short", kind]).ok()?;
+    find_echo_cancel_node(&list)
+}
+
+/// Sinks have monitor sources that also mention echo-cancel; skip those.
+fn find_echo_cancel_node(list: &str) -> Option<String> {
+    list.lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .find(|name| name.contains("echo-cancel") && !name.ends_with(".monitor"))
+        .map(str::to_string)
+}
+
+fn echo_cancel_loaded() -> bool {
+    // The Simple API can't introspect the server, so ask pactl
+    ["modules", "sources"].iter().any(|kind| {
+        std::process::Command::new("pactl")
+            .args(["list", "short", kind])
+            .output()
+            .map(|out| String::from_utf8_lossy(&out.stdout).contains("echo-cancel"))
+            .unwrap_or(false)
+    })
+}
+
+fn run_playback(
+    playback_rx: flume::Receiver<PlaybackRequest>,
+    playback_mute: Arc<PlaybackMute>,
+    properties: &StreamProperties,
+    sink: Option<String>,
+    target_frames: Option<usize>,
+) -> Result<(), AecError> {
+    let playback_simple = create_simple_stream(
+        Direction::Playback,
+        properties,
+        sink.as_deref(),
+        target_frames,
+    )?;
+
+    let mut ramp = MuteRamp::new(playback_mute, SAMPLE_RATE);
+    // In samples written to the server, whose latency tells how many of
+    // them are still to play
+    let mut markers = PlaybackMarkers::default();
+    'requests: loop {
+        let mut request = if markers.is_waiting() {
+            match playback_rx.recv_timeout(PLAYBACK_POLL) {
+                Ok(request) => request,
+                Err(flume::RecvTimeoutError::Timeout) => {
+                    track_playback(&playback_simple, &mut markers);
+                    continue;
+                }
+                Err(flume::RecvTimeoutError::Disconnected) => break,
+            }
         } else {
-            Resampler::new(request.sample_rate, SAMPLE_RATE)?.process(&request.samples)?
+            match playback_rx.recv() {
+                Ok(request) => request,
+                Err(_) => break,
+            }
         };
 
-        let byte_slice = unsafe {
-            std::slice::from_raw_parts(
-                samples.as_ptr() as *const u8,
-                samples.len() * std::mem::size_of::<f32>(),
-            )
+        let Some(mut samples) = request.samples_at(SAMPLE_RATE) else {
+            continue;
         };
 
-        if playback_simple.write(byte_slice).is_err() {
-            break;
+        let mut pending = samples.as_mut_slice();
+        while !pending.is_empty() {
+            let audible = ramp.audible(pending.len());
+            let written = if audible > 0 {
+                let (chunk, rest) = std::mem::take(&mut pending).split_at_mut(audible);
+                pending = rest;
+                ramp.apply(chunk);
+                write_samples(&playback_simple, chunk).map(|()| chunk.len())
+            } else {
+                // Muted, silence keeps the sink fed while the request waits
+                write_samples(&playback_simple, &[0.0; BUFFER_FRAMES]).map(|()| BUFFER_FRAMES)
+            };
+            match written {
+                Ok(len) => markers.append(len, None),
+                Err(_) => break 'requests,
+            }
+            track_playback(&playback_simple, &mut markers);
         }
+
+        markers.append(0, request.done);
+        track_playback(&playback_simple, &mut markers);
     }
 
     Ok(())
 }
 
-fn create_simple_stream(direction: Direction, description: &str) -> Result<Simple, AecError> {
+/// Count every sample the server's latency no longer covers as played.
+fn track_playback(simple: &Simple, markers: &mut PlaybackMarkers) {
+    if let Some(latency) = simple.get_latency() {
+        let buffered = latency.0 * SAMPLE_RATE as u64 / 1_000_000;
+        markers.consume_to(markers.appended().saturating_sub(buffered));
+    }
+}
+
+fn write_samples(simple: &Simple, samples: &[f32]) -> Result<(), libpulse_binding::error::PAErr> {
+    let bytes = unsafe {
+        std::slice::from_raw_parts(
+            samples.as_ptr() as *const u8,
+            std::mem::size_of_val(samples),
+        )
+    };
+    simple.write(bytes)
+}
+
+/// With `target_frames`, ask the server for fragments (capture) or a target
+/// fill level (playback) of that size instead of its roughly 2 s default.
+/// `device` names a source or sink; None uses the server default.
+fn create_simple_stream(
+    direction: Direction,
+    properties: &StreamProperties,
+    device: Option<&str>,
+    target_frames: Option<usize>,
+) -> Result<Simple, AecError> {
     let spec = Spec {
         format: Format::F32le,
         channels: 1,
@@ -87,15 +433,116 @@ fn create_simple_stream(direction: Direction, description: &str) -> Result<Simpl
         ));
     }
 
+    // u32::MAX leaves a field at the server default
+    let attr = target_frames.map(|frames| {
+        let bytes = (frames * std::mem::size_of::<f32>()) as u32;
+        BufferAttr {
+            maxlength: u32::MAX,
+            tlength: if direction == Direction::Playback {
+                bytes
+            } else {
+                u32::MAX
+            },
+            prebuf: u32::MAX,
+            minreq: u32::MAX,
+            fragsize: if direction == Direction::Record {
+                bytes
+            } else {
+                u32::MAX
+            },
+        }
+    });
+
+    let description = if direction == Direction::Record {
+        &properties.capture_name
+    } else {
+        &properties.playback_name
+    };
     Simple::new(
         None,
-        "sys-voice",
+        &properties.app_name,
         direction,
-        None,
+        device,
         description,
         &spec,
         None,
-        None,
+        attr.as_ref(),
     )
-    .map_err(|e| AecError::BackendError(format!("PulseAudio error: {e:?}")))
+    .map_err(|e| AecError::OsError {
+        message: format!("PulseAudio error: {e:?}"),
+        code: e.0 as i64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pipewire_is_recognized_from_server_info() {
+        assert!(is_pipewire(
+            "Server String: /run/user/1000/pulse/native\nServer Name: PulseAudio (on PipeWire 1.0.5)\n"
+        ));
+        assert!(!is_pipewire(
+            "Server Name: pulseaudio\nServer Version: 16.1\n"
+        ));
+    }
+
+    #[test]
+    fn source_rate_is_read_from_the_sample_spec() {
+        let sources = "\
+55\talsa_input.usb-headset.mono-fallback\tPipeWire\ts16le 1ch 16000Hz\tRUNNING
+62\techo-cancel-source\tPipeWire\tfloat32le 1ch 48000Hz\tIDLE
+";
+        assert_eq!(
+            source_rate(sources, "alsa_input.usb-headset.mono-fallback"),
+            Some(16000)
+        );
+        assert_eq!(source_rate(sources, "echo-cancel-source"), Some(48000));
+        assert_eq!(source_rate(sources, "missing"), None);
+        assert_eq!(
+            default_source("Server Name: pulseaudio\nDefault Source: echo-cancel-source\n")
+                .as_deref(),
+            Some("echo-cancel-source")
+        );
+    }
+
+    #[test]
+    fn echo_cancel_node_skips_monitors() {
+        let sources = "\
+55\talsa_input.pci-0000_00_1f.3.analog-stereo\tPipeWire\ts32le 2ch 48000Hz\tSUSPENDED
+61\techo-cancel-sink.monitor\tPipeWire\tfloat32le 1ch 48000Hz\tIDLE
+62\techo-cancel-source\tPipeWire\tfloat32le 1ch 48000Hz\tIDLE
+";
+        assert_eq!(
+            find_echo_cancel_node(sources).as_deref(),
+            Some("echo-cancel-source")
+        );
+        assert_eq!(find_echo_cancel_node("55\talsa_input\tPipeWire\n"), None);
+    }
+
+    #[test]
+    fn only_sources_coming_and_going_change_the_list() {
+        assert!(is_source_list_event("Event 'new' on source #63"));
+        assert!(is_source_list_event("Event 'remove' on source #63"));
+        assert!(!is_source_list_event("Event 'change' on source #63"));
+        assert!(!is_source_list_event("Event 'new' on source-output #12"));
+        assert!(!is_source_list_event("Event 'remove' on sink #48"));
+    }
+
+    #[test]
+    fn input_devices_leave_out_monitors() {
+        let sources = "\
+55\talsa_input.usb-headset.mono-fallback\tPipeWire\ts16le 1ch 48000Hz\tSUSPENDED
+56\talsa_output.pci-0000_00_1f.3.analog-stereo.monitor\tPipeWire\ts32le 2ch 48000Hz\tIDLE
+57\talsa_input.pci-0000_00_1f.3.analog-stereo\tPipeWire\ts32le 2ch 48000Hz\tSUSPENDED
+";
+        assert_eq!(
+            source_names(sources),
+            [
+                "alsa_input.usb-headset.mono-fallback",
+                "alsa_input.pci-0000_00_1f.3.analog-stereo"
+            ]
+        );
+    }
 }
@@ -1,36 +1,328 @@
-use crate::backends::PlaybackRequest;
-use crate::resampler::Resampler;
-use crate::AecError;
+use crate::backends::{
+    check_downmix, keeps_channels, rates_in_ranges, Chunk, ChunkSender, DeviceClock, MuteRamp,
+    NativeSlot, PlaybackMarkers, PlaybackMute, PlaybackRequest, StreamInfo,
+};
+#[cfg(feature = "system-audio")]
+use crate::backends::{screen_capture, system_mix::SystemMix};
+use crate::latency::LatencyPlan;
+use crate::{
+    AecConfig, AecError, AecSupport, DownmixMode, Ducking, NativeUnit, Processing, SharingMode,
+};
+use block2::RcBlock;
+use coreaudio::audio_unit::audio_format::LinearPcmFlags;
+use coreaudio::audio_unit::macos_helpers::{
+    audio_unit_from_device_id, get_audio_device_ids, get_audio_device_supports_scope,
+    get_default_device_id, get_hogging_pid, toggle_hog_mode,
+};
 use coreaudio::audio_unit::render_callback::{self, data};
-use coreaudio::audio_unit::types::IOType;
+use coreaudio::audio_unit::types::{IOType, Type};
 use coreaudio::audio_unit::{AudioUnit, Element, SampleFormat, Scope, StreamFormat};
+use objc2::msg_send;
+use objc2::rc::Retained;
+use objc2::runtime::{AnyClass, Bool};
+use objc2_foundation::NSString;
 
 use flume::{Receiver, Sender};
 use std::collections::VecDeque;
+use std::ffi::c_void;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Reported by `CaptureHandle::backend_name()`
+pub const BACKEND_NAME: &str = "coreaudio-vpio";
+/// Reported instead of `BACKEND_NAME` for `Processing::Raw`
+pub const RAW_BACKEND_NAME: &str = "coreaudio-hal";
+
+// AVMediaTypeAudio
+const AV_MEDIA_TYPE_AUDIO: &str = "soun";
+
+// AVAuthorizationStatus values
+const AV_AUTHORIZATION_STATUS_RESTRICTED: isize = 1;
+const AV_AUTHORIZATION_STATUS_DENIED: isize = 2;
+
+#[link(name = "AVFoundation", kind = "framework")]
+extern "C" {}
+
+// kAUVoiceIOProperty_BypassVoiceProcessing
+const K_AU_VOICE_IO_PROPERTY_BYPASS_VOICE_PROCESSING: u32 = 2100;
+
+// kAUVoiceIOProperty_OtherAudioDuckingConfiguration (macOS 14+)
+const K_AU_VOICE_IO_PROPERTY_OTHER_AUDIO_DUCKING_CONFIGURATION: u32 = 2108;
+
+/// Frames per slice assumed when the unit doesn't report it
+const DEFAULT_SLICE_FRAMES: usize = 512;
+
+/// Highest channel element probed for a per-channel input volume
+const MAX_VOLUME_CHANNELS: u32 = 8;
+
+/// AUVoiceIOOtherAudioDuckingConfiguration
+#[repr(C)]
+struct OtherAudioDuckingConfiguration {
+    enable_advanced_ducking: u8,
+    ducking_level: u32,
+}
+
+/// Hog mode held on an input device, released on drop.
+struct HogMode {
+    device: coreaudio::sys::AudioDeviceID,
+    /// False when the process already held it, e.g. through another handle,
+    /// and releasing is up to that holder
+    release: bool,
+}
+
+impl Drop for HogMode {
+    fn drop(&mut self) {
+        if self.release {
+            if let Err(e) = toggle_hog_mode(self.device) {
+                tracing::warn!("failed to release hog mode: {e:?}");
+            }
+        }
+    }
+}
 
 /// Shared buffer for playback samples
 struct PlaybackBuffer {
     samples: VecDeque<f32>,
+    markers: PlaybackMarkers,
+    ramp: MuteRamp,
 }
 
 /// Create macOS backend. Spawns a task that owns audio resources.
-/// Returns (sample_rate, buffer_size). Task stops when sender fails.
+/// `Processing::Raw` opens the input device through a plain HAL unit
+/// instead of VoiceProcessingIO, with no playback path. `input_device` is a
+/// device UID; None uses the default input.
+/// Returns the stream format, mono unless system audio is kept on a channel
+/// of its own. Task stops when sender fails.
 pub fn create_backend(
-    public_sender: Sender<Vec<f32>>,
+    config: &AecConfig,
+    input_device: Option<&str>,
+    public_sender: ChunkSender,
     playback_rx: Receiver<PlaybackRequest>,
-) -> Result<(u32, usize), AecError> {
-    let (callback_tx, callback_rx) = flume::bounded::<Vec<f32>>(32);
+    playback_mute: Arc<PlaybackMute>,
+    native: Arc<NativeSlot>,
+    runtime: &tokio::runtime::Handle,
+) -> Result<StreamInfo, AecError> {
+    // VoiceProcessingIO starts without error when mic access is denied and just
+    // delivers silence, so the TCC status has to be checked up front
+    check_microphone_permission()?;
+
+    // Listed moments ago, but it may have been unplugged since
+    let device = match input_device {
+        Some(uid) => Some(device_for_uid(uid).ok_or(AecError::DeviceUnavailable)?),
+        None => None,
+    };
+
+    // Taken before the unit opens the device, so no other process can change
+    // its format under the capture
+    let hog = match config.sharing_mode {
+        SharingMode::Exclusive => hog_input_device(device),
+        SharingMode::Shared => None,
+    };
+    let sharing_mode = match hog {
+        Some(_) => SharingMode::Exclusive,
+        None => SharingMode::Shared,
+    };
+
+    let (callback_tx, callback_rx) = flume::bounded::<Chunk>(32);
+    let callback_tx = ChunkSender::new(callback_tx, &callback_rx, config.overflow_policy);
+    let raw = config.processing == Processing::Raw;
+    let mut audio_unit = if raw {
+        create_raw_input_unit(device)?
+    } else {
+        let mut audio_unit = create_vpio_unit(device)?;
+        set_other_audio_ducking(&mut audio_unit, config.other_audio_ducking);
+        audio_unit
+    };
+
+    // let enable_output: u32 = 1;
+    // audio_unit
+    //     .set_property(
+    //         coreaudio::sys::kAudioOutputUnitProperty_EnableIO,
+    //         Scope::Output,
+    //         Element::Output,
+    //         Some(&enable_output),
+    //     )
+    //     .map_err(|e| AecError::BackendError(format!("failed to enable output: {e:?}")))?;
+
+    // Query native format - VoiceProcessingIO has strict requirements
+    let native_format = audio_unit
+        .stream_format(Scope::Output, Element::Input)
+        .map_err(|e| os_error("failed to get native format", e))?;
+
+    // Voice processing outputs a single processed channel; only the raw HAL
+    // unit exposes the device's individual channels
+    let input_channel = match config.downmix {
+        DownmixMode::SelectChannel(index) if !keeps_channels(config.channels) => {
+            if raw {
+                check_downmix(config.downmix, native_format.channels as usize)?;
+                index as usize
+            } else {
+                tracing::warn!("voice processing delivers one channel, ignoring SelectChannel");
+                0
+            }
+        }
+        _ => 0,
+    };
+    let capture_channels = input_channel as u32 + 1;
+
+    // Use native sample rate and prefer f32 mono non-interleaved (canonical for VPIO);
+    // fall back to 16-bit integer for devices that don't offer float
+    let sample_format = match set_stream_formats(
+        &mut audio_unit,
+        native_format.sample_rate,
+        SampleFormat::F32,
+        capture_channels,
+        !raw,
+    ) {
+        Ok(()) => SampleFormat::F32,
+        Err(e) => {
+            tracing::warn!("f32 capture format rejected ({e}), trying i16");
+            set_stream_formats(
+                &mut audio_unit,
+                native_format.sample_rate,
+                SampleFormat::I16,
+                capture_channels,
+                !raw,
+            )?;
+            SampleFormat::I16
+        }
+    };
+
+    let native_rate = native_format.sample_rate as u32;
 
     // Create shared playback buffer for render callback
     let playback_buffer = Arc::new(Mutex::new(PlaybackBuffer {
         samples: VecDeque::with_capacity(48000), // ~1 second at 48kHz
+        markers: PlaybackMarkers::default(),
+        ramp: MuteRamp::new(playback_mute, native_rate),
     }));
-    // Create VoiceProcessingIO audio unit - this enables OS-level AEC
-    // VoiceProcessingIO automatically monitors system output for echo reference
-    let mut audio_unit = AudioUnit::new(IOType::VoiceProcessingIO).map_err(|e| {
-        AecError::BackendError(format!("failed to create VoiceProcessingIO: {e:?}"))
-    })?;
+
+    // Started ahead of the microphone so a missing permission fails the open
+    #[cfg(feature = "system-audio")]
+    let system = match config.system_audio {
+        crate::SystemAudio::Off => None,
+        mode => {
+            let (system_tx, system_rx) = flume::bounded::<Vec<f32>>(32);
+            let stream = screen_capture::start(system_tx)?;
+            let mix = SystemMix::new(mode, system_rx, screen_capture::SAMPLE_RATE, native_rate)?;
+            Some((mix, stream))
+        }
+    };
+    #[cfg(feature = "system-audio")]
+    let channels = system.as_ref().map_or(1, |(mix, _)| mix.channels());
+    #[cfg(not(feature = "system-audio"))]
+    let channels = 1;
+
+    // Must precede the input callback, which sizes its buffer from this
+    let target_frames = LatencyPlan::new(config.latency_target).device_buffer_frames(native_rate);
+    if let Some(frames) = target_frames {
+        let frames = frames as u32;
+        if let Err(e) = audio_unit.set_property(
+            coreaudio::sys::kAudioDevicePropertyBufferFrameSize,
+            Scope::Global,
+            Element::Output,
+            Some(&frames),
+        ) {
+            tracing::warn!("buffer size of {frames} frames rejected ({e:?}), keeping default");
+        }
+    }
+
+    // The raw HAL unit has its output disabled, so there is nothing to render into
+    let render_buffer = (!raw).then_some(&playback_buffer);
+    match sample_format {
+        SampleFormat::I16 => {
+            set_i16_callbacks(&mut audio_unit, callback_tx, input_channel, render_buffer)?
+        }
+        _ => set_f32_callbacks(&mut audio_unit, callback_tx, input_channel, render_buffer)?,
+    }
+
+    audio_unit
+        .initialize()
+        .map_err(|e| os_error("failed to initialize", e))?;
+
+    audio_unit
+        .start()
+        .map_err(|e| os_error("failed to start", e))?;
+
+    // Query buffer size from audio unit: the granted device buffer under a
+    // latency target, otherwise frames per slice
+    let buffer_size: u32 = match target_frames {
+        Some(frames) => audio_unit
+            .get_property(
+                coreaudio::sys::kAudioDevicePropertyBufferFrameSize,
+                Scope::Global,
+                Element::Output,
+            )
+            .unwrap_or(frames as u32),
+        None => audio_unit
+            .get_property(
+                coreaudio::sys::kAudioUnitProperty_MaximumFramesPerSlice,
+                Scope::Global,
+                Element::Output,
+            )
+            .unwrap_or(DEFAULT_SLICE_FRAMES as u32),
+    };
+
+    // Dropping the receiver makes play_audio fail for raw capture
+    let buffer_for_playback = playback_buffer.clone();
+    runtime.spawn(async move {
+        if raw {
+            return;
+        }
+        while let Ok(mut request) = playback_rx.recv_async().await {
+            let Some(samples) = request.samples_at(native_rate) else {
+                continue;
+            };
+
+            if let Ok(mut buffer) = buffer_for_playback.lock() {
+                buffer.markers.append(samples.len(), request.done);
+                buffer.samples.extend(samples);
+            }
+        }
+    });
+
+    let native_guard = native.publish(NativeUnit::AudioUnit(*audio_unit.as_ref() as *mut c_void));
+
+    // Spawn task that owns audio_unit and forwards capture - stops on sender disconnect
+    runtime.spawn(async move {
+        let _audio_unit = audio_unit; // Hold for RAII, Drop stops audio
+        let _native_guard = native_guard; // Dropped first, withdrawing the unit before it stops
+        let _hog = hog; // Released after the unit has stopped
+        #[cfg(feature = "system-audio")]
+        let (mut system_mix, _system_stream) = system.unzip();
+
+        while let Ok(chunk) = callback_rx.recv_async().await {
+            #[cfg(feature = "system-audio")]
+            let chunk = match &mut system_mix {
+                Some(mix) => Chunk {
+                    start: chunk.start * mix.channels() as u64,
+                    samples: mix.mix(chunk.samples),
+                },
+                None => chunk,
+            };
+            if !public_sender.forward_async(chunk).await {
+                break;
+            }
+        }
+    });
+
+    Ok(StreamInfo {
+        sample_rate: native_rate,
+        buffer_size: buffer_size as usize,
+        channels,
+        playback_rate: (!raw).then_some(native_rate),
+        sharing_mode: Some(sharing_mode),
+        backend: if raw { RAW_BACKEND_NAME } else { BACKEND_NAME },
+        aec: !raw,
+    })
+}
+
+/// VoiceProcessingIO audio unit with input enabled - this enables OS-level AEC.
+/// VoiceProcessingIO automatically monitors system output for echo reference.
+/// Captures from `device`, or the default input when None.
+fn create_vpio_unit(device: Option<coreaudio::sys::AudioDeviceID>) -> Result<AudioUnit, AecError> {
+    let mut audio_unit = AudioUnit::new(IOType::VoiceProcessingIO)
+        .map_err(|e| os_error("failed to create VoiceProcessingIO", e))?;
 
     // coreaudio-rs may auto-initialize; must uninitialize before configuring properties
     let _ = audio_unit.uninitialize();
@@ -43,55 +335,345 @@ pub fn create_backend(
             Element::Input,
             Some(&enable_input),
         )
-        .map_err(|e| AecError::BackendError(format!("failed to enable input: {e:?}")))?;
+        .map_err(|e| os_error("failed to enable input", e))?;
 
-    // let enable_output: u32 = 1;
-    // audio_unit
-    //     .set_property(
-    //         coreaudio::sys::kAudioOutputUnitProperty_EnableIO,
-    //         Scope::Output,
-    //         Element::Output,
-    //         Some(&enable_output),
-    //     )
-    //     .map_err(|e| AecError::BackendError(format!("failed to enable output: {e:?}")))?;
+    if let Some(device) = device {
+        audio_unit
+            .set_property(
+                coreaudio::sys::kAudioOutputUnitProperty_CurrentDevice,
+                Scope::Global,
+                Element::Input,
+                Some(&device),
+            )
+            .map_err(|e| os_error("failed to select input device", e))?;
+    }
 
-    // Query native format - VoiceProcessingIO has strict requirements
-    let native_format = audio_unit
-        .stream_format(Scope::Output, Element::Input)
-        .map_err(|e| AecError::BackendError(format!("failed to get native format: {e:?}")))?;
+    Ok(audio_unit)
+}
 
-    // Use native sample rate but request f32 mono non-interleaved (canonical for VPIO)
+/// Apply a non-default ducking level. Best effort: systems before macOS 14
+/// reject the property and keep ducking at the default level.
+fn set_other_audio_ducking(audio_unit: &mut AudioUnit, ducking: Ducking) {
+    // AUVoiceIOOtherAudioDuckingLevel values
+    let level = match ducking {
+        Ducking::Default => return,
+        Ducking::Minimum => 10,
+        Ducking::Medium => 20,
+        Ducking::Maximum => 30,
+    };
+    let config = OtherAudioDuckingConfiguration {
+        enable_advanced_ducking: 1,
+        ducking_level: level,
+    };
+    if let Err(e) = audio_unit.set_property(
+        K_AU_VOICE_IO_PROPERTY_OTHER_AUDIO_DUCKING_CONFIGURATION,
+        Scope::Global,
+        Element::Output,
+        Some(&config),
+    ) {
+        tracing::warn!("other audio ducking level rejected ({e:?}), keeping default");
+    }
+}
+
+/// Bypass voice processing on a running VoiceProcessingIO unit, or restore
+/// it. Capture continues either way.
+pub fn set_voice_processing_bypass(unit: *mut c_void, bypass: bool) -> Result<(), AecError> {
+    let value = u32::from(bypass);
+    let status = unsafe {
+        coreaudio::sys::AudioUnitSetProperty(
+            unit as coreaudio::sys::AudioUnit,
+            K_AU_VOICE_IO_PROPERTY_BYPASS_VOICE_PROCESSING,
+            coreaudio::sys::kAudioUnitScope_Global,
+            0,
+            &value as *const u32 as *const c_void,
+            std::mem::size_of::<u32>() as u32,
+        )
+    };
+    status_result("failed to set voice processing bypass", status)
+}
+
+/// Take hog mode on `device`, or the default input device. None, with a
+/// warning, when another process holds it or the device refuses.
+fn hog_input_device(device: Option<coreaudio::sys::AudioDeviceID>) -> Option<HogMode> {
+    let device = device.or_else(|| get_default_device_id(true))?;
+    let own_pid = std::process::id() as libc::pid_t;
+    let result = match get_hogging_pid(device) {
+        Ok(pid) if pid == own_pid => {
+            return Some(HogMode {
+                device,
+                release: false,
+            })
+        }
+        Ok(-1) => toggle_hog_mode(device),
+        Ok(pid) => {
+            tracing::warn!("input device hogged by process {pid}, capturing shared");
+            return None;
+        }
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(pid) if pid == own_pid => Some(HogMode {
+            device,
+            release: true,
+        }),
+        Ok(pid) => {
+            tracing::warn!("hog mode went to process {pid}, capturing shared");
+            None
+        }
+        Err(e) => {
+            tracing::warn!("hog mode refused ({e:?}), capturing shared");
+            None
+        }
+    }
+}
+
+/// Input-only HAL unit on `device`, or the default input device, without
+/// voice processing.
+fn create_raw_input_unit(
+    device: Option<coreaudio::sys::AudioDeviceID>,
+) -> Result<AudioUnit, AecError> {
+    let device_id = device
+        .or_else(|| get_default_device_id(true))
+        .ok_or(AecError::DeviceUnavailable)?;
+    let mut audio_unit = audio_unit_from_device_id(device_id, true)
+        .map_err(|e| os_error("failed to create HAL input unit", e))?;
+    let _ = audio_unit.uninitialize();
+    Ok(audio_unit)
+}
+
+/// UIDs of the devices with input channels. Unlike device IDs, a UID
+/// survives unplugging and reboots.
+pub fn input_devices() -> Vec<String> {
+    get_audio_device_ids()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|&device| get_audio_device_supports_scope(device, Scope::Input).unwrap_or(false))
+        .filter_map(device_uid)
+        .collect()
+}
+
+/// Nominal rates the input device with UID `uid`, or the default input,
+/// can be switched to. Devices report discrete rates as ranges of one
+/// rate; wider ranges are filled with the standard rates they cover.
+pub fn supported_sample_rates(uid: Option<&str>) -> Result<Vec<u32>, AecError> {
+    let device = match uid {
+        Some(uid) => device_for_uid(uid),
+        None => get_default_device_id(true),
+    }
+    .ok_or(AecError::DeviceUnavailable)?;
+
+    let address = coreaudio::sys::AudioObjectPropertyAddress {
+        mSelector: coreaudio::sys::kAudioDevicePropertyAvailableNominalSampleRates,
+        mScope: coreaudio::sys::kAudioObjectPropertyScopeGlobal,
+        mElement: coreaudio::sys::kAudioObjectPropertyElementMaster,
+    };
+    let mut size = 0u32;
+    let status = unsafe {
+        coreaudio::sys::AudioObjectGetPropertyDataSize(
+            device,
+            &address,
+            0,
+            std::ptr::null(),
+            &mut size,
+        )
+    };
+    status_result("failed to size available sample rates", status)?;
+
+    let count = size as usize / std::mem::size_of::<coreaudio::sys::AudioValueRange>();
+    let mut ranges = vec![
+        coreaudio::sys::AudioValueRange {
+            mMinimum: 0.0,
+            mMaximum: 0.0,
+        };
+        count
+    ];
+    let status = unsafe {
+        coreaudio::sys::AudioObjectGetPropertyData(
+            device,
+            &address,
+            0,
+            std::ptr::null(),
+            &mut size,
+            ranges.as_mut_ptr() as *mut c_void,
+        )
+    };
+    status_result("failed to read available sample rates", status)?;
+
+    let ranges: Vec<(f64, f64)> = ranges
+        .iter()
+        .map(|range| (range.mMinimum, range.mMaximum))
+        .collect();
+    Ok(rates_in_ranges(&ranges))
+}
+
+/// A listener on the system's device list, removed on drop.
+pub struct DeviceNotifier {
+    /// Boxed so its address, handed to Core Audio, stays put
+    changed: Box<Sender<()>>,
+}
+
+// SAFETY: Core Audio only reads the sender, which is Send, from its own thread
+unsafe impl Send for DeviceNotifier {}
+
+impl Drop for DeviceNotifier {
+    fn drop(&mut self) {
+        // Returns once any running call of the listener has finished
+        unsafe {
+            coreaudio::sys::AudioObjectRemovePropertyListener(
+                coreaudio::sys::kAudioObjectSystemObject,
+                &devices_address(),
+                Some(devices_changed),
+                &*self.changed as *const Sender<()> as *mut c_void,
+            )
+        };
+    }
+}
+
+/// Signal `changed` whenever a device appears or goes away.
+pub fn notify_device_changes(changed: Sender<()>) -> Result<DeviceNotifier, AecError> {
+    let changed = Box::new(changed);
+    let status = unsafe {
+        coreaudio::sys::AudioObjectAddPropertyListener(
+            coreaudio::sys::kAudioObjectSystemObject,
+            &devices_address(),
+            Some(devices_changed),
+            &*changed as *const Sender<()> as *mut c_void,
+        )
+    };
+    status_result("failed to listen for device changes", status)?;
+    Ok(DeviceNotifier { changed })
+}
+
+fn devices_address() -> coreaudio::sys::AudioObjectPropertyAddress {
+    coreaudio::sys::AudioObjectPropertyAddress {
+        mSelector: coreaudio::sys::kAudioHardwarePropertyDevices,
+        mScope: coreaudio::sys::kAudioObjectPropertyScopeGlobal,
+        mElement: coreaudio::sys::kAudioObjectPropertyElementMaster,
+    }
+}
+
+extern "C" fn devices_changed(
+    _: coreaudio::sys::AudioObjectID,
+    _: u32,
+    _: *const coreaudio::sys::AudioObjectPropertyAddress,
+    changed: *mut c_void,
+) -> coreaudio::sys::OSStatus {
+    let changed = unsafe { &*(changed as *const Sender<()>) };
+    let _ = changed.try_send(());
+    0
+}
+
+fn device_for_uid(uid: &str) -> Option<coreaudio::sys::AudioDeviceID> {
+    get_audio_device_ids()
+        .ok()?
+        .into_iter()
+        .find(|&device| device_uid(device).as_deref() == Some(uid))
+}
+
+fn device_uid(device: coreaudio::sys::AudioDeviceID) -> Option<String> {
+    let address = coreaudio::sys::AudioObjectPropertyAddress {
+        mSelector: coreaudio::sys::kAudioDevicePropertyDeviceUID,
+        mScope: coreaudio::sys::kAudioObjectPropertyScopeGlobal,
+        mElement: coreaudio::sys::kAudioObjectPropertyElementMaster,
+    };
+    let mut uid: *mut NSString = std::ptr::null_mut();
+    let mut size = std::mem::size_of::<*mut NSString>() as u32;
+    let status = unsafe {
+        coreaudio::sys::AudioObjectGetPropertyData(
+            device,
+            &address,
+            0,
+            std::ptr::null(),
+            &mut size,
+            &mut uid as *mut *mut NSString as *mut c_void,
+        )
+    };
+    if status != 0 {
+        return None;
+    }
+    // The caller owns the returned CFString, toll-free bridged to NSString
+    let uid = unsafe { Retained::from_raw(uid) }?;
+    Some(uid.to_string())
+}
+
+/// Keep the OSStatus of a failed Core Audio call alongside the context.
+fn os_error(context: &str, e: coreaudio::Error) -> AecError {
+    AecError::OsError {
+        message: format!("{context}: {e:?}"),
+        code: e.as_os_status() as i64,
+    }
+}
+
+/// Set the capture output to `capture_channels` channels (non-interleaved
+/// when mono) and, when `with_render` is set, the render input to mono, so
+/// both callbacks agree on the sample type.
+fn set_stream_formats(
+    audio_unit: &mut AudioUnit,
+    sample_rate: f64,
+    sample_format: SampleFormat,
+    capture_channels: u32,
+    with_render: bool,
+) -> Result<(), AecError> {
+    let sample_flag = match sample_format {
+        SampleFormat::F32 => LinearPcmFlags::IS_FLOAT,
+        _ => LinearPcmFlags::IS_SIGNED_INTEGER,
+    };
     let stream_format = StreamFormat {
-        sample_rate: native_format.sample_rate,
-        sample_format: SampleFormat::F32,
-        flags: coreaudio::audio_unit::audio_format::LinearPcmFlags::IS_FLOAT
-            | coreaudio::audio_unit::audio_format::LinearPcmFlags::IS_PACKED
-            | coreaudio::audio_unit::audio_format::LinearPcmFlags::IS_NON_INTERLEAVED,
+        sample_rate,
+        sample_format,
+        flags: sample_flag | LinearPcmFlags::IS_PACKED | LinearPcmFlags::IS_NON_INTERLEAVED,
         channels: 1,
     };
 
+    // coreaudio-rs only hands multichannel input over interleaved
+    let capture_format = if capture_channels > 1 {
+        StreamFormat {
+            channels: capture_channels,
+            flags: sample_flag | LinearPcmFlags::IS_PACKED,
+            ..stream_format
+        }
+    } else {
+        stream_format
+    };
     audio_unit
-        .set_stream_format(stream_format, Scope::Output, Element::Input)
-        .map_err(|e| AecError::BackendError(format!("failed to set input stream format: {e:?}")))?;
+        .set_stream_format(capture_format, Scope::Output, Element::Input)
+        .map_err(|e| os_error("failed to set input stream format", e))?;
+
+    if !with_render {
+        return Ok(());
+    }
 
     // Also set stream format for output element (for render callback)
     audio_unit
         .set_stream_format(stream_format, Scope::Input, Element::Output)
-        .map_err(|e| {
-            AecError::BackendError(format!("failed to set output stream format: {e:?}"))
-        })?;
+        .map_err(|e| os_error("failed to set output stream format", e))
+}
 
-    let native_rate = native_format.sample_rate as u32;
+fn set_f32_callbacks(
+    audio_unit: &mut AudioUnit,
+    callback_tx: ChunkSender,
+    input_channel: usize,
+    playback_buffer: Option<&Arc<Mutex<PlaybackBuffer>>>,
+) -> Result<(), AecError> {
+    if input_channel > 0 {
+        set_channel_input_callback(audio_unit, callback_tx, input_channel, |s: f32| s)?;
+    } else {
+        let mut clock = DeviceClock::default();
+        audio_unit
+            .set_input_callback(
+                move |args: render_callback::Args<data::NonInterleaved<f32>>| {
+                    callback_tx.skip(skipped_frames(&mut clock, &args));
+                    let buffer = args.data.channels().next().unwrap();
+                    callback_tx.send(buffer.to_vec());
+                    Ok(())
+                },
+            )
+            .map_err(|e| os_error("failed to set input callback", e))?;
+    }
 
-    audio_unit
-        .set_input_callback(
-            move |args: render_callback::Args<data::NonInterleaved<f32>>| {
-                let buffer = args.data.channels().next().unwrap();
-                let _ = callback_tx.try_send(buffer.to_vec());
-                Ok(())
-            },
-        )
-        .map_err(|e| AecError::BackendError(format!("failed to set input callback: {e:?}")))?;
+    let Some(playback_buffer) = playback_buffer else {
+        return Ok(());
+    };
 
     // Set render callback for playback output - VoiceProcessingIO AEC uses this as echo reference
     let buffer_for_render = playback_buffer.clone();
@@ -100,10 +682,17 @@ pub fn create_backend(
             move |mut args: render_callback::Args<data::NonInterleaved<f32>>| {
                 let output_buffer = args.data.channels_mut().next().unwrap();
                 // Use try_lock to avoid blocking in audio callback
-                if let Ok(mut buffer) = buffer_for_render.try_lock() {
-                    for sample in output_buffer.iter_mut() {
-                        *sample = buffer.samples.pop_front().unwrap_or(0.0);
+                if let Ok(mut guard) = buffer_for_render.try_lock() {
+                    let buffer = &mut *guard;
+                    let audible = buffer.ramp.audible(output_buffer.len());
+                    let available = buffer.samples.len().min(audible);
+                    let (played, silent) = output_buffer.split_at_mut(available);
+                    for sample in played {
+                        *sample =
+                            buffer.samples.pop_front().unwrap_or(0.0) * buffer.ramp.next_gain();
                     }
+                    silent.fill(0.0);
+                    buffer.markers.consume(available);
                 } else {
                     for sample in output_buffer.iter_mut() {
                         *sample = 0.0;
@@ -112,56 +701,341 @@ pub fn create_backend(
                 Ok(())
             },
         )
-        .map_err(|e| AecError::BackendError(format!("failed to set render callback: {e:?}")))?;
+        .map_err(|e| os_error("failed to set render callback", e))
+}
+
+/// Integer counterpart of `set_f32_callbacks`, converting at the callback edge.
+fn set_i16_callbacks(
+    audio_unit: &mut AudioUnit,
+    callback_tx: ChunkSender,
+    input_channel: usize,
+    playback_buffer: Option<&Arc<Mutex<PlaybackBuffer>>>,
+) -> Result<(), AecError> {
+    let to_f32 = |s: i16| s as f32 / 32768.0;
+    if input_channel > 0 {
+        set_channel_input_callback(audio_unit, callback_tx, input_channel, to_f32)?;
+    } else {
+        let mut clock = DeviceClock::default();
+        audio_unit
+            .set_input_callback(
+                move |args: render_callback::Args<data::NonInterleaved<i16>>| {
+                    callback_tx.skip(skipped_frames(&mut clock, &args));
+                    let buffer = args.data.channels().next().unwrap();
+                    let samples = buffer.iter().map(|&s| to_f32(s)).collect();
+                    callback_tx.send(samples);
+                    Ok(())
+                },
+            )
+            .map_err(|e| os_error("failed to set input callback", e))?;
+    }
+
+    let Some(playback_buffer) = playback_buffer else {
+        return Ok(());
+    };
 
+    let buffer_for_render = playback_buffer.clone();
     audio_unit
-        .initialize()
-        .map_err(|e| AecError::BackendError(format!("failed to initialize: {e:?}")))?;
+        .set_render_callback(
+            move |mut args: render_callback::Args<data::NonInterleaved<i16>>| {
+                let output_buffer = args.data.channels_mut().next().unwrap();
+                if let Ok(mut guard) = buffer_for_render.try_lock() {
+                    let buffer = &mut *guard;
+                    let audible = buffer.ramp.audible(output_buffer.len());
+                    let available = buffer.samples.len().min(audible);
+                    let (played, silent) = output_buffer.split_at_mut(available);
+                    for sample in played {
+                        let value =
+                            buffer.samples.pop_front().unwrap_or(0.0) * buffer.ramp.next_gain();
+                        *sample = (value.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+                    }
+                    silent.fill(0);
+                    buffer.markers.consume(available);
+                } else {
+                    for sample in output_buffer.iter_mut() {
+                        *sample = 0;
+                    }
+                }
+                Ok(())
+            },
+        )
+        .map_err(|e| os_error("failed to set render callback", e))
+}
 
+/// Input callback for an interleaved capture format of `input_channel + 1`
+/// channels that forwards only the last one.
+fn set_channel_input_callback<S>(
+    audio_unit: &mut AudioUnit,
+    callback_tx: ChunkSender,
+    input_channel: usize,
+    to_f32: fn(S) -> f32,
+) -> Result<(), AecError>
+where
+    S: coreaudio::audio_unit::Sample + Copy + 'static,
+{
+    let mut clock = DeviceClock::default();
     audio_unit
-        .start()
-        .map_err(|e| AecError::BackendError(format!("failed to start: {e:?}")))?;
+        .set_input_callback(move |args: render_callback::Args<data::Interleaved<S>>| {
+            callback_tx.skip(skipped_frames(&mut clock, &args));
+            let samples = args
+                .data
+                .buffer
+                .chunks_exact(args.data.channels)
+                .map(|frame| to_f32(frame[input_channel]))
+                .collect();
+            callback_tx.send(samples);
+            Ok(())
+        })
+        .map_err(|e| os_error("failed to set input callback", e))
+}
 
-    // Query buffer size from audio unit (frames per slice)
-    let buffer_size: u32 = audio_unit
-        .get_property(
-            coreaudio::sys::kAudioUnitProperty_MaximumFramesPerSlice,
-            Scope::Global,
-            Element::Output,
-        )
-        .unwrap_or(512);
+/// Frames the HAL lost before an input callback, going by the sample time
+/// it stamps each one with. Each callback delivers one channel, so these
+/// are also samples.
+fn skipped_frames<D>(clock: &mut DeviceClock, args: &render_callback::Args<D>) -> u64 {
+    let time_stamp = &args.time_stamp;
+    if time_stamp.mFlags & coreaudio::sys::kAudioTimeStampSampleTimeValid as u32 == 0 {
+        return 0;
+    }
+    clock.gap(time_stamp.mSampleTime as u64, args.num_frames)
+}
 
-    let buffer_for_playback = playback_buffer.clone();
-    tokio::spawn(async move {
-        while let Ok(request) = playback_rx.recv_async().await {
-            let samples = if request.sample_rate == native_rate {
-                request.samples
-            } else {
-                let Ok(mut r) = Resampler::new(request.sample_rate, native_rate) else {
-                    return;
-                };
-                let Ok(s) = r.process(&request.samples) else {
-                    return;
-                };
-                s
-            };
+/// Map the AVCaptureDevice authorization status to `PermissionDenied`.
+/// NotDetermined is allowed through: starting the audio unit triggers the prompt.
+fn check_microphone_permission() -> Result<(), AecError> {
+    let Some(capture_device) = AnyClass::get(c"AVCaptureDevice") else {
+        return Ok(());
+    };
 
-            if let Ok(mut buffer) = buffer_for_playback.lock() {
-                buffer.samples.extend(samples);
-            }
+    let media_type = NSString::from_str(AV_MEDIA_TYPE_AUDIO);
+    let status: isize =
+        unsafe { msg_send![capture_device, authorizationStatusForMediaType: &*media_type] };
+
+    match status {
+        AV_AUTHORIZATION_STATUS_RESTRICTED | AV_AUTHORIZATION_STATUS_DENIED => {
+            Err(AecError::PermissionDenied)
         }
-    });
+        _ => Ok(()),
+    }
+}
 
-    // Spawn task that owns audio_unit and forwards capture - stops on sender disconnect
-    tokio::spawn(async move {
-        let _audio_unit = audio_unit; // Hold for RAII, Drop stops audio
+/// Trigger the TCC microphone prompt and resolve with the user's answer.
+/// Resolves immediately if the user has already decided.
+pub async fn request_microphone_permission() -> bool {
+    let Some(capture_device) = AnyClass::get(c"AVCaptureDevice") else {
+        return true;
+    };
 
-        while let Ok(samples) = callback_rx.recv_async().await {
-            if public_sender.send_async(samples).await.is_err() {
-                break;
+    let (granted_tx, granted_rx) = tokio::sync::oneshot::channel::<bool>();
+
+    // Block and NSString are not Send, so keep them out of the await
+    {
+        let granted_tx = Mutex::new(Some(granted_tx));
+        let handler = RcBlock::new(move |granted: Bool| {
+            if let Some(tx) = granted_tx.lock().ok().and_then(|mut tx| tx.take()) {
+                let _ = tx.send(granted.as_bool());
             }
+        });
+
+        let media_type = NSString::from_str(AV_MEDIA_TYPE_AUDIO);
+        let _: () = unsafe {
+            msg_send![
+                capture_device,
+                requestAccessForMediaType: &*media_type,
+                completionHandler: &*handler
+            ]
+        };
+    }
+
+    granted_rx.await.unwrap_or(false)
+}
+
+/// Look up the VoiceProcessingIO component without instantiating it.
+pub fn aec_supported() -> AecSupport {
+    let ty: Type = IOType::VoiceProcessingIO.into();
+    let Some(sub_type) = ty.as_subtype_u32() else {
+        return AecSupport::default();
+    };
+
+    let desc = coreaudio::sys::AudioComponentDescription {
+        componentType: ty.as_u32(),
+        componentSubType: sub_type,
+        componentManufacturer: coreaudio::sys::kAudioUnitManufacturer_Apple,
+        componentFlags: 0,
+        componentFlagsMask: 0,
+    };
+    let component = unsafe { coreaudio::sys::AudioComponentFindNext(std::ptr::null_mut(), &desc) };
+
+    AecSupport {
+        hardware: !component.is_null(),
+        software: false,
+    }
+}
+
+/// Delay from the output samples VoiceProcessingIO renders to the echo
+/// reaching its input, as Core Audio reports it: each device's latency,
+/// safety offset and IO buffer, for the input with UID `input_uid` (or the
+/// default) and the default output. This is the path the unit aligns its
+/// reference over; the acoustic path from speaker to mic adds to it.
+pub fn aec_reference_delay(input_uid: Option<&str>) -> Option<Duration> {
+    let input = match input_uid {
+        Some(uid) => device_for_uid(uid),
+        None => get_default_device_id(true),
+    }?;
+    let output = get_default_device_id(false)?;
+    Some(
+        device_path_delay(input, coreaudio::sys::kAudioObjectPropertyScopeInput)?
+            + device_path_delay(output, coreaudio::sys::kAudioObjectPropertyScopeOutput)?,
+    )
+}
+
+/// Latency, safety offset and buffer of one direction of `device`.
+fn device_path_delay(device: coreaudio::sys::AudioDeviceID, scope: u32) -> Option<Duration> {
+    let frames =
+        device_property::<u32>(device, coreaudio::sys::kAudioDevicePropertyLatency, scope)?
+            + device_property::<u32>(
+                device,
+                coreaudio::sys::kAudioDevicePropertySafetyOffset,
+                scope,
+            )?
+            + device_property::<u32>(
+                device,
+                coreaudio::sys::kAudioDevicePropertyBufferFrameSize,
+                scope,
+            )?;
+    let rate = device_property::<f64>(
+        device,
+        coreaudio::sys::kAudioDevicePropertyNominalSampleRate,
+        coreaudio::sys::kAudioObjectPropertyScopeGlobal,
+    )?;
+    (rate > 0.0).then(|| Duration::from_secs_f64(frames as f64 / rate))
+}
+
+/// I/O buffer of the default input device, or the frames per slice
+/// capture falls back to when it can't be read.
+pub fn default_buffer_frames() -> usize {
+    get_default_device_id(true)
+        .and_then(|device| {
+            device_property::<u32>(
+                device,
+                coreaudio::sys::kAudioDevicePropertyBufferFrameSize,
+                coreaudio::sys::kAudioObjectPropertyScopeGlobal,
+            )
+        })
+        .map_or(DEFAULT_SLICE_FRAMES, |frames| frames as usize)
+}
+
+fn device_property<T: Default>(
+    device: coreaudio::sys::AudioDeviceID,
+    selector: u32,
+    scope: u32,
+) -> Option<T> {
+    let address = coreaudio::sys::AudioObjectPropertyAddress {
+        mSelector: selector,
+        mScope: scope,
+        mElement: coreaudio::sys::kAudioObjectPropertyElementMaster,
+    };
+    let mut value = T::default();
+    let mut size = std::mem::size_of::<T>() as u32;
+    let status = unsafe {
+        coreaudio::sys::AudioObjectGetPropertyData(
+            device,
+            &address,
+            0,
+            std::ptr::null(),
+            &mut size,
+            &mut value as *mut T as *mut c_void,
+        )
+    };
+    (status == 0).then_some(value)
+}
+
+/// Input volume of the default input device, 0.0 to 1.0.
+pub fn hardware_gain() -> Result<f32, AecError> {
+    let device = get_default_device_id(true).ok_or(AecError::DeviceUnavailable)?;
+    // Per-channel volumes move together when set here, so the first stands
+    // for all of them
+    let element = *volume_elements(device)
+        .first()
+        .ok_or(AecError::AecNotSupported)?;
+
+    let mut gain: f32 = 0.0;
+    let mut size = std::mem::size_of::<f32>() as u32;
+    let status = unsafe {
+        coreaudio::sys::AudioObjectGetPropertyData(
+            device,
+            &volume_address(element),
+            0,
+            std::ptr::null(),
+            &mut size,
+            &mut gain as *mut f32 as *mut c_void,
+        )
+    };
+    status_result("failed to read input volume", status)?;
+    Ok(gain)
+}
+
+/// Set the input volume of the default input device, 0.0 to 1.0.
+pub fn set_hardware_gain(gain: f32) -> Result<(), AecError> {
+    let device = get_default_device_id(true).ok_or(AecError::DeviceUnavailable)?;
+    let elements = volume_elements(device);
+    if elements.is_empty() {
+        return Err(AecError::AecNotSupported);
+    }
+
+    for element in elements {
+        let address = volume_address(element);
+        let mut settable: coreaudio::sys::Boolean = 0;
+        let status = unsafe {
+            coreaudio::sys::AudioObjectIsPropertySettable(device, &address, &mut settable)
+        };
+        if status != 0 || settable == 0 {
+            return Err(AecError::AecNotSupported);
         }
-    });
 
-    Ok((native_rate, buffer_size as usize))
+        let status = unsafe {
+            coreaudio::sys::AudioObjectSetPropertyData(
+                device,
+                &address,
+                0,
+                std::ptr::null(),
+                std::mem::size_of::<f32>() as u32,
+                &gain as *const f32 as *const c_void,
+            )
+        };
+        status_result("failed to set input volume", status)?;
+    }
+    Ok(())
+}
+
+/// Elements carrying an input volume: the main element where the device
+/// has one, otherwise each channel that has its own.
+fn volume_elements(device: coreaudio::sys::AudioDeviceID) -> Vec<u32> {
+    let has_volume = |element| unsafe {
+        coreaudio::sys::AudioObjectHasProperty(device, &volume_address(element)) != 0
+    };
+    if has_volume(coreaudio::sys::kAudioObjectPropertyElementMaster) {
+        return vec![coreaudio::sys::kAudioObjectPropertyElementMaster];
+    }
+    (1..=MAX_VOLUME_CHANNELS)
+        .filter(|&e| has_volume(e))
+        .collect()
+}
+
+fn volume_address(element: u32) -> coreaudio::sys::AudioObjectPropertyAddress {
+    coreaudio::sys::AudioObjectPropertyAddress {
+        mSelector: coreaudio::sys::kAudioDevicePropertyVolumeScalar,
+        mScope: coreaudio::sys::kAudioObjectPropertyScopeInput,
+        mElement: element,
+    }
+}
+
+fn status_result(context: &str, status: coreaudio::sys::OSStatus) -> Result<(), AecError> {
+    if status == 0 {
+        Ok(())
+    } else {
+        Err(AecError::OsError {
+            message: context.to_string(),
+            code: status as i64,
+        })
+    }
 }
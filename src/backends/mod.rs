@@ -13,7 +13,36 @@ mod linux;
 #[cfg(target_os = "android")]
 mod android;
 
-use crate::AecError;
+#[cfg(target_os = "android")]
+mod bluetooth_sco;
+
+#[cfg(feature = "backend-file")]
+mod file;
+
+#[cfg(feature = "backend-cpal")]
+mod portable;
+
+#[cfg(all(target_os = "macos", feature = "system-audio"))]
+mod screen_capture;
+
+#[cfg(feature = "system-audio")]
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+mod system_mix;
+
+mod shared;
+
+pub(crate) use shared::create_backend;
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::resampler::Resampler;
+use crate::{
+    dsp, AecConfig, AecError, AecSupport, CaptureStatus, Channels, DeviceRole, DownmixMode,
+    LinuxAudioApi, NativeUnit, OverflowPolicy, SharingMode,
+};
 
 /// Handle for sending audio to the backend for playback.
 /// Audio played through this handle goes through the same engine as capture,
@@ -21,62 +50,1069 @@ use crate::AecError;
 #[derive(Clone)]
 pub struct BackendHandle {
     playback_tx: flume::Sender<PlaybackRequest>,
+    command_tx: flume::Sender<BackendCommand>,
+    playback_level: Option<Arc<PlaybackLevel>>,
+    playback_mute: Arc<PlaybackMute>,
+    native: Arc<NativeSlot>,
+    /// Set while `set_aec_reference_enabled(false)` has voice processing
+    /// bypassed
+    aec_bypassed: Arc<AtomicBool>,
+    /// From `StreamInfo::playback_rate`, set once the backend has opened
+    playback_rate: Option<u32>,
+    /// Role of the default input device capture comes from, which the
+    /// volume and meter queries act on; None for other inputs
+    default_device: Option<DeviceRole>,
+    /// Entry of `AecConfig::preferred_input_devices` the backend opened
+    input_device: Option<String>,
+    /// The backend's end of its chunk queue, which it drops on exit after
+    /// closing the device. Weak, so it doesn't keep the stream open.
+    chunks: flume::WeakSender<Chunk>,
+    /// Keeps a stream shared with other handles open until this drops
+    _stream: Option<Arc<shared::SharedStream>>,
+}
+
+/// Tells when a backend has exited, without holding it open
+pub(crate) struct ExitWatch(flume::WeakSender<Chunk>);
+
+impl ExitWatch {
+    pub fn has_exited(&self) -> bool {
+        self.0.upgrade().is_none()
+    }
+}
+
+/// Sending end of a bounded queue that applies an `OverflowPolicy` when
+/// the queue is full.
+pub(crate) struct QueueSender<T> {
+    tx: flume::Sender<T>,
+    /// Receiving end the oldest item is taken from under `DropOldest`
+    oldest: Option<flume::Receiver<T>>,
+    policy: OverflowPolicy,
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            oldest: self.oldest.clone(),
+            policy: self.policy,
+        }
+    }
+}
+
+impl<T> QueueSender<T> {
+    pub fn new(tx: flume::Sender<T>, rx: &flume::Receiver<T>, policy: OverflowPolicy) -> Self {
+        Self {
+            tx,
+            oldest: (policy == OverflowPolicy::DropOldest).then(|| rx.clone()),
+            policy,
+        }
+    }
+
+    /// Whether the receiving side has gone, not counting the end held
+    /// for `DropOldest`.
+    pub fn is_disconnected(&self) -> bool {
+        self.tx.receiver_count() <= usize::from(self.oldest.is_some())
+    }
+
+    /// Queue `item` under the policy, blocking the thread for `Block`.
+    /// False once the receiving side has gone.
+    pub fn send(&self, item: T) -> bool {
+        match self.policy {
+            OverflowPolicy::Block => self.tx.send(item).is_ok(),
+            OverflowPolicy::DropNewest => !matches!(
+                self.tx.try_send(item),
+                Err(flume::TrySendError::Disconnected(_))
+            ),
+            OverflowPolicy::DropOldest => self.displace(item),
+        }
+    }
+
+    /// `send` that awaits room for `Block` instead of blocking the thread.
+    pub async fn send_async(&self, item: T) -> bool {
+        match self.policy {
+            OverflowPolicy::Block => self.tx.send_async(item).await.is_ok(),
+            _ => self.send(item),
+        }
+    }
+
+    /// Discard the oldest items until `item` fits.
+    fn displace(&self, mut item: T) -> bool {
+        loop {
+            if self.is_disconnected() {
+                return false;
+            }
+            match self.tx.try_send(item) {
+                Ok(()) => return true,
+                Err(flume::TrySendError::Disconnected(_)) => return false,
+                Err(flume::TrySendError::Full(back)) => item = back,
+            }
+            // Nothing queued to displace, as in a rendezvous channel
+            let displaced = self.oldest.as_ref().and_then(|rx| rx.try_recv().ok());
+            if displaced.is_none() {
+                return true;
+            }
+        }
+    }
+}
+
+/// Captured samples and where they start in the device's input, counted
+/// in interleaved samples. Input lost on the way to the pipeline leaves a
+/// gap between one chunk's end and the next one's start.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Chunk {
+    pub start: u64,
+    pub samples: Vec<f32>,
+}
+
+/// Captured chunks on their way to the pipeline. Each is stamped with its
+/// place in the input as it is handed over, so a chunk the queue's
+/// `OverflowPolicy` drops is still counted.
+#[derive(Clone)]
+pub(crate) struct ChunkSender {
+    queue: QueueSender<Chunk>,
+    /// Samples handed over or reported lost so far
+    produced: Arc<AtomicU64>,
+}
+
+impl ChunkSender {
+    pub fn new(
+        tx: flume::Sender<Chunk>,
+        rx: &flume::Receiver<Chunk>,
+        policy: OverflowPolicy,
+    ) -> Self {
+        Self {
+            queue: QueueSender::new(tx, rx, policy),
+            produced: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Queue the next `samples` of input. False once the receiving side
+    /// has gone.
+    pub fn send(&self, samples: Vec<f32>) -> bool {
+        self.queue.send(self.stamp(samples))
+    }
+
+    /// Queue a chunk stamped further up the capture path, keeping its place.
+    pub fn forward(&self, chunk: Chunk) -> bool {
+        self.queue.send(chunk)
+    }
+
+    /// `forward` that awaits room for `Block` instead of blocking the thread.
+    #[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+    pub async fn forward_async(&self, chunk: Chunk) -> bool {
+        self.queue.send_async(chunk).await
+    }
+
+    /// Count `samples` of input the device reported lost before the next
+    /// chunk.
+    #[cfg_attr(
+        not(any(target_os = "macos", target_os = "ios", target_os = "windows")),
+        allow(dead_code)
+    )]
+    pub fn skip(&self, samples: u64) {
+        self.produced.fetch_add(samples, Ordering::Relaxed);
+    }
+
+    fn stamp(&self, samples: Vec<f32>) -> Chunk {
+        let start = self
+            .produced
+            .fetch_add(samples.len() as u64, Ordering::Relaxed);
+        Chunk { start, samples }
+    }
+
+    fn downgrade(&self) -> flume::WeakSender<Chunk> {
+        self.queue.tx.downgrade()
+    }
+}
+
+/// Follows a device's own frame counter across callbacks, to find input
+/// the OS lost between them.
+#[derive(Default)]
+#[cfg_attr(
+    not(any(target_os = "macos", target_os = "ios", target_os = "windows")),
+    allow(dead_code)
+)]
+pub(crate) struct DeviceClock {
+    /// Device frame the next callback should start at
+    next: Option<u64>,
+}
+
+#[cfg_attr(
+    not(any(target_os = "macos", target_os = "ios", target_os = "windows")),
+    allow(dead_code)
+)]
+impl DeviceClock {
+    /// Frames missing before a callback of `frames` starting at device
+    /// frame `position`.
+    pub fn gap(&mut self, position: u64, frames: usize) -> u64 {
+        let gap = self.next.map_or(0, |next| position.saturating_sub(next));
+        self.next = Some(position + frames as u64);
+        gap
+    }
+}
+
+/// Fill level of a backend's playback buffer, published by the backend
+/// whenever samples are added or consumed.
+#[derive(Default)]
+pub(crate) struct PlaybackLevel {
+    queued_us: AtomicU64,
+}
+
+impl PlaybackLevel {
+    #[cfg_attr(not(target_os = "android"), allow(dead_code))]
+    pub fn store(&self, frames: usize, sample_rate: u32) {
+        let queued_us = frames as u64 * 1_000_000 / sample_rate.max(1) as u64;
+        self.queued_us.store(queued_us, Ordering::Relaxed);
+    }
+
+    pub fn load(&self) -> Duration {
+        Duration::from_micros(self.queued_us.load(Ordering::Relaxed))
+    }
+}
+
+/// Set by `CaptureHandle::set_playback_muted`, read by the backend's output
+/// each time it pulls audio.
+#[derive(Default)]
+pub(crate) struct PlaybackMute {
+    muted: AtomicBool,
+}
+
+impl PlaybackMute {
+    pub fn set(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+}
+
+/// Fade between playing and muted, short enough to feel immediate but long
+/// enough not to click
+const MUTE_RAMP: Duration = Duration::from_millis(5);
+
+/// Gain an output applies to the samples it pulls, following a
+/// `PlaybackMute` over `MUTE_RAMP`. Once faded out the output pulls nothing,
+/// so the queue holds its place until unmuted.
+#[cfg_attr(
+    not(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "windows",
+        target_os = "linux",
+        target_os = "android"
+    )),
+    allow(dead_code)
+)]
+pub(crate) struct MuteRamp {
+    mute: Arc<PlaybackMute>,
+    /// Latched by `audible` so one callback fades in a single direction
+    muted: bool,
+    gain: f32,
+    step: f32,
+}
+
+#[cfg_attr(
+    not(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "windows",
+        target_os = "linux",
+        target_os = "android"
+    )),
+    allow(dead_code)
+)]
+impl MuteRamp {
+    /// Ramp for an output pulling frames at `sample_rate`
+    pub fn new(mute: Arc<PlaybackMute>, sample_rate: u32) -> Self {
+        let frames = (MUTE_RAMP.as_secs_f32() * sample_rate as f32).max(1.0);
+        Self {
+            mute,
+            muted: false,
+            gain: 1.0,
+            step: 1.0 / frames,
+        }
+    }
+
+    /// How many of the `len` frames an output callback needs to take from
+    /// its queue; the rest are silence. Called once per callback, before
+    /// `next_gain`.
+    pub fn audible(&mut self, len: usize) -> usize {
+        self.muted = self.mute.is_muted();
+        if !self.muted {
+            return len;
+        }
+        ((self.gain / self.step).ceil() as usize).min(len)
+    }
+
+    /// Gain for the next frame taken from the queue
+    pub fn next_gain(&mut self) -> f32 {
+        self.gain = if self.muted {
+            (self.gain - self.step).max(0.0)
+        } else {
+            (self.gain + self.step).min(1.0)
+        };
+        self.gain
+    }
+
+    /// Apply `next_gain` to mono samples taken from the queue
+    pub fn apply(&mut self, samples: &mut [f32]) {
+        if !self.muted && self.gain == 1.0 {
+            return;
+        }
+        for sample in samples {
+            *sample *= self.next_gain();
+        }
+    }
+}
+
+/// Platform object behind the stream, for `CaptureHandle::with_native_unit`.
+/// Empty unless the backend publishes one.
+#[derive(Default)]
+pub(crate) struct NativeSlot {
+    unit: Mutex<Option<NativeUnit>>,
+}
+
+// SAFETY: the pointer is only handed out under the lock, which the backend
+// takes to withdraw it before releasing the object
+unsafe impl Send for NativeSlot {}
+unsafe impl Sync for NativeSlot {}
+
+impl NativeSlot {
+    /// Hand out `unit` until the returned guard drops, which must happen
+    /// before the object it points to is released.
+    #[cfg_attr(not(any(target_os = "macos", target_os = "ios")), allow(dead_code))]
+    pub fn publish(self: &Arc<Self>, unit: NativeUnit) -> NativeGuard {
+        *self.lock() = Some(unit);
+        NativeGuard(self.clone())
+    }
+
+    /// Run `f` on the published unit, holding off its withdrawal meanwhile.
+    pub fn with<R>(&self, f: impl FnOnce(NativeUnit) -> R) -> Option<R> {
+        let unit = self.lock();
+        unit.map(f)
+    }
+
+    /// A panic in a caller's closure leaves the slot itself consistent
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<NativeUnit>> {
+        self.unit
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Withdraws a published unit when dropped
+pub(crate) struct NativeGuard(Arc<NativeSlot>);
+
+impl Drop for NativeGuard {
+    fn drop(&mut self) {
+        *self.0.lock() = None;
+    }
+}
+
+/// Format of the chunks a backend delivers, reported once the stream is open.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct StreamInfo {
+    pub sample_rate: u32,
+    pub buffer_size: usize,
+    /// Interleaved channels per frame
+    pub channels: u16,
+    /// Rate the output plays `play_audio` samples at; None without one
+    pub playback_rate: Option<u32>,
+    /// Device sharing mode the OS granted, where the backend negotiates one
+    pub sharing_mode: Option<SharingMode>,
+    /// Identifier reported by `CaptureHandle::backend_name()`
+    pub backend: &'static str,
+    /// Whether the opened stream runs through an echo canceller
+    pub aec: bool,
+}
+
+/// Runtime reconfiguration handled by the thread that owns the audio resources.
+/// Backends that don't support commands drop the receiver, so sends fail.
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+pub(crate) enum BackendCommand {
+    SetReferenceDevice {
+        device_id: Option<String>,
+        reply: flume::Sender<Result<(), AecError>>,
+    },
 }
 
 pub(crate) struct PlaybackRequest {
+    /// Interleaved, `channels` per frame
     pub samples: Vec<f32>,
     pub sample_rate: u32,
+    /// Always 1 except on Windows, the only backend with a multichannel
+    /// output path; elsewhere multichannel audio is downmixed before queueing
+    #[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+    pub channels: u16,
+    /// Signalled once the output has consumed the last of `samples`
+    pub done: Option<PlaybackDone>,
+}
+
+/// Answers a tracked request: `Ok` once it has played out, or the error
+/// that kept it from playing
+pub(crate) type PlaybackDone = flume::Sender<Result<(), AecError>>;
+
+#[cfg_attr(
+    not(any(target_os = "macos", target_os = "windows", target_os = "linux")),
+    allow(dead_code)
+)]
+impl PlaybackRequest {
+    /// Take the samples, converted to `rate`. If they can't be, the error
+    /// goes to `done` instead and this returns `None`, so one bad request
+    /// doesn't end the playback loop.
+    pub fn samples_at(&mut self, rate: u32) -> Option<Vec<f32>> {
+        let samples = std::mem::take(&mut self.samples);
+        if self.sample_rate == rate {
+            return Some(samples);
+        }
+        match Resampler::convert(&samples, self.sample_rate, rate, self.channels) {
+            Ok(samples) => Some(samples),
+            Err(e) => {
+                if let Some(done) = self.done.take() {
+                    let _ = done.try_send(Err(e));
+                }
+                None
+            }
+        }
+    }
+}
+
+/// How often Windows and Linux check on audio still waiting to play out
+#[cfg_attr(not(any(target_os = "windows", target_os = "linux")), allow(dead_code))]
+pub(crate) const PLAYBACK_POLL: Duration = Duration::from_millis(10);
+
+/// A request waiting for room in a full playback queue
+pub(crate) type PlaybackSend = flume::r#async::SendFut<'static, PlaybackRequest>;
+
+/// Build a request, downmixed to mono where the output path is mono.
+fn playback_request(
+    samples: Vec<f32>,
+    sample_rate: u32,
+    channels: u16,
+    done: Option<PlaybackDone>,
+) -> PlaybackRequest {
+    #[cfg(not(target_os = "windows"))]
+    let (samples, channels) = match channels {
+        1 => (samples, 1),
+        _ => (dsp::downmix(&samples, channels as usize), 1),
+    };
+    PlaybackRequest {
+        samples,
+        sample_rate,
+        channels,
+        done,
+    }
+}
+
+fn playback_closed() -> AecError {
+    AecError::BackendError("playback channel closed".to_string())
+}
+
+/// Completion senders waiting on played audio. Callback-driven backends
+/// keep them under the playback buffer's lock, and positions count every
+/// sample ever appended, so the callback only has to report how many it
+/// took. Windows and Linux count frames written to the device and work out
+/// how many have played from what is still buffered there.
+#[cfg_attr(
+    not(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "android",
+        target_os = "windows",
+        target_os = "linux"
+    )),
+    allow(dead_code)
+)]
+#[derive(Default)]
+pub(crate) struct PlaybackMarkers {
+    appended: u64,
+    consumed: u64,
+    pending: VecDeque<(u64, PlaybackDone)>,
+}
+
+#[cfg_attr(
+    not(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "android",
+        target_os = "windows",
+        target_os = "linux"
+    )),
+    allow(dead_code)
+)]
+impl PlaybackMarkers {
+    /// Note `len` samples appended to the buffer, signalling `done` once
+    /// they have all been consumed.
+    pub fn append(&mut self, len: usize, done: Option<PlaybackDone>) {
+        self.appended += len as u64;
+        if let Some(done) = done {
+            self.pending.push_back((self.appended, done));
+        }
+    }
+
+    /// Note `len` samples played or dropped from the front of the buffer.
+    #[cfg_attr(
+        not(any(target_os = "macos", target_os = "ios", target_os = "android")),
+        allow(dead_code)
+    )]
+    pub fn consume(&mut self, len: usize) {
+        self.consume_to(self.consumed + len as u64);
+    }
+
+    /// Note that everything appended before `position` has played. Earlier
+    /// positions than already reported are ignored.
+    pub fn consume_to(&mut self, position: u64) {
+        self.consumed = self.consumed.max(position);
+        while let Some(&(position, _)) = self.pending.front() {
+            if position > self.consumed {
+                break;
+            }
+            if let Some((_, done)) = self.pending.pop_front() {
+                let _ = done.try_send(Ok(()));
+            }
+        }
+    }
+
+    /// Samples appended so far
+    pub fn appended(&self) -> u64 {
+        self.appended
+    }
+
+    /// Whether a completion sender is still waiting
+    pub fn is_waiting(&self) -> bool {
+        !self.pending.is_empty()
+    }
 }
 
 impl BackendHandle {
-    pub fn play_audio(&self, samples: Vec<f32>, sample_rate: u32) -> Result<(), AecError> {
+    fn shared(self, stream: Arc<shared::SharedStream>) -> Self {
+        Self {
+            _stream: Some(stream),
+            ..self
+        }
+    }
+
+    pub fn play_audio(
+        &self,
+        samples: Vec<f32>,
+        sample_rate: u32,
+        channels: u16,
+        done: Option<PlaybackDone>,
+    ) -> Result<(), AecError> {
         self.playback_tx
-            .send(PlaybackRequest {
-                samples,
-                sample_rate,
-            })
-            .map_err(|_| AecError::BackendError("playback channel closed".to_string()))
+            .send(playback_request(samples, sample_rate, channels, done))
+            .map_err(|_| playback_closed())
+    }
+
+    /// `play_audio` without blocking on a full playback queue: the request
+    /// is queued at once if there is room, and otherwise returned as a
+    /// future that queues it.
+    pub fn play_audio_async(
+        &self,
+        samples: Vec<f32>,
+        sample_rate: u32,
+        channels: u16,
+        done: Option<PlaybackDone>,
+    ) -> Result<Option<PlaybackSend>, AecError> {
+        let request = playback_request(samples, sample_rate, channels, done);
+        match self.playback_tx.try_send(request) {
+            Ok(()) => Ok(None),
+            Err(flume::TrySendError::Full(request)) => {
+                Ok(Some(self.playback_tx.clone().into_send_async(request)))
+            }
+            Err(flume::TrySendError::Disconnected(_)) => Err(playback_closed()),
+        }
+    }
+
+    pub fn playback_rate(&self) -> Option<u32> {
+        self.playback_rate
+    }
+
+    pub fn input_device(&self) -> Option<&str> {
+        self.input_device.as_deref()
+    }
+
+    pub fn playback_queued(&self) -> Option<Duration> {
+        self.playback_level.as_ref().map(|level| level.load())
+    }
+
+    pub fn set_playback_muted(&self, muted: bool) {
+        self.playback_mute.set(muted);
+    }
+
+    pub fn is_playback_muted(&self) -> bool {
+        self.playback_mute.is_muted()
+    }
+
+    pub fn hardware_gain(&self) -> Result<f32, AecError> {
+        let Some(role) = self.default_device else {
+            return Err(AecError::AecNotSupported);
+        };
+        hardware_gain(role)
+    }
+
+    pub fn set_hardware_gain(&self, gain: f32) -> Result<(), AecError> {
+        let Some(role) = self.default_device else {
+            return Err(AecError::AecNotSupported);
+        };
+        set_hardware_gain(gain, role)
+    }
+
+    pub fn hardware_input_level(&self) -> Option<f32> {
+        hardware_input_level(self.default_device?)
+    }
+
+    pub fn aec_reference_delay(&self) -> Option<Duration> {
+        aec_reference_delay(self.input_device())
+    }
+
+    pub fn with_native_unit<R>(&self, f: impl FnOnce(NativeUnit) -> R) -> Result<R, AecError> {
+        self.native.with(f).ok_or(AecError::AecNotSupported)
+    }
+
+    pub fn exit_watch(&self) -> ExitWatch {
+        ExitWatch(self.chunks.clone())
+    }
+
+    pub fn set_aec_reference_enabled(&self, enabled: bool) -> Result<(), AecError> {
+        set_voice_processing_bypass(&self.native, !enabled)?;
+        self.aec_bypassed.store(!enabled, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn is_aec_reference_enabled(&self) -> bool {
+        !self.aec_bypassed.load(Ordering::Relaxed)
+    }
+
+    pub fn set_reference_device(&self, device_id: Option<String>) -> Result<(), AecError> {
+        let (reply, reply_rx) = flume::bounded(1);
+        self.command_tx
+            .send(BackendCommand::SetReferenceDevice { device_id, reply })
+            .map_err(|_| AecError::AecNotSupported)?;
+        reply_rx
+            .recv()
+            .map_err(|_| AecError::BackendError("backend stopped".to_string()))?
     }
 }
 
 /// Create the appropriate platform backend.
 /// Spawns a capture task that owns audio resources.
-/// Returns (sample_rate, buffer_size, handle). Task stops when sender disconnects.
-pub(crate) fn create_backend(
-    sender: flume::Sender<Vec<f32>>,
-) -> Result<(u32, usize, BackendHandle), AecError> {
+/// Returns the delivered stream format and a playback handle. Task stops when sender disconnects.
+fn open_backend(
+    config: &AecConfig,
+    sender: ChunkSender,
+    status_tx: flume::Sender<CaptureStatus>,
+    runtime: &tokio::runtime::Handle,
+) -> Result<(StreamInfo, BackendHandle), AecError> {
     let (playback_tx, playback_rx) = flume::bounded::<PlaybackRequest>(16);
-    let handle = BackendHandle { playback_tx };
+    let (command_tx, command_rx) = flume::unbounded::<BackendCommand>();
 
-    #[cfg(target_os = "macos")]
-    {
-        let (rate, size) = macos::create_backend(sender, playback_rx)?;
-        return Ok((rate, size, handle));
+    // Only Android keeps a playback buffer it can measure
+    let playback_level = Arc::new(PlaybackLevel::default());
+    let playback_mute = Arc::new(PlaybackMute::default());
+    let native = Arc::new(NativeSlot::default());
+    let handle = BackendHandle {
+        playback_tx,
+        command_tx,
+        playback_level: cfg!(target_os = "android").then(|| playback_level.clone()),
+        playback_mute: playback_mute.clone(),
+        native: native.clone(),
+        aec_bypassed: Arc::new(AtomicBool::new(false)),
+        playback_rate: None,
+        default_device: Some(config.device_role),
+        input_device: None,
+        chunks: sender.downgrade(),
+        _stream: None,
+    };
+
+    // Only the Oboe output stream can pull from a callback
+    #[cfg(not(target_os = "android"))]
+    if config.render_source.is_some() {
+        return Err(AecError::AecNotSupported);
     }
 
-    #[cfg(target_os = "ios")]
-    {
-        let (rate, size) = ios::create_backend(sender, playback_rx)?;
-        return Ok((rate, size, handle));
+    // Only ScreenCaptureKit can capture system output alongside the mic
+    #[cfg(all(feature = "system-audio", not(target_os = "macos")))]
+    if config.system_audio != crate::SystemAudio::Off {
+        return Err(AecError::AecNotSupported);
     }
 
-    #[cfg(target_os = "windows")]
-    {
-        let (rate, size) = windows::create_backend(sender, playback_rx)?;
-        return Ok((rate, size, handle));
+    // Nothing to play through or reconfigure, so those channels close
+    #[cfg(feature = "backend-file")]
+    if let Some(source) = &config.input_file {
+        drop((playback_rx, command_rx, status_tx));
+        let handle = BackendHandle {
+            playback_level: None,
+            default_device: None,
+            ..handle
+        };
+        let info = file::create_backend(source, config, sender)?;
+        return Ok((info, handle));
     }
 
+    #[cfg(not(target_os = "windows"))]
+    drop(command_rx);
+
+    // Device loss is detected by the pipeline; only iOS reports interruptions
+    #[cfg(not(target_os = "ios"))]
+    drop(status_tx);
+
+    // Only the Core Audio backends hand out their unit
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    drop(native);
+
+    // Only backends that run their loops as tokio tasks need the runtime
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "linux")))]
+    let _ = runtime;
+
+    // Held back so the fallback can still deliver if the native backend fails
+    #[cfg(feature = "backend-cpal")]
+    let fallback_sender = sender.clone();
+
+    let input_device = preferred_input_device(&config.preferred_input_devices);
+    // The volume and meter queries only reach the default device
+    let handle = BackendHandle {
+        default_device: handle.default_device.filter(|_| input_device.is_none()),
+        input_device: input_device.clone(),
+        ..handle
+    };
+
+    #[cfg(target_os = "macos")]
+    let result = macos::create_backend(
+        config,
+        input_device.as_deref(),
+        sender,
+        playback_rx,
+        playback_mute,
+        native,
+        runtime,
+    );
+
+    #[cfg(target_os = "ios")]
+    let result = ios::create_backend(
+        config,
+        sender,
+        playback_rx,
+        playback_mute,
+        status_tx,
+        native,
+    );
+
+    #[cfg(target_os = "windows")]
+    let result = windows::create_backend(
+        config,
+        input_device,
+        sender,
+        playback_rx,
+        playback_mute,
+        command_rx,
+        runtime,
+    );
+
     #[cfg(target_os = "linux")]
-    {
-        let (rate, size) = linux::create_backend(sender, playback_rx)?;
-        return Ok((rate, size, handle));
+    let result = linux::create_backend(
+        config,
+        input_device.as_deref(),
+        sender,
+        playback_rx,
+        playback_mute,
+        runtime,
+    );
+
+    #[cfg(target_os = "android")]
+    let result =
+        android::create_backend(config, sender, playback_rx, playback_level, playback_mute);
+
+    #[cfg(not(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "windows",
+        target_os = "linux",
+        target_os = "android"
+    )))]
+    let result: Result<StreamInfo, AecError> = {
+        let _ = (config, sender, playback_rx, command_rx, playback_mute);
+        Err(AecError::AecNotSupported)
+    };
+
+    // Capture without AEC beats no capture, but a denied permission would
+    // deny cpal too
+    #[cfg(feature = "backend-cpal")]
+    let (result, handle) = match result {
+        Err(e) if !matches!(e, AecError::PermissionDenied) && cpal_can_substitute(config) => {
+            tracing::warn!("native backend failed ({e}), falling back to cpal without AEC");
+            // cpal opens its own default device
+            let handle = BackendHandle {
+                playback_level: None,
+                default_device: Some(config.device_role),
+                input_device: None,
+                ..handle
+            };
+            (portable::create_backend(config, fallback_sender), handle)
+        }
+        result => {
+            // The pipeline only sees the stream end once every sender drops
+            drop(fallback_sender);
+            (result, handle)
+        }
+    };
+
+    let info = result?;
+    let handle = BackendHandle {
+        playback_rate: info.playback_rate,
+        ..handle
+    };
+    Ok((info, handle))
+}
+
+/// Whether cpal capture still gives what `config` asks for when the native
+/// backend fails. It has no system audio to mix in.
+#[cfg(feature = "backend-cpal")]
+fn cpal_can_substitute(config: &AecConfig) -> bool {
+    #[cfg(feature = "system-audio")]
+    if config.system_audio != crate::SystemAudio::Off {
+        return false;
+    }
+    let _ = config;
+    true
+}
+
+/// First of `preferred` among the input devices present, None for the
+/// default device.
+fn preferred_input_device(preferred: &[String]) -> Option<String> {
+    if preferred.is_empty() {
+        return None;
     }
+    let picked = pick_input_device(preferred, &input_devices());
+    if picked.is_none() {
+        tracing::warn!("none of the preferred input devices is present, using the default");
+    }
+    picked
+}
+
+fn pick_input_device(preferred: &[String], present: &[String]) -> Option<String> {
+    preferred.iter().find(|id| present.contains(id)).cloned()
+}
+
+/// IDs of the input devices present, in the platform's own form.
+pub(crate) fn input_devices() -> Vec<String> {
+    #[cfg(target_os = "macos")]
+    let devices = macos::input_devices();
+
+    #[cfg(target_os = "windows")]
+    let devices = windows::input_devices();
+
+    #[cfg(target_os = "linux")]
+    let devices = linux::input_devices();
+
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "linux")))]
+    let devices = Vec::new();
+
+    devices
+}
+
+/// Rates probed on platforms that answer per rate or in ranges
+#[cfg_attr(not(any(target_os = "macos", target_os = "windows")), allow(dead_code))]
+pub(crate) const STANDARD_RATES: [u32; 12] = [
+    8000, 11025, 16000, 22050, 24000, 32000, 44100, 48000, 88200, 96000, 176400, 192000,
+];
+
+/// Rates the input device `device_id`, or the default input, runs at
+/// without conversion, lowest first.
+pub(crate) fn supported_sample_rates(device_id: Option<&str>) -> Result<Vec<u32>, AecError> {
+    #[cfg(target_os = "macos")]
+    let rates = macos::supported_sample_rates(device_id);
+
+    #[cfg(target_os = "windows")]
+    let rates = windows::supported_sample_rates(device_id);
+
+    #[cfg(target_os = "linux")]
+    let rates = linux::supported_sample_rates(device_id);
+
+    // No device IDs are listed here, so only the default input can be asked
+    #[cfg(target_os = "ios")]
+    let rates = match device_id {
+        Some(_) => Err(AecError::DeviceUnavailable),
+        None => Ok(ios::supported_sample_rates()),
+    };
 
     #[cfg(target_os = "android")]
-    {
-        let (rate, size) = android::create_backend(sender, playback_rx)?;
-        return Ok((rate, size, handle));
+    let rates = match device_id {
+        Some(_) => Err(AecError::DeviceUnavailable),
+        None => android::supported_sample_rates(),
+    };
+
+    #[cfg(not(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "windows",
+        target_os = "linux",
+        target_os = "android"
+    )))]
+    let rates = {
+        let _ = device_id;
+        Ok(Vec::new())
+    };
+
+    rates
+}
+
+/// Device buffer a stream opened without a latency target is expected to
+/// get. Zero on platforms without a native backend.
+pub(crate) fn default_buffer_frames() -> usize {
+    #[cfg(target_os = "macos")]
+    let frames = macos::default_buffer_frames();
+
+    #[cfg(target_os = "ios")]
+    let frames = ios::BUFFER_SIZE as usize;
+
+    #[cfg(target_os = "windows")]
+    let frames = windows::DEFAULT_BUFFER_FRAMES;
+
+    #[cfg(target_os = "linux")]
+    let frames = linux::BUFFER_FRAMES;
+
+    #[cfg(target_os = "android")]
+    let frames = android::default_buffer_frames();
+
+    #[cfg(not(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "windows",
+        target_os = "linux",
+        target_os = "android"
+    )))]
+    let frames = 0;
+
+    frames
+}
+
+/// Discrete rates from Core Audio style ranges, where a range of one rate
+/// names it and a wider one covers every standard rate inside it.
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+pub(crate) fn rates_in_ranges(ranges: &[(f64, f64)]) -> Vec<u32> {
+    let mut rates: Vec<u32> = ranges
+        .iter()
+        .flat_map(|&(min, max)| {
+            let exact = (min == max).then_some(min.round() as u32);
+            let covered = STANDARD_RATES
+                .into_iter()
+                .filter(move |&rate| min < max && (min..=max).contains(&(rate as f64)));
+            exact.into_iter().chain(covered)
+        })
+        .collect();
+    rates.sort_unstable();
+    rates.dedup();
+    rates
+}
+
+/// Device list watch behind `watch_devices`, stopped when dropped.
+pub(crate) struct DeviceWatch {
+    /// Platform listener holding the sender the watch thread waits on
+    _notifier: Box<dyn Send>,
+}
+
+/// Call `callback` with the input device list whenever it changes.
+pub(crate) fn watch_devices(
+    callback: impl FnMut(Vec<String>) + Send + 'static,
+) -> Result<DeviceWatch, AecError> {
+    let (changed_tx, changed_rx) = flume::unbounded::<()>();
+    let notifier = notify_device_changes(changed_tx)?;
+    let known = input_devices();
+    std::thread::Builder::new()
+        .name("sys-voice-devices".to_string())
+        .spawn(move || report_device_changes(changed_rx, known, input_devices, callback))
+        .map_err(|e| AecError::BackendError(format!("failed to spawn device watch: {e}")))?;
+    Ok(DeviceWatch {
+        _notifier: notifier,
+    })
+}
+
+/// Re-list devices on every signal, reporting lists that differ from the
+/// last. Returns once the notifier drops its sender.
+fn report_device_changes(
+    changed: flume::Receiver<()>,
+    mut known: Vec<String>,
+    mut list: impl FnMut() -> Vec<String>,
+    mut callback: impl FnMut(Vec<String>),
+) {
+    while changed.recv().is_ok() {
+        // One listing covers a burst of notifications
+        while changed.try_recv().is_ok() {}
+        let devices = list();
+        if devices != known {
+            known = devices.clone();
+            callback(devices);
+        }
     }
+}
+
+/// Start the platform's device change notifications.
+fn notify_device_changes(changed: flume::Sender<()>) -> Result<Box<dyn Send>, AecError> {
+    #[cfg(target_os = "macos")]
+    let notifier = macos::notify_device_changes(changed).map(|n| Box::new(n) as Box<dyn Send>);
+
+    #[cfg(target_os = "windows")]
+    let notifier = windows::notify_device_changes(changed).map(|n| Box::new(n) as Box<dyn Send>);
+
+    #[cfg(target_os = "linux")]
+    let notifier = linux::notify_device_changes(changed).map(|n| Box::new(n) as Box<dyn Send>);
+
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "linux")))]
+    let notifier = {
+        let _ = changed;
+        Err(AecError::AecNotSupported)
+    };
+
+    notifier
+}
+
+/// Show the OS microphone permission prompt where the platform has one.
+/// Other platforms have no request API and resolve to true.
+pub(crate) async fn request_microphone_permission() -> bool {
+    #[cfg(target_os = "macos")]
+    let granted = macos::request_microphone_permission().await;
+
+    #[cfg(target_os = "ios")]
+    let granted = ios::request_microphone_permission().await;
+
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    let granted = true;
+
+    granted
+}
+
+/// Linux sound systems reachable right now.
+pub(crate) fn linux_audio_apis() -> Vec<LinuxAudioApi> {
+    #[cfg(target_os = "linux")]
+    let apis = linux::audio_apis();
+
+    #[cfg(not(target_os = "linux"))]
+    let apis = Vec::new();
+
+    apis
+}
+
+/// Probe echo cancellation capability without opening a capture stream.
+pub(crate) fn aec_supported() -> AecSupport {
+    #[cfg(target_os = "macos")]
+    let support = macos::aec_supported();
+
+    #[cfg(target_os = "ios")]
+    let support = ios::aec_supported();
+
+    #[cfg(target_os = "windows")]
+    let support = windows::aec_supported();
+
+    #[cfg(target_os = "linux")]
+    let support = linux::aec_supported();
+
+    #[cfg(target_os = "android")]
+    let support = AecSupport {
+        hardware: true,
+        software: false,
+    };
 
     #[cfg(not(any(
         target_os = "macos",
@@ -85,8 +1121,398 @@ pub(crate) fn create_backend(
         target_os = "linux",
         target_os = "android"
     )))]
-    {
-        let _ = (sender, playback_rx);
+    let support = AecSupport::default();
+
+    support
+}
+
+/// Input volume of the default capture device, where the OS exposes one.
+/// Only Windows has more than one default, picked by `role`.
+fn hardware_gain(role: DeviceRole) -> Result<f32, AecError> {
+    #[cfg(not(target_os = "windows"))]
+    let _ = role;
+
+    #[cfg(target_os = "macos")]
+    let gain = macos::hardware_gain();
+
+    #[cfg(target_os = "ios")]
+    let gain = ios::hardware_gain();
+
+    #[cfg(target_os = "windows")]
+    let gain = windows::hardware_gain(role);
+
+    #[cfg(target_os = "linux")]
+    let gain = linux::hardware_gain();
+
+    #[cfg(not(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "windows",
+        target_os = "linux"
+    )))]
+    let gain = Err(AecError::AecNotSupported);
+
+    gain
+}
+
+fn set_hardware_gain(gain: f32, role: DeviceRole) -> Result<(), AecError> {
+    #[cfg(not(target_os = "windows"))]
+    let _ = role;
+
+    #[cfg(target_os = "macos")]
+    let result = macos::set_hardware_gain(gain);
+
+    #[cfg(target_os = "ios")]
+    let result = ios::set_hardware_gain(gain);
+
+    #[cfg(target_os = "windows")]
+    let result = windows::set_hardware_gain(gain, role);
+
+    #[cfg(target_os = "linux")]
+    let result = linux::set_hardware_gain(gain);
+
+    #[cfg(not(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "windows",
+        target_os = "linux"
+    )))]
+    let result = {
+        let _ = gain;
         Err(AecError::AecNotSupported)
+    };
+
+    result
+}
+
+/// Peak level of the default capture device from the OS meter, where
+/// there is one.
+fn hardware_input_level(role: DeviceRole) -> Option<f32> {
+    #[cfg(target_os = "windows")]
+    let level = windows::hardware_input_level(role);
+
+    #[cfg(not(target_os = "windows"))]
+    let level = {
+        let _ = role;
+        None
+    };
+
+    level
+}
+
+/// Render-to-capture delay the OS echo canceller aligns its reference over,
+/// where it can be read back. Opaque on Windows, Linux and Android.
+fn aec_reference_delay(input_device: Option<&str>) -> Option<Duration> {
+    #[cfg(target_os = "macos")]
+    let delay = macos::aec_reference_delay(input_device);
+
+    #[cfg(target_os = "ios")]
+    let delay = ios::aec_reference_delay();
+
+    #[cfg(not(target_os = "macos"))]
+    let _ = input_device;
+
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    let delay = None;
+
+    delay
+}
+
+/// Bypass voice processing on the published unit, or restore it. Only the
+/// Core Audio units can drop their echo reference live; the Windows AEC
+/// control can redirect it but not detach it.
+fn set_voice_processing_bypass(native: &NativeSlot, bypass: bool) -> Result<(), AecError> {
+    #[cfg(target_os = "macos")]
+    let result =
+        native.with(|NativeUnit::AudioUnit(unit)| macos::set_voice_processing_bypass(unit, bypass));
+
+    #[cfg(target_os = "ios")]
+    let result =
+        native.with(|NativeUnit::AudioUnit(unit)| ios::set_voice_processing_bypass(unit, bypass));
+
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    let result: Option<Result<(), AecError>> = {
+        let _ = (native, bypass);
+        None
+    };
+
+    result.unwrap_or(Err(AecError::AecNotSupported))
+}
+
+/// Background thread name tagged with `AecConfig::thread_label`, so
+/// profiles and crash stacks from concurrent handles can be told apart.
+pub(crate) fn thread_name(base: &str, config: &AecConfig) -> String {
+    match &config.thread_label {
+        Some(label) => format!("{base}-{label}"),
+        None => base.to_string(),
+    }
+}
+
+/// Whether `channels` asks for the device channels as they are, leaving
+/// the pipeline to shape them, rather than a mono capture.
+pub(crate) fn keeps_channels(channels: Channels) -> bool {
+    matches!(channels, Channels::Native | Channels::StereoTrue)
+}
+
+/// Reject a `SelectChannel` index the opened device doesn't have.
+pub(crate) fn check_downmix(mode: DownmixMode, channels: usize) -> Result<(), AecError> {
+    match mode {
+        DownmixMode::SelectChannel(index) if index as usize >= channels => {
+            Err(AecError::InvalidConfig(format!(
+                "input channel {index} selected but the device has {channels}"
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Reduce interleaved frames to mono as `mode` asks. Call `check_downmix`
+/// first.
+pub(crate) fn to_mono(samples: &[f32], channels: usize, mode: DownmixMode) -> Vec<f32> {
+    match mode {
+        DownmixMode::Average => dsp::downmix(samples, channels),
+        DownmixMode::SelectChannel(index) => dsp::select_channel(samples, channels, index as usize),
+    }
+}
+
+/// Fit interleaved playback to an output with another channel count: mono
+/// is copied to every output channel, and a mono output gets the average.
+/// Otherwise channels map by position, so stereo lands on the front pair
+/// of a surround output, and channels the output lacks are dropped.
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+pub(crate) fn remap_channels(samples: Vec<f32>, from: usize, to: usize) -> Vec<f32> {
+    if from == to {
+        return samples;
+    }
+    if to == 1 {
+        return dsp::downmix(&samples, from);
+    }
+    samples
+        .chunks_exact(from)
+        .flat_map(|frame| {
+            (0..to).map(move |channel| match from {
+                1 => frame[0],
+                _ => frame.get(channel).copied().unwrap_or(0.0),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn muting_fades_out_and_holds_the_queue() {
+        let mute = Arc::new(PlaybackMute::default());
+        // 1 kHz makes the 5 ms ramp five frames
+        let mut ramp = MuteRamp::new(mute.clone(), 1000);
+        let mut queue: VecDeque<f32> = vec![1.0; 100].into();
+        let pull = |ramp: &mut MuteRamp, queue: &mut VecDeque<f32>| {
+            let mut out = [0.0; 4];
+            let taken = ramp.audible(out.len()).min(queue.len());
+            for (sample, queued) in out.iter_mut().zip(queue.drain(..taken)) {
+                *sample = queued * ramp.next_gain();
+            }
+            out
+        };
+
+        assert_eq!(pull(&mut ramp, &mut queue), [1.0; 4]);
+        mute.set(true);
+        let fade: Vec<f32> = [pull(&mut ramp, &mut queue), pull(&mut ramp, &mut queue)].concat();
+        let expected = [0.8, 0.6, 0.4, 0.2, 0.0, 0.0, 0.0, 0.0];
+        assert!(fade.iter().zip(expected).all(|(s, e)| (s - e).abs() < 1e-6));
+        let held = queue.len();
+        assert_eq!(pull(&mut ramp, &mut queue), [0.0; 4]);
+        assert_eq!(queue.len(), held);
+
+        mute.set(false);
+        let fade_in = pull(&mut ramp, &mut queue);
+        assert!(fade_in.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(fade_in[0] > 0.0 && fade_in[3] < 1.0);
+        assert_eq!(queue.len(), held - 4);
+    }
+
+    #[test]
+    fn rate_ranges_become_discrete_rates() {
+        let ranges = [(44100.0, 44100.0), (48000.0, 48000.0), (8000.0, 24000.0)];
+        assert_eq!(
+            rates_in_ranges(&ranges),
+            vec![8000, 11025, 16000, 22050, 24000, 44100, 48000]
+        );
+        assert!(rates_in_ranges(&[]).is_empty());
+    }
+
+    #[test]
+    fn full_queues_drop_by_policy() {
+        let fill = |policy| {
+            let (tx, rx) = flume::bounded(2);
+            let sender = QueueSender::new(tx, &rx, policy);
+            for chunk in 1..=3 {
+                assert!(sender.send(chunk));
+            }
+            let kept: Vec<i32> = rx.try_iter().collect();
+            drop(rx);
+            (kept, sender.send(4))
+        };
+
+        assert_eq!(fill(OverflowPolicy::DropNewest), (vec![1, 2], false));
+        assert_eq!(fill(OverflowPolicy::DropOldest), (vec![2, 3], false));
+    }
+
+    #[test]
+    fn dropped_chunks_leave_a_gap() {
+        let (tx, rx) = flume::bounded(2);
+        let sender = ChunkSender::new(tx, &rx, OverflowPolicy::DropOldest);
+        for chunk in 1..=3 {
+            assert!(sender.send(vec![chunk as f32; 4]));
+        }
+        sender.skip(2);
+        assert!(sender.send(vec![4.0; 4]));
+        let starts: Vec<u64> = rx.try_iter().map(|chunk| chunk.start).collect();
+        assert_eq!(starts, [8, 14]);
+
+        let mut clock = DeviceClock::default();
+        assert_eq!(clock.gap(1000, 480), 0);
+        assert_eq!(clock.gap(1480, 480), 0);
+        assert_eq!(clock.gap(2400, 480), 440);
+    }
+
+    #[test]
+    fn only_changed_device_lists_are_reported() {
+        let ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        let (tx, rx) = flume::unbounded();
+        // A burst of notifications for one headset plugged in
+        for _ in 0..3 {
+            tx.send(()).unwrap();
+        }
+        drop(tx);
+
+        let mut listings = 0;
+        let mut reported = Vec::new();
+        report_device_changes(
+            rx,
+            ids(&["mic"]),
+            || {
+                listings += 1;
+                ids(&["mic", "headset"])
+            },
+            |devices| reported.push(devices),
+        );
+        assert_eq!(listings, 1);
+        assert_eq!(reported, [ids(&["mic", "headset"])]);
+
+        // A volume change, say, leaves the list alone
+        let (tx, rx) = flume::unbounded();
+        tx.send(()).unwrap();
+        drop(tx);
+        report_device_changes(
+            rx,
+            ids(&["mic"]),
+            || ids(&["mic"]),
+            |_| panic!("unchanged list reported"),
+        );
+    }
+
+    #[test]
+    fn first_present_preferred_device_is_picked() {
+        let ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        let preferred = ids(&["usb-headset", "built-in"]);
+
+        let picked = pick_input_device(&preferred, &ids(&["built-in", "usb-headset"]));
+        assert_eq!(picked.as_deref(), Some("usb-headset"));
+        let picked = pick_input_device(&preferred, &ids(&["built-in", "webcam"]));
+        assert_eq!(picked.as_deref(), Some("built-in"));
+        assert_eq!(pick_input_device(&preferred, &ids(&["webcam"])), None);
+    }
+
+    #[test]
+    fn playback_channels_remap_by_position() {
+        let stereo = vec![0.1, 0.2, 0.3, 0.4];
+        assert_eq!(remap_channels(stereo.clone(), 2, 2), stereo);
+        assert_eq!(
+            remap_channels(vec![0.5, -0.5], 1, 2),
+            vec![0.5, 0.5, -0.5, -0.5]
+        );
+        let mono = remap_channels(stereo.clone(), 2, 1);
+        assert!((mono[0] - 0.15).abs() < 1e-6 && (mono[1] - 0.35).abs() < 1e-6);
+        assert_eq!(
+            remap_channels(stereo.clone(), 2, 4),
+            vec![0.1, 0.2, 0.0, 0.0, 0.3, 0.4, 0.0, 0.0]
+        );
+        assert_eq!(
+            remap_channels(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6], 3, 2),
+            vec![0.1, 0.2, 0.4, 0.5]
+        );
+    }
+
+    #[test]
+    fn markers_fire_once_their_samples_are_consumed() {
+        let mut markers = PlaybackMarkers::default();
+        let (first_tx, first_rx) = flume::bounded(1);
+        let (second_tx, second_rx) = flume::bounded(1);
+        markers.append(100, Some(first_tx));
+        markers.append(50, None);
+        markers.append(50, Some(second_tx));
+
+        markers.consume(99);
+        assert!(first_rx.try_recv().is_err());
+        markers.consume(1);
+        assert!(first_rx.try_recv().is_ok());
+
+        markers.consume(99);
+        assert!(second_rx.try_recv().is_err());
+        markers.consume(10);
+        assert!(second_rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn markers_follow_a_device_position() {
+        let mut markers = PlaybackMarkers::default();
+        let (done_tx, done_rx) = flume::bounded(1);
+        markers.append(480, None);
+        markers.append(0, Some(done_tx));
+        assert!(markers.is_waiting());
+
+        // 480 written, 100 still buffered in the device
+        markers.consume_to(markers.appended() - 100);
+        assert!(done_rx.try_recv().is_err());
+        markers.consume_to(200);
+        assert!(markers.is_waiting());
+        markers.consume_to(markers.appended());
+        assert!(done_rx.try_recv().is_ok());
+        assert!(!markers.is_waiting());
+    }
+
+    #[test]
+    fn playback_request_converts_or_answers_with_the_error() {
+        let (done_tx, done_rx) = flume::bounded(1);
+        let mut request = playback_request(vec![0.5; 1234], 16000, 1, Some(done_tx));
+        let samples = request.samples_at(48000).unwrap();
+        assert!(samples.len() >= 3 * 1234, "tail dropped: {}", samples.len());
+        assert!(request.done.is_some());
+
+        // Too low a rate to resample: the request's ticket gets the error
+        let (done_tx, done_rx_bad) = flume::bounded(1);
+        let mut request = playback_request(vec![0.5; 10], 10, 1, Some(done_tx));
+        assert!(request.samples_at(48000).is_none());
+        assert!(matches!(
+            done_rx_bad.try_recv(),
+            Ok(Err(AecError::InvalidConfig(_)))
+        ));
+        assert!(done_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn native_unit_is_withdrawn_with_its_guard() {
+        let slot = Arc::new(NativeSlot::default());
+        assert_eq!(slot.with(|_| ()), None);
+
+        let unit = NativeUnit::AudioUnit(std::ptr::dangling_mut());
+        let guard = slot.publish(unit);
+        assert_eq!(slot.with(|published| published), Some(unit));
+
+        drop(guard);
+        assert_eq!(slot.with(|_| ()), None);
     }
 }
@@ -0,0 +1,165 @@
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{BufferSize, FromSample, SampleFormat, SizedSample, StreamConfig, StreamError};
+
+use crate::backends::{
+    check_downmix, keeps_channels, thread_name, to_mono, ChunkSender, StreamInfo,
+};
+use crate::latency::LatencyPlan;
+use crate::{AecConfig, AecError, DownmixMode};
+
+/// Reported by `CaptureHandle::backend_name()`
+pub const BACKEND_NAME: &str = "cpal";
+
+/// Buffer length assumed when cpal picks the device default
+const DEFAULT_BUFFER_MS: u32 = 10;
+
+/// Open the default input device through cpal, with no echo cancellation.
+/// cpal streams are not `Send` on every host, so a dedicated thread builds
+/// and owns the stream, dropping it when the receiver goes away or the
+/// device errors. The sender drops with the stream, which the pipeline
+/// reports as `DeviceLost`.
+pub fn create_backend(config: &AecConfig, sender: ChunkSender) -> Result<StreamInfo, AecError> {
+    let (meta_tx, meta_rx) = flume::bounded::<Result<StreamInfo, AecError>>(1);
+    // None keeps every channel
+    let downmix = (!keeps_channels(config.channels)).then_some(config.downmix);
+    let plan = LatencyPlan::new(config.latency_target);
+
+    std::thread::Builder::new()
+        .name(thread_name("sys-voice-cpal", config))
+        .spawn(move || {
+            let (done_tx, done_rx) = flume::bounded::<()>(1);
+            let (stream, info) = match open_stream(&plan, downmix, sender, done_tx) {
+                Ok(opened) => opened,
+                Err(e) => {
+                    let _ = meta_tx.send(Err(e));
+                    return;
+                }
+            };
+            let _ = meta_tx.send(Ok(info));
+
+            let _ = done_rx.recv();
+            drop(stream);
+        })
+        .map_err(|e| AecError::BackendError(format!("failed to spawn cpal thread: {e:?}")))?;
+
+    meta_rx.recv().map_err(|_| {
+        AecError::BackendError("cpal thread died before sending metadata".to_string())
+    })?
+}
+
+fn open_stream(
+    plan: &LatencyPlan,
+    downmix: Option<DownmixMode>,
+    sender: ChunkSender,
+    done_tx: flume::Sender<()>,
+) -> Result<(cpal::Stream, StreamInfo), AecError> {
+    let device = cpal::default_host()
+        .default_input_device()
+        .ok_or(AecError::DeviceUnavailable)?;
+    let supported = device
+        .default_input_config()
+        .map_err(|e| AecError::BackendError(format!("failed to query input config: {e}")))?;
+
+    let sample_rate = supported.sample_rate().0;
+    let native_channels = supported.channels();
+    if let Some(mode) = downmix {
+        check_downmix(mode, native_channels as usize)?;
+    }
+    let format = supported.sample_format();
+    let stream_config = supported.config();
+    let build =
+        |config: &StreamConfig| build_stream(&device, config, format, downmix, &sender, &done_tx);
+
+    // A fixed buffer is only a request; hosts that reject it get the default
+    let fixed = plan.device_buffer_frames(sample_rate).and_then(|frames| {
+        let config = StreamConfig {
+            buffer_size: BufferSize::Fixed(frames as u32),
+            ..stream_config.clone()
+        };
+        build(&config).ok().map(|stream| (stream, frames))
+    });
+    let (stream, buffer_size) = match fixed {
+        Some(opened) => opened,
+        None => {
+            let stream = build(&stream_config)
+                .map_err(|e| AecError::BackendError(format!("failed to build cpal stream: {e}")))?;
+            (stream, (sample_rate * DEFAULT_BUFFER_MS / 1000) as usize)
+        }
+    };
+
+    stream
+        .play()
+        .map_err(|e| AecError::BackendError(format!("failed to start cpal stream: {e}")))?;
+
+    let info = StreamInfo {
+        sample_rate,
+        buffer_size,
+        channels: if downmix.is_some() {
+            1
+        } else {
+            native_channels
+        },
+        playback_rate: None,
+        sharing_mode: None,
+        backend: BACKEND_NAME,
+        aec: false,
+    };
+    Ok((stream, info))
+}
+
+fn build_stream(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    format: SampleFormat,
+    downmix: Option<DownmixMode>,
+    sender: &ChunkSender,
+    done_tx: &flume::Sender<()>,
+) -> Result<cpal::Stream, cpal::BuildStreamError> {
+    match format {
+        SampleFormat::F32 => build::<f32>(device, config, downmix, sender, done_tx),
+        SampleFormat::I16 => build::<i16>(device, config, downmix, sender, done_tx),
+        SampleFormat::U16 => build::<u16>(device, config, downmix, sender, done_tx),
+        _ => Err(cpal::BuildStreamError::StreamConfigNotSupported),
+    }
+}
+
+fn build<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    downmix: Option<DownmixMode>,
+    sender: &ChunkSender,
+    done_tx: &flume::Sender<()>,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let channels = config.channels as usize;
+    let sender = sender.clone();
+    let data_done = done_tx.clone();
+    let error_done = done_tx.clone();
+
+    device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            let samples: Vec<f32> = data.iter().map(|s| s.to_sample::<f32>()).collect();
+            let samples = match downmix {
+                Some(mode) => to_mono(&samples, channels, mode),
+                None => samples,
+            };
+
+            // A full queue is handled by the overflow policy; a closed one
+            // means the handle is gone
+            if !sender.send(samples) {
+                let _ = data_done.try_send(());
+            }
+        },
+        move |e: StreamError| {
+            tracing::warn!("cpal stream error: {e}");
+            if matches!(e, StreamError::DeviceNotAvailable) {
+                let _ = error_done.try_send(());
+            }
+        },
+        None,
+    )
+}
@@ -0,0 +1,267 @@
+//! System audio output captured through ScreenCaptureKit, for
+//! `AecConfig::system_audio`. Audio capture arrived in macOS 13; the
+//! framework is loaded at runtime so the crate still links on older systems.
+
+use crate::AecError;
+use block2::{Block, RcBlock};
+use flume::Sender;
+use objc2::rc::{Allocated, Retained};
+use objc2::runtime::{
+    AnyClass, AnyObject, AnyProtocol, Bool, MessageReceiver, NSObject, NSObjectProtocol, Sel,
+};
+use objc2::{define_class, msg_send, sel, AnyThread, ClassType, DefinedClass};
+use objc2_foundation::NSString;
+use std::ffi::{c_void, CStr};
+use std::ptr;
+use std::sync::Once;
+use std::time::Duration;
+
+/// Rate system audio is requested at, one of the few ScreenCaptureKit offers
+pub(crate) const SAMPLE_RATE: u32 = 48000;
+
+const SCREEN_CAPTURE_KIT: &CStr =
+    c"/System/Library/Frameworks/ScreenCaptureKit.framework/ScreenCaptureKit";
+
+// SCStreamOutputType.audio
+const SC_STREAM_OUTPUT_TYPE_AUDIO: isize = 1;
+// SCStreamErrorUserDeclined
+const SC_STREAM_ERROR_USER_DECLINED: isize = -3801;
+// kCMSampleBufferFlag_AudioBufferList_Assure16ByteAlignment
+const AUDIO_BUFFER_LIST_ASSURE_16_BYTE_ALIGNMENT: u32 = 1;
+
+/// How long to wait for ScreenCaptureKit's completion handlers
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[link(name = "CoreMedia", kind = "framework")]
+extern "C" {
+    fn CMSampleBufferGetAudioBufferListWithRetainedBlockBuffer(
+        sample_buffer: *mut c_void,
+        buffer_list_size_needed_out: *mut usize,
+        buffer_list_out: *mut coreaudio::sys::AudioBufferList,
+        buffer_list_size: usize,
+        block_buffer_structure_allocator: *const c_void,
+        block_buffer_block_allocator: *const c_void,
+        flags: u32,
+        block_buffer_out: *mut *mut c_void,
+    ) -> i32;
+}
+
+#[link(name = "CoreFoundation", kind = "framework")]
+extern "C" {
+    fn CFRelease(cf: *const c_void);
+}
+
+define_class!(
+    // SAFETY: NSObject has no subclassing requirements and SystemAudioOutput
+    // doesn't implement Drop
+    #[unsafe(super(NSObject))]
+    #[name = "SysVoiceSystemAudioOutput"]
+    #[ivars = Sender<Vec<f32>>]
+    struct SystemAudioOutput;
+
+    unsafe impl NSObjectProtocol for SystemAudioOutput {}
+
+    impl SystemAudioOutput {
+        // SCStreamOutput, called on a ScreenCaptureKit queue
+        #[unsafe(method(stream:didOutputSampleBuffer:ofType:))]
+        fn stream_did_output(
+            &self,
+            _stream: *mut AnyObject,
+            sample_buffer: *mut c_void,
+            kind: isize,
+        ) {
+            if kind != SC_STREAM_OUTPUT_TYPE_AUDIO {
+                return;
+            }
+            if let Some(samples) = audio_samples(sample_buffer) {
+                let _ = self.ivars().try_send(samples);
+            }
+        }
+    }
+);
+
+impl SystemAudioOutput {
+    fn new(sender: Sender<Vec<f32>>) -> Retained<Self> {
+        // ScreenCaptureKit checks the protocol, which has no Rust binding to
+        // declare it through
+        static ADOPT: Once = Once::new();
+        ADOPT.call_once(|| {
+            if let Some(protocol) = AnyProtocol::get(c"SCStreamOutput") {
+                unsafe {
+                    objc2::ffi::class_addProtocol(
+                        Self::class() as *const AnyClass as *mut AnyClass,
+                        protocol,
+                    )
+                };
+            }
+        });
+
+        let this = Self::alloc().set_ivars(sender);
+        unsafe { msg_send![super(this), init] }
+    }
+}
+
+/// A running ScreenCaptureKit stream. Capture stops when it drops.
+pub(crate) struct SystemAudioStream {
+    stream: Retained<AnyObject>,
+    _output: Retained<SystemAudioOutput>,
+}
+
+// SAFETY: SCStream can be stopped from any thread, and the output is only
+// touched by ScreenCaptureKit
+unsafe impl Send for SystemAudioStream {}
+
+impl Drop for SystemAudioStream {
+    fn drop(&mut self) {
+        let handler: *const Block<dyn Fn(*mut AnyObject)> = ptr::null();
+        let _: () = unsafe { msg_send![&self.stream, stopCaptureWithCompletionHandler: handler] };
+    }
+}
+
+/// Start capturing the main display's audio output as mono `f32` at
+/// `SAMPLE_RATE`, sent in the chunks ScreenCaptureKit delivers.
+/// `AecNotSupported` before macOS 13; `PermissionDenied` without the
+/// Screen Recording permission.
+pub(crate) fn start(sender: Sender<Vec<f32>>) -> Result<SystemAudioStream, AecError> {
+    if unsafe { libc::dlopen(SCREEN_CAPTURE_KIT.as_ptr(), libc::RTLD_LAZY) }.is_null() {
+        return Err(AecError::AecNotSupported);
+    }
+    let config_class = class(c"SCStreamConfiguration")?;
+    let config: Retained<AnyObject> = unsafe { msg_send![config_class, new] };
+    let captures_audio: bool =
+        unsafe { msg_send![&config, respondsToSelector: sel!(setCapturesAudio:)] };
+    if !captures_audio {
+        return Err(AecError::AecNotSupported);
+    }
+    unsafe {
+        let _: () = msg_send![&config, setCapturesAudio: true];
+        let _: () = msg_send![&config, setSampleRate: SAMPLE_RATE as isize];
+        let _: () = msg_send![&config, setChannelCount: 1isize];
+        // Video can't be turned off, only made as cheap as possible
+        let _: () = msg_send![&config, setWidth: 2usize];
+        let _: () = msg_send![&config, setHeight: 2usize];
+    }
+
+    let display = main_display()?;
+    let (array_class, filter_class) = (class(c"NSArray")?, class(c"SCContentFilter")?);
+    let windows: Retained<AnyObject> = unsafe { msg_send![array_class, array] };
+    let filter: Allocated<AnyObject> = unsafe { msg_send![filter_class, alloc] };
+    let filter: Retained<AnyObject> =
+        unsafe { msg_send![filter, initWithDisplay: &*display, excludingWindows: &*windows] };
+
+    let stream_class = class(c"SCStream")?;
+    let stream: Allocated<AnyObject> = unsafe { msg_send![stream_class, alloc] };
+    let delegate: *const AnyObject = ptr::null();
+    let stream: Retained<AnyObject> = unsafe {
+        msg_send![stream, initWithFilter: &*filter, configuration: &*config, delegate: delegate]
+    };
+
+    // `type:` is a Rust keyword, so this one can't go through msg_send!
+    let output = SystemAudioOutput::new(sender);
+    let mut error: *mut AnyObject = ptr::null_mut();
+    let queue: *const AnyObject = ptr::null();
+    let added: Bool = unsafe {
+        (&*stream).send_message(
+            Sel::register(c"addStreamOutput:type:sampleHandlerQueue:error:"),
+            (
+                Retained::as_ptr(&output) as *const AnyObject,
+                SC_STREAM_OUTPUT_TYPE_AUDIO,
+                queue,
+                &mut error as *mut *mut AnyObject,
+            ),
+        )
+    };
+    if !added.as_bool() {
+        return Err(ns_error("failed to add system audio output", error));
+    }
+
+    let (done_tx, done_rx) = flume::bounded::<Result<(), AecError>>(1);
+    let handler = RcBlock::new(move |error: *mut AnyObject| {
+        let result = if error.is_null() {
+            Ok(())
+        } else {
+            Err(ns_error("failed to start system audio capture", error))
+        };
+        let _ = done_tx.try_send(result);
+    });
+    let _: () = unsafe { msg_send![&stream, startCaptureWithCompletionHandler: &*handler] };
+    let stream = SystemAudioStream {
+        stream,
+        _output: output,
+    };
+    done_rx
+        .recv_timeout(START_TIMEOUT)
+        .map_err(|_| AecError::BackendError("system audio capture did not start".to_string()))??;
+    Ok(stream)
+}
+
+/// First display in the shareable content, whose filter covers all audio
+/// output. Fails with the permission error when Screen Recording is denied.
+fn main_display() -> Result<Retained<AnyObject>, AecError> {
+    let (display_tx, display_rx) = flume::bounded(1);
+    let handler = RcBlock::new(move |content: *mut AnyObject, error: *mut AnyObject| {
+        let result = if content.is_null() {
+            Err(ns_error("failed to list shareable content", error))
+        } else {
+            let displays: *mut AnyObject = unsafe { msg_send![content, displays] };
+            let display: Option<Retained<AnyObject>> = unsafe { msg_send![displays, firstObject] };
+            display.ok_or(AecError::DeviceUnavailable)
+        };
+        let _ = display_tx.try_send(result);
+    });
+    let content_class = class(c"SCShareableContent")?;
+    let _: () =
+        unsafe { msg_send![content_class, getShareableContentWithCompletionHandler: &*handler] };
+    display_rx
+        .recv_timeout(START_TIMEOUT)
+        .map_err(|_| AecError::BackendError("shareable content did not arrive".to_string()))?
+}
+
+fn class(name: &CStr) -> Result<&'static AnyClass, AecError> {
+    AnyClass::get(name).ok_or(AecError::AecNotSupported)
+}
+
+/// Map an NSError, which may be nil, to `PermissionDenied` or `OsError`.
+fn ns_error(context: &str, error: *mut AnyObject) -> AecError {
+    if error.is_null() {
+        return AecError::BackendError(context.to_string());
+    }
+    let code: isize = unsafe { msg_send![error, code] };
+    if code == SC_STREAM_ERROR_USER_DECLINED {
+        return AecError::PermissionDenied;
+    }
+    let description: Retained<NSString> = unsafe { msg_send![error, localizedDescription] };
+    AecError::OsError {
+        message: format!("{context}: {description}"),
+        code: code as i64,
+    }
+}
+
+/// Copy the samples out of a mono float audio sample buffer.
+fn audio_samples(sample_buffer: *mut c_void) -> Option<Vec<f32>> {
+    let mut list: coreaudio::sys::AudioBufferList = unsafe { std::mem::zeroed() };
+    let mut block_buffer: *mut c_void = ptr::null_mut();
+    let status = unsafe {
+        CMSampleBufferGetAudioBufferListWithRetainedBlockBuffer(
+            sample_buffer,
+            ptr::null_mut(),
+            &mut list,
+            std::mem::size_of::<coreaudio::sys::AudioBufferList>(),
+            ptr::null(),
+            ptr::null(),
+            AUDIO_BUFFER_LIST_ASSURE_16_BYTE_ALIGNMENT,
+            &mut block_buffer,
+        )
+    };
+    if status != 0 {
+        return None;
+    }
+
+    let buffer = &list.mBuffers[0];
+    let samples = (!buffer.mData.is_null()).then(|| {
+        let len = buffer.mDataByteSize as usize / std::mem::size_of::<f32>();
+        unsafe { std::slice::from_raw_parts(buffer.mData as *const f32, len) }.to_vec()
+    });
+    unsafe { CFRelease(block_buffer) };
+    samples
+}
@@ -0,0 +1,398 @@
+//! Lets handles with compatible configs receive from one device stream
+//! instead of each opening their own.
+//!
+//! The first handle opens the backend and a fan-out thread that copies
+//! every chunk to each subscribed handle. Later handles whose config needs
+//! the same stream subscribe to it. Each handle keeps its own pipeline, so
+//! rate, gain and chunking stay per handle. The stream closes once the last
+//! handle drops. The fan-out also keeps the last `AecConfig::pre_roll` of
+//! audio, which a handle subscribing late receives first.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use super::{
+    keeps_channels, open_backend, thread_name, BackendHandle, Chunk, ChunkSender, StreamInfo,
+};
+use crate::latency::LatencyPlan;
+#[cfg(feature = "system-audio")]
+use crate::SystemAudio;
+use crate::{
+    AecConfig, AecError, CaptureStatus, DeviceRole, DownmixMode, Ducking, LinuxAudioApi,
+    OverflowPolicy, Processing, ResamplerQuality, SharingMode, StreamProperties,
+};
+
+/// Open streams, by the settings their backend was opened with
+static STREAMS: Mutex<Vec<(StreamKey, Weak<SharedStream>)>> = Mutex::new(Vec::new());
+
+/// Backend settings two handles must agree on to share a stream.
+#[derive(Debug, Clone, PartialEq)]
+struct StreamKey {
+    processing: Processing,
+    sharing_mode: SharingMode,
+    keeps_channels: bool,
+    /// None when channels are kept, since nothing is downmixed
+    downmix: Option<DownmixMode>,
+    latency_target: Option<Duration>,
+    overflow_policy: OverflowPolicy,
+    playback_buffer_max: Option<Duration>,
+    other_audio_ducking: Ducking,
+    linux_api: LinuxAudioApi,
+    stream_properties: StreamProperties,
+    device_role: DeviceRole,
+    preferred_input_devices: Vec<String>,
+    bluetooth_sco: bool,
+    device_resampler_quality: ResamplerQuality,
+    #[cfg(feature = "system-audio")]
+    system_audio: SystemAudio,
+}
+
+impl StreamKey {
+    /// None for configs that need a stream of their own: file input is
+    /// read once per handle, a render source owns the output, and a raw
+    /// capture tee is set when the backend opens.
+    fn for_config(config: &AecConfig) -> Option<Self> {
+        #[cfg(feature = "backend-file")]
+        if config.input_file.is_some() {
+            return None;
+        }
+        #[cfg(feature = "debug-capture")]
+        if config.raw_capture.is_some() {
+            return None;
+        }
+        if config.render_source.is_some() {
+            return None;
+        }
+
+        let keeps_channels = keeps_channels(config.channels);
+        Some(Self {
+            processing: config.processing,
+            sharing_mode: config.sharing_mode,
+            keeps_channels,
+            downmix: (!keeps_channels).then_some(config.downmix),
+            latency_target: config.latency_target,
+            overflow_policy: config.overflow_policy,
+            playback_buffer_max: config.playback_buffer_max,
+            other_audio_ducking: config.other_audio_ducking,
+            linux_api: config.linux_api,
+            stream_properties: config.stream_properties.clone(),
+            device_role: config.device_role,
+            preferred_input_devices: config.preferred_input_devices.clone(),
+            bluetooth_sco: config.bluetooth_sco,
+            device_resampler_quality: config.device_resampler_quality,
+            #[cfg(feature = "system-audio")]
+            system_audio: config.system_audio,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Subscribers {
+    audio: Vec<ChunkSender>,
+    status: Vec<flume::Sender<CaptureStatus>>,
+    /// Set once the stream has ended, after which nobody can subscribe
+    closed: bool,
+    /// Recent audio for handles that subscribe late
+    history: History,
+}
+
+impl Subscribers {
+    fn add(
+        &mut self,
+        sender: ChunkSender,
+        status_tx: flume::Sender<CaptureStatus>,
+        pre_roll: usize,
+    ) -> bool {
+        if self.closed {
+            return false;
+        }
+        // One chunk, so it fits the new handle's empty queue
+        let recent = self.history.tail(pre_roll);
+        if !recent.samples.is_empty() {
+            sender.forward(recent);
+        }
+        self.history.max = self.history.max.max(pre_roll);
+        self.audio.push(sender);
+        self.status.push(status_tx);
+        true
+    }
+}
+
+/// The most recent chunks, up to the largest pre-roll any subscriber has
+/// asked for.
+#[derive(Default)]
+struct History {
+    chunks: VecDeque<Vec<f32>>,
+    /// Samples across `chunks`
+    len: usize,
+    /// Samples to keep
+    max: usize,
+    /// Where the newest chunk ends in the stream's input
+    end: u64,
+}
+
+impl History {
+    fn push(&mut self, chunk: &Chunk) {
+        self.end = chunk.start + chunk.samples.len() as u64;
+        if self.max == 0 {
+            return;
+        }
+        self.len += chunk.samples.len();
+        self.chunks.push_back(chunk.samples.clone());
+        while let Some(oldest) = self.chunks.front() {
+            if self.len - oldest.len() < self.max {
+                break;
+            }
+            self.len -= oldest.len();
+            self.chunks.pop_front();
+        }
+    }
+
+    /// The last `samples` of the history, or all of it if shorter, placed
+    /// to end where the newest chunk does.
+    fn tail(&self, samples: usize) -> Chunk {
+        let skip = self.len.saturating_sub(samples);
+        let samples: Vec<f32> = self.chunks.iter().flatten().skip(skip).copied().collect();
+        Chunk {
+            start: self.end.saturating_sub(samples.len() as u64),
+            samples,
+        }
+    }
+}
+
+/// `pre_roll` in interleaved samples of the stream's format.
+fn pre_roll_samples(pre_roll: Duration, info: &StreamInfo) -> usize {
+    (pre_roll.as_secs_f64() * info.sample_rate as f64) as usize * info.channels as usize
+}
+
+/// An open backend stream. Every handle receiving from it holds one
+/// reference through its `BackendHandle`.
+pub(crate) struct SharedStream {
+    info: StreamInfo,
+    handle: BackendHandle,
+    subscribers: Arc<Mutex<Subscribers>>,
+}
+
+impl SharedStream {
+    /// Add a handle's channels, or false if the stream has already ended.
+    /// The handle first receives up to `pre_roll` samples of recent audio.
+    fn subscribe(
+        &self,
+        sender: ChunkSender,
+        status_tx: flume::Sender<CaptureStatus>,
+        pre_roll: usize,
+    ) -> bool {
+        self.subscribers
+            .lock()
+            .is_ok_and(|mut subscribers| subscribers.add(sender, status_tx, pre_roll))
+    }
+
+    /// Whether the fan-out has ended. Old handles may still hold the
+    /// stream, but no new one can join it.
+    fn is_closed(&self) -> bool {
+        self.subscribers
+            .lock()
+            .map_or(true, |subscribers| subscribers.closed)
+    }
+}
+
+/// Subscribe to an open stream matching `config`, opening one if none is.
+/// Returns the stream format and a handle that keeps the stream open.
+pub(crate) fn create_backend(
+    config: &AecConfig,
+    sender: ChunkSender,
+    status_tx: flume::Sender<CaptureStatus>,
+    runtime: &tokio::runtime::Handle,
+) -> Result<(StreamInfo, BackendHandle), AecError> {
+    let Some(key) = StreamKey::for_config(config) else {
+        return open_backend(config, sender, status_tx, runtime);
+    };
+
+    // Held across the open so two handles can't both open the device
+    let mut streams = STREAMS
+        .lock()
+        .map_err(|_| AecError::BackendError("stream registry poisoned".to_string()))?;
+    // A closed stream would be found, fail to subscribe and be opened
+    // again next to itself on every later open
+    streams.retain(|(_, stream)| stream.upgrade().is_some_and(|s| !s.is_closed()));
+
+    let existing = streams
+        .iter()
+        .filter(|(k, _)| *k == key)
+        .find_map(|(_, stream)| stream.upgrade());
+    if let Some(stream) = existing {
+        let pre_roll = pre_roll_samples(config.pre_roll, &stream.info);
+        if stream.subscribe(sender.clone(), status_tx.clone(), pre_roll) {
+            tracing::debug!("sharing an open {} stream", stream.info.backend);
+            let handle = stream.handle.clone().shared(stream.clone());
+            return Ok((stream.info, handle));
+        }
+    }
+
+    let (stream_tx, stream_rx) =
+        flume::bounded::<Chunk>(LatencyPlan::new(config.latency_target).queue_depth);
+    let (stream_status_tx, stream_status_rx) = flume::unbounded::<CaptureStatus>();
+    let stream_tx = ChunkSender::new(stream_tx, &stream_rx, config.overflow_policy);
+    let (info, handle) = open_backend(config, stream_tx, stream_status_tx, runtime)?;
+
+    let subscribers = Arc::new(Mutex::new(Subscribers {
+        audio: vec![sender],
+        status: vec![status_tx],
+        closed: false,
+        history: History {
+            max: pre_roll_samples(config.pre_roll, &info),
+            ..History::default()
+        },
+    }));
+
+    let fan_out_subscribers = subscribers.clone();
+    std::thread::Builder::new()
+        .name(thread_name("sys-voice-fanout", config))
+        .spawn(move || fan_out(stream_rx, &fan_out_subscribers))
+        .map_err(|e| AecError::BackendError(format!("failed to spawn fan-out thread: {e:?}")))?;
+
+    let status_subscribers = subscribers.clone();
+    runtime.spawn(async move {
+        while let Ok(status) = stream_status_rx.recv_async().await {
+            if let Ok(subscribers) = status_subscribers.lock() {
+                for tx in &subscribers.status {
+                    let _ = tx.send(status);
+                }
+            }
+        }
+    });
+
+    let stream = Arc::new(SharedStream {
+        info,
+        handle: handle.clone(),
+        subscribers,
+    });
+    streams.push((key, Arc::downgrade(&stream)));
+    Ok((info, handle.shared(stream)))
+}
+
+/// Copy every chunk to each subscriber until the backend stops or the last
+/// subscriber goes away, then close the stream. Dropping the receiver is
+/// what stops the backend. A subscriber whose queue is full is handled by
+/// its `OverflowPolicy`, so under `Block` it holds back the others.
+fn fan_out(stream_rx: flume::Receiver<Chunk>, subscribers: &Mutex<Subscribers>) {
+    while let Ok(chunk) = stream_rx.recv() {
+        let Ok(mut subscribers) = subscribers.lock() else {
+            break;
+        };
+        subscribers.audio.retain(|tx| tx.forward(chunk.clone()));
+        if subscribers.audio.is_empty() {
+            break;
+        }
+        subscribers.history.push(&chunk);
+    }
+
+    // Handles still subscribed see their stream end as `DeviceLost`
+    if let Ok(mut subscribers) = subscribers.lock() {
+        subscribers.closed = true;
+        subscribers.audio.clear();
+        subscribers.status.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Channels;
+
+    #[test]
+    fn pipeline_settings_do_not_split_streams() {
+        let first = AecConfig::default();
+        let second = AecConfig {
+            sample_rate: 16000,
+            output_chunk_frames: Some(160),
+            thread_label: Some("second".to_string()),
+            ..AecConfig::default()
+        };
+        assert_eq!(
+            StreamKey::for_config(&first),
+            StreamKey::for_config(&second)
+        );
+
+        let stereo = AecConfig {
+            channels: Channels::StereoTrue,
+            ..AecConfig::default()
+        };
+        assert_ne!(
+            StreamKey::for_config(&first),
+            StreamKey::for_config(&stereo)
+        );
+    }
+
+    #[test]
+    fn fan_out_copies_chunks_and_closes_with_the_last_subscriber() {
+        let (stream_tx, stream_rx) = flume::unbounded();
+        let (first_tx, first_rx) = flume::unbounded();
+        let (second_tx, second_rx) = flume::unbounded();
+        let subscribers = Arc::new(Mutex::new(Subscribers {
+            audio: vec![
+                ChunkSender::new(first_tx, &first_rx, OverflowPolicy::DropNewest),
+                ChunkSender::new(second_tx, &second_rx, OverflowPolicy::DropNewest),
+            ],
+            ..Subscribers::default()
+        }));
+
+        let thread_subscribers = subscribers.clone();
+        let thread = std::thread::spawn(move || fan_out(stream_rx, &thread_subscribers));
+
+        let chunk = |start, samples| Chunk { start, samples };
+        stream_tx.send(chunk(8, vec![0.5; 4])).unwrap();
+        // Copies keep their place in the stream
+        assert_eq!(first_rx.recv().unwrap(), chunk(8, vec![0.5; 4]));
+        assert_eq!(second_rx.recv().unwrap(), chunk(8, vec![0.5; 4]));
+
+        drop((first_rx, second_rx));
+        stream_tx.send(chunk(12, vec![0.25; 4])).unwrap();
+        thread.join().unwrap();
+
+        assert!(subscribers.lock().unwrap().closed);
+        assert!(stream_tx.send(chunk(16, vec![0.0])).is_err());
+    }
+
+    #[test]
+    fn late_subscribers_receive_the_pre_roll_first() {
+        let mut history = History {
+            max: 6,
+            ..History::default()
+        };
+        for (start, value) in [(0, 1.0), (4, 2.0), (8, 3.0)] {
+            history.push(&Chunk {
+                start,
+                samples: vec![value; 4],
+            });
+        }
+        // Whole chunks are kept until the oldest is no longer needed
+        assert_eq!(history.chunks.len(), 2);
+        let tail = history.tail(6);
+        assert_eq!(tail.samples, vec![2.0, 2.0, 3.0, 3.0, 3.0, 3.0]);
+        assert_eq!(tail.start, 6);
+        assert_eq!(history.tail(100).samples.len(), 8);
+
+        let info = StreamInfo {
+            sample_rate: 4,
+            buffer_size: 4,
+            channels: 1,
+            playback_rate: None,
+            sharing_mode: None,
+            backend: "test",
+            aec: false,
+        };
+        let mut subscribers = Subscribers {
+            history,
+            ..Subscribers::default()
+        };
+        let (tx, rx) = flume::unbounded();
+        let (status_tx, _status_rx) = flume::unbounded();
+        let sender = ChunkSender::new(tx, &rx, OverflowPolicy::DropNewest);
+        let pre_roll = pre_roll_samples(Duration::from_millis(750), &info);
+        assert!(subscribers.add(sender, status_tx, pre_roll));
+        assert_eq!(rx.try_recv().unwrap().samples, vec![3.0; 3]);
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,167 @@
+//! Combines a system audio stream with the microphone capture for
+//! `AecConfig::system_audio`. The two arrive on separate clocks and in
+//! different chunk sizes, so system samples are buffered and paced by the
+//! microphone.
+
+use crate::resampler::Resampler;
+use crate::SystemAudio;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// System audio buffered before it is mixed in, so jitter in its delivery
+/// doesn't punch gaps into it
+const PREBUFFER: Duration = Duration::from_millis(50);
+/// Most system audio held back; the oldest is dropped past it, so a system
+/// clock running fast can't build up delay
+const MAX_PENDING: Duration = Duration::from_millis(500);
+
+pub(crate) struct SystemMix {
+    mode: SystemAudio,
+    system: flume::Receiver<Vec<f32>>,
+    resampler: Option<Resampler>,
+    pending: VecDeque<f32>,
+    prebuffer: usize,
+    max_pending: usize,
+    /// Set once `prebuffer` samples have arrived, cleared on underrun
+    primed: bool,
+}
+
+impl SystemMix {
+    /// Mix mono system audio arriving at `system_rate` into microphone
+    /// chunks at `mic_rate`.
+    pub fn new(
+        mode: SystemAudio,
+        system: flume::Receiver<Vec<f32>>,
+        system_rate: u32,
+        mic_rate: u32,
+    ) -> Result<Self, crate::AecError> {
+        let resampler = (system_rate != mic_rate)
+            .then(|| Resampler::new(system_rate, mic_rate, 1))
+            .transpose()?;
+        let frames = |duration: Duration| (duration.as_secs_f64() * mic_rate as f64) as usize;
+        Ok(Self {
+            mode,
+            system,
+            resampler,
+            pending: VecDeque::new(),
+            prebuffer: frames(PREBUFFER),
+            max_pending: frames(MAX_PENDING),
+            primed: false,
+        })
+    }
+
+    /// Channels per frame of the mixed output
+    pub fn channels(&self) -> u16 {
+        match self.mode {
+            SystemAudio::Separate => 2,
+            SystemAudio::Off | SystemAudio::Mixed => 1,
+        }
+    }
+
+    /// Combine a mono microphone chunk with as much system audio. Silence
+    /// stands in for system audio that hasn't arrived.
+    pub fn mix(&mut self, mic: Vec<f32>) -> Vec<f32> {
+        while let Ok(chunk) = self.system.try_recv() {
+            match &mut self.resampler {
+                Some(resampler) => match resampler.process(&chunk) {
+                    Ok(resampled) => self.pending.extend(resampled),
+                    Err(e) => tracing::warn!("system audio resampling failed: {e}"),
+                },
+                None => self.pending.extend(chunk),
+            }
+        }
+        let excess = self.pending.len().saturating_sub(self.max_pending);
+        self.pending.drain(..excess);
+
+        if self.pending.len() >= self.prebuffer.max(mic.len()) {
+            self.primed = true;
+        }
+        if self.pending.len() < mic.len() {
+            self.primed = false;
+        }
+        let system: Vec<f32> = if self.primed {
+            self.pending.drain(..mic.len()).collect()
+        } else {
+            vec![0.0; mic.len()]
+        };
+
+        match self.mode {
+            SystemAudio::Off => mic,
+            SystemAudio::Mixed => mic
+                .iter()
+                .zip(&system)
+                .map(|(m, s)| (m + s).clamp(-1.0, 1.0))
+                .collect(),
+            SystemAudio::Separate => mic
+                .iter()
+                .zip(&system)
+                .flat_map(|(&m, &s)| [m, s])
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 1000;
+
+    fn mixer(mode: SystemAudio) -> (flume::Sender<Vec<f32>>, SystemMix) {
+        let (tx, rx) = flume::unbounded();
+        (tx, SystemMix::new(mode, rx, RATE, RATE).unwrap())
+    }
+
+    #[test]
+    fn mixed_sums_and_clamps() {
+        let (tx, mut mix) = mixer(SystemAudio::Mixed);
+        tx.send(vec![0.25; 50]).unwrap();
+        tx.send(vec![0.75; 10]).unwrap();
+
+        let out = mix.mix(vec![0.5; 60]);
+        assert_eq!(mix.channels(), 1);
+        assert!(out[..50].iter().all(|&s| s == 0.75));
+        assert!(out[50..].iter().all(|&s| s == 1.0));
+    }
+
+    #[test]
+    fn separate_interleaves_mic_then_system() {
+        let (tx, mut mix) = mixer(SystemAudio::Separate);
+        tx.send((0..50).map(|i| -(i as f32) / 100.0).collect())
+            .unwrap();
+
+        let mic: Vec<f32> = (0..50).map(|i| i as f32 / 100.0).collect();
+        let out = mix.mix(mic.clone());
+        assert_eq!(mix.channels(), 2);
+        assert_eq!(out.len(), 100);
+        for (i, frame) in out.chunks_exact(2).enumerate() {
+            assert_eq!(frame, [mic[i], -mic[i]]);
+        }
+    }
+
+    #[test]
+    fn waits_for_the_prebuffer_then_stays_contiguous() {
+        let (tx, mut mix) = mixer(SystemAudio::Separate);
+        let system = |out: Vec<f32>| out.into_iter().skip(1).step_by(2).collect::<Vec<_>>();
+
+        // 30 ms of system audio is short of the 50 ms prebuffer
+        tx.send((1..=30).map(|i| i as f32).collect()).unwrap();
+        assert_eq!(system(mix.mix(vec![0.0; 10])), vec![0.0; 10]);
+
+        tx.send((31..=60).map(|i| i as f32).collect()).unwrap();
+        let first = system(mix.mix(vec![0.0; 10]));
+        let second = system(mix.mix(vec![0.0; 10]));
+        assert_eq!(first, (1..=10).map(|i| i as f32).collect::<Vec<_>>());
+        assert_eq!(second, (11..=20).map(|i| i as f32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn backlog_past_the_limit_drops_the_oldest() {
+        let (tx, mut mix) = mixer(SystemAudio::Separate);
+        // 600 ms against a 500 ms limit
+        tx.send((0..600).map(|i| i as f32).collect()).unwrap();
+
+        let out = mix.mix(vec![0.0; 10]);
+        assert_eq!(out[1], 100.0);
+    }
+}
@@ -1,97 +1,449 @@
-use crate::backends::PlaybackRequest;
-use crate::resampler::Resampler;
-use crate::AecError;
+use crate::backends::{
+    check_downmix, keeps_channels, remap_channels, to_mono, BackendCommand, ChunkSender,
+    DeviceClock, MuteRamp, PlaybackMarkers, PlaybackMute, PlaybackRequest, StreamInfo,
+    PLAYBACK_POLL, STANDARD_RATES,
+};
+use crate::latency::LatencyPlan;
+use crate::raw_capture::{RawCapture, RawFormat};
+use crate::{AecConfig, AecError, AecSupport, DeviceRole, DownmixMode, Processing, SharingMode};
+use std::sync::Arc;
 
 use wasapi::{
-    initialize_mta, DeviceEnumerator, Direction, SampleType, ShareMode, StreamMode, WaveFormat,
+    initialize_mta, AudioClient, Device, DeviceEnumerator, Direction, Role, SampleType, ShareMode,
+    StreamMode, WasapiError, WaveFormat,
+};
+use windows::core::{implement, Interface, PCWSTR};
+use windows::Win32::Foundation::PROPERTYKEY;
+use windows::Win32::Media::Audio::Endpoints::{IAudioEndpointVolume, IAudioMeterInformation};
+use windows::Win32::Media::Audio::{
+    eCapture, eCommunications, eConsole, EDataFlow, ERole, IMMDeviceEnumerator,
+    IMMNotificationClient, IMMNotificationClient_Impl, MMDeviceEnumerator, DEVICE_STATE,
 };
+use windows::Win32::System::Com::{CoCreateInstance, CLSCTX_ALL};
+
+/// Reported by `CaptureHandle::backend_name()`
+pub const BACKEND_NAME: &str = "wasapi";
+
+// HRESULTs that indicate the capture endpoint can't be opened for reasons other than a fault
+const E_ACCESSDENIED: i32 = 0x80070005_u32 as i32;
+const AUDCLNT_E_DEVICE_IN_USE: i32 = 0x8889000A_u32 as i32;
+const AUDCLNT_E_DEVICE_INVALIDATED: i32 = 0x88890004_u32 as i32;
+
+/// Shared-mode buffer requested without a latency target (20ms, in 100ns units)
+const DEFAULT_BUFFER_HNS: i64 = 200_000;
+
+/// `DEFAULT_BUFFER_HNS` at 48 kHz, the usual mix rate. The engine may
+/// round the buffer it grants up to whole periods.
+pub const DEFAULT_BUFFER_FRAMES: usize = (DEFAULT_BUFFER_HNS as usize * 48000) / 10_000_000;
 
 /// Create WASAPI capture backend with AEC.
 /// Spawns a blocking task that owns all WASAPI resources.
-/// Returns (sample_rate, buffer_size) queried from the actual device format.
+/// Returns the stream format queried from the actual device format.
+/// With `Channels::Native` or `Channels::StereoTrue` all device channels
+/// are delivered interleaved. `input_device` is an endpoint ID; None opens
+/// the default capture endpoint for the role.
 pub fn create_backend(
-    sender: flume::Sender<Vec<f32>>,
+    config: &AecConfig,
+    input_device: Option<String>,
+    sender: ChunkSender,
     playback_rx: flume::Receiver<PlaybackRequest>,
-) -> Result<(u32, usize), AecError> {
+    playback_mute: Arc<PlaybackMute>,
+    command_rx: flume::Receiver<BackendCommand>,
+    runtime: &tokio::runtime::Handle,
+) -> Result<StreamInfo, AecError> {
     // COM must be initialized for WASAPI
     let hr = initialize_mta();
     if hr.0 != 0 {
-        return Err(AecError::BackendError(format!("COM init failed: {hr:?}")));
+        return Err(AecError::OsError {
+            message: "COM init failed".to_string(),
+            code: hr.0 as i64,
+        });
     }
 
     // Verify devices are available before spawning task
-    let enumerator = DeviceEnumerator::new()
-        .map_err(|e| AecError::BackendError(format!("DeviceEnumerator::new: {e:?}")))?;
-    enumerator
-        .get_default_device(&Direction::Capture)
-        .map_err(|_| AecError::DeviceUnavailable)?;
-    enumerator
-        .get_default_device(&Direction::Render)
-        .map_err(|_| AecError::DeviceUnavailable)?;
-
-    let (meta_tx, meta_rx) = flume::bounded::<Result<(u32, usize), AecError>>(1);
+    let role = config.device_role;
+    let enumerator =
+        DeviceEnumerator::new().map_err(|e| wasapi_error("DeviceEnumerator::new", e))?;
+    capture_device(&enumerator, input_device.as_deref(), role)?;
+    default_device(&enumerator, Direction::Render, role)?;
+
+    // Exclusive streams bypass the audio engine, and with it the AEC effect
+    if config.sharing_mode == SharingMode::Exclusive {
+        tracing::warn!("WASAPI capture always runs shared so AEC stays active");
+    }
 
-    tokio::task::spawn_blocking(move || {
-        if let Err(e) = capture_loop(sender, meta_tx.clone()) {
+    let (meta_tx, meta_rx) = flume::bounded::<Result<StreamInfo, AecError>>(1);
+    // None keeps every channel
+    let downmix = (!keeps_channels(config.channels)).then_some(config.downmix);
+    let with_aec = config.processing == Processing::Voice;
+    let buffer_duration_hns = LatencyPlan::new(config.latency_target)
+        .device_buffer
+        .map_or(DEFAULT_BUFFER_HNS, |d| (d.as_nanos() / 100) as i64);
+
+    #[cfg(feature = "debug-capture")]
+    let raw_capture = config.raw_capture.clone();
+    #[cfg(not(feature = "debug-capture"))]
+    let raw_capture = None;
+
+    runtime.spawn_blocking(move || {
+        if let Err(e) = capture_loop(
+            sender,
+            meta_tx.clone(),
+            command_rx,
+            downmix,
+            with_aec,
+            input_device,
+            role,
+            buffer_duration_hns,
+            raw_capture,
+        ) {
             let _ = meta_tx.send(Err(e));
         }
     });
 
     // Spawn playback task to handle outgoing audio
-    tokio::task::spawn_blocking(move || {
-        if let Err(e) = playback_loop(playback_rx) {
+    let (rate_tx, rate_rx) = flume::bounded::<u32>(1);
+    runtime.spawn_blocking(move || {
+        if let Err(e) = playback_loop(
+            playback_rx,
+            playback_mute,
+            rate_tx,
+            role,
+            buffer_duration_hns,
+        ) {
             tracing::error!("Playback loop error: {e:?}");
         }
     });
 
     // Wait for metadata from the capture thread
-    meta_rx.recv().map_err(|_| {
+    let info = meta_rx.recv().map_err(|_| {
         AecError::BackendError("capture thread died before sending metadata".to_string())
-    })?
+    })??;
+    // Capture carries on if the output fails to open; there is just no rate
+    Ok(StreamInfo {
+        playback_rate: rate_rx.recv().ok(),
+        ..info
+    })
+}
+
+/// Check whether the default capture endpoint exposes the AEC control.
+/// The client is initialized (required for GetService) but never started.
+pub fn aec_supported() -> AecSupport {
+    AecSupport {
+        hardware: probe_aec_control().unwrap_or(false),
+        software: false,
+    }
+}
+
+fn probe_aec_control() -> Option<bool> {
+    // Already-initialized COM on this thread (even as STA) is fine for probing
+    let _ = initialize_mta();
+
+    let enumerator = DeviceEnumerator::new().ok()?;
+    let capture_device = enumerator.get_default_device(&Direction::Capture).ok()?;
+    let mut audio_client = capture_device.get_iaudioclient().ok()?;
+    let format = audio_client.get_mixformat().ok()?;
+
+    let stream_mode = StreamMode::PollingShared {
+        autoconvert: true,
+        buffer_duration_hns: DEFAULT_BUFFER_HNS,
+    };
+    audio_client
+        .initialize_client(&format, &Direction::Capture, &stream_mode)
+        .ok()?;
+
+    Some(audio_client.get_aec_control().is_ok())
+}
+
+/// Master input volume of the default capture endpoint, 0.0 to 1.0.
+pub fn hardware_gain(role: DeviceRole) -> Result<f32, AecError> {
+    let volume: IAudioEndpointVolume = activate_capture_endpoint(role)?;
+    unsafe { volume.GetMasterVolumeLevelScalar() }
+        .map_err(|e| com_error("GetMasterVolumeLevelScalar", e))
+}
+
+/// Set the master input volume of the default capture endpoint.
+pub fn set_hardware_gain(gain: f32, role: DeviceRole) -> Result<(), AecError> {
+    let volume: IAudioEndpointVolume = activate_capture_endpoint(role)?;
+    unsafe { volume.SetMasterVolumeLevelScalar(gain, std::ptr::null()) }
+        .map_err(|e| com_error("SetMasterVolumeLevelScalar", e))
+}
+
+/// Peak sample level of the default capture endpoint over the last
+/// meter period, 0.0 to 1.0, as the engine measures it before the capture
+/// effects. None if the endpoint has no meter.
+pub fn hardware_input_level(role: DeviceRole) -> Option<f32> {
+    let meter: IAudioMeterInformation = activate_capture_endpoint(role).ok()?;
+    unsafe { meter.GetPeakValue() }.ok()
+}
+
+/// wasapi doesn't wrap the endpoint volume and meter interfaces, so
+/// activate them on the default capture endpoint directly.
+fn activate_capture_endpoint<T: Interface>(role: DeviceRole) -> Result<T, AecError> {
+    // Already-initialized COM on this thread (even as STA) is fine here
+    let _ = initialize_mta();
+    let role = match role {
+        DeviceRole::Console => eConsole,
+        DeviceRole::Communications => eCommunications,
+    };
+
+    unsafe {
+        let enumerator: IMMDeviceEnumerator =
+            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+                .map_err(|e| com_error("CoCreateInstance", e))?;
+        let device = enumerator
+            .GetDefaultAudioEndpoint(eCapture, role)
+            .map_err(|_| AecError::DeviceUnavailable)?;
+        device
+            .Activate(CLSCTX_ALL, None)
+            .map_err(|e| com_error("IMMDevice::Activate", e))
+    }
+}
+
+/// The default endpoint for `role`. The AEC reference is linked to the
+/// render device opened this way, so both directions follow the role.
+fn default_device(
+    enumerator: &DeviceEnumerator,
+    direction: Direction,
+    role: DeviceRole,
+) -> Result<Device, AecError> {
+    let role = match role {
+        DeviceRole::Console => Role::Console,
+        DeviceRole::Communications => Role::Communications,
+    };
+    enumerator
+        .get_default_device_for_role(&direction, &role)
+        .map_err(|_| AecError::DeviceUnavailable)
+}
+
+/// The active capture endpoint with ID `id`, or the default one for `role`.
+fn capture_device(
+    enumerator: &DeviceEnumerator,
+    id: Option<&str>,
+    role: DeviceRole,
+) -> Result<Device, AecError> {
+    let Some(id) = id else {
+        return default_device(enumerator, Direction::Capture, role);
+    };
+    capture_devices(enumerator)?
+        .into_iter()
+        .find(|device| device.get_id().is_ok_and(|device_id| device_id == id))
+        .ok_or(AecError::DeviceUnavailable)
+}
+
+/// Active capture endpoints.
+fn capture_devices(enumerator: &DeviceEnumerator) -> Result<Vec<Device>, AecError> {
+    let collection = enumerator
+        .get_device_collection(&Direction::Capture)
+        .map_err(|e| wasapi_error("get_device_collection", e))?;
+    let count = collection
+        .get_nbr_devices()
+        .map_err(|e| wasapi_error("get_nbr_devices", e))?;
+    Ok((0..count)
+        .filter_map(|index| collection.get_device_at_index(index).ok())
+        .collect())
+}
+
+/// Endpoint IDs of the active capture devices.
+pub fn input_devices() -> Vec<String> {
+    // Already-initialized COM on this thread (even as STA) is fine here
+    let _ = initialize_mta();
+    let Ok(enumerator) = DeviceEnumerator::new() else {
+        return Vec::new();
+    };
+    capture_devices(&enumerator)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|device| device.get_id().ok())
+        .collect()
+}
+
+/// Rates the capture endpoint with ID `id`, or the default one, takes
+/// without conversion: the mix format's rate, plus each standard rate
+/// `IsFormatSupported` accepts in shared or exclusive mode at the mix
+/// format's channel count.
+pub fn supported_sample_rates(id: Option<&str>) -> Result<Vec<u32>, AecError> {
+    // Already-initialized COM on this thread (even as STA) is fine here
+    let _ = initialize_mta();
+    let enumerator =
+        DeviceEnumerator::new().map_err(|e| wasapi_error("DeviceEnumerator::new", e))?;
+    let device = capture_device(&enumerator, id, DeviceRole::Console)?;
+    let audio_client = device
+        .get_iaudioclient()
+        .map_err(|e| map_capture_error("get_iaudioclient", e))?;
+    let mix_format = audio_client
+        .get_mixformat()
+        .map_err(|e| wasapi_error("get_mixformat", e))?;
+    let channels = mix_format.get_nchannels() as usize;
+
+    let accepts = |format: WaveFormat, mode: ShareMode| {
+        matches!(audio_client.is_supported(&format, &mode), Ok(None))
+    };
+    let mut rates: Vec<u32> = STANDARD_RATES
+        .into_iter()
+        .filter(|&rate| {
+            let float = WaveFormat::new(32, 32, &SampleType::Float, rate as usize, channels, None);
+            let pcm = WaveFormat::new(16, 16, &SampleType::Int, rate as usize, channels, None);
+            accepts(float, ShareMode::Shared) || accepts(pcm, ShareMode::Exclusive)
+        })
+        .collect();
+    rates.push(mix_format.get_samplespersec());
+    rates.sort_unstable();
+    rates.dedup();
+    Ok(rates)
 }
 
+/// An endpoint notification callback, unregistered on drop.
+pub struct DeviceNotifier {
+    enumerator: IMMDeviceEnumerator,
+    client: IMMNotificationClient,
+}
+
+// SAFETY: MMDeviceEnumerator is free-threaded, and the client only holds a
+// flume sender
+unsafe impl Send for DeviceNotifier {}
+
+impl Drop for DeviceNotifier {
+    fn drop(&mut self) {
+        let _ = unsafe {
+            self.enumerator
+                .UnregisterEndpointNotificationCallback(&self.client)
+        };
+    }
+}
+
+#[implement(IMMNotificationClient)]
+struct EndpointNotifications {
+    changed: flume::Sender<()>,
+}
+
+impl EndpointNotifications {
+    fn signal(&self) -> windows::core::Result<()> {
+        let _ = self.changed.try_send(());
+        Ok(())
+    }
+}
+
+// Called on threads of the audio service's choosing
+impl IMMNotificationClient_Impl for EndpointNotifications_Impl {
+    fn OnDeviceStateChanged(&self, _: &PCWSTR, _: DEVICE_STATE) -> windows::core::Result<()> {
+        self.signal()
+    }
+
+    fn OnDeviceAdded(&self, _: &PCWSTR) -> windows::core::Result<()> {
+        self.signal()
+    }
+
+    fn OnDeviceRemoved(&self, _: &PCWSTR) -> windows::core::Result<()> {
+        self.signal()
+    }
+
+    fn OnDefaultDeviceChanged(
+        &self,
+        _: EDataFlow,
+        _: ERole,
+        _: &PCWSTR,
+    ) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn OnPropertyValueChanged(&self, _: &PCWSTR, _: &PROPERTYKEY) -> windows::core::Result<()> {
+        Ok(())
+    }
+}
+
+/// Signal `changed` whenever an endpoint is added, removed, enabled or
+/// disabled.
+pub fn notify_device_changes(changed: flume::Sender<()>) -> Result<DeviceNotifier, AecError> {
+    // Already-initialized COM on this thread (even as STA) is fine here
+    let _ = initialize_mta();
+    unsafe {
+        let enumerator: IMMDeviceEnumerator =
+            CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+                .map_err(|e| com_error("CoCreateInstance", e))?;
+        let client: IMMNotificationClient = EndpointNotifications { changed }.into();
+        enumerator
+            .RegisterEndpointNotificationCallback(&client)
+            .map_err(|e| com_error("RegisterEndpointNotificationCallback", e))?;
+        Ok(DeviceNotifier { enumerator, client })
+    }
+}
+
+fn com_error(context: &str, err: windows::core::Error) -> AecError {
+    AecError::OsError {
+        message: format!("{context}: {err}"),
+        code: err.code().0 as i64,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn capture_loop(
-    sender: flume::Sender<Vec<f32>>,
-    meta_tx: flume::Sender<Result<(u32, usize), AecError>>,
+    sender: ChunkSender,
+    meta_tx: flume::Sender<Result<StreamInfo, AecError>>,
+    command_rx: flume::Receiver<BackendCommand>,
+    downmix: Option<DownmixMode>,
+    with_aec: bool,
+    input_device: Option<String>,
+    role: DeviceRole,
+    buffer_duration_hns: i64,
+    raw_capture: Option<RawCapture>,
 ) -> Result<(), AecError> {
     // Re-initialize COM on this thread
     let hr = initialize_mta();
     if hr.0 != 0 {
-        return Err(AecError::BackendError(format!("COM init failed: {hr:?}")));
+        return Err(AecError::OsError {
+            message: "COM init failed".to_string(),
+            code: hr.0 as i64,
+        });
     }
 
-    let enumerator = DeviceEnumerator::new()
-        .map_err(|e| AecError::BackendError(format!("DeviceEnumerator::new: {e:?}")))?;
-    let capture_device = enumerator
-        .get_default_device(&Direction::Capture)
-        .map_err(|_| AecError::DeviceUnavailable)?;
-    let render_device = enumerator
-        .get_default_device(&Direction::Render)
-        .map_err(|_| AecError::DeviceUnavailable)?;
+    let enumerator =
+        DeviceEnumerator::new().map_err(|e| wasapi_error("DeviceEnumerator::new", e))?;
+    let capture_device = capture_device(&enumerator, input_device.as_deref(), role)?;
+    let render_device = default_device(&enumerator, Direction::Render, role)?;
 
     let desired_format = WaveFormat::new(32, 32, &SampleType::Float, 48000, 1, None);
 
     let mut audio_client = capture_device
         .get_iaudioclient()
-        .map_err(|e| AecError::BackendError(format!("get_iaudioclient: {e:?}")))?;
+        .map_err(|e| map_capture_error("get_iaudioclient", e))?;
 
-    let capture_format = match audio_client.is_supported(&desired_format, &ShareMode::Shared) {
-        Ok(None) => desired_format,
-        Ok(Some(suggested)) => suggested,
-        Err(_) => audio_client
+    // The mix format carries the device's full channel layout; a mono
+    // request would leave the engine to average the channels
+    let capture_format = if !matches!(downmix, Some(DownmixMode::Average)) {
+        audio_client
             .get_mixformat()
-            .map_err(|e| AecError::BackendError(format!("get_mixformat: {e:?}")))?,
+            .map_err(|e| wasapi_error("get_mixformat", e))?
+    } else {
+        match audio_client.is_supported(&desired_format, &ShareMode::Shared) {
+            Ok(None) => desired_format,
+            Ok(Some(suggested)) => suggested,
+            Err(_) => audio_client
+                .get_mixformat()
+                .map_err(|e| wasapi_error("get_mixformat", e))?,
+        }
     };
 
+    if let Some(mode) = downmix {
+        check_downmix(mode, capture_format.get_nchannels() as usize)?;
+    }
+
     let stream_mode = StreamMode::EventsShared {
         autoconvert: true,
-        buffer_duration_hns: 200_000,
+        buffer_duration_hns,
     };
     audio_client
         .initialize_client(&capture_format, &Direction::Capture, &stream_mode)
-        .map_err(|e| AecError::BackendError(format!("initialize_client: {e:?}")))?;
-
-    if let Ok(aec_control) = audio_client.get_aec_control() {
+        .map_err(|e| map_capture_error("initialize_client", e))?;
+
+    // Raw capture leaves the AEC unlinked, so set_reference_device reports
+    // AecNotSupported
+    let aec_control = if with_aec {
+        audio_client.get_aec_control().ok()
+    } else {
+        None
+    };
+    if let Some(aec_control) = &aec_control {
         if let Ok(render_id) = render_device.get_id() {
             let _ = aec_control.set_echo_cancellation_render_endpoint(Some(render_id));
         }
@@ -99,43 +451,92 @@ fn capture_loop(
 
     let capture_client = audio_client
         .get_audiocaptureclient()
-        .map_err(|e| AecError::BackendError(format!("get_audiocaptureclient: {e:?}")))?;
+        .map_err(|e| wasapi_error("get_audiocaptureclient", e))?;
 
     let event_handle = audio_client
         .set_get_eventhandle()
-        .map_err(|e| AecError::BackendError(format!("set_get_eventhandle: {e:?}")))?;
+        .map_err(|e| wasapi_error("set_get_eventhandle", e))?;
 
     audio_client
         .start_stream()
-        .map_err(|e| AecError::BackendError(format!("start_stream: {e:?}")))?;
+        .map_err(|e| map_capture_error("start_stream", e))?;
 
     let block_align = capture_format.get_blockalign() as usize;
     let native_channels = capture_format.get_nchannels() as usize;
     let bits = capture_format.get_bitspersample();
     let is_float = matches!(capture_format.get_subformat(), Ok(SampleType::Float));
+    // Fail the open rather than deliver nothing from every buffer
+    convert_to_f32(&[], bits, is_float)?;
     let native_sample_rate = capture_format.get_samplespersec();
+    let raw_format = RawFormat {
+        sample_rate: native_sample_rate,
+        channels: native_channels as u16,
+        bits_per_sample: bits,
+        valid_bits: capture_format.get_validbitspersample(),
+        float: is_float,
+    };
 
     let device_buffer_frames = audio_client
         .get_buffer_size()
-        .map_err(|e| AecError::BackendError(format!("get_buffer_size: {e:?}")))?;
+        .map_err(|e| wasapi_error("get_buffer_size", e))?;
 
-    let _ = meta_tx.send(Ok((native_sample_rate, device_buffer_frames as usize)));
+    let delivered_channels = if downmix.is_some() {
+        1
+    } else {
+        native_channels
+    };
+    let _ = meta_tx.send(Ok(StreamInfo {
+        sample_rate: native_sample_rate,
+        buffer_size: device_buffer_frames as usize,
+        channels: delivered_channels as u16,
+        // Filled in from the playback loop
+        playback_rate: None,
+        sharing_mode: None,
+        backend: BACKEND_NAME,
+        aec: aec_control.is_some(),
+    }));
 
     let buffer_size = (device_buffer_frames as usize) * block_align;
     let mut buffer = vec![0u8; buffer_size];
+    let mut clock = DeviceClock::default();
 
     loop {
         // Wait for event with timeout. Timeout is normal - continue waiting for data.
         let _ = event_handle.wait_for_event(100);
 
-        let (frames_read, _buffer_info) = match capture_client.read_from_device(&mut buffer) {
+        // COM objects live on this thread, so reconfiguration is applied here
+        while let Ok(command) = command_rx.try_recv() {
+            match command {
+                BackendCommand::SetReferenceDevice { device_id, reply } => {
+                    let result = match &aec_control {
+                        Some(aec_control) => aec_control
+                            .set_echo_cancellation_render_endpoint(device_id)
+                            .map_err(|e| wasapi_error("set_echo_cancellation_render_endpoint", e)),
+                        None => Err(AecError::AecNotSupported),
+                    };
+                    let _ = reply.send(result);
+                }
+            }
+        }
+
+        let (frames_read, buffer_info) = match capture_client.read_from_device(&mut buffer) {
             Ok(result) => result,
+            // Device unplugged or disabled; stopping lets the pipeline report DeviceLost
+            Err(WasapiError::Windows(e)) if e.code().0 == AUDCLNT_E_DEVICE_INVALIDATED => {
+                return Err(AecError::DeviceUnavailable);
+            }
             Err(_) => continue, // No data available yet
         };
 
         if frames_read == 0 {
             continue;
         }
+        // The engine flags a glitch, and the device position says how much
+        let skipped = clock.gap(buffer_info.index, frames_read as usize);
+        if buffer_info.flags.data_discontinuity {
+            tracing::debug!("WASAPI capture glitch: {skipped} frames lost");
+        }
+        sender.skip(skipped * delivered_channels as u64);
 
         let data_bytes = (frames_read as usize) * block_align;
         let data = &buffer[..data_bytes];
@@ -148,48 +549,91 @@ fn capture_loop(
             )));
         }
 
-        let samples = convert_to_f32(&data, bits, is_float, native_channels);
+        if let Some(raw_capture) = &raw_capture {
+            raw_capture.tee(&raw_format, data);
+        }
+        let samples = convert_to_f32(data, bits, is_float)?;
+        let samples = match downmix {
+            Some(mode) if native_channels > 1 => to_mono(&samples, native_channels, mode),
+            _ => samples,
+        };
         if samples.is_empty() {
             continue;
         }
 
-        if sender.send(samples).is_err() {
+        if !sender.send(samples) {
             break;
         }
     }
 
     audio_client
         .stop_stream()
-        .map_err(|e| AecError::BackendError(format!("stop_stream: {e:?}")))?;
+        .map_err(|e| wasapi_error("stop_stream", e))?;
 
     Ok(())
 }
 
-fn playback_loop(playback_rx: flume::Receiver<PlaybackRequest>) -> Result<(), AecError> {
+/// Map capture client errors so privacy-settings denial is reported as
+/// `PermissionDenied` rather than a generic backend failure.
+fn map_capture_error(context: &str, err: WasapiError) -> AecError {
+    let hresult = match &err {
+        WasapiError::Windows(e) => Some(e.code().0),
+        _ => None,
+    };
+
+    match hresult {
+        Some(E_ACCESSDENIED) => AecError::PermissionDenied,
+        Some(AUDCLNT_E_DEVICE_IN_USE) => AecError::DeviceUnavailable,
+        _ => wasapi_error(context, err),
+    }
+}
+
+/// Keep the HRESULT of a failed WASAPI call alongside the context. Errors
+/// raised inside the wasapi crate itself have no code.
+fn wasapi_error(context: &str, err: WasapiError) -> AecError {
+    match &err {
+        WasapiError::Windows(e) => AecError::OsError {
+            message: format!("{context}: {e}"),
+            code: e.code().0 as i64,
+        },
+        _ => AecError::BackendError(format!("{context}: {err:?}")),
+    }
+}
+
+/// Render loop for `play_audio`. Sends the negotiated rate on `rate_tx`
+/// once the stream has started.
+fn playback_loop(
+    playback_rx: flume::Receiver<PlaybackRequest>,
+    playback_mute: Arc<PlaybackMute>,
+    rate_tx: flume::Sender<u32>,
+    role: DeviceRole,
+    buffer_duration_hns: i64,
+) -> Result<(), AecError> {
     // Re-initialize COM on this thread
     let hr = initialize_mta();
     if hr.0 != 0 {
-        return Err(AecError::BackendError(format!("COM init failed: {hr:?}")));
+        return Err(AecError::OsError {
+            message: "COM init failed".to_string(),
+            code: hr.0 as i64,
+        });
     }
 
-    let enumerator = DeviceEnumerator::new()
-        .map_err(|e| AecError::BackendError(format!("DeviceEnumerator::new: {e:?}")))?;
-    let render_device = enumerator
-        .get_default_device(&Direction::Render)
-        .map_err(|_| AecError::DeviceUnavailable)?;
+    let enumerator =
+        DeviceEnumerator::new().map_err(|e| wasapi_error("DeviceEnumerator::new", e))?;
+    let render_device = default_device(&enumerator, Direction::Render, role)?;
 
     let desired_format = WaveFormat::new(32, 32, &SampleType::Float, 48000, 1, None);
 
     let mut audio_client = render_device
         .get_iaudioclient()
-        .map_err(|e| AecError::BackendError(format!("get_iaudioclient: {e:?}")))?;
+        .map_err(|e| wasapi_error("get_iaudioclient", e))?;
 
     let render_format = match audio_client.is_supported(&desired_format, &ShareMode::Shared) {
         Ok(None) => desired_format,
         Ok(Some(suggested)) => suggested,
         Err(_) => audio_client
             .get_mixformat()
-            .map_err(|e| AecError::BackendError(format!("get_mixformat: {e:?}")))?,
+            .map_err(|e| wasapi_error("get_mixformat", e))?,
     };
 
     let native_rate = render_format.get_samplespersec();
@@ -197,147 +641,141 @@ fn playback_loop(playback_rx: flume::Receiver<PlaybackRequest>) -> Result<(), Ae
 
     let stream_mode = StreamMode::EventsShared {
         autoconvert: true,
-        buffer_duration_hns: 200_000,
+        buffer_duration_hns,
     };
     audio_client
         .initialize_client(&render_format, &Direction::Render, &stream_mode)
-        .map_err(|e| AecError::BackendError(format!("initialize_client: {e:?}")))?;
+        .map_err(|e| wasapi_error("initialize_client", e))?;
 
     let render_client = audio_client
         .get_audiorenderclient()
-        .map_err(|e| AecError::BackendError(format!("get_audiorenderclient: {e:?}")))?;
+        .map_err(|e| wasapi_error("get_audiorenderclient", e))?;
 
     let event_handle = audio_client
         .set_get_eventhandle()
-        .map_err(|e| AecError::BackendError(format!("set_get_eventhandle: {e:?}")))?;
+        .map_err(|e| wasapi_error("set_get_eventhandle", e))?;
 
     audio_client
         .start_stream()
-        .map_err(|e| AecError::BackendError(format!("start_stream: {e:?}")))?;
+        .map_err(|e| wasapi_error("start_stream", e))?;
+    let _ = rate_tx.send(native_rate);
 
-    while let Ok(request) = playback_rx.recv() {
-        let samples = if request.sample_rate == native_rate {
-            request.samples
+    let mut ramp = MuteRamp::new(playback_mute, native_rate);
+    // In frames written to the endpoint, whose padding tells how many of
+    // them are still to play
+    let mut markers = PlaybackMarkers::default();
+    loop {
+        let mut request = if markers.is_waiting() {
+            match playback_rx.recv_timeout(PLAYBACK_POLL) {
+                Ok(request) => request,
+                Err(flume::RecvTimeoutError::Timeout) => {
+                    track_playback(&audio_client, &mut markers);
+                    continue;
+                }
+                Err(flume::RecvTimeoutError::Disconnected) => break,
+            }
         } else {
-            Resampler::new(request.sample_rate, native_rate)?.process(&request.samples)?
+            match playback_rx.recv() {
+                Ok(request) => request,
+                Err(_) => break,
+            }
         };
 
-        let samples = if native_channels > 1 {
-            samples
-                .iter()
-                .flat_map(|&s| std::iter::repeat(s).take(native_channels))
-                .collect()
-        } else {
-            samples
+        let Some(samples) = request.samples_at(native_rate) else {
+            continue;
         };
 
+        // The AEC reference is the endpoint's own mix, so every channel
+        // played here is cancelled, stereo included
+        let samples = remap_channels(samples, request.channels as usize, native_channels);
+
         let frames_per_write = 480;
-        for chunk in samples.chunks(frames_per_write * native_channels) {
+        let mut pending = samples.as_slice();
+        while !pending.is_empty() {
             let _ = event_handle.wait_for_event(100);
 
-            let frames = chunk.len() / native_channels;
-
-            let mut bytes: Vec<u8> = Vec::with_capacity(chunk.len() * 4);
-            for sample in chunk {
-                bytes.extend_from_slice(&sample.to_le_bytes());
+            // Muted, silence keeps the endpoint fed while the request waits
+            let audible = ramp.audible((pending.len() / native_channels).min(frames_per_write));
+            let frames = if audible > 0 {
+                audible
+            } else {
+                frames_per_write
+            };
+
+            let mut bytes: Vec<u8> = Vec::with_capacity(frames * native_channels * 4);
+            if audible > 0 {
+                let (chunk, rest) = pending.split_at(audible * native_channels);
+                pending = rest;
+                for frame in chunk.chunks(native_channels) {
+                    let gain = ramp.next_gain();
+                    for sample in frame {
+                        bytes.extend_from_slice(&(sample * gain).to_le_bytes());
+                    }
+                }
+            } else {
+                bytes.resize(frames * native_channels * 4, 0);
             }
 
-            if render_client
-                .write_to_device(frames, &bytes, None)
-                .is_err()
-            {
+            if render_client.write_to_device(frames, &bytes, None).is_err() {
                 break;
             }
+            markers.append(frames, None);
+            track_playback(&audio_client, &mut markers);
         }
+
+        markers.append(0, request.done);
+        track_playback(&audio_client, &mut markers);
     }
 
     audio_client
         .stop_stream()
-        .map_err(|e| AecError::BackendError(format!("stop_stream: {e:?}")))?;
+        .map_err(|e| wasapi_error("stop_stream", e))?;
 
     Ok(())
 }
 
-fn convert_to_f32(data: &[u8], bits: u16, is_float: bool, channels: usize) -> Vec<f32> {
-    if is_float && bits == 32 {
-        return convert_f32_to_mono(data, channels);
+/// Count every frame the endpoint no longer holds as played.
+fn track_playback(audio_client: &AudioClient, markers: &mut PlaybackMarkers) {
+    if let Ok(padding) = audio_client.get_current_padding() {
+        markers.consume_to(markers.appended().saturating_sub(padding as u64));
     }
-    if bits == 16 {
-        return convert_i16_to_mono(data, channels);
-    }
-    if bits == 24 {
-        return convert_i24_to_mono(data, channels);
-    }
-    if bits == 32 && !is_float {
-        return convert_i32_to_mono(data, channels);
-    }
-    Vec::new()
 }
 
-fn convert_f32_to_mono(data: &[u8], channels: usize) -> Vec<f32> {
-    let samples: Vec<f32> = data
-        .chunks_exact(4)
-        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
-        .collect();
-
-    if channels == 1 {
-        return samples;
+/// Decode device bytes to interleaved f32, keeping all channels.
+fn convert_to_f32(data: &[u8], bits: u16, is_float: bool) -> Result<Vec<f32>, AecError> {
+    match (bits, is_float) {
+        (32, true) => Ok(convert_f32(data)),
+        (16, false) => Ok(convert_i16(data)),
+        (24, false) => Ok(convert_i24(data)),
+        (32, false) => Ok(convert_i32(data)),
+        (bits, float) => Err(AecError::UnsupportedFormat { bits, float }),
     }
+}
 
-    samples
-        .chunks_exact(channels)
-        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+fn convert_f32(data: &[u8]) -> Vec<f32> {
+    data.chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
         .collect()
 }
 
-fn convert_i16_to_mono(data: &[u8], channels: usize) -> Vec<f32> {
-    let samples: Vec<f32> = data
-        .chunks_exact(2)
+fn convert_i16(data: &[u8]) -> Vec<f32> {
+    data.chunks_exact(2)
         .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0)
-        .collect();
-
-    if channels == 1 {
-        return samples;
-    }
-
-    samples
-        .chunks_exact(channels)
-        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
         .collect()
 }
 
-fn convert_i24_to_mono(data: &[u8], channels: usize) -> Vec<f32> {
-    let samples: Vec<f32> = data
-        .chunks_exact(3)
+fn convert_i24(data: &[u8]) -> Vec<f32> {
+    data.chunks_exact(3)
         .map(|b| {
             let val =
                 i32::from_le_bytes([b[0], b[1], b[2], if b[2] & 0x80 != 0 { 0xFF } else { 0 }]);
             val as f32 / 8388608.0
         })
-        .collect();
-
-    if channels == 1 {
-        return samples;
-    }
-
-    samples
-        .chunks_exact(channels)
-        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
         .collect()
 }
 
-fn convert_i32_to_mono(data: &[u8], channels: usize) -> Vec<f32> {
-    let samples: Vec<f32> = data
-        .chunks_exact(4)
+fn convert_i32(data: &[u8]) -> Vec<f32> {
+    data.chunks_exact(4)
         .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2147483648.0)
-        .collect();
-
-    if channels == 1 {
-        return samples;
-    }
-
-    samples
-        .chunks_exact(channels)
-        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
         .collect()
 }
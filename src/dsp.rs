@@ -0,0 +1,240 @@
+//! Pure sample-format helpers used by the capture pipeline.
+//!
+//! All functions operate on interleaved `f32` samples and have no state,
+//! so they can also be applied to chunks received from `CaptureHandle`.
+//! `deinterleave` and `interleave` convert to and from one buffer per
+//! channel, the layout the resampler works in.
+
+/// Duplicate each mono sample into an interleaved stereo frame.
+pub fn duplicate_to_stereo(samples: &[f32]) -> Vec<f32> {
+    samples.iter().flat_map(|&s| [s, s]).collect()
+}
+
+/// Average interleaved frames down to a single channel.
+/// A trailing partial frame is dropped.
+pub fn downmix(samples: &[f32], channels: usize) -> Vec<f32> {
+    if channels <= 1 {
+        return samples.to_vec();
+    }
+
+    samples
+        .chunks_exact(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect()
+}
+
+/// Keep a single channel of interleaved frames.
+/// A trailing partial frame is dropped.
+///
+/// Panics if `index` is not below `channels`.
+pub fn select_channel(samples: &[f32], channels: usize, index: usize) -> Vec<f32> {
+    assert!(index < channels.max(1), "channel {index} of {channels}");
+    samples
+        .chunks_exact(channels.max(1))
+        .map(|frame| frame[index])
+        .collect()
+}
+
+/// Scale samples in place by a linear gain factor.
+pub fn apply_gain(samples: &mut [f32], gain: f32) {
+    for sample in samples.iter_mut() {
+        *sample *= gain;
+    }
+}
+
+/// Root-mean-square level of the samples, 0.0 for an empty slice.
+pub fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let sum: f32 = samples.iter().map(|s| s * s).sum();
+    (sum / samples.len() as f32).sqrt()
+}
+
+/// Split interleaved frames into one buffer per channel.
+/// A trailing partial frame is dropped.
+pub fn deinterleave(samples: &[f32], channels: usize) -> Vec<Vec<f32>> {
+    let channels = channels.max(1);
+    let mut planes = vec![Vec::with_capacity(samples.len() / channels); channels];
+    deinterleave_into(samples, &mut planes);
+    planes
+}
+
+/// `deinterleave` into existing buffers, one per channel, reusing their
+/// allocations. Each buffer is cleared first.
+#[inline]
+pub fn deinterleave_into(samples: &[f32], planes: &mut [Vec<f32>]) {
+    if planes.is_empty() {
+        return;
+    }
+    for plane in planes.iter_mut() {
+        plane.clear();
+    }
+    for frame in samples.chunks_exact(planes.len()) {
+        for (plane, &sample) in planes.iter_mut().zip(frame) {
+            plane.push(sample);
+        }
+    }
+}
+
+/// Merge per-channel buffers into interleaved frames, stopping at the
+/// shortest buffer.
+pub fn interleave<P: AsRef<[f32]>>(planes: &[P]) -> Vec<f32> {
+    let frames = planes.iter().map(|p| p.as_ref().len()).min().unwrap_or(0);
+    let mut out = Vec::new();
+    interleave_into(planes, frames, &mut out);
+    out
+}
+
+/// Append the first `frames` samples of every buffer to `out` as
+/// interleaved frames.
+///
+/// Panics if a buffer holds fewer than `frames` samples.
+#[inline]
+pub fn interleave_into<P: AsRef<[f32]>>(planes: &[P], frames: usize, out: &mut Vec<f32>) {
+    out.reserve(frames * planes.len());
+    for frame in 0..frames {
+        for plane in planes {
+            out.push(plane.as_ref()[frame]);
+        }
+    }
+}
+
+/// Convert to 16-bit PCM, clamping anything outside [-1.0, 1.0].
+pub fn to_i16(samples: &[f32]) -> Vec<i16> {
+    samples
+        .iter()
+        .map(|&s| (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+        .collect()
+}
+
+/// Byte order for exported PCM.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Endianness {
+    /// What WAV and every supported OS use
+    #[default]
+    Little,
+    /// Network byte order, e.g. for RTP's L16 payload
+    Big,
+}
+
+/// Convert to 16-bit PCM bytes in `order`, clamping like `to_i16`.
+pub fn to_i16_bytes(samples: &[f32], order: Endianness) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(samples.len() * 2);
+    for sample in to_i16(samples) {
+        bytes.extend_from_slice(&match order {
+            Endianness::Little => sample.to_le_bytes(),
+            Endianness::Big => sample.to_be_bytes(),
+        });
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_to_stereo_interleaves_pairs() {
+        assert_eq!(
+            duplicate_to_stereo(&[0.1, -0.2]),
+            vec![0.1, 0.1, -0.2, -0.2]
+        );
+        assert!(duplicate_to_stereo(&[]).is_empty());
+    }
+
+    #[test]
+    fn downmix_averages_frames() {
+        assert_eq!(downmix(&[1.0, 0.0, 0.5, 0.5], 2), vec![0.5, 0.5]);
+        assert_eq!(downmix(&[0.25, 0.5, 0.75], 3), vec![0.5]);
+    }
+
+    #[test]
+    fn downmix_mono_is_identity() {
+        assert_eq!(downmix(&[0.1, 0.2, 0.3], 1), vec![0.1, 0.2, 0.3]);
+    }
+
+    #[test]
+    fn downmix_drops_partial_frame() {
+        assert_eq!(downmix(&[1.0, 1.0, 0.5], 2), vec![1.0]);
+    }
+
+    #[test]
+    fn downmix_inverts_duplicate_to_stereo() {
+        let mono = [0.25, -0.5, 0.75];
+        assert_eq!(downmix(&duplicate_to_stereo(&mono), 2), mono.to_vec());
+    }
+
+    #[test]
+    fn deinterleave_splits_channels_in_order() {
+        let planes = deinterleave(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0], 3);
+        assert_eq!(planes, vec![vec![1.0, 4.0], vec![2.0, 5.0], vec![3.0, 6.0]]);
+    }
+
+    #[test]
+    fn deinterleave_into_reuses_buffers() {
+        let mut planes = vec![vec![9.0; 4], vec![9.0; 4]];
+        deinterleave_into(&[0.1, 0.2, 0.3, 0.4], &mut planes);
+        assert_eq!(planes, vec![vec![0.1, 0.3], vec![0.2, 0.4]]);
+    }
+
+    #[test]
+    fn interleave_inverts_deinterleave() {
+        let samples = [0.1, -0.1, 0.2, -0.2, 0.3, -0.3];
+        assert_eq!(interleave(&deinterleave(&samples, 2)), samples.to_vec());
+    }
+
+    #[test]
+    fn interleave_stops_at_shortest_plane() {
+        assert_eq!(
+            interleave(&[vec![1.0, 3.0, 5.0], vec![2.0, 4.0]]),
+            vec![1.0, 2.0, 3.0, 4.0]
+        );
+    }
+
+    #[test]
+    fn interleave_into_appends_leading_frames() {
+        let mut out = vec![0.0];
+        interleave_into(&[[1.0, 3.0, 5.0], [2.0, 4.0, 6.0]], 2, &mut out);
+        assert_eq!(out, vec![0.0, 1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn select_channel_keeps_one_channel() {
+        let samples = [0.1, 0.9, 0.2, 0.8, 0.3];
+        assert_eq!(select_channel(&samples, 2, 1), vec![0.9, 0.8]);
+        assert_eq!(select_channel(&samples, 2, 0), vec![0.1, 0.2]);
+        assert_eq!(select_channel(&samples, 1, 0), samples.to_vec());
+    }
+
+    #[test]
+    fn apply_gain_scales_in_place() {
+        let mut samples = [0.5, -0.25];
+        apply_gain(&mut samples, 2.0);
+        assert_eq!(samples, [1.0, -0.5]);
+    }
+
+    #[test]
+    fn rms_of_constant_and_empty() {
+        assert_eq!(rms(&[0.5, -0.5, 0.5, -0.5]), 0.5);
+        assert_eq!(rms(&[]), 0.0);
+    }
+
+    #[test]
+    fn to_i16_scales_and_clamps() {
+        assert_eq!(to_i16(&[0.0, 1.0, -1.0]), vec![0, i16::MAX, -i16::MAX]);
+        assert_eq!(to_i16(&[2.0, -2.0]), vec![i16::MAX, -i16::MAX]);
+    }
+
+    #[test]
+    fn to_i16_bytes_follows_the_byte_order() {
+        assert_eq!(
+            to_i16_bytes(&[1.0, -1.0], Endianness::Little),
+            vec![0xFF, 0x7F, 0x01, 0x80]
+        );
+        assert_eq!(
+            to_i16_bytes(&[1.0, -1.0], Endianness::Big),
+            vec![0x7F, 0xFF, 0x80, 0x01]
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Both signals are reduced to this rate before correlating, which keeps
+/// the lag search cheap while covering the speech band.
+const METER_RATE: u32 = 8000;
+/// Reference history compared against the capture
+const WINDOW_SECS: f32 = 1.0;
+/// Longest playback-to-capture delay searched
+const MAX_LAG_SECS: f32 = 0.5;
+/// Reference RMS below this counts as "nothing played"
+const MIN_REFERENCE_RMS: f32 = 1e-4;
+
+/// Keeps recent played and captured audio so the echo left in the capture
+/// can be estimated by cross-correlation.
+pub(crate) struct EchoMeter {
+    reference: VecDeque<f32>,
+    capture: VecDeque<f32>,
+    capacity: usize,
+}
+
+impl EchoMeter {
+    pub fn new() -> Self {
+        let capacity = ((WINDOW_SECS + MAX_LAG_SECS) * METER_RATE as f32) as usize;
+        Self {
+            reference: VecDeque::with_capacity(capacity),
+            capture: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Record mono audio handed to the playback path.
+    pub fn push_reference(&mut self, samples: &[f32], sample_rate: u32) {
+        push_decimated(&mut self.reference, self.capacity, samples, sample_rate);
+    }
+
+    /// Record mono audio delivered from the capture path.
+    pub fn push_capture(&mut self, samples: &[f32], sample_rate: u32) {
+        push_decimated(&mut self.capture, self.capacity, samples, sample_rate);
+    }
+
+    /// Attenuation in dB between the reference and its best-aligned copy in
+    /// the capture. None until a full window of non-silent reference exists.
+    pub fn measure(&self) -> Option<f32> {
+        self.estimate().map(|(erle, _)| erle)
+    }
+
+    /// `measure` together with the playback-to-capture delay at which the
+    /// echo lined up best.
+    pub fn estimate(&self) -> Option<(f32, Duration)> {
+        let window = (WINDOW_SECS * METER_RATE as f32) as usize;
+        let max_lag = (MAX_LAG_SECS * METER_RATE as f32) as usize;
+        if self.reference.len() < window + max_lag || self.capture.len() < window {
+            return None;
+        }
+
+        let reference: Vec<f32> = self.reference.iter().copied().collect();
+        let capture: Vec<f32> = self.capture.iter().copied().collect();
+        let capture = &capture[capture.len() - window..];
+
+        // Echo shows up in the capture after it was handed to playback, so
+        // compare the capture window against progressively older reference
+        let mut best: Option<(f32, f32, usize)> = None; // (normalized score, gain, lag)
+        for lag in 0..=max_lag {
+            let end = reference.len() - lag;
+            let segment = &reference[end - window..end];
+
+            let energy: f32 = segment.iter().map(|s| s * s).sum();
+            if (energy / window as f32).sqrt() < MIN_REFERENCE_RMS {
+                continue;
+            }
+
+            let correlation: f32 = segment.iter().zip(capture).map(|(r, c)| r * c).sum();
+            let score = correlation.abs() / energy.sqrt();
+            if best.is_none_or(|(best_score, _, _)| score > best_score) {
+                best = Some((score, correlation / energy, lag));
+            }
+        }
+
+        let (_, gain, lag) = best?;
+        let delay = Duration::from_secs_f64(lag as f64 / METER_RATE as f64);
+        Some((-20.0 * gain.abs().max(f32::EPSILON).log10(), delay))
+    }
+}
+
+/// Box-filter and decimate to `METER_RATE`, keeping at most `capacity` samples.
+fn push_decimated(buffer: &mut VecDeque<f32>, capacity: usize, samples: &[f32], rate: u32) {
+    let step = rate as f64 / METER_RATE as f64;
+    let out_len = (samples.len() as f64 / step) as usize;
+
+    for i in 0..out_len {
+        let start = (i as f64 * step) as usize;
+        let end = (((i + 1) as f64 * step) as usize).clamp(start + 1, samples.len());
+        let span = &samples[start..end];
+        buffer.push_back(span.iter().sum::<f32>() / span.len() as f32);
+    }
+
+    let excess = buffer.len().saturating_sub(capacity);
+    buffer.drain(..excess);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic broadband test signal
+    fn noise(len: usize) -> Vec<f32> {
+        let mut state = 0x1234_5678_u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state as f32 / u32::MAX as f32) - 0.5
+            })
+            .collect()
+    }
+
+    #[test]
+    fn no_reference_is_none() {
+        let mut meter = EchoMeter::new();
+        meter.push_capture(&noise(16000), METER_RATE);
+        assert_eq!(meter.measure(), None);
+    }
+
+    #[test]
+    fn attenuated_delayed_echo_is_measured() {
+        let mut meter = EchoMeter::new();
+        let reference = noise(12000);
+        let delay = 800; // 100 ms
+
+        // Capture is the reference 20 dB down, arriving `delay` samples later
+        let mut capture = vec![0.0; delay];
+        capture.extend(reference.iter().map(|s| s * 0.1));
+        capture.truncate(reference.len());
+
+        meter.push_reference(&reference, METER_RATE);
+        meter.push_capture(&capture, METER_RATE);
+
+        let (erle, delay) = meter.estimate().unwrap();
+        assert!((erle - 20.0).abs() < 0.5, "measured {erle} dB");
+        assert_eq!(delay, Duration::from_millis(100));
+    }
+}
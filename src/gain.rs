@@ -0,0 +1,100 @@
+use crate::{dsp, AutoGainConfig};
+
+/// Per-chunk automatic gain. The gain moves toward `target_rms / rms`
+/// with separate attack and release smoothing and is held during silence.
+pub(crate) struct AutoGain {
+    config: AutoGainConfig,
+    /// Samples per second across all interleaved channels
+    sample_rate: f32,
+    gain: f32,
+}
+
+impl AutoGain {
+    pub fn new(config: AutoGainConfig, sample_rate: u32, channels: u16) -> Self {
+        Self {
+            config,
+            sample_rate: sample_rate as f32 * channels.max(1) as f32,
+            gain: 1.0,
+        }
+    }
+
+    /// Follow an output rate change without losing the current gain.
+    pub fn set_sample_rate(&mut self, sample_rate: u32, channels: u16) {
+        self.sample_rate = sample_rate as f32 * channels.max(1) as f32;
+    }
+
+    /// Update the gain from this chunk's level and apply it in place.
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let level = dsp::rms(samples);
+
+        if level > self.config.silence_rms {
+            let desired = (self.config.target_rms / level).min(self.config.max_gain);
+            let time_constant = if desired < self.gain {
+                self.config.attack
+            } else {
+                self.config.release
+            };
+
+            let chunk_secs = samples.len() as f32 / self.sample_rate;
+            let tau = time_constant.as_secs_f32();
+            let alpha = if tau > 0.0 {
+                1.0 - (-chunk_secs / tau).exp()
+            } else {
+                1.0
+            };
+            self.gain += (desired - self.gain) * alpha;
+        }
+
+        dsp::apply_gain(samples, self.gain);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn config() -> AutoGainConfig {
+        AutoGainConfig {
+            target_rms: 0.2,
+            max_gain: 8.0,
+            attack: Duration::from_millis(10),
+            release: Duration::from_millis(50),
+            silence_rms: 0.01,
+        }
+    }
+
+    #[test]
+    fn quiet_input_converges_to_target() {
+        let mut agc = AutoGain::new(config(), 48000, 1);
+        let mut last = Vec::new();
+        for _ in 0..100 {
+            last = vec![0.05; 480];
+            agc.process(&mut last);
+        }
+        assert!((dsp::rms(&last) - 0.2).abs() < 0.01);
+    }
+
+    #[test]
+    fn gain_is_capped() {
+        let mut agc = AutoGain::new(config(), 48000, 1);
+        for _ in 0..100 {
+            agc.process(&mut [0.015; 480]);
+        }
+        assert!(agc.gain <= 8.0);
+        assert!(agc.gain > 7.9);
+    }
+
+    #[test]
+    fn silence_holds_gain() {
+        let mut agc = AutoGain::new(config(), 48000, 1);
+        for _ in 0..100 {
+            agc.process(&mut [0.05; 480]);
+        }
+        let gain = agc.gain;
+        let mut silence = [0.001; 480];
+        agc.process(&mut silence);
+        assert_eq!(agc.gain, gain);
+        assert_eq!(silence[0], 0.001 * gain);
+    }
+}
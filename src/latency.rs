@@ -0,0 +1,164 @@
+//! Turns `AecConfig::latency_target` into concrete buffer sizes.
+
+use std::time::Duration;
+
+use crate::resampler::FRAME_DURATION_MS as DEFAULT_FRAME_MS;
+use crate::AecError;
+
+/// Chunk queue capacity used without a target
+const DEFAULT_QUEUE_DEPTH: usize = 32;
+
+/// Smaller resampler frames make the FFT overhead dominate
+const MIN_FRAME_MS: usize = 2;
+/// Larger resampler frames only add latency and memory
+const MAX_FRAME_MS: usize = 1000;
+/// Device buffers below this glitch on most hardware
+const MIN_DEVICE_BUFFER: Duration = Duration::from_millis(3);
+/// Backends default to roughly this, so a larger target gains nothing
+const MAX_DEVICE_BUFFER: Duration = Duration::from_millis(20);
+/// Fewer queued chunks than this drops audio on any scheduling hiccup
+const MIN_QUEUE_DEPTH: usize = 2;
+
+/// Buffer sizes chosen to fit a latency budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct LatencyPlan {
+    /// Device buffer to request, None to keep the backend default
+    pub device_buffer: Option<Duration>,
+    /// Resampler processing frame
+    pub frame_ms: usize,
+    /// Capacity of the chunk queues between backend, pipeline and caller
+    pub queue_depth: usize,
+}
+
+impl Default for LatencyPlan {
+    fn default() -> Self {
+        Self {
+            device_buffer: None,
+            frame_ms: DEFAULT_FRAME_MS,
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+        }
+    }
+}
+
+impl LatencyPlan {
+    /// Split the target between the device buffer and the resampler frame,
+    /// leaving the remaining third as scheduling headroom. Targets outside
+    /// what the hardware can do are clamped rather than rejected.
+    pub fn new(target: Option<Duration>) -> Self {
+        let Some(target) = target else {
+            return Self::default();
+        };
+
+        let share = target / 3;
+        let device_buffer = share.clamp(MIN_DEVICE_BUFFER, MAX_DEVICE_BUFFER);
+        let frame_ms = (share.as_millis() as usize).clamp(MIN_FRAME_MS, DEFAULT_FRAME_MS);
+
+        // A full queue is the worst-case backlog, so cap it to the budget
+        let queue_depth = (target.as_micros() / device_buffer.as_micros()) as usize;
+
+        Self {
+            device_buffer: Some(device_buffer),
+            frame_ms,
+            queue_depth: queue_depth.clamp(MIN_QUEUE_DEPTH, DEFAULT_QUEUE_DEPTH),
+        }
+    }
+
+    /// Requested device buffer in frames at `sample_rate`.
+    pub fn device_buffer_frames(&self, sample_rate: u32) -> Option<usize> {
+        self.device_buffer
+            .map(|d| (d.as_secs_f64() * sample_rate as f64).round().max(1.0) as usize)
+    }
+}
+
+/// Resampler frame in milliseconds: `AecConfig::resampler_frame` when set,
+/// otherwise the one the latency plan picks.
+pub(crate) fn resampler_frame_ms(
+    target: Option<Duration>,
+    frame: Option<Duration>,
+) -> Result<usize, AecError> {
+    let Some(frame) = frame else {
+        return Ok(LatencyPlan::new(target).frame_ms);
+    };
+    let range =
+        Duration::from_millis(MIN_FRAME_MS as u64)..=Duration::from_millis(MAX_FRAME_MS as u64);
+    if !range.contains(&frame) {
+        return Err(AecError::InvalidConfig(format!(
+            "resampler_frame must be {MIN_FRAME_MS} to {MAX_FRAME_MS} ms, got {frame:?}"
+        )));
+    }
+    if frame.subsec_nanos() % 1_000_000 != 0 {
+        return Err(AecError::InvalidConfig(format!(
+            "resampler_frame must be whole milliseconds, got {frame:?}"
+        )));
+    }
+    Ok(frame.as_millis() as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_target_keeps_defaults() {
+        assert_eq!(LatencyPlan::new(None), LatencyPlan::default());
+    }
+
+    #[test]
+    fn target_is_split_across_stages() {
+        let plan = LatencyPlan::new(Some(Duration::from_millis(30)));
+        assert_eq!(plan.device_buffer, Some(Duration::from_millis(10)));
+        assert_eq!(plan.frame_ms, 10);
+        assert_eq!(plan.queue_depth, 3);
+        assert_eq!(plan.device_buffer_frames(48000), Some(480));
+    }
+
+    #[test]
+    fn unachievable_targets_are_clamped() {
+        let tiny = LatencyPlan::new(Some(Duration::from_micros(500)));
+        assert_eq!(tiny.device_buffer, Some(MIN_DEVICE_BUFFER));
+        assert_eq!(tiny.frame_ms, MIN_FRAME_MS);
+        assert_eq!(tiny.queue_depth, MIN_QUEUE_DEPTH);
+
+        let huge = LatencyPlan::new(Some(Duration::from_secs(10)));
+        assert_eq!(huge.device_buffer, Some(MAX_DEVICE_BUFFER));
+        assert_eq!(huge.queue_depth, DEFAULT_QUEUE_DEPTH);
+    }
+
+    #[test]
+    fn resampler_frame_overrides_the_plan() {
+        let target = Some(Duration::from_millis(30));
+        assert_eq!(resampler_frame_ms(target, None).unwrap(), 10);
+        let frame = Some(Duration::from_millis(20));
+        assert_eq!(resampler_frame_ms(target, frame).unwrap(), 20);
+        assert!(matches!(
+            resampler_frame_ms(None, Some(Duration::from_micros(1500))),
+            Err(AecError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn resampler_frame_must_be_whole_milliseconds() {
+        for frame in [Duration::from_micros(2500), Duration::from_micros(5900)] {
+            assert!(matches!(
+                resampler_frame_ms(None, Some(frame)),
+                Err(AecError::InvalidConfig(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn resampler_frame_is_capped() {
+        let cap = Some(Duration::from_millis(MAX_FRAME_MS as u64));
+        assert_eq!(resampler_frame_ms(None, cap).unwrap(), MAX_FRAME_MS);
+        for frame in [
+            Duration::from_millis(1001),
+            Duration::from_secs(3600),
+            Duration::MAX,
+        ] {
+            assert!(matches!(
+                resampler_frame_ms(None, Some(frame)),
+                Err(AecError::InvalidConfig(_))
+            ));
+        }
+    }
+}
@@ -1,7 +1,29 @@
 mod backends;
+pub mod dsp;
+mod erle;
+mod gain;
+mod latency;
+#[cfg(feature = "opus")]
+mod ogg_opus;
+mod pipeline;
+mod playback_sync;
+// Always built so backends can hold an always-None tee without the feature
+#[cfg_attr(not(feature = "debug-capture"), allow(dead_code))]
+mod raw_capture;
+mod recent;
 mod resampler;
+mod selftest;
+mod utterance;
 
-use resampler::Resampler;
+pub use dsp::Endianness;
+#[cfg(feature = "debug-capture")]
+pub use raw_capture::{RawCapture, RawCaptureFn, RawFormat};
+pub use resampler::{Resample, ResamplerFactory};
+
+use backends::{Chunk, ChunkSender, QueueSender};
+use pipeline::{BufferPool, ControlHandle, Delivery, Pipeline, Reconnect, Retarget};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use thiserror::Error;
 
 /// Output channel configuration
@@ -9,26 +31,905 @@ use thiserror::Error;
 pub enum Channels {
     #[default]
     Mono,
+    /// The mono capture duplicated to both channels, L == R
     Stereo,
+    /// The device's own left and right channels, kept apart through
+    /// resampling. Channels past the first two are dropped, and a mono
+    /// device is duplicated as with `Stereo`. Windows, file and cpal
+    /// capture keep device channels; the other backends capture mono.
+    StereoTrue,
+    /// Every device channel, interleaved, without downmixing. Only
+    /// Windows, file and cpal capture deliver more than one channel;
+    /// others stay mono.
+    Native,
+}
+
+/// How a multichannel input device is reduced to mono
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DownmixMode {
+    /// Average all channels
+    #[default]
+    Average,
+    /// Keep only this zero-based input channel, e.g. the good capsule of a
+    /// dual-mic device. Out-of-range indexes fail with `InvalidConfig`.
+    SelectChannel(u16),
+}
+
+/// Which OS capture path to open
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Processing {
+    /// Voice processing: echo cancellation plus the OS's noise
+    /// suppression and gain control
+    #[default]
+    Voice,
+    /// Plain microphone input with no voice processing. The resampling
+    /// and channel pipeline still applies.
+    Raw,
 }
 
 #[derive(Debug, Clone)]
 pub struct AecConfig {
-    /// Target sample rate in Hz (typically 48000)
+    /// Target sample rate in Hz (typically 48000). Narrowband rates of
+    /// 8000 Hz and below resample in frames of at least 20 ms to keep the
+    /// 300-3400 Hz voice band flat, whatever `latency_target` asks for.
     pub sample_rate: u32,
+    /// Resample to `sample_rate`. With `false` chunks arrive at the
+    /// device's native rate, for callers with their own resampler;
+    /// `CaptureHandle::output_sample_rate` reports it.
+    pub resample: bool,
+    /// Converter used when resampling captured audio. None uses the
+    /// built-in rubato resampler, sized by `latency_target`.
+    pub resampler: Option<ResamplerFactory>,
     /// Output channels (stereo = duplicated mono from AEC)
     pub channels: Channels,
+    /// Channel reduction for multichannel inputs. Ignored with
+    /// `Channels::Native` and `Channels::StereoTrue`, by voice processing,
+    /// which already outputs a single channel, and on iOS and Android,
+    /// which always capture mono.
+    pub downmix: DownmixMode,
+    /// Deliver chunks from a dedicated thread (raised to realtime priority
+    /// where the OS allows) instead of a task on the tokio runtime
+    pub dedicated_delivery_thread: bool,
+    /// Re-buffer output so every chunk holds exactly this many frames,
+    /// except a final partial chunk when capture stops
+    pub output_chunk_frames: Option<usize>,
+    /// Software gain that steers each chunk toward a target level.
+    /// Intended for when the OS AGC is unavailable or disabled.
+    pub auto_gain: Option<AutoGainConfig>,
+    /// Your own processing, run on each chunk after resampling and
+    /// `auto_gain`, before the channel layout is applied. None (the
+    /// default) runs nothing.
+    pub process_hook: Option<ProcessHook>,
+    /// Read input from a WAV file instead of the audio device, through the
+    /// same resampling and channel pipeline
+    #[cfg(feature = "backend-file")]
+    pub input_file: Option<FileSource>,
+    /// Discard audio captured before `CaptureHandle::pause()` instead of
+    /// delivering it ahead of post-resume audio
+    pub pause_discard: bool,
+    /// Total capture latency to aim for. Device buffer, resampler frame and
+    /// queue depth are sized to fit it where the backend allows; otherwise
+    /// defaults apply. `CaptureHandle::input_latency` reports the result.
+    pub latency_target: Option<std::time::Duration>,
+    /// Input the built-in resampler converts at a time, in place of the
+    /// frame `latency_target` picks (10 ms without one), so conversion
+    /// latency doesn't depend on the device rate: 5 ms for low latency,
+    /// 20 ms for less CPU. Whole milliseconds from 2 to 1000, or
+    /// `InvalidConfig`. Narrowband output still uses at least 20 ms, and a
+    /// custom `resampler` ignores it.
+    pub resampler_frame: Option<std::time::Duration>,
+    /// What happens to captured audio when the caller falls behind and the
+    /// chunk queues fill. Ignored for file input, which waits for the
+    /// caller.
+    pub overflow_policy: OverflowPolicy,
+    /// Close the stream when nothing above silence (about -100 dBFS)
+    /// arrives for this long, whether chunks of zeros keep coming or none
+    /// do. `recv` then returns `DeviceUnavailable` and `join` reports
+    /// `CloseReason::SilenceTimeout`, turning a device that went quiet
+    /// without failing into an error to recover from. Not retried by
+    /// `auto_reconnect`. None (the default) waits indefinitely.
+    pub silence_timeout: Option<std::time::Duration>,
+    /// Chunk RMS that `CaptureHandle::is_receiving_signal` counts as signal.
+    /// The default 1e-4, about -80 dBFS, sits below any live microphone's
+    /// noise but above the zeros or dither of a muted one.
+    pub signal_floor: f32,
+    /// Recent audio a handle receives first when it joins a device stream
+    /// other handles already have open, so a consumer that attaches a
+    /// moment late still gets the start of an utterance. The stream keeps
+    /// as much as the longest pre-roll its handles have asked for,
+    /// including the one that opened it, so set it there too. Zero (the
+    /// default) keeps no history.
+    pub pre_roll: std::time::Duration,
+    /// Device access to request. `Shared` (the default) lets other apps,
+    /// such as a meeting client, capture at the same time. `Exclusive` is
+    /// honored on Android and on macOS, where it takes hog mode on the input
+    /// device until the handle drops, and falls back to shared when refused.
+    pub sharing_mode: SharingMode,
+    /// Voice processing (the default) or plain microphone capture
+    pub processing: Processing,
+    /// Suffix for background thread names (e.g. `android-audio-{label}`).
+    /// Defaults to a per-process handle number.
+    pub thread_label: Option<String>,
+    /// Most audio `play_audio` may queue ahead of the output. The oldest
+    /// samples are dropped past it; `None` leaves the queue unbounded.
+    /// Only enforced on Android.
+    pub playback_buffer_max: Option<std::time::Duration>,
+    /// What `play_audio` does with audio at a rate other than the output's,
+    /// which `CaptureHandle::playback_sample_rate` reports
+    pub playback_rate_mismatch: PlaybackRateMismatch,
+    /// Release `play_audio` audio in step with the capture clock
+    /// (`CaptureHandle::stream_position`) instead of queueing it to the
+    /// output at once: nothing plays before the first chunk is captured,
+    /// and playback runs at most 100 ms ahead of the capture. However the
+    /// two device clocks drift or the OS buffers output, the echo then
+    /// stays inside the canceller's window, which makes AEC tests like the
+    /// `aec_test` example deterministic. Costs up to that lead in playback
+    /// latency. Off by default.
+    pub playback_sync: bool,
+    /// Synthesize playback on demand instead of queueing it with
+    /// `play_audio`, which then fails. Android only; other backends return
+    /// `AecNotSupported`.
+    pub render_source: Option<RenderSource>,
+    /// How far voice processing lowers other apps' audio while capturing.
+    /// Apple platforms only; ignored elsewhere and for `Processing::Raw`.
+    pub other_audio_ducking: Ducking,
+    /// Sound system to capture through on Linux; ignored elsewhere.
+    /// `linux_audio_apis()` lists the ones present.
+    pub linux_api: LinuxAudioApi,
+    /// Names the sound server shows for the streams on Linux; ignored
+    /// elsewhere
+    pub stream_properties: StreamProperties,
+    /// Default device role to open on Windows; ignored elsewhere
+    pub device_role: DeviceRole,
+    /// Input devices to capture from, most preferred first, by the IDs
+    /// `input_devices()` lists. The first one present is opened, at startup
+    /// and on every reconnect; the default device when none is. Honored on
+    /// macOS, Windows and Linux. `CaptureHandle::input_device` reports the
+    /// pick.
+    pub preferred_input_devices: Vec<String>,
+    /// Capture from a connected Bluetooth headset on Android. Its
+    /// microphone is only reachable over SCO (hands-free) audio, which the
+    /// stream brings up and waits up to 5 s for before opening input and
+    /// output on the headset, then releases when it closes. Fails with
+    /// `DeviceUnavailable` when no headset connects, and needs the
+    /// MODIFY_AUDIO_SETTINGS permission. SCO carries 8 or 16 kHz speech,
+    /// resampled to `sample_rate`. Ignored elsewhere.
+    pub bluetooth_sco: bool,
+    /// Quality of Oboe's conversion between the device rate and 48 kHz on
+    /// Android: `Fastest` for battery-sensitive voice, `Best` for music.
+    /// Only applies where the device doesn't run at 48 kHz. Ignored
+    /// elsewhere.
+    pub device_resampler_quality: ResamplerQuality,
+    /// Enables `CaptureHandle::recv_utterance`, which groups chunks into
+    /// whole utterances. None (the default) leaves it unavailable.
+    pub utterance: Option<UtteranceConfig>,
+    /// Keep this much of the most recent delivered audio for
+    /// `CaptureHandle::snapshot_recent`, e.g. to save what led up to a
+    /// crash. A passive copy that doesn't affect `recv`. None (the
+    /// default) keeps nothing.
+    pub retain_recent: Option<std::time::Duration>,
+    /// Reopen the device when it is lost instead of ending the stream.
+    /// None (the default) reports `CaptureStatus::DeviceLost` and stops.
+    pub auto_reconnect: Option<ReconnectConfig>,
+    /// Tee the backend's input bytes, before they are decoded to `f32`.
+    /// A handle with one never shares its device stream.
+    #[cfg(feature = "debug-capture")]
+    pub raw_capture: Option<RawCapture>,
+    /// Capture the system's audio output alongside the microphone. macOS
+    /// 13 and later only; elsewhere opening fails with `AecNotSupported`.
+    #[cfg(feature = "system-audio")]
+    pub system_audio: SystemAudio,
 }
 
 impl Default for AecConfig {
     fn default() -> Self {
         Self {
             sample_rate: 48000,
+            resample: true,
+            resampler: None,
             channels: Channels::Mono,
+            downmix: DownmixMode::Average,
+            dedicated_delivery_thread: false,
+            output_chunk_frames: None,
+            auto_gain: None,
+            process_hook: None,
+            #[cfg(feature = "backend-file")]
+            input_file: None,
+            pause_discard: false,
+            latency_target: None,
+            resampler_frame: None,
+            overflow_policy: OverflowPolicy::DropNewest,
+            silence_timeout: None,
+            signal_floor: 1e-4,
+            pre_roll: std::time::Duration::ZERO,
+            sharing_mode: SharingMode::Shared,
+            processing: Processing::Voice,
+            thread_label: None,
+            playback_buffer_max: None,
+            playback_rate_mismatch: PlaybackRateMismatch::Resample,
+            playback_sync: false,
+            render_source: None,
+            other_audio_ducking: Ducking::Default,
+            linux_api: LinuxAudioApi::Auto,
+            stream_properties: StreamProperties::default(),
+            device_role: DeviceRole::Console,
+            preferred_input_devices: Vec::new(),
+            bluetooth_sco: false,
+            device_resampler_quality: ResamplerQuality::Medium,
+            utterance: None,
+            retain_recent: None,
+            auto_reconnect: None,
+            #[cfg(feature = "debug-capture")]
+            raw_capture: None,
+            #[cfg(feature = "system-audio")]
+            system_audio: SystemAudio::Off,
+        }
+    }
+}
+
+/// Starting points for `AecConfig` that trade latency against resistance to
+/// dropouts. Each sets individual fields, which can still be overridden:
+/// `AecConfig { sample_rate: 16000, ..CaptureProfile::LowLatency.into() }`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CaptureProfile {
+    /// A 20 ms `latency_target`, which shrinks device buffers, resampler
+    /// frames and chunk queues; `SharingMode::Exclusive`, honored on
+    /// Android and macOS; and a dedicated delivery thread
+    LowLatency,
+    /// `AecConfig::default()`: backend buffer sizes, 32-chunk queues and
+    /// shared access
+    #[default]
+    Balanced,
+    /// `Balanced` plus a dedicated delivery thread, so a busy tokio runtime
+    /// can't stall delivery, and `auto_reconnect` with default backoff
+    Robust,
+    /// Exactly what the device produces, for archival recording or as a
+    /// baseline when hunting processing artifacts: `Processing::Raw`, the
+    /// native rate (`resample: false`) and `Channels::Native`, with no
+    /// gain, hook or rechunking. Chunks arrive as the backend delivered
+    /// them, and `output_sample_rate` and `channels` report the device
+    /// format.
+    PassThrough,
+}
+
+impl From<CaptureProfile> for AecConfig {
+    fn from(profile: CaptureProfile) -> Self {
+        let balanced = Self::default();
+        match profile {
+            CaptureProfile::LowLatency => Self {
+                latency_target: Some(std::time::Duration::from_millis(20)),
+                sharing_mode: SharingMode::Exclusive,
+                dedicated_delivery_thread: true,
+                ..balanced
+            },
+            CaptureProfile::Balanced => balanced,
+            CaptureProfile::Robust => Self {
+                dedicated_delivery_thread: true,
+                auto_reconnect: Some(ReconnectConfig::default()),
+                ..balanced
+            },
+            CaptureProfile::PassThrough => Self {
+                processing: Processing::Raw,
+                resample: false,
+                channels: Channels::Native,
+                ..balanced
+            },
+        }
+    }
+}
+
+/// WAV input for offline runs and deterministic tests.
+/// Multichannel files are downmixed to mono unless `Channels::Native` or
+/// `Channels::StereoTrue` is requested. Reaching the end of the file is
+/// reported as `DeviceLost`.
+#[cfg(feature = "backend-file")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSource {
+    pub path: std::path::PathBuf,
+    /// Pace delivery at the file's real-time rate; otherwise read as fast
+    /// as the consumer keeps up
+    pub realtime: bool,
+    /// Device failure to simulate partway through the file. None (the
+    /// usual) plays it to the end.
+    pub fault: Option<FileFault>,
+}
+
+/// Failure `FileSource::fault` injects, for testing error handling and
+/// `auto_reconnect` without unplugging hardware. Times count file audio,
+/// so the failure lands at the same point on every run.
+#[cfg(feature = "backend-file")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileFault {
+    /// The stream stops after this much of the file, as an unplugged
+    /// device's does: `CaptureStatus::DeviceLost`, and `join` reports
+    /// `CloseReason::DeviceLost`. `auto_reconnect`, otherwise off for file
+    /// input, is honored for this one loss: the file reopens without the
+    /// fault, plays from the start, and its end closes the stream.
+    Disconnect(std::time::Duration),
+    /// Capture fails after this much of the file: `recv` returns
+    /// `AecError::BackendError` with the message, and `join` reports
+    /// `CloseReason::Failed`
+    Error {
+        at: std::time::Duration,
+        message: String,
+    },
+}
+
+/// Ducking of other apps' audio by Apple's VoiceProcessingIO.
+///
+/// Voice processing can't leave other audio untouched; `Minimum` is the
+/// closest it gets. The non-default levels also enable advanced ducking,
+/// which only ducks while someone is talking. Needs macOS 14 / iOS 17;
+/// older systems keep their default with a logged warning.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Ducking {
+    /// Whatever the OS does, which is strong ducking for the whole session
+    #[default]
+    Default,
+    Minimum,
+    Medium,
+    Maximum,
+}
+
+/// Linux sound system to capture through.
+///
+/// PipeWire is reached through its PulseAudio server, so `Pulse` and
+/// `PipeWire` share a backend and differ in which source they read. ALSA
+/// has no native backend; it is captured by the cpal fallback, without AEC,
+/// and needs the `backend-cpal` feature.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LinuxAudioApi {
+    /// PipeWire's echo-cancel source when it is loaded, otherwise the
+    /// default source of whichever server is running
+    #[default]
+    Auto,
+    /// The default source of the PulseAudio server
+    Pulse,
+    /// PipeWire's echo-cancel source, or its default source without one.
+    /// Fails with `AecNotSupported` when the server isn't PipeWire.
+    PipeWire,
+    /// Raw ALSA through cpal
+    Alsa,
+}
+
+/// Which of Windows' default devices to capture from and play through.
+///
+/// Windows keeps a separate default for communications, often a headset,
+/// which call apps use. The AEC reference follows the chosen render device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeviceRole {
+    /// The default device, as chosen for games and media
+    #[default]
+    Console,
+    /// The default communications device
+    Communications,
+}
+
+/// Quality of the rate conversion Android does inside Oboe, between the
+/// device's native rate and the 48 kHz the streams are opened at. Separate
+/// from the crate's own resampling to `sample_rate`. Higher settings cost
+/// more CPU, and so battery, on every callback of both streams.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResamplerQuality {
+    /// Interpolation only: cheapest, with audible aliasing on music
+    Fastest,
+    Low,
+    /// Plenty for speech
+    #[default]
+    Medium,
+    High,
+    /// For music capture, at the highest CPU cost
+    Best,
+}
+
+/// System output to capture with the microphone, e.g. for recording both
+/// sides of a meeting. Taken through ScreenCaptureKit, which needs the
+/// Screen Recording permission and is denied with `PermissionDenied`.
+///
+/// Includes audio played with `play_audio`. Voice processing doesn't touch
+/// it, so echo cancellation still applies only to the microphone.
+#[cfg(feature = "system-audio")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SystemAudio {
+    /// Microphone only
+    #[default]
+    Off,
+    /// Microphone and system audio summed into one channel
+    Mixed,
+    /// Microphone on channel 0 and system audio on channel 1. They stay
+    /// apart with `Channels::Native` or `Channels::StereoTrue`; mono output
+    /// downmixes them by `AecConfig::downmix`.
+    Separate,
+}
+
+/// Platform object behind a capture stream, from
+/// `CaptureHandle::with_native_unit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NativeUnit {
+    /// Core Audio `AudioUnit` on macOS and iOS: VoiceProcessingIO, or the
+    /// HAL (macOS) or RemoteIO (iOS) unit under `Processing::Raw`
+    AudioUnit(*mut std::ffi::c_void),
+}
+
+/// How the streams are labelled on the sound server, e.g. in pavucontrol.
+/// Linux only; other backends ignore it. The Simple API takes no property
+/// list, so there is no `media.role` to set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamProperties {
+    /// Application the streams are listed under
+    pub app_name: String,
+    /// Description of the capture stream
+    pub capture_name: String,
+    /// Description of the playback stream
+    pub playback_name: String,
+}
+
+impl Default for StreamProperties {
+    fn default() -> Self {
+        Self {
+            app_name: "sys-voice".to_string(),
+            capture_name: "AEC Capture".to_string(),
+            playback_name: "AEC Playback".to_string(),
+        }
+    }
+}
+
+/// Handling of captured audio that arrives while the chunk queues are full,
+/// i.e. while the caller isn't receiving fast enough.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Discard the chunk that doesn't fit, keeping the queued backlog
+    #[default]
+    DropNewest,
+    /// Discard the oldest queued audio to make room, so delivery stays on
+    /// the freshest audio, e.g. for live monitoring
+    DropOldest,
+    /// Wait for room on the audio thread. Nothing is discarded in the
+    /// crate, but a callback held up too long overruns in the OS instead,
+    /// and one slow handle holds up every handle sharing its stream.
+    Block,
+}
+
+/// Handling of `play_audio` input whose rate differs from the output's.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PlaybackRateMismatch {
+    /// Convert it to the output rate. Android and iOS interpolate linearly,
+    /// which softens high frequencies; the other platforms use the same
+    /// resampler as capture.
+    #[default]
+    Resample,
+    /// Fail with `InvalidConfig`, for callers who would rather produce
+    /// audio at `CaptureHandle::playback_sample_rate` than have it converted
+    Reject,
+}
+
+/// Boxed render function held by `RenderSource`
+pub type RenderFn = Box<dyn FnMut(&mut [f32]) + Send>;
+
+/// Callback the playback stream pulls audio from: it is handed each output
+/// buffer (mono, 48 kHz, pre-filled with silence) to overwrite from the
+/// realtime thread. The stream takes the callback once when it starts, so
+/// rendering is lock-free and a source drives only the first handle it is
+/// used with.
+#[derive(Clone)]
+pub struct RenderSource(Arc<Mutex<Option<RenderFn>>>);
+
+impl RenderSource {
+    pub fn new(render: impl FnMut(&mut [f32]) + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(Some(Box::new(render)))))
+    }
+
+    /// Move the callback out for the stream to own. None once taken.
+    #[cfg_attr(not(target_os = "android"), allow(dead_code))]
+    pub(crate) fn take(&self) -> Option<RenderFn> {
+        self.0.lock().ok().and_then(|mut render| render.take())
+    }
+}
+
+impl std::fmt::Debug for RenderSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RenderSource")
+    }
+}
+
+/// One delivered chunk: interleaved `f32` samples labeled with the rate
+/// and channel count they were produced at. Derefs to `[f32]`, so slice
+/// code keeps working; `into_vec` hands back the buffer without copying.
+///
+/// The labels follow the stream, so chunks queued before
+/// `set_output_sample_rate` still report the old rate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioFrame {
+    samples: Vec<f32>,
+    sample_rate: u32,
+    channels: u16,
+    first: bool,
+}
+
+impl AudioFrame {
+    pub fn new(samples: Vec<f32>, sample_rate: u32, channels: u16) -> Self {
+        Self {
+            samples,
+            sample_rate,
+            channels,
+            first: false,
+        }
+    }
+
+    /// Whether this is the first chunk the handle delivered, where the
+    /// stream begins after device negotiation. Echo cancellation is still
+    /// converging over the first chunks, so a transcriber may want to
+    /// discard or de-weight audio from here. After `new_async`, which
+    /// drops its warm-up audio, it marks the first chunk that follows.
+    pub fn is_first(&self) -> bool {
+        self.first
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Interleaved channels per frame.
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    /// Samples per channel.
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+
+    /// Playback length of the chunk.
+    pub fn duration(&self) -> std::time::Duration {
+        std::time::Duration::from_secs_f64(self.frames() as f64 / self.sample_rate.max(1) as f64)
+    }
+
+    pub fn as_slice(&self) -> &[f32] {
+        &self.samples
+    }
+
+    pub fn into_vec(self) -> Vec<f32> {
+        self.samples
+    }
+
+    /// The samples as interleaved 16-bit PCM bytes in `order`, clamped to
+    /// [-1.0, 1.0]. `Endianness::default()` is little-endian.
+    pub fn to_pcm16_bytes(&self, order: Endianness) -> Vec<u8> {
+        dsp::to_i16_bytes(&self.samples, order)
+    }
+}
+
+impl std::ops::Deref for AudioFrame {
+    type Target = [f32];
+
+    fn deref(&self) -> &[f32] {
+        &self.samples
+    }
+}
+
+impl From<AudioFrame> for Vec<f32> {
+    fn from(frame: AudioFrame) -> Self {
+        frame.samples
+    }
+}
+
+impl IntoIterator for AudioFrame {
+    type Item = f32;
+    type IntoIter = std::vec::IntoIter<f32>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.samples.into_iter()
+    }
+}
+
+/// Automatic gain control applied after resampling.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AutoGainConfig {
+    /// Level to steer toward, as linear RMS in [0.0, 1.0]
+    pub target_rms: f32,
+    /// Upper bound on the applied linear gain
+    pub max_gain: f32,
+    /// Time constant for reducing gain when input gets louder
+    pub attack: std::time::Duration,
+    /// Time constant for raising gain when input gets quieter
+    pub release: std::time::Duration,
+    /// Chunks below this RMS are treated as silence and leave the gain
+    /// unchanged, so the noise floor isn't pumped up between phrases
+    pub silence_rms: f32,
+}
+
+impl Default for AutoGainConfig {
+    fn default() -> Self {
+        Self {
+            target_rms: 0.1,
+            max_gain: 10.0,
+            attack: std::time::Duration::from_millis(20),
+            release: std::time::Duration::from_millis(500),
+            silence_rms: 0.005,
+        }
+    }
+}
+
+/// Backoff for `AecConfig::auto_reconnect`. The delay before each attempt
+/// doubles from `initial_delay` up to `max_delay`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectConfig {
+    pub initial_delay: std::time::Duration,
+    pub max_delay: std::time::Duration,
+    /// Attempts before giving up with `DeviceLost`; None retries forever
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay: std::time::Duration::from_millis(500),
+            max_delay: std::time::Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+/// Endpointing for `CaptureHandle::recv_utterance`.
+#[derive(Debug, Clone)]
+pub struct UtteranceConfig {
+    /// Audio kept from before the first speech chunk, so the onset of the
+    /// first word isn't clipped
+    pub pre_roll: std::time::Duration,
+    /// Non-speech that ends an utterance. It is included in the utterance.
+    pub trailing_silence: std::time::Duration,
+    /// Decides which chunks contain speech
+    pub detector: VoiceDetector,
+}
+
+impl Default for UtteranceConfig {
+    fn default() -> Self {
+        Self {
+            pre_roll: std::time::Duration::from_millis(300),
+            trailing_silence: std::time::Duration::from_millis(800),
+            detector: VoiceDetector::level(0.01),
+        }
+    }
+}
+
+/// Boxed detection function held by `VoiceDetector`
+pub type DetectFn = Box<dyn FnMut(&AudioFrame) -> bool + Send>;
+
+/// Voice activity detector for `UtteranceConfig`: called with each
+/// delivered chunk, returning whether it contains speech. Plug in a real
+/// VAD with `new`; `level` is a plain loudness gate. Clones share the
+/// callback.
+#[derive(Clone)]
+pub struct VoiceDetector(Arc<Mutex<DetectFn>>);
+
+impl VoiceDetector {
+    pub fn new(detect: impl FnMut(&AudioFrame) -> bool + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(Box::new(detect))))
+    }
+
+    /// Treat chunks louder than `rms` as speech. Only reliable in a quiet
+    /// room, since any noise above the threshold counts.
+    pub fn level(rms: f32) -> Self {
+        Self::new(move |frame| dsp::rms(frame) > rms)
+    }
+
+    pub(crate) fn is_speech(&self, frame: &AudioFrame) -> bool {
+        self.0.lock().is_ok_and(|mut detect| detect(frame))
+    }
+}
+
+impl std::fmt::Debug for VoiceDetector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("VoiceDetector")
+    }
+}
+
+/// Boxed processing function held by `ProcessHook`
+pub type ProcessFn = Box<dyn FnMut(&mut Vec<f32>) + Send>;
+
+/// Custom DSP for `AecConfig::process_hook`, e.g. a noise gate. Called with
+/// each chunk, interleaved at the device's channel count and already at
+/// the output rate, to change in place. It may change the length as long
+/// as it leaves whole frames.
+///
+/// Runs in the delivery task or thread, not the OS audio callback, so
+/// modest allocation is fine; time spent here shows in
+/// `CaptureStats::dsp_load`. Clones share the function.
+#[derive(Clone)]
+pub struct ProcessHook(Arc<Mutex<ProcessFn>>);
+
+impl ProcessHook {
+    pub fn new(process: impl FnMut(&mut Vec<f32>) + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(Box::new(process))))
+    }
+
+    pub(crate) fn run(&self, samples: &mut Vec<f32>) {
+        if let Ok(mut process) = self.0.lock() {
+            process(samples);
         }
     }
 }
 
+impl std::fmt::Debug for ProcessHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProcessHook")
+    }
+}
+
+/// Stream status changes, delivered separately from audio via `StatusReceiver`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureStatus {
+    /// The backend stopped on its own (device unplugged or invalidated).
+    /// No further audio will arrive.
+    DeviceLost,
+    /// The device was lost and `auto_reconnect` is about to try reopening
+    /// it, counting from 1
+    Reconnecting { attempt: u32 },
+    /// A reconnect attempt succeeded and audio continues
+    Reconnected,
+    /// The OS suspended capture, e.g. an incoming phone call on iOS
+    Interrupted,
+    /// Capture continues after an interruption
+    Resumed,
+}
+
+/// Why a stream closed, from `CaptureHandle::join`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The handle stopped it
+    Stopped,
+    /// The backend stopped on its own and wasn't reopened: the device was
+    /// lost or the input file ran out. `CaptureStatus::DeviceLost` was sent.
+    DeviceLost,
+    /// Processing failed; `recv` returned the error
+    Failed,
+    /// Nothing above silence arrived within `AecConfig::silence_timeout`,
+    /// and `recv` returned `DeviceUnavailable`. `no_chunks` is true when
+    /// the backend delivered nothing at all in that time, false when it
+    /// kept delivering silence.
+    SilenceTimeout { no_chunks: bool },
+}
+
+/// Lifecycle of a stream, delivered separately from audio via
+/// `EventReceiver`. `Started` comes first and `Stopped` last.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureEvent {
+    /// The device is open and audio is about to flow
+    Started,
+    /// The echo canceller has had 500 ms of input to converge, or without
+    /// one, the first audio arrived. Sent once, not again after a reconnect.
+    WarmedUp,
+    /// `auto_reconnect` reopened a lost device and audio continues
+    DeviceChanged,
+    /// The OS suspended capture, e.g. an incoming phone call on iOS
+    Interrupted,
+    /// Capture continues after an interruption
+    Resumed,
+    /// The stream closed, for the reason `join` returns
+    Stopped(CloseReason),
+}
+
+/// Receives `CaptureStatus` events independently of the audio samples.
+/// Returns None once capture has stopped.
+#[derive(Clone)]
+pub struct StatusReceiver {
+    receiver: flume::Receiver<CaptureStatus>,
+}
+
+impl StatusReceiver {
+    /// Receive the next status change asynchronously.
+    pub async fn recv(&self) -> Option<CaptureStatus> {
+        self.receiver.recv_async().await.ok()
+    }
+
+    /// Receive the next status change, blocking the current thread.
+    pub fn recv_blocking(&self) -> Option<CaptureStatus> {
+        self.receiver.recv().ok()
+    }
+
+    /// Try to receive a status change without blocking.
+    pub fn try_recv(&self) -> Option<CaptureStatus> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+/// Receives `CaptureEvent`s independently of the audio samples. Returns
+/// None after `Stopped`.
+#[derive(Clone)]
+pub struct EventReceiver {
+    receiver: flume::Receiver<CaptureEvent>,
+}
+
+impl EventReceiver {
+    /// Receive the next event asynchronously.
+    pub async fn recv(&self) -> Option<CaptureEvent> {
+        self.receiver.recv_async().await.ok()
+    }
+
+    /// Receive the next event, blocking the current thread.
+    pub fn recv_blocking(&self) -> Option<CaptureEvent> {
+        self.receiver.recv().ok()
+    }
+
+    /// Try to receive an event without blocking.
+    pub fn try_recv(&self) -> Option<CaptureEvent> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+/// How the capture device is shared with other apps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SharingMode {
+    /// Sole access to the device, lowest latency
+    Exclusive,
+    /// Mixed or distributed by the OS audio service
+    #[default]
+    Shared,
+}
+
+/// Diagnostics snapshot returned by `CaptureHandle::stats()`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CaptureStats {
+    /// Active backend, as returned by `CaptureHandle::backend_name()`
+    pub backend: &'static str,
+    /// Sharing mode the OS granted. Android and macOS negotiate one; other
+    /// backends report `None`.
+    pub sharing_mode: Option<SharingMode>,
+    /// Whether the capture runs through an echo canceller. False for
+    /// `Processing::Raw`, the file and cpal backends, and Linux without
+    /// the echo-cancel module.
+    pub aec_active: bool,
+    /// Time spent resampling and converting each chunk, as a percentage of
+    /// the chunk's real-time duration, exponentially averaged. Past 100 the
+    /// pipeline falls behind and the backend starts dropping audio.
+    pub dsp_load: f32,
+    /// Delivered channels carrying a signal of their own. Fewer than
+    /// `CaptureHandle::channels()` when mono is duplicated: always for
+    /// `Channels::Stereo`, and for `StereoTrue` on a mono device.
+    pub distinct_channels: u16,
+    /// Samples per channel `new_async` dropped while the stream warmed up,
+    /// at the rate they were delivered at. The first chunk `recv` returns
+    /// starts this far into the delivered stream, so timelines built on it
+    /// stay aligned. Zero after `new`.
+    pub warmup_skipped_samples: u64,
+    /// Buffers the pipeline has allocated to cut `output_chunk_frames`
+    /// chunks into. Stops growing once `CaptureHandle::recv_into` is
+    /// handing spent ones back faster than they are needed.
+    pub buffers_allocated: u64,
+}
+
+/// Result of `CaptureHandle::run_loopback_selftest`, meant to be attached
+/// to bug reports. Serializable with the `serde` feature.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SelfTestReport {
+    pub backend: &'static str,
+    pub aec_active: bool,
+    /// Length of tone played and captured
+    pub duration: std::time::Duration,
+    /// How far below the played level the test tone came back, in dB.
+    /// Near 0 means the echo passed straight through
+    pub tone_attenuation_db: f32,
+    /// Broadband estimate from `measure_erle`
+    pub erle_db: Option<f32>,
+    /// Delay from `play_audio` to the echo showing up in the capture
+    pub round_trip_latency: Option<std::time::Duration>,
+    /// Same as `CaptureHandle::input_latency`
+    pub input_latency: std::time::Duration,
+    /// Frames missing from the capture compared to the wall clock
+    pub dropped_frames: usize,
+}
+
+/// Echo cancellation capability reported by `aec_supported()`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AecSupport {
+    /// OS voice-processing AEC (VoiceProcessingIO, WASAPI AEC control,
+    /// Oboe VoiceCommunication)
+    pub hardware: bool,
+    /// Echo canceller loaded in the sound server (PulseAudio/PipeWire echo-cancel)
+    pub software: bool,
+}
+
 #[derive(Debug, Error)]
 pub enum AecError {
     #[error("audio device unavailable")]
@@ -45,118 +946,1423 @@ pub enum AecError {
 
     #[error("backend error: {0}")]
     BackendError(String),
+
+    /// A platform call failed with a native error code: `OSStatus` on
+    /// Apple platforms, `HRESULT` on Windows, Oboe `Result` on Android and
+    /// PulseAudio error codes on Linux.
+    #[error("backend error: {message} (OS error {code})")]
+    OsError { message: String, code: i64 },
+
+    /// The device delivers a sample format the crate can't convert, such
+    /// as an unusual bit depth.
+    #[error("unsupported sample format: {bits}-bit {}", if *.float { "float" } else { "integer" })]
+    UnsupportedFormat { bits: u16, float: bool },
+}
+
+impl AecError {
+    /// Native error code, when the failure came from a platform call.
+    pub fn os_code(&self) -> Option<i64> {
+        match self {
+            AecError::OsError { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+}
+
+/// Probe whether echo cancellation is available without starting capture.
+/// Cheap and side-effect free: no audio is captured and no prompt is shown.
+///
+/// Android can't query `AcousticEchoCanceler` without JNI, so it reports the
+/// VoiceCommunication preset as available.
+pub fn aec_supported() -> AecSupport {
+    backends::aec_supported()
+}
+
+/// Linux sound systems that can be captured from right now, for
+/// `AecConfig::linux_api`. Empty on other platforms.
+pub fn linux_audio_apis() -> Vec<LinuxAudioApi> {
+    backends::linux_audio_apis()
+}
+
+/// IDs of the input devices present right now, for
+/// `AecConfig::preferred_input_devices`: Core Audio device UIDs on macOS,
+/// endpoint IDs on Windows and source names on Linux. Empty elsewhere.
+pub fn input_devices() -> Vec<String> {
+    backends::input_devices()
+}
+
+/// Sample rates the input device `device_id` (from `input_devices()`), or
+/// the default input for None, runs at without resampling, lowest first.
+/// Picking one of them for `AecConfig::sample_rate` avoids conversion.
+///
+/// Best effort: macOS lists the device's nominal rates and Windows the
+/// rates `IsFormatSupported` accepts. Linux, iOS and Android report only
+/// the rate the source, session or a probe stream currently runs at, and
+/// iOS and Android only for the default input. Empty where the platform
+/// can't be asked; `DeviceUnavailable` for an unknown device.
+pub fn supported_sample_rates(device_id: Option<&str>) -> Result<Vec<u32>, AecError> {
+    backends::supported_sample_rates(device_id)
+}
+
+/// Capture config for a full-duplex app whose playback runs at
+/// `output_rate`, the rate of the output device it plays to. Chunks are
+/// delivered at that rate, so captured audio can be mixed or looped back
+/// without converting it a second time. `input_device` (from
+/// `input_devices()`) is preferred, or the default input for None.
+///
+/// Nothing is resampled when the input also runs at `output_rate`; when
+/// `supported_sample_rates` says it doesn't, capture converts once and a
+/// debug message names the input's rates, for picking a better-matched
+/// device. `InvalidConfig` for a zero rate, `DeviceUnavailable` for an
+/// unknown device.
+pub fn config_for_output(
+    output_rate: u32,
+    input_device: Option<&str>,
+) -> Result<AecConfig, AecError> {
+    if output_rate == 0 {
+        return Err(AecError::InvalidConfig(
+            "sample_rate must be non-zero".to_string(),
+        ));
+    }
+    let rates = backends::supported_sample_rates(input_device)?;
+    if !rates.is_empty() && !rates.contains(&output_rate) {
+        tracing::debug!(
+            "input runs at {rates:?} Hz, capture resamples to the {output_rate} Hz output"
+        );
+    }
+    Ok(AecConfig {
+        sample_rate: output_rate,
+        preferred_input_devices: input_device.map(str::to_string).into_iter().collect(),
+        ..Default::default()
+    })
+}
+
+/// Device buffer in frames that `CaptureHandle::buffer_size` is expected to
+/// report for a stream opened without `latency_target`, for sizing jitter
+/// and downstream buffers before capture starts.
+///
+/// Fixed on iOS (1024 frames) and Linux (480). macOS reads the default
+/// input device's I/O buffer, which sets how much each callback delivers,
+/// though `buffer_size` there reports the unit's upper bound per callback.
+/// Android opens an input stream for its burst size, which takes a moment.
+/// Windows gives the 20 ms it requests at 48 kHz, 960 frames, which the
+/// audio engine may round up.
+/// The value for an open stream is whatever `buffer_size` reports.
+pub fn default_buffer_frames() -> usize {
+    backends::default_buffer_frames()
+}
+
+/// Call `callback` with the new `input_devices()` list whenever a device is
+/// plugged in or removed, until the returned watcher drops. Runs without a
+/// capture stream, from a background thread; take the starting list from
+/// `input_devices()`.
+///
+/// Listens through Core Audio on macOS, `IMMNotificationClient` on Windows
+/// and `pactl subscribe` on Linux; `AecNotSupported` elsewhere.
+pub fn watch_devices(
+    callback: impl FnMut(Vec<String>) + Send + 'static,
+) -> Result<DeviceWatcher, AecError> {
+    backends::watch_devices(callback).map(|watch| DeviceWatcher { _watch: watch })
+}
+
+/// Keeps `watch_devices` reporting until dropped.
+pub struct DeviceWatcher {
+    _watch: backends::DeviceWatch,
+}
+
+/// Request microphone access from the OS, showing the system prompt if the
+/// user hasn't decided yet. Resolves with whether access was granted.
+///
+/// Uses `AVAudioSession.requestRecordPermission` on iOS and
+/// `AVCaptureDevice requestAccessForMediaType:` on macOS. Other platforms
+/// have no native request API and resolve to `true`; on Android the
+/// `RECORD_AUDIO` runtime permission must be requested from the Activity.
+pub async fn request_microphone_permission() -> bool {
+    backends::request_microphone_permission().await
+}
+
+/// Default `AecConfig::thread_label` for handles created in this process
+static NEXT_HANDLE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// `pipeline::distinct_channels` for the opened stream, warning when true
+/// stereo was asked for but the input can only be duplicated.
+fn distinct_channels(channels: Channels, info: &backends::StreamInfo) -> u16 {
+    if channels == Channels::StereoTrue && info.channels < 2 {
+        tracing::warn!(
+            "StereoTrue requested but {} delivers mono; both channels will be identical",
+            info.backend
+        );
+    }
+    pipeline::distinct_channels(channels, info.channels)
 }
 
+/// Opens the backend again for `Reconnect`, swapping in the new handle.
+/// The pipeline is built for the original format, so a device that comes
+/// back in another one counts as a failed attempt.
+fn reopener(
+    config: &AecConfig,
+    runtime: &tokio::runtime::Handle,
+    status_tx: &flume::Sender<CaptureStatus>,
+    backend: &Arc<Mutex<backends::BackendHandle>>,
+    original: backends::StreamInfo,
+) -> pipeline::OpenFn {
+    #[cfg_attr(not(feature = "backend-file"), allow(unused_mut))]
+    let mut config = config.clone();
+    // The simulated device comes back healthy
+    #[cfg(feature = "backend-file")]
+    if let Some(source) = &mut config.input_file {
+        source.fault = None;
+    }
+    let runtime = runtime.clone();
+    let status_tx = status_tx.clone();
+    let backend = backend.clone();
+    Arc::new(move |backend_tx| {
+        let (info, handle) =
+            backends::create_backend(&config, backend_tx, status_tx.clone(), &runtime)?;
+        if (info.sample_rate, info.channels) != (original.sample_rate, original.channels) {
+            return Err(AecError::BackendError(format!(
+                "device returned at {} Hz with {} channels, expected {} Hz with {}",
+                info.sample_rate, info.channels, original.sample_rate, original.channels
+            )));
+        }
+        if let Ok(mut backend) = backend.lock() {
+            handle.set_playback_muted(backend.is_playback_muted());
+            *backend = handle;
+        }
+        Ok(())
+    })
+}
+
+/// Status channel for the backend. Each status is passed on to
+/// `status_tx`, and interruptions are also sent as `CaptureEvent`s.
+fn backend_status(
+    runtime: &tokio::runtime::Handle,
+    status_tx: &flume::Sender<CaptureStatus>,
+    control: &Arc<pipeline::Control>,
+) -> flume::Sender<CaptureStatus> {
+    let (backend_tx, backend_rx) = flume::unbounded::<CaptureStatus>();
+    let status_tx = status_tx.clone();
+    let control = control.clone();
+    runtime.spawn(async move {
+        while let Ok(status) = backend_rx.recv_async().await {
+            match status {
+                CaptureStatus::Interrupted => control.emit(CaptureEvent::Interrupted),
+                CaptureStatus::Resumed => control.emit(CaptureEvent::Resumed),
+                _ => {}
+            }
+            let _ = status_tx.send(status);
+        }
+    });
+    backend_tx
+}
+
+/// Audio `new_async` lets an active canceller converge on, and
+/// `CaptureEvent::WarmedUp` waits for
+const AEC_WARMUP: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// `play_stream` pulls more audio while less than this is unplayed...
+const PLAY_STREAM_LEAD: std::time::Duration = std::time::Duration::from_millis(200);
+/// ...and fewer than this many chunks, so the playback queue never fills
+const PLAY_STREAM_MAX_CHUNKS: usize = 8;
+
+/// How often `join` checks whether the backend has exited
+const JOIN_POLL: std::time::Duration = std::time::Duration::from_millis(10);
+
 /// Handle for receiving AEC-processed audio samples.
 /// Capture stops automatically when dropped (channel disconnect stops backend).
 pub struct CaptureHandle {
-    receiver: flume::Receiver<Result<Vec<f32>, AecError>>,
-    backend: backends::BackendHandle,
-    sample_rate: u32,
+    receiver: flume::Receiver<Delivery>,
+    status_rx: flume::Receiver<CaptureStatus>,
+    control: ControlHandle,
+    /// Deliveries tagged with an earlier pause epoch are dropped on receive
+    discard_before: AtomicU64,
+    /// Set once a chunk has been delivered, so only the first is marked
+    delivered: AtomicBool,
+    pause_discard: bool,
+    playback_rate_mismatch: PlaybackRateMismatch,
+    echo_meter: Arc<Mutex<erle::EchoMeter>>,
+    /// Set when `AecConfig::playback_sync` is
+    playback_sync: Option<Arc<playback_sync::PlaybackSync>>,
+    /// Set when `AecConfig::retain_recent` is
+    recent: Option<Arc<Mutex<recent::RecentAudio>>>,
+    /// Where `recv_into` returns the buffers it has copied out of
+    buffers: Arc<BufferPool>,
+    /// Audio `record_to_capacity` received but had no room for, handed
+    /// out ahead of the queue
+    leftover: Mutex<Option<AudioFrame>>,
+    /// Replaced when `auto_reconnect` reopens the device
+    backend: Arc<Mutex<backends::BackendHandle>>,
+    /// Backend format, frame and factory, for rebuilding the resampler
+    stream_info: backends::StreamInfo,
+    resampler_frame_ms: usize,
+    resampler: Option<ResamplerFactory>,
+    sample_rate: AtomicU32,
+    resample: bool,
+    channels: AtomicU16,
+    distinct_channels: AtomicU16,
+    /// Reported as `CaptureStats::warmup_skipped_samples`
+    warmup_skipped: AtomicU64,
+    downmix: DownmixMode,
+    buffer_size: usize,
+    input_latency: std::time::Duration,
+    stats: CaptureStats,
+    /// Set when `AecConfig::utterance` is
+    endpointer: Option<Mutex<utterance::Endpointer>>,
 }
 
 impl CaptureHandle {
     /// Create and start a new AEC capture stream.
     /// Audio samples are received via the async recv() or blocking recv_blocking() methods.
+    ///
+    /// Must be called from within a tokio runtime; use `new_on` otherwise.
+    ///
+    /// Handles whose configs open the device the same way share one stream:
+    /// each gets its own copy of every chunk, shaped by its own rate, gain and
+    /// chunking settings, and the device closes when the last one drops.
+    /// Playback, reference device and hardware gain act on the shared stream.
+    /// File input and `render_source` always open a stream of their own.
     pub fn new(config: AecConfig) -> Result<Self, AecError> {
+        let runtime = tokio::runtime::Handle::try_current().map_err(|_| {
+            AecError::BackendError(
+                "CaptureHandle::new called outside a tokio runtime; use new_on".to_string(),
+            )
+        })?;
+        Self::new_on(config, runtime)
+    }
+
+    /// `new` for async callers: resolves once the stream is delivering, i.e.
+    /// the first chunk has arrived and, with AEC active, the canceller has
+    /// had half a second of audio to converge. That audio is dropped, so the
+    /// first `recv` returns settled input; `CaptureStats::warmup_skipped_samples`
+    /// says how much. Opening the device runs on the blocking pool.
+    ///
+    /// Fails like `new`, or with `BackendError` if capture stops first.
+    pub async fn new_async(config: AecConfig) -> Result<Self, AecError> {
+        let handle = Self::open_async(config, "new_async").await?;
+        handle.warm_up().await?;
+        Ok(handle)
+    }
+
+    /// Record `duration` of audio and stop, returning it interleaved at the
+    /// output rate and channel count, e.g. for a short voice command.
+    ///
+    /// Recording starts as soon as the device is open, without the warm-up
+    /// `new_async` waits for. Audio past `duration` is dropped. If capture
+    /// ends first, as a short input file does, everything it delivered is
+    /// returned, including the resampler tail.
+    ///
+    /// Fails like `new`, or with the first error capture reports.
+    pub async fn capture_for(
+        config: AecConfig,
+        duration: std::time::Duration,
+    ) -> Result<Vec<f32>, AecError> {
+        let handle = Self::open_async(config, "capture_for").await?;
+        let frames = (duration.as_secs_f64() * handle.output_sample_rate() as f64).round();
+        let wanted = frames as usize * handle.channels() as usize;
+
+        let mut samples = Vec::with_capacity(wanted);
+        while samples.len() < wanted {
+            match handle.recv().await {
+                Some(frame) => samples.extend_from_slice(&frame?),
+                None => break,
+            }
+        }
+        samples.truncate(wanted);
+        Ok(samples)
+    }
+
+    /// `new_on` run on the blocking pool of the current runtime.
+    async fn open_async(config: AecConfig, caller: &str) -> Result<Self, AecError> {
+        let runtime = tokio::runtime::Handle::try_current().map_err(|_| {
+            AecError::BackendError(format!(
+                "CaptureHandle::{caller} called outside a tokio runtime"
+            ))
+        })?;
+        let opener = runtime.clone();
+        runtime
+            .spawn_blocking(move || Self::new_on(config, opener))
+            .await
+            .map_err(|e| AecError::BackendError(format!("capture startup failed: {e}")))?
+    }
+
+    /// Drop delivered audio until the stream counts as ready for `new_async`.
+    async fn warm_up(&self) -> Result<(), AecError> {
+        let needed = if self.stats.aec_active {
+            AEC_WARMUP
+        } else {
+            std::time::Duration::ZERO
+        };
+        let mut received = std::time::Duration::ZERO;
+        loop {
+            let frame = match self.recv().await {
+                Some(result) => result?,
+                None => {
+                    return Err(AecError::BackendError(
+                        "capture stopped before delivering audio".to_string(),
+                    ))
+                }
+            };
+            if frame.is_empty() {
+                continue;
+            }
+            self.warmup_skipped
+                .fetch_add(frame.frames() as u64, Ordering::AcqRel);
+            received += frame.duration();
+            if received >= needed {
+                // The caller's first chunk is the one after the warm-up
+                self.delivered.store(false, Ordering::Release);
+                return Ok(());
+            }
+        }
+    }
+
+    /// Create and start a capture stream whose background tasks run on the
+    /// given runtime, so it can be called from any thread.
+    pub fn new_on(
+        mut config: AecConfig,
+        runtime: tokio::runtime::Handle,
+    ) -> Result<Self, AecError> {
         if config.sample_rate == 0 {
             return Err(AecError::InvalidConfig(
                 "sample_rate must be non-zero".to_string(),
             ));
         }
 
-        let (backend_tx, backend_rx) = flume::bounded::<Vec<f32>>(32);
-        let (native_rate, _buffer_size, backend_handle) = backends::create_backend(backend_tx)?;
+        config
+            .thread_label
+            .get_or_insert_with(|| NEXT_HANDLE_ID.fetch_add(1, Ordering::Relaxed).to_string());
 
-        let (public_tx, public_rx) = flume::bounded::<Result<Vec<f32>, AecError>>(32);
-        let target_rate = config.sample_rate;
-        let target_channels = config.channels;
+        // A file running out isn't a lost device, and reading one has no
+        // realtime deadline to drop audio for
+        #[cfg(feature = "backend-file")]
+        if let Some(source) = &config.input_file {
+            if !matches!(source.fault, Some(FileFault::Disconnect(_))) {
+                config.auto_reconnect = None;
+            }
+            config.overflow_policy = OverflowPolicy::Block;
+        }
 
-        let needs_stereo = target_channels == Channels::Stereo;
-        let needs_resampling = native_rate != target_rate;
+        let plan = latency::LatencyPlan::new(config.latency_target);
+        let (backend_tx, backend_rx) = flume::bounded::<Chunk>(plan.queue_depth);
+        let (status_tx, status_rx) = flume::unbounded::<CaptureStatus>();
+        let (control, shared_control) = ControlHandle::new(&backend_tx);
+        let backend_status_tx = backend_status(&runtime, &status_tx, &shared_control);
+        let backend_tx = ChunkSender::new(backend_tx, &backend_rx, config.overflow_policy);
+        let (stream_info, backend_handle) =
+            backends::create_backend(&config, backend_tx, backend_status_tx.clone(), &runtime)?;
 
-        let resampler = if needs_resampling {
-            Some(
-                Resampler::new(native_rate, target_rate)
-                    .map_err(|e| AecError::BackendError(format!("resampler init: {e:?}")))?,
-            )
-        } else {
-            None
+        let (public_tx, public_rx) = flume::bounded::<Delivery>(plan.queue_depth);
+        // Audio is only dropped where it enters, so the pipeline waits for
+        // the caller unless the freshest audio is wanted
+        let public_policy = match config.overflow_policy {
+            OverflowPolicy::DropOldest => OverflowPolicy::DropOldest,
+            _ => OverflowPolicy::Block,
         };
-
-        tokio::spawn(async move {
-            let mut resampler = resampler;
-
-            while let Ok(samples) = backend_rx.recv_async().await {
-                let processed = match process_audio_chunk(samples, &mut resampler, needs_stereo) {
-                    Ok(p) => p,
-                    Err(e) => {
-                        let _ = public_tx.send_async(Err(AecError::BackendError(e))).await;
-                        break;
-                    }
-                };
-                if public_tx.send_async(Ok(processed)).await.is_err() {
-                    break;
-                }
-            }
+        let public_tx = QueueSender::new(public_tx, &public_rx, public_policy);
+        let mut pipeline = Pipeline::new(&stream_info, &config)?;
+        pipeline.dry_run(stream_info.buffer_size)?;
+        let channels = pipeline.output_channels();
+        let output_rate = pipeline.output_rate();
+        let input_latency = std::time::Duration::from_secs_f64(
+            stream_info.buffer_size as f64 / stream_info.sample_rate as f64,
+        ) + pipeline.latency();
+        let echo_meter = pipeline.echo_meter();
+        let recent = pipeline.recent_audio();
+        let buffers = pipeline.buffer_pool();
+        let backend = Arc::new(Mutex::new(backend_handle));
+        let playback_sync = config.playback_sync.then(|| {
+            let sync = Arc::new(playback_sync::PlaybackSync::new(
+                backend.clone(),
+                echo_meter.clone(),
+            ));
+            pipeline.sync_playback(sync.clone());
+            sync
+        });
+        let reconnect = config.auto_reconnect.map(|backoff| Reconnect {
+            config: backoff,
+            queue_depth: plan.queue_depth,
+            policy: config.overflow_policy,
+            open: reopener(&config, &runtime, &backend_status_tx, &backend, stream_info),
+            #[cfg(feature = "backend-file")]
+            once: config.input_file.is_some(),
+            #[cfg(not(feature = "backend-file"))]
+            once: false,
         });
 
+        // Before the loop starts, so nothing it sends comes first
+        shared_control.emit(CaptureEvent::Started);
+        if config.dedicated_delivery_thread {
+            std::thread::Builder::new()
+                .name(backends::thread_name("sys-voice-delivery", &config))
+                .spawn(move || {
+                    pipeline::run_blocking(
+                        pipeline,
+                        backend_rx,
+                        public_tx,
+                        status_tx,
+                        shared_control,
+                        reconnect,
+                    )
+                })
+                .map_err(|e| {
+                    AecError::BackendError(format!("failed to spawn delivery thread: {e:?}"))
+                })?;
+        } else {
+            runtime.spawn(pipeline::run_async(
+                pipeline,
+                backend_rx,
+                public_tx,
+                status_tx,
+                shared_control,
+                reconnect,
+            ));
+        }
+
         Ok(Self {
             receiver: public_rx,
-            backend: backend_handle,
-            sample_rate: target_rate,
+            status_rx,
+            control,
+            discard_before: AtomicU64::new(0),
+            delivered: AtomicBool::new(false),
+            pause_discard: config.pause_discard,
+            playback_rate_mismatch: config.playback_rate_mismatch,
+            echo_meter,
+            playback_sync,
+            recent,
+            buffers,
+            leftover: Mutex::new(None),
+            backend,
+            stream_info,
+            resampler_frame_ms: latency::resampler_frame_ms(
+                config.latency_target,
+                config.resampler_frame,
+            )?,
+            resampler: config.resampler.clone(),
+            resample: config.resample,
+            sample_rate: AtomicU32::new(output_rate),
+            channels: AtomicU16::new(channels),
+            distinct_channels: AtomicU16::new(distinct_channels(config.channels, &stream_info)),
+            warmup_skipped: AtomicU64::new(0),
+            downmix: config.downmix,
+            buffer_size: stream_info.buffer_size,
+            input_latency,
+            stats: CaptureStats {
+                backend: stream_info.backend,
+                sharing_mode: stream_info.sharing_mode,
+                aec_active: stream_info.aec,
+                dsp_load: 0.0,
+                distinct_channels: 0,
+                warmup_skipped_samples: 0,
+                buffers_allocated: 0,
+            },
+            endpointer: config
+                .utterance
+                .map(|utterance| Mutex::new(utterance::Endpointer::new(utterance))),
         })
     }
 
     /// Receive audio samples asynchronously.
     /// Returns None when the capture stream is closed.
-    pub async fn recv(&self) -> Option<Result<Vec<f32>, AecError>> {
-        self.receiver.recv_async().await.ok()
+    pub async fn recv(&self) -> Option<Result<AudioFrame, AecError>> {
+        if let Some(frame) = self.take_leftover() {
+            return Some(Ok(frame));
+        }
+        loop {
+            let delivery = self.receiver.recv_async().await.ok()?;
+            if let Some(result) = self.accept(delivery) {
+                return Some(result);
+            }
+        }
     }
 
     /// Receive audio samples, blocking the current thread.
     /// Returns None when the capture stream is closed.
-    pub fn recv_blocking(&self) -> Option<Result<Vec<f32>, AecError>> {
-        self.receiver.recv().ok()
+    pub fn recv_blocking(&self) -> Option<Result<AudioFrame, AecError>> {
+        if let Some(frame) = self.take_leftover() {
+            return Some(Ok(frame));
+        }
+        loop {
+            let delivery = self.receiver.recv().ok()?;
+            if let Some(result) = self.accept(delivery) {
+                return Some(result);
+            }
+        }
     }
 
     /// Try to receive audio samples without blocking.
     /// Returns None if no samples are available or stream is closed.
-    pub fn try_recv(&self) -> Option<Result<Vec<f32>, AecError>> {
-        self.receiver.try_recv().ok()
+    pub fn try_recv(&self) -> Option<Result<AudioFrame, AecError>> {
+        if let Some(frame) = self.take_leftover() {
+            return Some(Ok(frame));
+        }
+        loop {
+            let delivery = self.receiver.try_recv().ok()?;
+            if let Some(result) = self.accept(delivery) {
+                return Some(result);
+            }
+        }
+    }
+
+    /// `recv` into a caller-owned buffer, so one scratch buffer can serve
+    /// the whole capture loop. `buf` is cleared and extended with the next
+    /// chunk, keeping its capacity; the result is the number of samples
+    /// written. On an error or a closed stream `buf` is left untouched.
+    ///
+    /// The chunk's own buffer goes back to the pipeline, which cuts
+    /// `AecConfig::output_chunk_frames` chunks into it again, so with both
+    /// in use delivery stops allocating once it reaches a steady state
+    /// (`CaptureStats::buffers_allocated`).
+    pub async fn recv_into(&self, buf: &mut Vec<f32>) -> Option<Result<usize, AecError>> {
+        let frame = match self.recv().await? {
+            Ok(frame) => frame,
+            Err(e) => return Some(Err(e)),
+        };
+        buf.clear();
+        buf.extend_from_slice(&frame);
+        let len = frame.len();
+        self.buffers.give(frame.into());
+        Some(Ok(len))
+    }
+
+    /// Receive into a buffer allocated once for `max_samples` interleaved
+    /// samples and return it when full, for recorders that can't afford to
+    /// grow memory as they go. The chunk that fills it is cut at a whole
+    /// frame and the rest of it kept, so the next call or `recv` carries on
+    /// without a gap. Combine with `OverflowPolicy` to bound the queues as
+    /// well.
+    ///
+    /// Returns early with what it has once capture stops, or at a change of
+    /// rate or channels, whose first chunk the next call starts with, so
+    /// one recording never mixes formats. If it fails instead, the error is
+    /// returned and the samples recorded before it are kept for the next
+    /// call or `recv`. `InvalidConfig` if `max_samples` can't hold one
+    /// whole frame of the next chunk, which is kept as well.
+    pub async fn record_to_capacity(&self, max_samples: usize) -> Result<Vec<f32>, AecError> {
+        let mut samples = Vec::with_capacity(max_samples);
+        let mut format = None;
+        while samples.len() < max_samples {
+            let mut frame = match self.recv().await {
+                Some(Ok(frame)) => frame,
+                Some(Err(e)) => {
+                    if let Some((rate, channels)) = format.filter(|_| !samples.is_empty()) {
+                        self.keep_leftover(AudioFrame::new(samples, rate, channels));
+                    }
+                    return Err(e);
+                }
+                None => break,
+            };
+            let frame_format = (frame.sample_rate(), frame.channels());
+            if *format.get_or_insert(frame_format) != frame_format {
+                self.keep_leftover(frame);
+                break;
+            }
+            let channels = frame.channels().max(1) as usize;
+            if max_samples < channels {
+                self.keep_leftover(frame);
+                return Err(AecError::InvalidConfig(format!(
+                    "record_to_capacity of {max_samples} samples can't hold a {channels}-channel frame"
+                )));
+            }
+            let room = max_samples - samples.len();
+            let take = frame.len().min(room - room % channels);
+            samples.extend_from_slice(&frame[..take]);
+            if take < frame.len() {
+                frame.samples.drain(..take);
+                frame.first = false;
+                self.keep_leftover(frame);
+                break;
+            }
+        }
+        Ok(samples)
+    }
+
+    fn take_leftover(&self) -> Option<AudioFrame> {
+        self.leftover.lock().ok()?.take()
+    }
+
+    /// Hand `frame` out before anything still queued. Only one is ever
+    /// held: it is set by `record_to_capacity`, which takes it first.
+    fn keep_leftover(&self, frame: AudioFrame) {
+        if let Ok(mut leftover) = self.leftover.lock() {
+            *leftover = Some(frame);
+        }
+    }
+
+    /// Receive the next utterance: audio from `UtteranceConfig::pre_roll`
+    /// before speech starts until `trailing_silence` passes without speech,
+    /// in the delivered format. The last utterance is cut short when the
+    /// stream closes; None follows.
+    ///
+    /// Reads chunks through `recv`, so don't mix the two on one handle.
+    /// Returns `InvalidConfig` without `AecConfig::utterance`.
+    pub async fn recv_utterance(&self) -> Option<Result<AudioFrame, AecError>> {
+        loop {
+            let chunk = self.recv().await;
+            if let Some(result) = self.endpoint(chunk) {
+                return result;
+            }
+        }
+    }
+
+    /// `recv_utterance`, blocking the current thread.
+    pub fn recv_utterance_blocking(&self) -> Option<Result<AudioFrame, AecError>> {
+        loop {
+            let chunk = self.recv_blocking();
+            if let Some(result) = self.endpoint(chunk) {
+                return result;
+            }
+        }
+    }
+
+    /// Feed one `recv` result to the endpointer. None means keep reading.
+    fn endpoint(
+        &self,
+        chunk: Option<Result<AudioFrame, AecError>>,
+    ) -> Option<Option<Result<AudioFrame, AecError>>> {
+        let Some(endpointer) = &self.endpointer else {
+            return Some(Some(Err(AecError::InvalidConfig(
+                "recv_utterance needs AecConfig::utterance".to_string(),
+            ))));
+        };
+        let Ok(mut endpointer) = endpointer.lock() else {
+            return Some(None);
+        };
+        match chunk {
+            Some(Ok(frame)) => endpointer.push(frame).map(|utterance| Some(Ok(utterance))),
+            Some(Err(e)) => Some(Some(Err(e))),
+            None => Some(endpointer.finish().map(Ok)),
+        }
+    }
+
+    /// Current backend, which a reconnect may have replaced.
+    fn backend(&self) -> backends::BackendHandle {
+        match self.backend.lock() {
+            Ok(backend) => backend.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    /// Drop chunks captured before a discarding pause, and mark the first
+    /// chunk delivered.
+    fn accept(&self, (epoch, result): Delivery) -> Option<Result<AudioFrame, AecError>> {
+        if epoch < self.discard_before.load(Ordering::Acquire) {
+            return None;
+        }
+        Some(result.map(|mut frame| {
+            frame.first = !frame.is_empty() && !self.delivered.swap(true, Ordering::AcqRel);
+            frame
+        }))
+    }
+
+    /// Stop delivering audio until `resume()`. The device keeps running so
+    /// echo cancellation stays converged; input captured meanwhile is dropped.
+    ///
+    /// Audio captured before the pause, including the partial chunk held in
+    /// the resampler and chunker, is delivered ahead of anything after the
+    /// resume. With `AecConfig::pause_discard` it is discarded instead, so
+    /// the first chunk after `resume()` is fresh audio.
+    pub fn pause(&self) {
+        let epoch = self.control.pause();
+        if self.pause_discard {
+            self.discard_before.store(epoch, Ordering::Release);
+        }
+    }
+
+    /// Resume delivery after `pause()`, starting from a fresh resampler state.
+    pub fn resume(&self) {
+        self.control.resume();
+    }
+
+    /// Whether delivery is currently paused.
+    pub fn is_paused(&self) -> bool {
+        self.control.is_paused()
+    }
+
+    /// Stop capture and return every sample still buffered: queued chunks,
+    /// the resampler tail and any partial `output_chunk_frames` chunk.
+    /// Errors queued behind the stop are discarded.
+    ///
+    /// Blocks until delivery has drained. On a current-thread tokio runtime,
+    /// call this from `spawn_blocking` so the delivery task can make progress.
+    pub fn finish(self) -> Vec<f32> {
+        self.control.stop();
+
+        let mut remaining = Vec::new();
+        while let Some(result) = self.recv_blocking() {
+            if let Ok(samples) = result {
+                remaining.extend_from_slice(&samples);
+            }
+        }
+        remaining
+    }
+
+    /// Stop capture and resolve once the backend has exited and closed the
+    /// input device, for shutdown paths that mustn't return before native
+    /// resources are freed. Audio still buffered is discarded; use `finish`
+    /// to keep it. On a stream shared with other handles, the device closes
+    /// when the last of them lets go, so this waits for that too.
+    ///
+    /// Returns why the stream closed: `Stopped` if it was still running,
+    /// otherwise what ended it first.
+    pub async fn join(self) -> CloseReason {
+        self.control.stop();
+        while self.receiver.recv_async().await.is_ok() {}
+        let reason = self.control.close_reason().unwrap_or(CloseReason::Stopped);
+
+        let exit = self.backend().exit_watch();
+        drop(self);
+        while !exit.has_exited() {
+            tokio::time::sleep(JOIN_POLL).await;
+        }
+        reason
+    }
+
+    /// `join`, blocking the current thread. On a current-thread tokio
+    /// runtime, call this from `spawn_blocking` so the delivery task can
+    /// make progress.
+    pub fn join_blocking(self) -> CloseReason {
+        self.control.stop();
+        while self.receiver.recv().is_ok() {}
+        let reason = self.control.close_reason().unwrap_or(CloseReason::Stopped);
+
+        let exit = self.backend().exit_watch();
+        drop(self);
+        while !exit.has_exited() {
+            std::thread::sleep(JOIN_POLL);
+        }
+        reason
+    }
+
+    /// Copy of the last `AecConfig::retain_recent` of audio, interleaved at
+    /// the current output rate and channel count, oldest first. Includes
+    /// audio not yet received and is unaffected by `recv`; audio from
+    /// before a rate or channel change is dropped. Empty without
+    /// `retain_recent`.
+    pub fn snapshot_recent(&self) -> Vec<f32> {
+        match &self.recent {
+            Some(recent) => recent.lock().map(|r| r.snapshot()).unwrap_or_default(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Subscribe to stream status changes such as device loss or interruption.
+    /// Receivers share one queue: each event goes to exactly one of them.
+    pub fn status(&self) -> StatusReceiver {
+        StatusReceiver {
+            receiver: self.status_rx.clone(),
+        }
+    }
+
+    /// Subscribe to lifecycle events, from `Started` to `Stopped`, in one
+    /// stream across platforms. Receivers share one queue: each event goes
+    /// to exactly one of them.
+    pub fn events(&self) -> EventReceiver {
+        EventReceiver {
+            receiver: self.control.events(),
+        }
     }
 
     /// Get the actual sample rate being used by the backend.
     /// May differ from requested rate if resampling is active.
     pub fn native_sample_rate(&self) -> u32 {
-        self.sample_rate
+        self.sample_rate.load(Ordering::Acquire)
+    }
+
+    /// Rate of delivered chunks: `AecConfig::sample_rate`, or the device's
+    /// own rate when `AecConfig::resample` is off.
+    pub fn output_sample_rate(&self) -> u32 {
+        self.sample_rate.load(Ordering::Acquire)
+    }
+
+    /// Change the delivered sample rate while the device keeps running.
+    ///
+    /// Audio already buffered at the old rate is flushed before the new
+    /// resampler takes over, so nothing is dropped or duplicated at the
+    /// boundary. Chunks already queued keep the old rate, and with
+    /// `output_chunk_frames` the last old-rate chunk may be partial.
+    pub fn set_output_sample_rate(&self, rate: u32) -> Result<(), AecError> {
+        if rate == 0 {
+            return Err(AecError::InvalidConfig(
+                "sample_rate must be non-zero".to_string(),
+            ));
+        }
+        if !self.resample {
+            return Err(AecError::InvalidConfig(
+                "resampling is disabled in AecConfig".to_string(),
+            ));
+        }
+
+        let resampler = Pipeline::resampler_for(
+            &self.stream_info,
+            rate,
+            self.resampler_frame_ms,
+            self.resampler.as_ref(),
+        )?;
+        self.control.retarget(Retarget { rate, resampler });
+        self.sample_rate.store(rate, Ordering::Release);
+        Ok(())
+    }
+
+    /// Number of interleaved channels in each delivered chunk.
+    pub fn channels(&self) -> u16 {
+        self.channels.load(Ordering::Acquire)
+    }
+
+    /// Change the delivered channel layout while the device keeps running.
+    ///
+    /// The device stays open with the channels it was opened for. A handle
+    /// opened as `Native` or `StereoTrue` can switch freely, as mono layouts
+    /// are then downmixed per `AecConfig::downmix`. One opened as `Mono` or
+    /// `Stereo` captures mono, so switching it to `StereoTrue` duplicates
+    /// that instead; true stereo needs a new handle. Chunks already queued
+    /// keep the old layout, and with `output_chunk_frames` the last one may
+    /// be partial.
+    pub fn set_channels(&self, channels: Channels) -> Result<(), AecError> {
+        let input_channels = self.stream_info.channels;
+        if !backends::keeps_channels(channels) && input_channels > 1 {
+            backends::check_downmix(self.downmix, input_channels as usize)?;
+        }
+
+        self.control.set_channels(channels);
+        self.channels.store(
+            pipeline::output_channels(channels, input_channels),
+            Ordering::Release,
+        );
+        self.distinct_channels.store(
+            distinct_channels(channels, &self.stream_info),
+            Ordering::Release,
+        );
+        Ok(())
+    }
+
+    /// Device buffer size in frames, as reported by the backend.
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Frames the device has produced since capture started, each counted
+    /// at the output rate in effect when it was captured, so
+    /// `set_output_sample_rate` doesn't rescale what came before. Unlike a
+    /// count of delivered audio it keeps running while paused and includes
+    /// input that was lost: chunks the overflow policy dropped, and gaps
+    /// the device reported on backends that report them (macOS, iOS,
+    /// Windows). Updated as chunks are processed.
+    pub fn stream_position(&self) -> u64 {
+        self.control.position()
+    }
+
+    /// Estimated delay from the microphone to a delivered chunk: one device
+    /// buffer plus resampling. Compare against `AecConfig::latency_target`
+    /// to see how much of the budget the backend could honor.
+    pub fn input_latency(&self) -> std::time::Duration {
+        self.input_latency
+    }
+
+    /// Device picked from `AecConfig::preferred_input_devices`, or None when
+    /// capture comes from the default device. Follows reconnects, which
+    /// pick again.
+    pub fn input_device(&self) -> Option<String> {
+        self.backend().input_device().map(str::to_string)
+    }
+
+    /// Identifier of the platform backend in use ("wasapi", "coreaudio-vpio",
+    /// "audiotoolbox-vpio", "pulse-simple", "pipewire-pulse", "oboe", or
+    /// "cpal" after a fallback), for bug reports and logs.
+    pub fn backend_name(&self) -> &'static str {
+        self.stats.backend
+    }
+
+    /// Snapshot of stream diagnostics.
+    pub fn stats(&self) -> CaptureStats {
+        CaptureStats {
+            dsp_load: self.control.load(),
+            distinct_channels: self.distinct_channels.load(Ordering::Acquire),
+            warmup_skipped_samples: self.warmup_skipped.load(Ordering::Acquire),
+            buffers_allocated: self.buffers.allocated(),
+            ..self.stats.clone()
+        }
+    }
+
+    /// Point echo cancellation at a different render endpoint while capture
+    /// keeps running, e.g. after the user switches playback devices.
+    /// `None` lets the OS follow the default render device.
+    ///
+    /// Only supported on Windows; other platforms return `AecNotSupported`.
+    pub fn set_reference_device(&self, device_id: Option<&str>) -> Result<(), AecError> {
+        self.backend()
+            .set_reference_device(device_id.map(str::to_string))
+    }
+
+    /// Input volume of the capture device as a 0.0-1.0 scalar, as set in
+    /// the OS sound settings. Linux reports 1.0 at 100% and above it when
+    /// the source is boosted.
+    ///
+    /// Supported on macOS, iOS, Windows and Linux (through `pactl`);
+    /// Android, file input, and devices without a volume control return
+    /// `AecNotSupported`.
+    pub fn hardware_gain(&self) -> Result<f32, AecError> {
+        self.backend().hardware_gain()
+    }
+
+    /// Set the capture device's input volume, 0.0 to 1.0. Raising it before
+    /// the ADC avoids the noise amplification of `auto_gain`. The setting is
+    /// system-wide and outlives the handle.
+    pub fn set_hardware_gain(&self, gain: f32) -> Result<(), AecError> {
+        if !(0.0..=1.0).contains(&gain) {
+            return Err(AecError::InvalidConfig(format!(
+                "hardware gain {gain} outside 0.0..=1.0"
+            )));
+        }
+        self.backend().set_hardware_gain(gain)
+    }
+
+    /// Peak level of the capture device from the OS's own meter, 0.0 to
+    /// 1.0, where 1.0 means the converter clipped. Measured before the OS
+    /// capture effects and software gain, so it tracks what a system
+    /// recording meter shows.
+    ///
+    /// Only Windows exposes an input meter; elsewhere, and for file input,
+    /// this is None and `dsp::rms` on delivered chunks is the fallback.
+    pub fn hardware_input_level(&self) -> Option<f32> {
+        self.backend().hardware_input_level()
+    }
+
+    /// Whether a chunk in the last half second of input had an RMS above
+    /// `AecConfig::signal_floor`, for a "microphone seems muted" hint. Any
+    /// energy counts, not just speech. False before the first chunk and
+    /// once a hardware-muted or dead microphone delivers only silence.
+    pub fn is_receiving_signal(&self) -> bool {
+        self.control.receiving_signal()
+    }
+
+    /// Delay between playback leaving the app and its echo reaching the
+    /// capture side, as far as the OS echo canceller aligns its reference:
+    /// device latencies, safety offsets and IO buffers, not the acoustic
+    /// path. Useful for lining up a residual echo suppressor run on
+    /// delivered chunks. Read fresh on each call, so it follows route
+    /// changes.
+    ///
+    /// Reported by VoiceProcessingIO on macOS and iOS. None where the
+    /// canceller is opaque (Windows, Linux, Android) and for streams
+    /// without echo cancellation.
+    pub fn aec_reference_delay(&self) -> Option<std::time::Duration> {
+        if !self.stream_info.aec {
+            return None;
+        }
+        self.backend().aec_reference_delay()
+    }
+
+    /// Switch the echo canceller's reference off and back on while capture
+    /// keeps running, to A/B echo cancellation during a live call, e.g. to
+    /// tell whether the reference is behind residual echo. Off bypasses
+    /// voice processing as a whole, so noise suppression and gain control
+    /// go with it. Acts on the shared device stream; a reconnect turns it
+    /// back on.
+    ///
+    /// Supported on macOS and iOS. The Windows AEC control can point the
+    /// reference elsewhere (`set_reference_device`) but not detach it, so
+    /// Windows, the other platforms and streams without echo cancellation
+    /// return `AecNotSupported`.
+    pub fn set_aec_reference_enabled(&self, enabled: bool) -> Result<(), AecError> {
+        if !self.stream_info.aec {
+            return Err(AecError::AecNotSupported);
+        }
+        self.backend().set_aec_reference_enabled(enabled)
+    }
+
+    /// Whether the echo canceller is using its reference, as last set by
+    /// `set_aec_reference_enabled`. False for streams without echo
+    /// cancellation.
+    pub fn is_aec_reference_enabled(&self) -> bool {
+        self.stream_info.aec && self.backend().is_aec_reference_enabled()
+    }
+
+    /// Run `f` with the platform object behind the stream, to set a property
+    /// this crate doesn't wrap. The object stays alive until `f` returns.
+    ///
+    /// Only macOS and iOS expose one, their Core Audio unit. Elsewhere, and
+    /// for file input or a stream that has ended, this returns
+    /// `AecNotSupported`. After a reconnect the unit is a new one, so
+    /// settings have to be applied again.
+    ///
+    /// # Safety
+    ///
+    /// The unit keeps running for the backend while `f` has it, and is
+    /// shared by every handle on the same device stream. `f` must not
+    /// release it, stop or uninitialize it, replace its callbacks, change
+    /// its stream formats, or keep the pointer after returning; the
+    /// backend's callbacks rely on all of these. `f` runs under a lock the
+    /// backend takes to shut down, so it should return promptly.
+    pub unsafe fn with_native_unit<R>(
+        &self,
+        f: impl FnOnce(NativeUnit) -> R,
+    ) -> Result<R, AecError> {
+        self.backend().with_native_unit(f)
     }
 
     /// Play audio through the same engine used for capture.
     /// This enables AEC to cancel the played audio from the recording.
-    /// Audio is played at the specified sample rate.
+    /// Audio is played at the specified sample rate, converted to the
+    /// output's unless `AecConfig::playback_rate_mismatch` rejects it.
     pub fn play_audio(&self, samples: Vec<f32>, sample_rate: u32) -> Result<(), AecError> {
-        self.backend.play_audio(samples, sample_rate)
+        self.queue_playback(samples, sample_rate, 1, None, true)
+            .map(drop)
+    }
+
+    /// `play_audio` for interleaved audio of `channels` per frame, e.g.
+    /// stereo. Windows plays it on the output's matching channels, and AEC
+    /// cancels all of them; other platforms play mono and downmix it first.
+    pub fn play_audio_interleaved(
+        &self,
+        samples: Vec<f32>,
+        sample_rate: u32,
+        channels: u16,
+    ) -> Result<(), AecError> {
+        if channels == 0 || !samples.len().is_multiple_of(channels as usize) {
+            return Err(AecError::InvalidConfig(format!(
+                "{} samples are not whole frames of {channels} channels",
+                samples.len()
+            )));
+        }
+        self.queue_playback(samples, sample_rate, channels, None, true)
+            .map(drop)
+    }
+
+    /// Rate the output plays at, which `play_audio` input matches to skip
+    /// conversion. None for file input, `Processing::Raw` on macOS, and
+    /// other streams without an output.
+    pub fn playback_sample_rate(&self) -> Option<u32> {
+        self.backend().playback_rate()
+    }
+
+    /// Hand `samples` to the current backend, subject to
+    /// `playback_rate_mismatch`. A full playback queue blocks with `block`,
+    /// and otherwise hands back a future that queues the audio.
+    fn queue_playback(
+        &self,
+        samples: Vec<f32>,
+        sample_rate: u32,
+        channels: u16,
+        done: Option<backends::PlaybackDone>,
+        block: bool,
+    ) -> Result<Option<backends::PlaybackSend>, AecError> {
+        let backend = self.backend();
+        if self.playback_rate_mismatch == PlaybackRateMismatch::Reject {
+            if let Some(rate) = backend.playback_rate().filter(|&rate| rate != sample_rate) {
+                return Err(AecError::InvalidConfig(format!(
+                    "playback audio at {sample_rate} Hz, output plays at {rate} Hz"
+                )));
+            }
+        }
+        match &self.playback_sync {
+            Some(sync) => sync
+                .hold(samples, sample_rate, channels, done)
+                .map(|()| None),
+            None => playback_sync::play(
+                &backend,
+                &self.echo_meter,
+                samples,
+                sample_rate,
+                channels,
+                done,
+                block,
+            ),
+        }
+    }
+
+    /// `play_audio` without blocking on a full playback queue, returning a
+    /// ticket for both halves of flow control: `queued` resolves once the
+    /// audio is accepted, and `finished`, or awaiting the ticket, once the
+    /// output has consumed the last of it, e.g. to re-arm a wake word after
+    /// the final TTS sentence.
+    ///
+    /// On Android, macOS and iOS that is when the output callback pulls it;
+    /// Windows and Linux go by the audio the device or sound server still
+    /// has buffered, checked every 10 ms. Fails where `play_audio` does.
+    pub fn play_audio_tracked(
+        &self,
+        samples: Vec<f32>,
+        sample_rate: u32,
+    ) -> Result<PlaybackTicket, AecError> {
+        let (done_tx, done_rx) = flume::bounded(1);
+        let send = self.queue_playback(samples, sample_rate, 1, Some(done_tx), false)?;
+        Ok(PlaybackTicket {
+            send,
+            done: done_rx,
+        })
+    }
+
+    /// Play every chunk of `stream`, at `sample_rate`, resolving once the
+    /// output has consumed the last one (see `play_audio_tracked` for when
+    /// that is per platform). The stream is only polled while less than
+    /// 200 ms is waiting to be played, so a synthesizer feeding it is paced
+    /// by the speaker rather than filling the playback queue.
+    ///
+    /// Fails where `play_audio` does, or if capture stops first.
+    pub async fn play_stream<S>(&self, mut stream: S, sample_rate: u32) -> Result<(), AecError>
+    where
+        S: futures_core::Stream<Item = Vec<f32>> + Unpin,
+    {
+        let mut pending = std::collections::VecDeque::new();
+        let mut queued = std::time::Duration::ZERO;
+
+        loop {
+            while queued >= PLAY_STREAM_LEAD || pending.len() >= PLAY_STREAM_MAX_CHUNKS {
+                let Some((length, played)) = pending.pop_front() else {
+                    break;
+                };
+                played.await?;
+                queued = queued.saturating_sub(length);
+            }
+
+            let next =
+                std::future::poll_fn(|cx| std::pin::Pin::new(&mut stream).poll_next(cx)).await;
+            let Some(samples) = next else {
+                break;
+            };
+            if samples.is_empty() {
+                continue;
+            }
+
+            let length = std::time::Duration::from_secs_f64(
+                samples.len() as f64 / sample_rate.max(1) as f64,
+            );
+            let mut ticket = self.play_audio_tracked(samples, sample_rate)?;
+            ticket.queued().await?;
+            pending.push_back((length, ticket));
+            queued += length;
+        }
+
+        for (_, played) in pending {
+            played.await?;
+        }
+        Ok(())
+    }
+
+    /// Audio handed to `play_audio` that the output has not consumed yet.
+    /// None on backends that don't track it (currently all but Android).
+    pub fn playback_queued(&self) -> Option<std::time::Duration> {
+        self.backend().playback_queued()
+    }
+
+    /// Silence the output without dropping what `play_audio` has queued,
+    /// e.g. to hold TTS while the user starts speaking. Unmuting picks up
+    /// where playback stopped. Both directions fade over a few milliseconds
+    /// to avoid clicks.
+    ///
+    /// Android, macOS and iOS stop pulling audio in the output callback, so
+    /// this takes effect immediately; Windows and Linux stop writing to the
+    /// device buffer, so audio already written plays out first. An Android
+    /// `render_source` isn't called while muted. Applies to every handle on
+    /// the same device stream and survives reconnects.
+    pub fn set_playback_muted(&self, muted: bool) {
+        self.backend().set_playback_muted(muted);
+    }
+
+    /// Whether `set_playback_muted` has muted the output
+    pub fn is_playback_muted(&self) -> bool {
+        self.backend().is_playback_muted()
+    }
+
+    /// Estimate how far audio sent through `play_audio` is attenuated in the
+    /// capture, in dB, by cross-correlating the last second of both.
+    ///
+    /// The figure includes the acoustic path loss as well as the canceller,
+    /// so compare it between runs rather than against a spec sheet. Returns
+    /// None until a second of non-silent audio has been played. Most accurate
+    /// when `play_audio` is fed in real time rather than a whole clip at once.
+    pub fn measure_erle(&self) -> Option<f32> {
+        self.echo_meter.lock().ok()?.measure()
+    }
+
+    /// Play a 440 Hz tone through `play_audio` for `duration` while
+    /// recording, and report how much of it came back along with drops and
+    /// latency. A one-call field diagnostic; run it for at least two
+    /// seconds so the ERLE window fills.
+    ///
+    /// Consumes the capture while it runs, so that audio never reaches the
+    /// caller. Fails where `play_audio` does (raw, file and cpal capture).
+    pub async fn run_loopback_selftest(
+        &self,
+        duration: std::time::Duration,
+    ) -> Result<SelfTestReport, AecError> {
+        selftest::run(self, duration).await
+    }
+
+    /// Record `duration` of capture to an Ogg Opus file at `path`, about a
+    /// tenth the size of the same audio as float WAV. The file is finished
+    /// early if the stream ends, and still finalized if the future is
+    /// dropped part way.
+    ///
+    /// Consumes the capture while it runs, so that audio never reaches the
+    /// caller. Opus takes 8, 12, 16, 24 or 48 kHz audio in one or two
+    /// channels; other output formats are rejected with `InvalidConfig`,
+    /// as is a format change while recording.
+    #[cfg(feature = "opus")]
+    pub async fn record_to_ogg_opus(
+        &self,
+        path: impl AsRef<std::path::Path>,
+        duration: std::time::Duration,
+    ) -> Result<(), AecError> {
+        let rate = self.output_sample_rate();
+        let channels = self.channels();
+        let mut writer = ogg_opus::OggOpusWriter::create(path.as_ref(), rate, channels)?;
+
+        let total = (duration.as_secs_f64() * rate as f64) as usize * channels as usize;
+        let mut recorded = 0;
+        while recorded < total {
+            let Some(frame) = self.recv().await else {
+                break;
+            };
+            let frame = frame?;
+            if (frame.sample_rate(), frame.channels()) != (rate, channels) {
+                return Err(AecError::InvalidConfig(
+                    "output format changed during an Ogg Opus recording".to_string(),
+                ));
+            }
+            let take = frame.len().min(total - recorded);
+            writer.write(&frame[..take])?;
+            recorded += take;
+        }
+        writer.finish()
+    }
+}
+
+/// Progress of audio handed to `CaptureHandle::play_audio_tracked`.
+/// Awaiting the ticket is the same as awaiting `finished`.
+#[must_use = "audio that didn't fit the playback queue is only queued once the ticket is awaited"]
+pub struct PlaybackTicket {
+    /// Set while the audio waits for room in a full playback queue
+    send: Option<backends::PlaybackSend>,
+    done: flume::Receiver<Result<(), AecError>>,
+}
+
+impl PlaybackTicket {
+    /// Resolve once the audio is in the playback queue: at once if it had
+    /// room, otherwise when the output has drained enough. Audio that
+    /// didn't fit is only queued while this or `finished` is awaited, so
+    /// await it before handing over the next chunk for gapless playback.
+    /// Fails if capture stops first.
+    pub async fn queued(&mut self) -> Result<(), AecError> {
+        if let Some(send) = &mut self.send {
+            send.await.map_err(|_| playback_stopped())?;
+            self.send = None;
+        }
+        Ok(())
+    }
+
+    /// Resolve once the output has consumed the last of the audio, queueing
+    /// it first if `queued` hasn't. Fails if capture stops first, or with
+    /// the error that kept the audio from playing, e.g. a sample rate the
+    /// output can't convert.
+    pub async fn finished(mut self) -> Result<(), AecError> {
+        self.queued().await?;
+        self.done
+            .recv_async()
+            .await
+            .map_err(|_| playback_stopped())?
+    }
+}
+
+impl std::future::IntoFuture for PlaybackTicket {
+    type Output = Result<(), AecError>;
+    type IntoFuture = std::pin::Pin<Box<dyn std::future::Future<Output = Self::Output> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(self.finished())
     }
 }
 
+fn playback_stopped() -> AecError {
+    AecError::BackendError("playback stopped before the audio finished".to_string())
+}
+
 // Drop on CaptureHandle drops backend, which stops capture via RAII
 
-fn process_audio_chunk(
-    samples: Vec<f32>,
-    resampler: &mut Option<Resampler>,
-    needs_stereo: bool,
-) -> Result<Vec<f32>, String> {
-    let samples = if let Some(r) = resampler {
-        r.process(&samples)
-            .map_err(|e| format!("resample: {e:?}"))?
-    } else {
-        samples
-    };
-
-    if needs_stereo {
-        Ok(samples.iter().flat_map(|&s| [s, s]).collect())
-    } else {
-        Ok(samples)
+/// Voice-processed and raw capture of the same microphone at once, e.g. to
+/// record the unprocessed input for debugging or training while a call
+/// runs on the processed one.
+///
+/// Opens two independent streams: `Processing::Voice` on the one hand and
+/// `Processing::Raw` on the other, each with its own pipeline. They start
+/// within milliseconds of each other but aren't sample-aligned, and voice
+/// processing adds latency the raw stream doesn't have. Playback goes
+/// through `processed()`, so its echo is cancelled there and heard in
+/// `raw()`.
+///
+/// Supported on macOS (a HAL unit beside VoiceProcessingIO), Windows (a
+/// second WASAPI stream without the AEC link) and Android (a second Oboe
+/// stream with the VoiceRecognition preset). iOS runs one I/O unit per
+/// audio session, and on Linux both would read the same sound-server
+/// source, so `new` fails there with `AecNotSupported`.
+pub struct DualCapture {
+    processed: CaptureHandle,
+    raw: CaptureHandle,
+}
+
+impl DualCapture {
+    /// Open both streams from `config`, whose `processing` is ignored. The
+    /// raw stream leaves out `render_source` and system audio.
+    ///
+    /// Must be called from within a tokio runtime; use `new_on` otherwise.
+    /// File input has no raw path and fails with `InvalidConfig`.
+    pub fn new(config: AecConfig) -> Result<Self, AecError> {
+        let runtime = tokio::runtime::Handle::try_current().map_err(|_| {
+            AecError::BackendError(
+                "DualCapture::new called outside a tokio runtime; use new_on".to_string(),
+            )
+        })?;
+        Self::new_on(config, runtime)
+    }
+
+    /// `new` with background tasks on the given runtime, so it can be
+    /// called from any thread.
+    pub fn new_on(config: AecConfig, runtime: tokio::runtime::Handle) -> Result<Self, AecError> {
+        if !cfg!(any(
+            target_os = "macos",
+            target_os = "windows",
+            target_os = "android"
+        )) {
+            return Err(AecError::AecNotSupported);
+        }
+        #[cfg(feature = "backend-file")]
+        if config.input_file.is_some() {
+            return Err(AecError::InvalidConfig(
+                "dual capture needs a microphone, not file input".to_string(),
+            ));
+        }
+
+        let raw_config = AecConfig {
+            processing: Processing::Raw,
+            render_source: None,
+            #[cfg(feature = "system-audio")]
+            system_audio: SystemAudio::Off,
+            ..config.clone()
+        };
+        let processed = CaptureHandle::new_on(
+            AecConfig {
+                processing: Processing::Voice,
+                ..config
+            },
+            runtime.clone(),
+        )?;
+        let raw = CaptureHandle::new_on(raw_config, runtime)?;
+        Ok(Self { processed, raw })
+    }
+
+    /// The echo-cancelled stream, which also plays audio
+    pub fn processed(&self) -> &CaptureHandle {
+        &self.processed
+    }
+
+    /// The unprocessed microphone stream
+    pub fn raw(&self) -> &CaptureHandle {
+        &self.raw
+    }
+
+    /// Split into the processed and raw handles, to receive from each on
+    /// its own task. Each stream stops when its handle drops.
+    pub fn into_parts(self) -> (CaptureHandle, CaptureHandle) {
+        (self.processed, self.raw)
     }
 }
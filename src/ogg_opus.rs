@@ -0,0 +1,263 @@
+//! Ogg Opus file output for `CaptureHandle::record_to_ogg_opus`, laid out
+//! per RFC 7845: an identification header, a comment header, then one
+//! 20 ms Opus packet per Ogg packet.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use audiopus::coder::Encoder;
+use audiopus::{Application, SampleRate};
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+
+use crate::AecError;
+
+/// Opus frame length, the usual choice for voice
+const FRAME_MS: usize = 20;
+/// Granule positions count samples at 48 kHz whatever the input rate
+const GRANULE_RATE: u32 = 48000;
+/// Largest packet RFC 6716 recommends an encoder produce
+const MAX_PACKET: usize = 4000;
+/// Written as the encoder vendor in the comment header
+const VENDOR: &str = concat!("sys-voice ", env!("CARGO_PKG_VERSION"));
+
+/// Encodes interleaved `f32` audio into an Ogg Opus file. The stream is
+/// finalized by `finish`, or on drop if that wasn't called, so a recording
+/// cut short still plays.
+pub(crate) struct OggOpusWriter {
+    packets: PacketWriter<BufWriter<File>>,
+    encoder: Encoder,
+    serial: u32,
+    channels: usize,
+    /// Interleaved samples per Opus frame
+    frame_len: usize,
+    /// Input held back until it fills a frame
+    pending: Vec<f32>,
+    /// 48 kHz samples per input frame
+    granule_scale: u64,
+    /// Encoder delay the decoder skips, at 48 kHz
+    pre_skip: u64,
+    /// Input frames written so far, padding excluded
+    frames: u64,
+    finished: bool,
+}
+
+impl OggOpusWriter {
+    /// Create the file at `path` for `channels` (1 or 2) at `sample_rate`,
+    /// which Opus only accepts at 8, 12, 16, 24 or 48 kHz.
+    pub fn create(path: &Path, sample_rate: u32, channels: u16) -> Result<Self, AecError> {
+        let opus_rate = SampleRate::try_from(sample_rate as i32).map_err(|_| {
+            AecError::InvalidConfig(format!(
+                "Opus can't encode {sample_rate} Hz; use 8, 12, 16, 24 or 48 kHz"
+            ))
+        })?;
+        let opus_channels = match channels {
+            1 => audiopus::Channels::Mono,
+            2 => audiopus::Channels::Stereo,
+            _ => {
+                return Err(AecError::InvalidConfig(format!(
+                    "Ogg Opus recording takes 1 or 2 channels, not {channels}"
+                )))
+            }
+        };
+
+        let encoder = Encoder::new(opus_rate, opus_channels, Application::Voip)
+            .map_err(|e| opus_error("failed to create Opus encoder", e))?;
+        let granule_scale = (GRANULE_RATE / sample_rate) as u64;
+        let lookahead = encoder
+            .lookahead()
+            .map_err(|e| opus_error("failed to query Opus lookahead", e))?;
+        let pre_skip = lookahead as u64 * granule_scale;
+
+        let file = File::create(path).map_err(|e| io_error("failed to create", path, e))?;
+        let mut writer = Self {
+            packets: PacketWriter::new(BufWriter::new(file)),
+            encoder,
+            serial: stream_serial(),
+            channels: channels as usize,
+            frame_len: sample_rate as usize * FRAME_MS / 1000 * channels as usize,
+            pending: Vec::new(),
+            granule_scale,
+            pre_skip,
+            frames: 0,
+            finished: false,
+        };
+
+        // Each header goes on a page of its own
+        let head = opus_head(channels as u8, pre_skip as u16, sample_rate);
+        writer.write_packet(head, PacketWriteEndInfo::EndPage, 0)?;
+        writer.write_packet(opus_tags(), PacketWriteEndInfo::EndPage, 0)?;
+        Ok(writer)
+    }
+
+    /// Encode every whole frame `samples` completes, holding the rest back.
+    pub fn write(&mut self, samples: &[f32]) -> Result<(), AecError> {
+        self.pending.extend_from_slice(samples);
+        let whole = self.pending.len() / self.frame_len * self.frame_len;
+        let pending = std::mem::take(&mut self.pending);
+        for frame in pending[..whole].chunks_exact(self.frame_len) {
+            self.frames += (self.frame_len / self.channels) as u64;
+            let granule = self.granule();
+            self.encode(frame, PacketWriteEndInfo::NormalPacket, granule)?;
+        }
+        self.pending = pending[whole..].to_vec();
+        Ok(())
+    }
+
+    /// Encode the partial frame, padded with silence, as the end of the
+    /// stream and flush the file.
+    pub fn finish(mut self) -> Result<(), AecError> {
+        self.finalize()
+    }
+
+    fn finalize(&mut self) -> Result<(), AecError> {
+        self.finished = true;
+        // The last packet's granule position trims the padding back off
+        self.frames += (self.pending.len() / self.channels) as u64;
+        let mut last = std::mem::take(&mut self.pending);
+        last.resize(self.frame_len, 0.0);
+        let granule = self.granule();
+        self.encode(&last, PacketWriteEndInfo::EndStream, granule)?;
+        self.packets
+            .inner_mut()
+            .flush()
+            .map_err(|e| AecError::BackendError(format!("failed to flush Ogg Opus file: {e}")))
+    }
+
+    /// Granule position after the frames written so far
+    fn granule(&self) -> u64 {
+        self.pre_skip + self.frames * self.granule_scale
+    }
+
+    fn encode(
+        &mut self,
+        frame: &[f32],
+        end: PacketWriteEndInfo,
+        granule: u64,
+    ) -> Result<(), AecError> {
+        let mut packet = vec![0u8; MAX_PACKET];
+        let len = self
+            .encoder
+            .encode_float(frame, &mut packet)
+            .map_err(|e| opus_error("Opus encoding failed", e))?;
+        packet.truncate(len);
+        self.write_packet(packet, end, granule)
+    }
+
+    fn write_packet(
+        &mut self,
+        packet: Vec<u8>,
+        end: PacketWriteEndInfo,
+        granule: u64,
+    ) -> Result<(), AecError> {
+        self.packets
+            .write_packet(packet.into_boxed_slice(), self.serial, end, granule)
+            .map_err(|e| AecError::BackendError(format!("failed to write Ogg Opus file: {e}")))
+    }
+}
+
+impl Drop for OggOpusWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(e) = self.finalize() {
+                tracing::warn!("Ogg Opus recording not finalized: {e}");
+            }
+        }
+    }
+}
+
+/// Identification header, RFC 7845 section 5.1, for channel mapping family 0
+fn opus_head(channels: u8, pre_skip: u16, sample_rate: u32) -> Vec<u8> {
+    let mut head = Vec::with_capacity(19);
+    head.extend_from_slice(b"OpusHead");
+    head.push(1);
+    head.push(channels);
+    head.extend_from_slice(&pre_skip.to_le_bytes());
+    head.extend_from_slice(&sample_rate.to_le_bytes());
+    // Output gain
+    head.extend_from_slice(&0i16.to_le_bytes());
+    // Mono or stereo, no mapping table
+    head.push(0);
+    head
+}
+
+/// Comment header, RFC 7845 section 5.2, naming the vendor and no tags
+fn opus_tags() -> Vec<u8> {
+    let mut tags = Vec::with_capacity(16 + VENDOR.len());
+    tags.extend_from_slice(b"OpusTags");
+    tags.extend_from_slice(&(VENDOR.len() as u32).to_le_bytes());
+    tags.extend_from_slice(VENDOR.as_bytes());
+    tags.extend_from_slice(&0u32.to_le_bytes());
+    tags
+}
+
+/// Logical stream serial number, which only has to be unlikely to collide
+/// if the file is ever chained or multiplexed
+fn stream_serial() -> u32 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.subsec_nanos());
+    nanos ^ std::process::id().rotate_left(16)
+}
+
+fn opus_error(context: &str, e: audiopus::Error) -> AecError {
+    AecError::BackendError(format!("{context}: {e}"))
+}
+
+fn io_error(context: &str, path: &Path, e: std::io::Error) -> AecError {
+    AecError::BackendError(format!("{context} {}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ogg::reading::PacketReader;
+
+    #[test]
+    fn recording_is_a_complete_ogg_opus_stream() {
+        let path = std::env::temp_dir().join(format!("sys-voice-opus-{}.opus", std::process::id()));
+        let mut writer = OggOpusWriter::create(&path, 16000, 1).unwrap();
+        // 1.005 s: 50 whole 20 ms frames and a partial one
+        let tone: Vec<f32> = (0..16080).map(|i| (i as f32 * 0.1).sin() * 0.3).collect();
+        for chunk in tone.chunks(333) {
+            writer.write(chunk).unwrap();
+        }
+        let pre_skip = writer.pre_skip;
+        writer.finish().unwrap();
+
+        let mut reader = PacketReader::new(File::open(&path).unwrap());
+        let mut packets = Vec::new();
+        while let Some(packet) = reader.read_packet().unwrap() {
+            packets.push(packet);
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        let head = &packets[0].data;
+        assert_eq!(&head[..8], b"OpusHead");
+        assert_eq!(head[9], 1);
+        assert_eq!(u16::from_le_bytes([head[10], head[11]]) as u64, pre_skip);
+        assert_eq!(u32::from_le_bytes(head[12..16].try_into().unwrap()), 16000);
+        assert_eq!(&packets[1].data[..8], b"OpusTags");
+
+        let audio = &packets[2..];
+        assert_eq!(audio.len(), 51);
+        let last = audio.last().unwrap();
+        assert!(last.last_in_stream());
+        // Padding in the last frame is trimmed by its granule position
+        assert_eq!(last.absgp_page(), pre_skip + 16080 * 3);
+    }
+
+    #[test]
+    fn unsupported_formats_are_rejected() {
+        let path = std::env::temp_dir().join("sys-voice-opus-never-created.opus");
+        assert!(matches!(
+            OggOpusWriter::create(&path, 44100, 1),
+            Err(AecError::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            OggOpusWriter::create(&path, 48000, 4),
+            Err(AecError::InvalidConfig(_))
+        ));
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,1741 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::backends::{to_mono, Chunk, ChunkSender, QueueSender, StreamInfo};
+use crate::erle::EchoMeter;
+use crate::gain::AutoGain;
+use crate::latency;
+use crate::playback_sync::PlaybackSync;
+use crate::recent::RecentAudio;
+use crate::resampler::{Resample, Resampler, ResamplerFactory};
+#[cfg(feature = "backend-file")]
+use crate::FileFault;
+use crate::{
+    dsp, AecConfig, AecError, AudioFrame, CaptureEvent, CaptureStatus, Channels, CloseReason,
+    DownmixMode, OverflowPolicy, ProcessHook, ReconnectConfig, AEC_WARMUP,
+};
+
+/// How far back a chunk above `AecConfig::signal_floor` keeps
+/// `is_receiving_signal` true
+const SIGNAL_WINDOW: Duration = Duration::from_millis(500);
+/// Weight of the newest chunk in the DSP load average
+const LOAD_SMOOTHING: f32 = 0.1;
+/// Samples at or below this magnitude, about -100 dBFS, count as silence
+/// for `AecConfig::silence_timeout`
+const SILENCE_FLOOR: f32 = 1e-5;
+
+/// Converts raw backend chunks into the rate and channel layout
+/// requested in `AecConfig`.
+pub(crate) struct Pipeline {
+    resampler: Option<Box<dyn Resample>>,
+    auto_gain: Option<AutoGain>,
+    process_hook: Option<ProcessHook>,
+    channels: Channels,
+    /// Applied when a mono layout is asked of multichannel input, after a
+    /// switch away from the layout the device was opened with
+    downmix: DownmixMode,
+    input_channels: u16,
+    input_rate: u32,
+    output_rate: u32,
+    /// Smoothed processing time as a percentage of the audio's duration
+    load: f32,
+    timeline: Timeline,
+    chunk_frames: Option<usize>,
+    chunker: Option<Chunker>,
+    /// Spent buffers the chunker cuts chunks into
+    buffers: Arc<BufferPool>,
+    echo_meter: Arc<Mutex<EchoMeter>>,
+    /// Set when `AecConfig::retain_recent` is
+    recent: Option<Arc<Mutex<RecentAudio>>>,
+    /// Set when `AecConfig::playback_sync` is, clocked by `advance`
+    playback_sync: Option<Arc<PlaybackSync>>,
+    silence_timeout: Option<Duration>,
+    signal_floor: f32,
+    /// Input frames since a chunk last rose above `signal_floor`, None
+    /// until one has
+    since_signal: Option<u64>,
+    /// Input frames left before `CaptureEvent::WarmedUp`, None once sent
+    warm_up: Option<u64>,
+    /// Input frames left before a `FileFault::Error`, and its message
+    #[cfg(feature = "backend-file")]
+    fault: Option<(u64, String)>,
+}
+
+impl Pipeline {
+    pub fn new(info: &StreamInfo, config: &AecConfig) -> Result<Self, AecError> {
+        if config.output_chunk_frames == Some(0) {
+            return Err(AecError::InvalidConfig(
+                "output_chunk_frames must be non-zero".to_string(),
+            ));
+        }
+
+        if let Some(agc) = &config.auto_gain {
+            if !(agc.target_rms > 0.0 && agc.max_gain > 0.0) {
+                return Err(AecError::InvalidConfig(
+                    "auto_gain target_rms and max_gain must be positive".to_string(),
+                ));
+            }
+        }
+
+        let output_rate = if config.resample {
+            config.sample_rate
+        } else {
+            info.sample_rate
+        };
+
+        let mut pipeline = Self {
+            resampler: Self::resampler_for(
+                info,
+                output_rate,
+                latency::resampler_frame_ms(config.latency_target, config.resampler_frame)?,
+                config.resampler.as_ref(),
+            )?,
+            auto_gain: config
+                .auto_gain
+                .map(|agc| AutoGain::new(agc, output_rate, info.channels)),
+            process_hook: config.process_hook.clone(),
+            channels: config.channels,
+            downmix: config.downmix,
+            input_channels: info.channels,
+            input_rate: info.sample_rate,
+            output_rate,
+            load: 0.0,
+            timeline: Timeline::new(info.channels),
+            chunk_frames: config.output_chunk_frames,
+            chunker: None,
+            // Enough for every chunk the public queue can hold, plus the
+            // one being filled and the one the caller is reading
+            buffers: Arc::new(BufferPool::new(
+                latency::LatencyPlan::new(config.latency_target).queue_depth + 2,
+            )),
+            echo_meter: Arc::new(Mutex::new(EchoMeter::new())),
+            recent: config
+                .retain_recent
+                .map(|retention| Arc::new(Mutex::new(RecentAudio::new(retention)))),
+            playback_sync: None,
+            silence_timeout: config.silence_timeout,
+            signal_floor: config.signal_floor,
+            since_signal: None,
+            // Without a canceller to converge, the first audio is ready
+            warm_up: Some(if info.aec {
+                (AEC_WARMUP.as_secs_f64() * info.sample_rate as f64) as u64
+            } else {
+                0
+            }),
+            #[cfg(feature = "backend-file")]
+            fault: match config.input_file.as_ref().and_then(|s| s.fault.as_ref()) {
+                Some(FileFault::Error { at, message }) => Some((
+                    (at.as_secs_f64() * info.sample_rate as f64) as u64,
+                    message.clone(),
+                )),
+                _ => None,
+            },
+        };
+        pipeline.rebuild_chunker();
+
+        Ok(pipeline)
+    }
+
+    /// Run one device buffer of silence through the pipeline before any
+    /// audio, so a format it can't process fails the open instead of
+    /// closing the stream on its first chunk. Nothing the caller can see
+    /// keeps the silence: the hook, gain, meter and recent audio are
+    /// detached, and the resampler is reset afterwards.
+    pub fn dry_run(&mut self, buffer_size: usize) -> Result<(), AecError> {
+        let hook = self.process_hook.take();
+        let auto_gain = self.auto_gain.take();
+        let recent = self.recent.take();
+        let echo_meter =
+            std::mem::replace(&mut self.echo_meter, Arc::new(Mutex::new(EchoMeter::new())));
+        let load = self.load;
+
+        let samples = buffer_size.max(1) * self.input_channels.max(1) as usize;
+        let result = self
+            .process(vec![0.0; samples])
+            .and_then(|chunks| {
+                for chunk in chunks {
+                    self.buffers.give(chunk.into());
+                }
+                self.finish()
+            })
+            .map(drop);
+
+        self.process_hook = hook;
+        self.auto_gain = auto_gain;
+        self.recent = recent;
+        self.echo_meter = echo_meter;
+        self.load = load;
+        self.reset();
+        result
+    }
+
+    fn rebuild_chunker(&mut self) {
+        let channels = self.output_channels() as usize;
+        self.chunker = self
+            .chunk_frames
+            .map(|frames| Chunker::new(frames * channels));
+    }
+
+    /// Resampler from the backend rate to `output_rate`, built by `factory`
+    /// if there is one, or None when the rates already match.
+    pub fn resampler_for(
+        info: &StreamInfo,
+        output_rate: u32,
+        frame_ms: usize,
+        factory: Option<&ResamplerFactory>,
+    ) -> Result<Option<Box<dyn Resample>>, AecError> {
+        if info.sample_rate == output_rate {
+            return Ok(None);
+        }
+        if let Some(factory) = factory {
+            return factory
+                .build(info.sample_rate, output_rate, info.channels)
+                .map(Some);
+        }
+        let resampler =
+            Resampler::with_frame_duration(info.sample_rate, output_rate, info.channels, frame_ms)?;
+        Ok(Some(Box::new(resampler)))
+    }
+
+    /// Switch to a new output rate. Call after `finish`, so no audio
+    /// buffered at the old rate is lost.
+    pub fn retarget(&mut self, retarget: Retarget) {
+        self.timeline
+            .rate_changed(self.input_rate, self.output_rate);
+        self.resampler = retarget.resampler;
+        self.output_rate = retarget.rate;
+        if let Some(agc) = &mut self.auto_gain {
+            agc.set_sample_rate(retarget.rate, self.input_channels);
+        }
+    }
+
+    /// Switch to a new channel layout. Returns the partial chunk buffered in
+    /// the old layout, which would otherwise be mixed into the new one.
+    pub fn set_channels(&mut self, channels: Channels) -> Option<AudioFrame> {
+        let remaining = self
+            .chunker
+            .as_mut()
+            .and_then(Chunker::take_remaining)
+            .map(|remaining| self.label(remaining));
+        self.channels = channels;
+        self.rebuild_chunker();
+        remaining
+    }
+
+    /// Rate of the processed output.
+    pub fn output_rate(&self) -> u32 {
+        self.output_rate
+    }
+
+    /// Interleaved channels per frame in the processed output.
+    pub fn output_channels(&self) -> u16 {
+        output_channels(self.channels, self.input_channels)
+    }
+
+    /// Delay added on top of the backend's own buffering.
+    pub fn latency(&self) -> Duration {
+        self.resampler
+            .as_ref()
+            .map_or(Duration::ZERO, |r| r.latency())
+    }
+
+    /// Shared with `CaptureHandle`, which feeds it the playback reference.
+    pub fn echo_meter(&self) -> Arc<Mutex<EchoMeter>> {
+        self.echo_meter.clone()
+    }
+
+    /// Pool `CaptureHandle::recv_into` hands spent chunks back to
+    pub fn buffer_pool(&self) -> Arc<BufferPool> {
+        self.buffers.clone()
+    }
+
+    /// Buffer `CaptureHandle::snapshot_recent` reads, if one is kept
+    pub fn recent_audio(&self) -> Option<Arc<Mutex<RecentAudio>>> {
+        self.recent.clone()
+    }
+
+    /// Release `sync`'s playback as the stream position advances.
+    pub fn sync_playback(&mut self, sync: Arc<PlaybackSync>) {
+        self.playback_sync = Some(sync);
+    }
+
+    /// Process one backend chunk into zero or more chunks ready for delivery.
+    pub fn process(&mut self, samples: Vec<f32>) -> Result<Vec<AudioFrame>, AecError> {
+        let started = Instant::now();
+        let frames = samples.len() / self.input_channels.max(1) as usize;
+
+        let samples = if let Some(r) = &mut self.resampler {
+            r.process(&samples)?
+        } else {
+            samples
+        };
+        let chunks = self.convert(samples)?;
+
+        if frames > 0 {
+            let budget = frames as f32 / self.input_rate as f32;
+            let load = 100.0 * started.elapsed().as_secs_f32() / budget;
+            self.load += LOAD_SMOOTHING * (load - self.load);
+        }
+        Ok(chunks)
+    }
+
+    /// Count a chunk of device input, and any input lost before it.
+    pub fn advance(&mut self, chunk: &Chunk) {
+        let position = self.timeline.advance(chunk);
+        if let Some(sync) = &self.playback_sync {
+            sync.advance(position, self.input_rate);
+        }
+    }
+
+    /// Frames the device has produced, at the output rate in effect for
+    /// each stretch of them.
+    pub fn output_position(&self) -> u64 {
+        self.timeline
+            .output_frames(self.input_rate, self.output_rate)
+    }
+
+    /// Take the RMS of a device chunk, returning whether one within the
+    /// last `SIGNAL_WINDOW` of input rose above `signal_floor`.
+    pub fn observe_level(&mut self, samples: &[f32]) -> bool {
+        if dsp::rms(samples) > self.signal_floor {
+            self.since_signal = Some(0);
+        } else if let Some(since) = &mut self.since_signal {
+            *since += (samples.len() / self.input_channels.max(1) as usize) as u64;
+        }
+        let window = (SIGNAL_WINDOW.as_secs_f64() * self.input_rate as f64) as u64;
+        self.since_signal.is_some_and(|since| since <= window)
+    }
+
+    /// Count `samples` of device input toward the warm-up, returning true
+    /// for the chunk that completes it and false before and after.
+    pub fn warmed_up(&mut self, samples: usize) -> bool {
+        let Some(remaining) = &mut self.warm_up else {
+            return false;
+        };
+        let frames = (samples / self.input_channels.max(1) as usize) as u64;
+        *remaining = remaining.saturating_sub(frames);
+        if *remaining > 0 {
+            return false;
+        }
+        self.warm_up = None;
+        true
+    }
+
+    /// Count `samples` toward a `FileFault::Error`, returning the error
+    /// once the chunk that reaches it arrives.
+    #[cfg(feature = "backend-file")]
+    fn injected_fault(&mut self, samples: usize) -> Option<AecError> {
+        let (remaining, _) = self.fault.as_mut()?;
+        let frames = (samples / self.input_channels.max(1) as usize) as u64;
+        if frames <= *remaining {
+            *remaining -= frames;
+            return None;
+        }
+        let (_, message) = self.fault.take()?;
+        tracing::info!("file input: injecting {message:?}");
+        Some(AecError::BackendError(message))
+    }
+
+    /// Measure the position from the next chunk on, so the time a lost
+    /// device was away isn't counted as a gap.
+    pub fn resync_position(&mut self) {
+        self.timeline.resync();
+    }
+
+    /// Processing time as a percentage of the real-time duration of the
+    /// audio processed, exponentially averaged over recent chunks.
+    pub fn load(&self) -> f32 {
+        self.load
+    }
+
+    /// Everything still buffered once the backend has stopped: the resampler
+    /// tail and the final partial chunk.
+    pub fn finish(&mut self) -> Result<Vec<AudioFrame>, AecError> {
+        let tail = match &mut self.resampler {
+            Some(r) => r.flush()?,
+            None => Vec::new(),
+        };
+
+        let mut chunks = self.convert(tail)?;
+        if let Some(remaining) = self.chunker.as_mut().and_then(Chunker::take_remaining) {
+            chunks.push(self.label(remaining));
+        }
+        Ok(chunks)
+    }
+
+    /// Drop resampler history so audio after a resume starts fresh.
+    /// Call after `finish`, which has already emptied the chunker.
+    pub fn reset(&mut self) {
+        if let Some(r) = &mut self.resampler {
+            r.reset();
+        }
+    }
+
+    /// Gain, channel conversion and re-chunking shared by `process` and `finish`.
+    fn convert(&mut self, mut samples: Vec<f32>) -> Result<Vec<AudioFrame>, AecError> {
+        if samples.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        if let Some(agc) = &mut self.auto_gain {
+            agc.process(&mut samples);
+        }
+
+        if let Some(hook) = &self.process_hook {
+            hook.run(&mut samples);
+            if !samples
+                .len()
+                .is_multiple_of(self.input_channels.max(1) as usize)
+            {
+                return Err(AecError::InvalidConfig(format!(
+                    "process_hook returned {} samples, not whole {}-channel frames",
+                    samples.len(),
+                    self.input_channels
+                )));
+            }
+            if samples.is_empty() {
+                return Ok(Vec::new());
+            }
+        }
+
+        if let Ok(mut meter) = self.echo_meter.lock() {
+            if self.input_channels > 1 {
+                let mono = dsp::downmix(&samples, self.input_channels as usize);
+                meter.push_capture(&mono, self.output_rate);
+            } else {
+                meter.push_capture(&samples, self.output_rate);
+            }
+        }
+
+        let samples = match (self.channels, self.input_channels) {
+            (Channels::Mono, channels) if channels > 1 => {
+                to_mono(&samples, channels as usize, self.downmix)
+            }
+            (Channels::Stereo, channels) if channels > 1 => {
+                dsp::duplicate_to_stereo(&to_mono(&samples, channels as usize, self.downmix))
+            }
+            (Channels::Stereo, _) | (Channels::StereoTrue, 1) => dsp::duplicate_to_stereo(&samples),
+            // Front left and right lead every standard channel order
+            (Channels::StereoTrue, channels) if channels > 2 => samples
+                .chunks_exact(channels as usize)
+                .flat_map(|frame| [frame[0], frame[1]])
+                .collect(),
+            _ => samples,
+        };
+
+        if let Some(Ok(mut recent)) = self.recent.as_ref().map(|r| r.lock()) {
+            recent.push(&samples, self.output_rate, self.output_channels());
+        }
+
+        let chunks = match &mut self.chunker {
+            Some(chunker) => chunker.push(&samples, &self.buffers),
+            None => vec![samples],
+        };
+        Ok(chunks.into_iter().map(|c| self.label(c)).collect())
+    }
+
+    /// Tag processed samples with the current output format.
+    fn label(&self, samples: Vec<f32>) -> AudioFrame {
+        AudioFrame::new(samples, self.output_rate, self.output_channels())
+    }
+}
+
+/// Input frames the device has produced, read from the stamps on its
+/// chunks, so input a queue dropped or the device reported lost counts as
+/// the gap it left. Also kept at the output rate, which can change.
+struct Timeline {
+    channels: u16,
+    /// Stamp the current stream's first chunk starts at
+    origin: Option<u64>,
+    /// Frames counted before the current stream opened
+    base: u64,
+    frames: u64,
+    /// Output frames up to the last rate change...
+    output_base: u64,
+    /// ...and the input frame it happened at
+    rate_changed_at: u64,
+}
+
+impl Timeline {
+    fn new(channels: u16) -> Self {
+        Self {
+            channels: channels.max(1),
+            origin: None,
+            base: 0,
+            frames: 0,
+            output_base: 0,
+            rate_changed_at: 0,
+        }
+    }
+
+    /// Count up to the end of `chunk`, returning the position in input
+    /// frames. Empty chunks only wake the loop and carry no stamp.
+    fn advance(&mut self, chunk: &Chunk) -> u64 {
+        if chunk.samples.is_empty() {
+            return self.frames;
+        }
+        let origin = *self.origin.get_or_insert(chunk.start);
+        let end = chunk.start + chunk.samples.len() as u64;
+        self.frames = self.base + end.saturating_sub(origin) / self.channels as u64;
+        self.frames
+    }
+
+    /// Carry on from the current position with a new stream's stamps, so
+    /// the time a lost device was away isn't counted as a gap.
+    fn resync(&mut self) {
+        self.base = self.frames;
+        self.origin = None;
+    }
+
+    fn output_frames(&self, input_rate: u32, output_rate: u32) -> u64 {
+        let since = self.frames.saturating_sub(self.rate_changed_at) as u128;
+        self.output_base + (since * output_rate as u128 / input_rate.max(1) as u128) as u64
+    }
+
+    /// Fix the output position counted at `old_rate` before it changes.
+    fn rate_changed(&mut self, input_rate: u32, old_rate: u32) {
+        self.output_base = self.output_frames(input_rate, old_rate);
+        self.rate_changed_at = self.frames;
+    }
+}
+
+/// Interleaved channels per frame delivered for `channels` when the
+/// backend delivers `input_channels`.
+pub(crate) fn output_channels(channels: Channels, input_channels: u16) -> u16 {
+    match channels {
+        Channels::Mono => 1,
+        Channels::Stereo | Channels::StereoTrue => 2,
+        Channels::Native => input_channels,
+    }
+}
+
+/// Channels of the output for `channels` that carry a signal of their
+/// own, the rest being copies. Below `output_channels` for `Stereo`, which
+/// always duplicates mono, and for `StereoTrue` on a mono input.
+pub(crate) fn distinct_channels(channels: Channels, input_channels: u16) -> u16 {
+    match channels {
+        Channels::Mono | Channels::Stereo => 1,
+        Channels::StereoTrue => input_channels.clamp(1, 2),
+        Channels::Native => input_channels,
+    }
+}
+
+/// Re-buffers processed audio into fixed-size chunks.
+struct Chunker {
+    chunk_len: usize,
+    pending: Vec<f32>,
+}
+
+impl Chunker {
+    fn new(chunk_len: usize) -> Self {
+        Self {
+            chunk_len,
+            pending: Vec::with_capacity(chunk_len * 2),
+        }
+    }
+
+    fn push(&mut self, samples: &[f32], buffers: &BufferPool) -> Vec<Vec<f32>> {
+        self.pending.extend_from_slice(samples);
+
+        let mut chunks = Vec::new();
+        while self.pending.len() >= self.chunk_len {
+            let mut chunk = buffers.take(self.chunk_len);
+            chunk.extend(self.pending.drain(..self.chunk_len));
+            chunks.push(chunk);
+        }
+        chunks
+    }
+
+    fn take_remaining(&mut self) -> Option<Vec<f32>> {
+        if self.pending.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.pending))
+        }
+    }
+}
+
+/// Buffers of delivered chunks that `CaptureHandle::recv_into` has copied
+/// out and handed back, for the chunker to cut later chunks into. Once
+/// enough are in circulation, steady-state delivery stops allocating.
+pub(crate) struct BufferPool {
+    tx: flume::Sender<Vec<f32>>,
+    rx: flume::Receiver<Vec<f32>>,
+    /// Buffers allocated because none was spare, for `CaptureStats`
+    allocated: AtomicU64,
+}
+
+impl BufferPool {
+    fn new(capacity: usize) -> Self {
+        let (tx, rx) = flume::bounded(capacity);
+        Self {
+            tx,
+            rx,
+            allocated: AtomicU64::new(0),
+        }
+    }
+
+    /// Empty buffer with room for `len` samples, a spare one if there is.
+    fn take(&self, len: usize) -> Vec<f32> {
+        let mut buffer = self.rx.try_recv().unwrap_or_default();
+        buffer.clear();
+        if buffer.capacity() < len {
+            self.allocated.fetch_add(1, Ordering::Relaxed);
+            buffer.reserve_exact(len);
+        }
+        buffer
+    }
+
+    /// Keep a spent buffer for reuse. Dropped if the pool is full.
+    pub fn give(&self, buffer: Vec<f32>) {
+        let _ = self.tx.try_send(buffer);
+    }
+
+    pub fn allocated(&self) -> u64 {
+        self.allocated.load(Ordering::Relaxed)
+    }
+}
+
+/// Chunk tagged with the pause epoch it was captured in, so
+/// `CaptureHandle` can drop audio from before a discarding pause.
+pub(crate) type Delivery = (u64, Result<AudioFrame, AecError>);
+
+/// Output rate change built by `CaptureHandle` and applied by the loop.
+pub(crate) struct Retarget {
+    pub rate: u32,
+    pub resampler: Option<Box<dyn Resample>>,
+}
+
+/// State shared between `CaptureHandle` and the delivery loop.
+#[derive(Default)]
+pub(crate) struct Control {
+    stopped: AtomicBool,
+    paused: AtomicBool,
+    /// Bumped on every pause; the loop flushes and resets when it changes
+    epoch: AtomicU64,
+    /// Pending output rate change, taken before the next chunk
+    retarget: Mutex<Option<Retarget>>,
+    /// Pending channel layout change, taken before the next chunk
+    channels: Mutex<Option<Channels>>,
+    /// `Pipeline::load` as f32 bits, published after every chunk
+    load: AtomicU32,
+    /// `Pipeline::observe_level`, published after every chunk
+    signal: AtomicBool,
+    /// `Pipeline::output_position`, published after every chunk
+    position: AtomicU64,
+    /// Backend channel an empty chunk is sent on to wake the loop. Weak,
+    /// so it doesn't keep the stream open.
+    wake: Mutex<Option<flume::WeakSender<Chunk>>>,
+    /// Set by the loop as it exits
+    close_reason: Mutex<Option<CloseReason>>,
+    /// Lifecycle events for `CaptureHandle::events`, dropped after
+    /// `Stopped` so the receiver ends
+    events: Mutex<Option<flume::Sender<CaptureEvent>>>,
+}
+
+impl Control {
+    /// Wake the loop through `backend_tx` from now on.
+    fn set_wake(&self, backend_tx: &flume::Sender<Chunk>) {
+        if let Ok(mut wake) = self.wake.lock() {
+            *wake = Some(backend_tx.downgrade());
+        }
+    }
+
+    /// Record why the loop is exiting.
+    fn close(&self, reason: CloseReason) {
+        if let Ok(mut close_reason) = self.close_reason.lock() {
+            *close_reason = Some(reason);
+        }
+        self.emit(CaptureEvent::Stopped(reason));
+        if let Ok(mut events) = self.events.lock() {
+            *events = None;
+        }
+    }
+
+    /// Send a lifecycle event, unless the stream has already stopped.
+    pub fn emit(&self, event: CaptureEvent) {
+        if let Ok(events) = self.events.lock() {
+            if let Some(tx) = events.as_ref() {
+                let _ = tx.send(event);
+            }
+        }
+    }
+}
+
+/// Lets `CaptureHandle` stop or pause delivery while the public receiver
+/// is still alive, so buffered audio can be drained instead of discarded.
+pub(crate) struct ControlHandle {
+    control: Arc<Control>,
+    events: flume::Receiver<CaptureEvent>,
+}
+
+impl ControlHandle {
+    /// Returns the handle and the state the delivery loop polls.
+    pub fn new(backend_tx: &flume::Sender<Chunk>) -> (Self, Arc<Control>) {
+        let (events_tx, events) = flume::unbounded();
+        let control = Arc::new(Control {
+            events: Mutex::new(Some(events_tx)),
+            ..Control::default()
+        });
+        control.set_wake(backend_tx);
+        let handle = Self {
+            control: control.clone(),
+            events,
+        };
+        (handle, control)
+    }
+
+    /// Ask the delivery loop to flush and exit.
+    pub fn stop(&self) {
+        self.control.stopped.store(true, Ordering::Release);
+        self.wake();
+    }
+
+    /// Stop delivering and start a new epoch. Returns the new epoch: every
+    /// chunk tagged below it was captured before the pause.
+    pub fn pause(&self) -> u64 {
+        self.control.paused.store(true, Ordering::Release);
+        let epoch = self.control.epoch.fetch_add(1, Ordering::AcqRel) + 1;
+        self.wake();
+        epoch
+    }
+
+    pub fn resume(&self) {
+        self.control.paused.store(false, Ordering::Release);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.control.paused.load(Ordering::Acquire)
+    }
+
+    /// Latest stream position in output frames published by the loop.
+    pub fn position(&self) -> u64 {
+        self.control.position.load(Ordering::Relaxed)
+    }
+
+    /// Whether recent input rose above `AecConfig::signal_floor`, as
+    /// published by the loop.
+    pub fn receiving_signal(&self) -> bool {
+        self.control.signal.load(Ordering::Relaxed)
+    }
+
+    /// Latest DSP load percentage published by the loop.
+    pub fn load(&self) -> f32 {
+        f32::from_bits(self.control.load.load(Ordering::Relaxed))
+    }
+
+    /// Hand a new resampler to the loop. It replaces any change the loop
+    /// hasn't applied yet.
+    pub fn retarget(&self, retarget: Retarget) {
+        if let Ok(mut pending) = self.control.retarget.lock() {
+            *pending = Some(retarget);
+        }
+        self.wake();
+    }
+
+    /// Hand a new channel layout to the loop, replacing any change it
+    /// hasn't applied yet.
+    pub fn set_channels(&self, channels: Channels) {
+        if let Ok(mut pending) = self.control.channels.lock() {
+            *pending = Some(channels);
+        }
+        self.wake();
+    }
+
+    /// Lifecycle events sent by the loop, ending after `Stopped`.
+    pub fn events(&self) -> flume::Receiver<CaptureEvent> {
+        self.events.clone()
+    }
+
+    /// Why the loop exited, or None while it runs.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        *self.control.close_reason.lock().ok()?
+    }
+
+    /// An empty chunk wakes the loop if the backend is between callbacks.
+    fn wake(&self) {
+        let wake = self
+            .control
+            .wake
+            .lock()
+            .ok()
+            .and_then(|w| w.as_ref()?.upgrade());
+        if let Some(tx) = wake {
+            let _ = tx.try_send(Chunk::default());
+        }
+    }
+}
+
+/// Handle one backend chunk: flush and reset on a new pause epoch, flush
+/// and swap resamplers on a rate change, flush the partial chunk on a
+/// channel change, drop input while paused, otherwise process.
+fn step(
+    pipeline: &mut Pipeline,
+    control: &Control,
+    epoch: &mut u64,
+    chunk: Chunk,
+) -> Vec<Delivery> {
+    let mut deliveries = Vec::new();
+
+    let current = control.epoch.load(Ordering::Acquire);
+    if current != *epoch {
+        // Buffered audio belongs to the epoch before the pause
+        let tag = *epoch;
+        deliveries.extend(finish_results(pipeline).into_iter().map(|r| (tag, r)));
+        pipeline.reset();
+        *epoch = current;
+    }
+
+    // Flushing first delivers every old-rate sample before the swap, so
+    // nothing is dropped or resampled twice at the boundary
+    let retarget = control.retarget.lock().ok().and_then(|mut r| r.take());
+    if let Some(retarget) = retarget {
+        deliveries.extend(finish_results(pipeline).into_iter().map(|r| (*epoch, r)));
+        pipeline.retarget(retarget);
+    }
+
+    let channels = control.channels.lock().ok().and_then(|mut c| c.take());
+    if let Some(channels) = channels {
+        deliveries.extend(pipeline.set_channels(channels).map(|c| (*epoch, Ok(c))));
+    }
+
+    // The device produced this audio whether or not it is delivered
+    pipeline.advance(&chunk);
+    control
+        .position
+        .store(pipeline.output_position(), Ordering::Relaxed);
+    let samples = chunk.samples;
+    // Empty chunks only wake the loop
+    if !samples.is_empty() {
+        let signal = pipeline.observe_level(&samples);
+        control.signal.store(signal, Ordering::Relaxed);
+        if pipeline.warmed_up(samples.len()) {
+            control.emit(CaptureEvent::WarmedUp);
+        }
+    }
+
+    #[cfg(feature = "backend-file")]
+    if let Some(e) = pipeline.injected_fault(samples.len()) {
+        deliveries.push((*epoch, Err(e)));
+        return deliveries;
+    }
+
+    if control.paused.load(Ordering::Acquire) {
+        return deliveries;
+    }
+
+    match pipeline.process(samples) {
+        Ok(chunks) => deliveries.extend(chunks.into_iter().map(|c| (*epoch, Ok(c)))),
+        Err(e) => deliveries.push((*epoch, Err(e))),
+    }
+    control
+        .load
+        .store(pipeline.load().to_bits(), Ordering::Relaxed);
+    deliveries
+}
+
+/// Forward backend chunks through the pipeline as a tokio task.
+/// Stops when either side of the channel disconnects, processing fails or
+/// the handle stops it. The backend side only disconnects first when it
+/// stopped on its own, which is reported as `DeviceLost` unless `reconnect`
+/// opens a new stream to carry on with.
+pub(crate) async fn run_async(
+    mut pipeline: Pipeline,
+    mut backend_rx: flume::Receiver<Chunk>,
+    public_tx: QueueSender<Delivery>,
+    status_tx: flume::Sender<CaptureStatus>,
+    control: Arc<Control>,
+    mut reconnect: Option<Reconnect>,
+) {
+    let mut epoch = 0;
+    let mut silence = pipeline.silence_timeout.map(SilenceWatch::new);
+    loop {
+        loop {
+            let chunk = match next_chunk_async(&backend_rx, &mut silence).await {
+                Next::Chunk(chunk) => chunk,
+                Next::Closed => break,
+                Next::Silent(reason) => {
+                    let results = finish_results(&mut pipeline);
+                    for result in results
+                        .into_iter()
+                        .chain([Err(AecError::DeviceUnavailable)])
+                    {
+                        let _ = public_tx.send_async((epoch, result)).await;
+                    }
+                    return control.close(reason);
+                }
+            };
+            let stopping = control.stopped.load(Ordering::Acquire);
+            let deliveries = step(&mut pipeline, &control, &mut epoch, chunk);
+            let failed = deliveries.iter().any(|(_, r)| r.is_err());
+            for delivery in deliveries {
+                if !public_tx.send_async(delivery).await {
+                    return control.close(CloseReason::Stopped);
+                }
+            }
+            if failed {
+                return control.close(CloseReason::Failed);
+            }
+            if stopping {
+                break;
+            }
+        }
+
+        for result in finish_results(&mut pipeline) {
+            let _ = public_tx.send_async((epoch, result)).await;
+        }
+        if control.stopped.load(Ordering::Acquire) {
+            return control.close(CloseReason::Stopped);
+        }
+
+        let reopened = match &reconnect {
+            Some(reconnect) => {
+                reconnect
+                    .reopen_async(&control, &public_tx, &status_tx)
+                    .await
+            }
+            None => None,
+        };
+        match reopened {
+            Some(rx) => {
+                if reconnect.as_ref().is_some_and(|reconnect| reconnect.once) {
+                    reconnect = None;
+                }
+                pipeline.reset();
+                pipeline.resync_position();
+                control.emit(CaptureEvent::DeviceChanged);
+                backend_rx = rx;
+                silence = pipeline.silence_timeout.map(SilenceWatch::new);
+            }
+            None => {
+                let _ = status_tx.send(CaptureStatus::DeviceLost);
+                return control.close(CloseReason::DeviceLost);
+            }
+        }
+    }
+}
+
+/// Blocking equivalent of `run_async` for the dedicated delivery thread.
+pub(crate) fn run_blocking(
+    mut pipeline: Pipeline,
+    mut backend_rx: flume::Receiver<Chunk>,
+    public_tx: QueueSender<Delivery>,
+    status_tx: flume::Sender<CaptureStatus>,
+    control: Arc<Control>,
+    mut reconnect: Option<Reconnect>,
+) {
+    raise_thread_priority();
+
+    let mut epoch = 0;
+    let mut silence = pipeline.silence_timeout.map(SilenceWatch::new);
+    loop {
+        loop {
+            let chunk = match next_chunk_blocking(&backend_rx, &mut silence) {
+                Next::Chunk(chunk) => chunk,
+                Next::Closed => break,
+                Next::Silent(reason) => {
+                    let results = finish_results(&mut pipeline);
+                    for result in results
+                        .into_iter()
+                        .chain([Err(AecError::DeviceUnavailable)])
+                    {
+                        let _ = public_tx.send((epoch, result));
+                    }
+                    return control.close(reason);
+                }
+            };
+            let stopping = control.stopped.load(Ordering::Acquire);
+            let deliveries = step(&mut pipeline, &control, &mut epoch, chunk);
+            let failed = deliveries.iter().any(|(_, r)| r.is_err());
+            for delivery in deliveries {
+                if !public_tx.send(delivery) {
+                    return control.close(CloseReason::Stopped);
+                }
+            }
+            if failed {
+                return control.close(CloseReason::Failed);
+            }
+            if stopping {
+                break;
+            }
+        }
+
+        for result in finish_results(&mut pipeline) {
+            let _ = public_tx.send((epoch, result));
+        }
+        if control.stopped.load(Ordering::Acquire) {
+            return control.close(CloseReason::Stopped);
+        }
+
+        let reopened = reconnect
+            .as_ref()
+            .and_then(|reconnect| reconnect.reopen_blocking(&control, &public_tx, &status_tx));
+        match reopened {
+            Some(rx) => {
+                if reconnect.as_ref().is_some_and(|reconnect| reconnect.once) {
+                    reconnect = None;
+                }
+                pipeline.reset();
+                pipeline.resync_position();
+                control.emit(CaptureEvent::DeviceChanged);
+                backend_rx = rx;
+                silence = pipeline.silence_timeout.map(SilenceWatch::new);
+            }
+            None => {
+                let _ = status_tx.send(CaptureStatus::DeviceLost);
+                return control.close(CloseReason::DeviceLost);
+            }
+        }
+    }
+}
+
+/// What the delivery loop waits for next
+enum Next {
+    Chunk(Chunk),
+    /// Every backend sender has dropped
+    Closed,
+    /// `AecConfig::silence_timeout` ran out
+    Silent(CloseReason),
+}
+
+/// Tracks `AecConfig::silence_timeout`: how long since the backend last
+/// delivered a chunk, and since one last held sound.
+struct SilenceWatch {
+    timeout: Duration,
+    last_chunk: Instant,
+    last_sound: Instant,
+}
+
+impl SilenceWatch {
+    fn new(timeout: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            timeout,
+            last_chunk: now,
+            last_sound: now,
+        }
+    }
+
+    /// Empty chunks only wake the loop, so they don't count
+    fn observe(&mut self, samples: &[f32]) {
+        if samples.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        self.last_chunk = now;
+        if samples.iter().any(|s| s.abs() > SILENCE_FLOOR) {
+            self.last_sound = now;
+        }
+    }
+
+    /// How long to wait for the next chunk before checking again
+    fn remaining(&self) -> Duration {
+        self.timeout.saturating_sub(self.last_sound.elapsed())
+    }
+
+    fn expired(&self) -> Option<CloseReason> {
+        (self.last_sound.elapsed() >= self.timeout).then(|| CloseReason::SilenceTimeout {
+            no_chunks: self.last_chunk.elapsed() >= self.timeout,
+        })
+    }
+
+    /// A received chunk, unless the window ran out while waiting for it
+    fn check(&mut self, chunk: Chunk) -> Next {
+        self.observe(&chunk.samples);
+        self.expired().map_or(Next::Chunk(chunk), Next::Silent)
+    }
+}
+
+async fn next_chunk_async(
+    backend_rx: &flume::Receiver<Chunk>,
+    silence: &mut Option<SilenceWatch>,
+) -> Next {
+    let Some(watch) = silence else {
+        return backend_rx
+            .recv_async()
+            .await
+            .map_or(Next::Closed, Next::Chunk);
+    };
+    loop {
+        match tokio::time::timeout(watch.remaining(), backend_rx.recv_async()).await {
+            Ok(Ok(chunk)) => return watch.check(chunk),
+            Ok(Err(_)) => return Next::Closed,
+            Err(_) => {
+                if let Some(reason) = watch.expired() {
+                    return Next::Silent(reason);
+                }
+            }
+        }
+    }
+}
+
+fn next_chunk_blocking(
+    backend_rx: &flume::Receiver<Chunk>,
+    silence: &mut Option<SilenceWatch>,
+) -> Next {
+    let Some(watch) = silence else {
+        return backend_rx.recv().map_or(Next::Closed, Next::Chunk);
+    };
+    loop {
+        match backend_rx.recv_timeout(watch.remaining()) {
+            Ok(chunk) => return watch.check(chunk),
+            Err(flume::RecvTimeoutError::Disconnected) => return Next::Closed,
+            Err(flume::RecvTimeoutError::Timeout) => {
+                if let Some(reason) = watch.expired() {
+                    return Next::Silent(reason);
+                }
+            }
+        }
+    }
+}
+
+/// Opens the backend again after device loss, per `AecConfig::auto_reconnect`.
+pub(crate) struct Reconnect {
+    pub config: ReconnectConfig,
+    pub queue_depth: usize,
+    pub policy: OverflowPolicy,
+    /// Opens a stream delivering into the sender and installs its handle
+    pub open: OpenFn,
+    /// Reopen after the first loss only. For file input, where only a
+    /// `FileFault` is a loss and the end of the file is final.
+    pub once: bool,
+}
+
+/// Backend opener held by `Reconnect`
+pub(crate) type OpenFn = Arc<dyn Fn(ChunkSender) -> Result<(), AecError> + Send + Sync>;
+
+impl Reconnect {
+    /// Retry with exponential backoff until a stream opens, returning its
+    /// receiver, or None once attempts run out or the handle goes away.
+    async fn reopen_async(
+        &self,
+        control: &Control,
+        public_tx: &QueueSender<Delivery>,
+        status_tx: &flume::Sender<CaptureStatus>,
+    ) -> Option<flume::Receiver<Chunk>> {
+        let mut delay = self.config.initial_delay;
+        for attempt in 1.. {
+            if self.config.max_attempts.is_some_and(|max| attempt > max) {
+                return None;
+            }
+            let _ = status_tx.send(CaptureStatus::Reconnecting { attempt });
+
+            // A stop or pause wakes the wait through the new channel
+            let (tx, rx) = flume::bounded(self.queue_depth);
+            control.set_wake(&tx);
+            let _ = tokio::time::timeout(delay, rx.recv_async()).await;
+            if control.stopped.load(Ordering::Acquire) || public_tx.is_disconnected() {
+                return None;
+            }
+
+            let open = self.open.clone();
+            let tx = ChunkSender::new(tx, &rx, self.policy);
+            match tokio::task::spawn_blocking(move || open(tx)).await {
+                Ok(Ok(())) => return self.reopened(rx, attempt, status_tx),
+                Ok(Err(e)) => tracing::warn!("reconnect attempt {attempt} failed: {e}"),
+                Err(e) => tracing::warn!("reconnect attempt {attempt} failed: {e}"),
+            }
+            delay = (delay * 2).min(self.config.max_delay);
+        }
+        None
+    }
+
+    /// Blocking equivalent of `reopen_async`.
+    fn reopen_blocking(
+        &self,
+        control: &Control,
+        public_tx: &QueueSender<Delivery>,
+        status_tx: &flume::Sender<CaptureStatus>,
+    ) -> Option<flume::Receiver<Chunk>> {
+        let mut delay = self.config.initial_delay;
+        for attempt in 1.. {
+            if self.config.max_attempts.is_some_and(|max| attempt > max) {
+                return None;
+            }
+            let _ = status_tx.send(CaptureStatus::Reconnecting { attempt });
+
+            let (tx, rx) = flume::bounded(self.queue_depth);
+            control.set_wake(&tx);
+            let _ = rx.recv_timeout(delay);
+            if control.stopped.load(Ordering::Acquire) || public_tx.is_disconnected() {
+                return None;
+            }
+
+            match (self.open)(ChunkSender::new(tx, &rx, self.policy)) {
+                Ok(()) => return self.reopened(rx, attempt, status_tx),
+                Err(e) => tracing::warn!("reconnect attempt {attempt} failed: {e}"),
+            }
+            delay = (delay * 2).min(self.config.max_delay);
+        }
+        None
+    }
+
+    fn reopened(
+        &self,
+        rx: flume::Receiver<Chunk>,
+        attempt: u32,
+        status_tx: &flume::Sender<CaptureStatus>,
+    ) -> Option<flume::Receiver<Chunk>> {
+        tracing::info!("capture reconnected after {attempt} attempt(s)");
+        let _ = status_tx.send(CaptureStatus::Reconnected);
+        Some(rx)
+    }
+}
+
+fn finish_results(pipeline: &mut Pipeline) -> Vec<Result<AudioFrame, AecError>> {
+    match pipeline.finish() {
+        Ok(chunks) => chunks.into_iter().map(Ok).collect(),
+        Err(e) => vec![Err(e)],
+    }
+}
+
+/// Best-effort bump of the current thread to realtime priority.
+/// Unprivileged processes are often refused (e.g. Linux without rtprio),
+/// in which case delivery continues at normal priority.
+#[cfg(unix)]
+fn raise_thread_priority() {
+    unsafe {
+        let max = libc::sched_get_priority_max(libc::SCHED_FIFO);
+        let mut param: libc::sched_param = std::mem::zeroed();
+        param.sched_priority = max;
+        let rc = libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param);
+        if rc != 0 {
+            tracing::debug!("could not raise delivery thread priority: {rc}");
+        }
+    }
+}
+
+#[cfg(windows)]
+fn raise_thread_priority() {
+    const THREAD_PRIORITY_TIME_CRITICAL: i32 = 15;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentThread() -> *mut std::ffi::c_void;
+        fn SetThreadPriority(thread: *mut std::ffi::c_void, priority: i32) -> i32;
+    }
+
+    let ok = unsafe { SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_TIME_CRITICAL) };
+    if ok == 0 {
+        tracing::debug!("could not raise delivery thread priority");
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn raise_thread_priority() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> StreamInfo {
+        StreamInfo {
+            sample_rate: 16000,
+            buffer_size: 160,
+            channels: 1,
+            playback_rate: None,
+            sharing_mode: None,
+            backend: "test",
+            aec: false,
+        }
+    }
+
+    /// Stamps test input the way `ChunkSender` does
+    #[derive(Default)]
+    struct Input(u64);
+
+    impl Input {
+        fn next(&mut self, samples: Vec<f32>) -> Chunk {
+            let start = self.0;
+            self.0 += samples.len() as u64;
+            Chunk { start, samples }
+        }
+    }
+
+    #[test]
+    fn retarget_keeps_every_sample_at_the_boundary() {
+        let config = AecConfig {
+            sample_rate: 16000,
+            ..Default::default()
+        };
+        let mut pipeline = Pipeline::new(&info(), &config).unwrap();
+        let control = Control::default();
+        let mut epoch = 0;
+        let mut stamps = Input::default();
+
+        let input: Vec<f32> = (0..3200).map(|i| i as f32 / 3200.0).collect();
+        let mut output = Vec::new();
+        for chunk in input[..1600].chunks(160) {
+            for (_, result) in step(
+                &mut pipeline,
+                &control,
+                &mut epoch,
+                stamps.next(chunk.to_vec()),
+            ) {
+                output.extend(result.unwrap());
+            }
+        }
+
+        *control.retarget.lock().unwrap() = Some(Retarget {
+            rate: 8000,
+            resampler: Pipeline::resampler_for(
+                &info(),
+                8000,
+                latency::LatencyPlan::default().frame_ms,
+                None,
+            )
+            .unwrap(),
+        });
+        let before = output.len();
+        for chunk in input[1600..].chunks(160) {
+            for (_, result) in step(
+                &mut pipeline,
+                &control,
+                &mut epoch,
+                stamps.next(chunk.to_vec()),
+            ) {
+                let frame = result.unwrap();
+                assert_eq!(frame.sample_rate(), 8000);
+                output.extend(frame);
+            }
+        }
+
+        // Native-rate audio passes through untouched up to the switch
+        assert_eq!(&output[..before], &input[..1600]);
+        // Then 10 ms of 16 kHz input becomes 80 frames at 8 kHz
+        assert_eq!(output.len() - before, 800);
+    }
+
+    #[test]
+    fn load_is_published_for_audio_but_not_wakeups() {
+        let config = AecConfig {
+            sample_rate: 48000,
+            ..Default::default()
+        };
+        let mut pipeline = Pipeline::new(&info(), &config).unwrap();
+        let control = Control::default();
+        let mut epoch = 0;
+        let mut input = Input::default();
+
+        step(&mut pipeline, &control, &mut epoch, Chunk::default());
+        assert_eq!(f32::from_bits(control.load.load(Ordering::Relaxed)), 0.0);
+
+        step(
+            &mut pipeline,
+            &control,
+            &mut epoch,
+            input.next(vec![0.1; 1600]),
+        );
+        let load = f32::from_bits(control.load.load(Ordering::Relaxed));
+        assert!(load > 0.0 && load < 100.0, "load {load}");
+    }
+
+    #[test]
+    fn signal_lasts_half_a_second_past_the_last_sound() {
+        let config = AecConfig {
+            sample_rate: 16000,
+            ..Default::default()
+        };
+        let mut pipeline = Pipeline::new(&info(), &config).unwrap();
+        let control = Control::default();
+        let mut epoch = 0;
+        let mut input = Input::default();
+        let signal = |control: &Control| control.signal.load(Ordering::Relaxed);
+
+        step(
+            &mut pipeline,
+            &control,
+            &mut epoch,
+            input.next(vec![0.0; 160]),
+        );
+        assert!(!signal(&control));
+
+        step(
+            &mut pipeline,
+            &control,
+            &mut epoch,
+            input.next(vec![0.01; 160]),
+        );
+        assert!(signal(&control));
+        // Dither below the floor, up to 500 ms of it
+        for _ in 0..50 {
+            step(
+                &mut pipeline,
+                &control,
+                &mut epoch,
+                input.next(vec![1e-5; 160]),
+            );
+            assert!(signal(&control));
+        }
+        step(
+            &mut pipeline,
+            &control,
+            &mut epoch,
+            input.next(vec![1e-5; 160]),
+        );
+        assert!(!signal(&control));
+    }
+
+    #[test]
+    fn dry_run_processes_a_device_buffer() {
+        let odd = StreamInfo {
+            sample_rate: 44100,
+            buffer_size: 4096,
+            channels: 2,
+            ..info()
+        };
+        let config = AecConfig {
+            output_chunk_frames: Some(100),
+            retain_recent: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let mut pipeline = Pipeline::new(&odd, &config).unwrap();
+        pipeline.dry_run(odd.buffer_size).unwrap();
+        // The silence leaves nothing behind for the first real chunk
+        let recent = pipeline.recent_audio().unwrap();
+        assert!(recent.lock().unwrap().snapshot().is_empty());
+        assert!(pipeline.finish().unwrap().is_empty());
+
+        let config = AecConfig {
+            output_chunk_frames: Some(0),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Pipeline::new(&odd, &config),
+            Err(AecError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn disabled_resampling_delivers_the_native_rate() {
+        let config = AecConfig {
+            sample_rate: 48000,
+            resample: false,
+            ..Default::default()
+        };
+        let mut pipeline = Pipeline::new(&info(), &config).unwrap();
+        assert_eq!(pipeline.output_rate(), 16000);
+
+        let input = vec![0.25; 160];
+        let chunks = pipeline.process(input.clone()).unwrap();
+        assert_eq!(chunks, vec![AudioFrame::new(input, 16000, 1)]);
+    }
+
+    #[test]
+    fn process_hook_runs_before_the_channel_layout() {
+        let config = AecConfig {
+            sample_rate: 16000,
+            channels: Channels::Stereo,
+            process_hook: Some(ProcessHook::new(|samples| {
+                // A gate that closes on quiet chunks
+                if dsp::rms(samples) < 0.1 {
+                    samples.fill(0.0);
+                }
+            })),
+            ..Default::default()
+        };
+        let mut pipeline = Pipeline::new(&info(), &config).unwrap();
+        pipeline.dry_run(info().buffer_size).unwrap();
+
+        let quiet = pipeline.process(vec![0.05; 160]).unwrap();
+        assert_eq!(quiet, vec![AudioFrame::new(vec![0.0; 320], 16000, 2)]);
+        let loud = pipeline.process(vec![0.5; 160]).unwrap();
+        assert_eq!(loud, vec![AudioFrame::new(vec![0.5; 320], 16000, 2)]);
+
+        let config = AecConfig {
+            sample_rate: 16000,
+            channels: Channels::StereoTrue,
+            process_hook: Some(ProcessHook::new(|samples| samples.truncate(3))),
+            ..Default::default()
+        };
+        let stereo = StreamInfo {
+            channels: 2,
+            ..info()
+        };
+        let mut pipeline = Pipeline::new(&stereo, &config).unwrap();
+        assert!(matches!(
+            pipeline.process(vec![0.5; 320]),
+            Err(AecError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn custom_resampler_replaces_the_built_in_one() {
+        /// Doubles the rate by repeating samples, holding back the last one
+        /// until flushed
+        struct Repeat(Option<f32>);
+
+        impl Resample for Repeat {
+            fn process(&mut self, input: &[f32]) -> Result<Vec<f32>, AecError> {
+                let mut output = Vec::new();
+                for &sample in input {
+                    if let Some(held) = self.0.replace(sample) {
+                        output.extend([held, held]);
+                    }
+                }
+                Ok(output)
+            }
+
+            fn flush(&mut self) -> Result<Vec<f32>, AecError> {
+                Ok(self.0.take().map_or(Vec::new(), |held| vec![held, held]))
+            }
+        }
+
+        let built = Arc::new(Mutex::new(Vec::new()));
+        let seen = built.clone();
+        let config = AecConfig {
+            sample_rate: 32000,
+            resampler: Some(ResamplerFactory::new(move |from, to, channels| {
+                seen.lock().unwrap().push((from, to, channels));
+                Ok(Box::new(Repeat(None)))
+            })),
+            ..Default::default()
+        };
+        let mut pipeline = Pipeline::new(&info(), &config).unwrap();
+        pipeline.dry_run(info().buffer_size).unwrap();
+        // The dry run reuses the converter instead of building another
+        assert_eq!(*built.lock().unwrap(), vec![(16000, 32000, 1)]);
+        assert_eq!(pipeline.latency(), Duration::ZERO);
+
+        let delivered = pipeline.process(vec![0.5; 160]).unwrap();
+        assert_eq!(delivered.iter().map(|c| c.len()).sum::<usize>(), 318);
+        let tail = pipeline.finish().unwrap();
+        assert_eq!(tail, vec![AudioFrame::new(vec![0.5; 2], 32000, 1)]);
+    }
+
+    #[test]
+    fn stereo_true_keeps_left_and_right() {
+        let config = AecConfig {
+            sample_rate: 16000,
+            channels: Channels::StereoTrue,
+            ..Default::default()
+        };
+        let quad = StreamInfo {
+            channels: 4,
+            ..info()
+        };
+        let mut pipeline = Pipeline::new(&quad, &config).unwrap();
+        assert_eq!(pipeline.output_channels(), 2);
+        let chunks = pipeline
+            .process(vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8])
+            .unwrap();
+        assert_eq!(
+            chunks,
+            vec![AudioFrame::new(vec![0.1, 0.2, 0.5, 0.6], 16000, 2)]
+        );
+
+        let mut pipeline = Pipeline::new(&info(), &config).unwrap();
+        let chunks = pipeline.process(vec![0.1, 0.2]).unwrap();
+        assert_eq!(
+            chunks,
+            vec![AudioFrame::new(vec![0.1, 0.1, 0.2, 0.2], 16000, 2)]
+        );
+    }
+
+    #[test]
+    fn duplicated_channels_are_not_distinct() {
+        assert_eq!(distinct_channels(Channels::Stereo, 2), 1);
+        assert_eq!(distinct_channels(Channels::StereoTrue, 1), 1);
+        assert_eq!(distinct_channels(Channels::StereoTrue, 4), 2);
+        assert_eq!(distinct_channels(Channels::Native, 4), 4);
+        assert_eq!(distinct_channels(Channels::Mono, 4), 1);
+    }
+
+    #[test]
+    fn chunks_reuse_buffers_handed_back() {
+        let config = AecConfig {
+            sample_rate: 16000,
+            output_chunk_frames: Some(80),
+            ..Default::default()
+        };
+        let mut pipeline = Pipeline::new(&info(), &config).unwrap();
+        let buffers = pipeline.buffer_pool();
+
+        // Nothing handed back: every chunk is a new buffer
+        let first = pipeline.process(vec![0.1; 160]).unwrap();
+        assert_eq!(buffers.allocated(), 2);
+        let reused: Vec<_> = first.iter().map(|c| c.as_ptr()).collect();
+        for chunk in first {
+            buffers.give(chunk.into());
+        }
+
+        for _ in 0..10 {
+            let chunks = pipeline.process(vec![0.2; 160]).unwrap();
+            assert_eq!(chunks.len(), 2);
+            for chunk in chunks {
+                assert!(reused.contains(&chunk.as_ptr()));
+                assert_eq!(chunk.as_slice(), &[0.2; 80][..]);
+                buffers.give(chunk.into());
+            }
+        }
+        assert_eq!(buffers.allocated(), 2);
+    }
+
+    #[test]
+    fn channel_switch_flushes_the_old_layout_and_downmixes() {
+        let config = AecConfig {
+            sample_rate: 16000,
+            channels: Channels::StereoTrue,
+            output_chunk_frames: Some(4),
+            ..Default::default()
+        };
+        let stereo = StreamInfo {
+            channels: 2,
+            ..info()
+        };
+        let mut pipeline = Pipeline::new(&stereo, &config).unwrap();
+        assert!(pipeline
+            .process(vec![0.2, 0.4, 0.6, 0.8])
+            .unwrap()
+            .is_empty());
+
+        let remaining = pipeline.set_channels(Channels::Mono);
+        assert_eq!(
+            remaining,
+            Some(AudioFrame::new(vec![0.2, 0.4, 0.6, 0.8], 16000, 2))
+        );
+        assert_eq!(pipeline.output_channels(), 1);
+
+        let chunks = pipeline
+            .process(vec![0.0, 1.0, 0.5, 0.5, 1.0, 0.0, 0.25, 0.75])
+            .unwrap();
+        assert_eq!(
+            chunks,
+            vec![AudioFrame::new(vec![0.5, 0.5, 0.5, 0.5], 16000, 1)]
+        );
+    }
+
+    #[test]
+    fn timeline_counts_gaps_and_keeps_output_frames_across_rate_changes() {
+        let mut timeline = Timeline::new(2);
+        let chunk = |start: u64| Chunk {
+            start,
+            samples: vec![0.0; 320],
+        };
+
+        // Stamps needn't start at zero, as after a shared stream's history
+        assert_eq!(timeline.advance(&chunk(640)), 160);
+        assert_eq!(timeline.advance(&chunk(960)), 320);
+        // One chunk dropped between these two
+        assert_eq!(timeline.advance(&chunk(1600)), 640);
+        assert_eq!(timeline.advance(&Chunk::default()), 640);
+        assert_eq!(timeline.output_frames(16000, 48000), 1920);
+
+        // 640 frames counted at 48 kHz, the rest at 8 kHz
+        timeline.rate_changed(16000, 48000);
+        assert_eq!(timeline.output_frames(16000, 8000), 1920);
+        timeline.advance(&chunk(1920));
+        assert_eq!(timeline.output_frames(16000, 8000), 2000);
+
+        // A reopened stream starts its stamps over
+        timeline.resync();
+        assert_eq!(timeline.advance(&chunk(0)), 960);
+        assert_eq!(timeline.output_frames(16000, 8000), 2080);
+    }
+
+    #[test]
+    fn lost_backend_is_reopened_until_attempts_run_out() {
+        let config = AecConfig {
+            sample_rate: 16000,
+            ..Default::default()
+        };
+        let pipeline = Pipeline::new(&info(), &config).unwrap();
+        let (backend_tx, backend_rx) = flume::bounded(4);
+        let (control, shared_control) = ControlHandle::new(&backend_tx);
+        drop(backend_tx);
+
+        // Only the first reopen finds the device, which delivers one chunk
+        let opened = Arc::new(AtomicBool::new(false));
+        let open: OpenFn = Arc::new(move |tx: ChunkSender| {
+            if opened.swap(true, Ordering::AcqRel) {
+                return Err(AecError::DeviceUnavailable);
+            }
+            assert!(tx.send(vec![0.5; 160]));
+            Ok(())
+        });
+        let reconnect = Reconnect {
+            config: ReconnectConfig {
+                initial_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(1),
+                max_attempts: Some(1),
+            },
+            queue_depth: 4,
+            policy: OverflowPolicy::Block,
+            open,
+            once: false,
+        };
+
+        let (public_tx, public_rx) = flume::unbounded();
+        let (status_tx, status_rx) = flume::unbounded();
+        run_blocking(
+            pipeline,
+            backend_rx,
+            QueueSender::new(public_tx, &public_rx, OverflowPolicy::Block),
+            status_tx,
+            shared_control,
+            Some(reconnect),
+        );
+
+        let delivered: usize = public_rx
+            .try_iter()
+            .map(|(_, chunk)| chunk.unwrap().len())
+            .sum();
+        assert_eq!(delivered, 160);
+        assert_eq!(
+            status_rx.try_iter().collect::<Vec<_>>(),
+            vec![
+                CaptureStatus::Reconnecting { attempt: 1 },
+                CaptureStatus::Reconnected,
+                CaptureStatus::Reconnecting { attempt: 1 },
+                CaptureStatus::DeviceLost,
+            ]
+        );
+        drop(control);
+    }
+
+    #[test]
+    fn silence_timeout_tells_silence_from_no_chunks() {
+        let config = AecConfig {
+            sample_rate: 16000,
+            silence_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        for send_zeros in [true, false] {
+            let pipeline = Pipeline::new(&info(), &config).unwrap();
+            let (backend_tx, backend_rx) = flume::unbounded();
+            let (control, shared_control) = ControlHandle::new(&backend_tx);
+            let feeder = std::thread::spawn(move || {
+                // Held for longer than the timeout either way
+                let mut input = Input::default();
+                for _ in 0..20 {
+                    if send_zeros {
+                        let _ = backend_tx.send(input.next(vec![0.0; 160]));
+                    }
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+            });
+
+            let (public_tx, public_rx) = flume::unbounded();
+            let (status_tx, _status_rx) = flume::unbounded();
+            run_blocking(
+                pipeline,
+                backend_rx,
+                QueueSender::new(public_tx, &public_rx, OverflowPolicy::Block),
+                status_tx,
+                shared_control,
+                None,
+            );
+            feeder.join().unwrap();
+
+            let last = public_rx.try_iter().last().unwrap().1;
+            assert!(matches!(last, Err(AecError::DeviceUnavailable)));
+            assert_eq!(
+                control.close_reason(),
+                Some(CloseReason::SilenceTimeout {
+                    no_chunks: !send_zeros
+                })
+            );
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::backends::{BackendHandle, PlaybackDone, PlaybackSend};
+use crate::erle::EchoMeter;
+use crate::resampler::Resampler;
+use crate::{dsp, AecError};
+
+/// Playback is handed to the output in slices of this length...
+const SLICE: Duration = Duration::from_millis(20);
+/// ...each once the capture is no more than this short of where it starts
+const LEAD: Duration = Duration::from_millis(100);
+
+/// Holds `play_audio` audio back for `AecConfig::playback_sync` and
+/// releases it as the capture advances, so the output plays on the
+/// capture's clock instead of its own.
+pub(crate) struct PlaybackSync {
+    backend: Arc<Mutex<BackendHandle>>,
+    echo_meter: Arc<Mutex<EchoMeter>>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    held: VecDeque<Slice>,
+    /// Capture time at which the last held slice ends
+    scheduled: Duration,
+    /// Capture time of the latest chunk, None before the first
+    captured: Option<Duration>,
+}
+
+impl State {
+    /// Split interleaved `samples` into slices scheduled back to back from
+    /// the end of the audio already held, or from now if nothing is.
+    fn push(
+        &mut self,
+        samples: &[f32],
+        sample_rate: u32,
+        channels: u16,
+        done: Option<PlaybackDone>,
+    ) {
+        let mut start = self.scheduled.max(self.captured.unwrap_or_default());
+        let slice_len =
+            (SLICE.as_secs_f64() * sample_rate as f64) as usize * channels.max(1) as usize;
+        // Empty audio still needs a slice to carry `done`
+        let slices: Vec<&[f32]> = if samples.is_empty() {
+            vec![&[]]
+        } else {
+            samples.chunks(slice_len.max(1)).collect()
+        };
+        for slice in slices {
+            let frames = slice.len() / channels.max(1) as usize;
+            self.held.push_back(Slice {
+                start,
+                samples: slice.to_vec(),
+                sample_rate,
+                channels,
+                done: None,
+            });
+            start += Duration::from_secs_f64(frames as f64 / sample_rate.max(1) as f64);
+        }
+        if let Some(last) = self.held.back_mut() {
+            last.done = done;
+        }
+        self.scheduled = start;
+    }
+
+    /// Move the capture time to `captured`. Time spent muted pushes the
+    /// held audio back by as much.
+    fn advance(&mut self, captured: Duration, muted: bool) {
+        let previous = self.captured.replace(captured).unwrap_or(captured);
+        if muted {
+            let paused = captured.saturating_sub(previous);
+            for slice in &mut self.held {
+                slice.start += paused;
+            }
+            self.scheduled += paused;
+        }
+    }
+
+    /// Slices starting within `LEAD` of the capture time. Nothing is due
+    /// before the first chunk, or while muted.
+    fn due(&mut self, muted: bool) -> Vec<Slice> {
+        let Some(captured) = self.captured.filter(|_| !muted) else {
+            return Vec::new();
+        };
+        let count = self
+            .held
+            .iter()
+            .take_while(|slice| slice.start <= captured + LEAD)
+            .count();
+        self.held.drain(..count).collect()
+    }
+}
+
+struct Slice {
+    /// Capture time the slice is due to start playing at
+    start: Duration,
+    samples: Vec<f32>,
+    sample_rate: u32,
+    channels: u16,
+    done: Option<PlaybackDone>,
+}
+
+impl PlaybackSync {
+    pub fn new(backend: Arc<Mutex<BackendHandle>>, echo_meter: Arc<Mutex<EchoMeter>>) -> Self {
+        Self {
+            backend,
+            echo_meter,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Queue interleaved `samples` to start when the audio already held
+    /// ends, or now if nothing is. They are converted to the output rate
+    /// first, so the backend doesn't resample slice by slice.
+    pub fn hold(
+        &self,
+        samples: Vec<f32>,
+        sample_rate: u32,
+        channels: u16,
+        done: Option<PlaybackDone>,
+    ) -> Result<(), AecError> {
+        let output_rate = self.backend().playback_rate().unwrap_or(sample_rate);
+        let (samples, sample_rate) = if output_rate == sample_rate {
+            (samples, sample_rate)
+        } else {
+            let converted = Resampler::convert(&samples, sample_rate, output_rate, channels)?;
+            (converted, output_rate)
+        };
+
+        let Ok(mut state) = self.state.lock() else {
+            return Ok(());
+        };
+        state.push(&samples, sample_rate, channels, done);
+        self.release(&mut state);
+        Ok(())
+    }
+
+    /// Note that the capture reached `position` input frames at `rate`,
+    /// handing the output whatever is now due. While the output is muted
+    /// the held audio waits instead, so unmuting doesn't release it at once.
+    pub fn advance(&self, position: u64, rate: u32) {
+        let captured = Duration::from_secs_f64(position as f64 / rate.max(1) as f64);
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        let muted = self.backend().is_playback_muted();
+        state.advance(captured, muted);
+        self.release(&mut state);
+    }
+
+    /// Send the slices that are due, in order. Called under the state lock
+    /// so two threads releasing at once can't reorder them.
+    fn release(&self, state: &mut State) {
+        let backend = self.backend();
+        for slice in state.due(backend.is_playback_muted()) {
+            // A closed backend drops `done`, which fails the caller's future
+            let _ = play(
+                &backend,
+                &self.echo_meter,
+                slice.samples,
+                slice.sample_rate,
+                slice.channels,
+                slice.done,
+                true,
+            );
+        }
+    }
+
+    fn backend(&self) -> BackendHandle {
+        match self.backend.lock() {
+            Ok(backend) => backend.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+}
+
+/// Hand playback to `backend`, recording it as the echo reference. A full
+/// queue blocks with `block`, and otherwise hands back a future that
+/// queues the audio.
+pub(crate) fn play(
+    backend: &BackendHandle,
+    echo_meter: &Mutex<EchoMeter>,
+    samples: Vec<f32>,
+    sample_rate: u32,
+    channels: u16,
+    done: Option<PlaybackDone>,
+    block: bool,
+) -> Result<Option<PlaybackSend>, AecError> {
+    if let Ok(mut meter) = echo_meter.lock() {
+        match channels {
+            1 => meter.push_reference(&samples, sample_rate),
+            _ => meter.push_reference(&dsp::downmix(&samples, channels as usize), sample_rate),
+        }
+    }
+    if block {
+        backend
+            .play_audio(samples, sample_rate, channels, done)
+            .map(|()| None)
+    } else {
+        backend.play_audio_async(samples, sample_rate, channels, done)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn starts(slices: &[Slice]) -> Vec<Duration> {
+        slices.iter().map(|slice| slice.start).collect()
+    }
+
+    #[test]
+    fn playback_follows_the_capture_clock() {
+        let mut state = State::default();
+        // 200 ms queued before capture starts: nothing plays yet
+        state.push(&[0.5; 9600], 48000, 1, None);
+        assert!(state.due(false).is_empty());
+
+        state.advance(ms(10), false);
+        let due = state.due(false);
+        assert_eq!(
+            starts(&due),
+            [ms(0), ms(20), ms(40), ms(60), ms(80), ms(100)]
+        );
+        assert!(due.iter().all(|slice| slice.samples.len() == 960));
+
+        state.advance(ms(50), false);
+        assert_eq!(starts(&state.due(false)), [ms(120), ms(140)]);
+
+        // Muted for 100 ms of capture: the rest moves back by as much
+        state.advance(ms(150), true);
+        assert!(state.due(true).is_empty());
+        state.advance(ms(160), false);
+        assert_eq!(starts(&state.due(false)), [ms(260)]);
+
+        // Audio queued after playback ran dry starts at the capture time
+        state.advance(ms(500), false);
+        assert_eq!(starts(&state.due(false)), [ms(280)]);
+        let (done_tx, _done_rx) = flume::bounded(1);
+        state.push(&[], 16000, 1, Some(done_tx));
+        let due = state.due(false);
+        assert_eq!(starts(&due), [ms(500)]);
+        assert!(due[0].done.is_some());
+    }
+}
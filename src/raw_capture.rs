@@ -0,0 +1,112 @@
+//! Tee of the bytes a backend receives from the OS, before any decoding,
+//! for diagnosing format problems such as a driver sending a different
+//! sample width than it reports.
+
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Sample format the OS reported for a chunk of raw bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawFormat {
+    pub sample_rate: u32,
+    /// Interleaved channels per frame
+    pub channels: u16,
+    /// Container size of each sample
+    pub bits_per_sample: u16,
+    /// Bits the OS says carry signal, at most `bits_per_sample`
+    pub valid_bits: u16,
+    /// IEEE float rather than signed integer samples
+    pub float: bool,
+}
+
+/// Boxed callback held by `RawCapture`
+pub type RawCaptureFn = Box<dyn FnMut(&RawFormat, &[u8]) + Send>;
+
+/// Receives the backend's input exactly as the OS delivered it. Called
+/// on the capture thread, which is a realtime audio callback on iOS, so
+/// keep it short. Clones share the callback.
+///
+/// Only Windows and iOS decode device bytes themselves; the other
+/// backends are handed `f32` samples and never call it.
+#[derive(Clone)]
+pub struct RawCapture(Arc<Mutex<RawCaptureFn>>);
+
+impl RawCapture {
+    pub fn new(tee: impl FnMut(&RawFormat, &[u8]) + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(Box::new(tee))))
+    }
+
+    /// Append raw bytes to a new file at `path`, headerless. The format is
+    /// logged when the first chunk arrives and whenever it changes.
+    pub fn file(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = std::fs::File::create(&path)?;
+        let mut logged: Option<RawFormat> = None;
+        Ok(Self::new(move |format, bytes| {
+            if logged != Some(*format) {
+                tracing::info!("raw capture to {}: {format:?}", path.display());
+                logged = Some(*format);
+            }
+            if let Err(e) = file.write_all(bytes) {
+                tracing::warn!("raw capture to {} failed: {e}", path.display());
+            }
+        }))
+    }
+
+    /// Pass a chunk to the callback. Skipped rather than waited for if a
+    /// previous call is still running, since the caller may be realtime.
+    #[cfg_attr(not(any(target_os = "windows", target_os = "ios")), allow(dead_code))]
+    pub(crate) fn tee(&self, format: &RawFormat, bytes: &[u8]) {
+        if let Ok(mut tee) = self.0.try_lock() {
+            tee(format, bytes);
+        }
+    }
+}
+
+impl std::fmt::Debug for RawCapture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RawCapture")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMAT: RawFormat = RawFormat {
+        sample_rate: 48000,
+        channels: 2,
+        bits_per_sample: 32,
+        valid_bits: 24,
+        float: false,
+    };
+
+    #[test]
+    fn callback_sees_format_and_bytes_unchanged() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let raw = RawCapture::new(move |format, bytes| {
+            sink.lock().unwrap().push((*format, bytes.to_vec()));
+        });
+
+        raw.clone().tee(&FORMAT, &[1, 2, 3, 4]);
+        raw.tee(&FORMAT, &[5, 6]);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![(FORMAT, vec![1, 2, 3, 4]), (FORMAT, vec![5, 6])]
+        );
+    }
+
+    #[test]
+    fn file_appends_every_chunk() {
+        let path = std::env::temp_dir().join(format!("sys-voice-raw-{}.bin", std::process::id()));
+        let raw = RawCapture::file(&path).unwrap();
+        raw.tee(&FORMAT, &[0xde, 0xad]);
+        raw.tee(&FORMAT, &[0xbe, 0xef]);
+        drop(raw);
+
+        assert_eq!(std::fs::read(&path).unwrap(), vec![0xde, 0xad, 0xbe, 0xef]);
+        let _ = std::fs::remove_file(&path);
+    }
+}
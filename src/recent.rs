@@ -0,0 +1,59 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// The last `AecConfig::retain_recent` of delivered audio, kept for
+/// `CaptureHandle::snapshot_recent` alongside normal delivery.
+pub(crate) struct RecentAudio {
+    samples: VecDeque<f32>,
+    retention: Duration,
+    sample_rate: u32,
+    channels: u16,
+}
+
+impl RecentAudio {
+    pub fn new(retention: Duration) -> Self {
+        Self {
+            samples: VecDeque::new(),
+            retention,
+            sample_rate: 0,
+            channels: 0,
+        }
+    }
+
+    /// Append interleaved output audio, dropping the oldest past the
+    /// retention. A format change starts the buffer over, so a snapshot
+    /// is always in one format.
+    pub fn push(&mut self, samples: &[f32], sample_rate: u32, channels: u16) {
+        if (sample_rate, channels) != (self.sample_rate, self.channels) {
+            self.samples.clear();
+            self.sample_rate = sample_rate;
+            self.channels = channels;
+        }
+        let capacity =
+            (self.retention.as_secs_f64() * sample_rate as f64) as usize * channels.max(1) as usize;
+        self.samples.extend(samples);
+        let excess = self.samples.len().saturating_sub(capacity);
+        self.samples.drain(..excess);
+    }
+
+    pub fn snapshot(&self) -> Vec<f32> {
+        self.samples.iter().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_newest_audio_in_the_current_format() {
+        let mut recent = RecentAudio::new(Duration::from_millis(500));
+        recent.push(&[0.1; 3], 4, 2);
+        recent.push(&[0.2, 0.2, 0.3, 0.3], 4, 2);
+        // Half a second of 4 Hz stereo is four samples
+        assert_eq!(recent.snapshot(), vec![0.2, 0.2, 0.3, 0.3]);
+
+        recent.push(&[0.5], 4, 1);
+        assert_eq!(recent.snapshot(), vec![0.5]);
+    }
+}
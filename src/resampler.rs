@@ -1,18 +1,92 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use rubato::{FftFixedIn, Resampler as RubatoResampler};
 
-use crate::AecError;
+use crate::{dsp, AecError};
+
+/// Processing frame used unless a latency target asks for smaller ones
+pub(crate) const FRAME_DURATION_MS: usize = 10;
+
+/// Output rates at or below this are narrowband telephony audio, whose
+/// 300-3400 Hz voice band reaches close to the output's Nyquist limit
+const NARROWBAND_RATE: u32 = 8000;
+
+/// Shortest frame for narrowband output. The filter's transition band
+/// narrows as chunks grow; shorter frames roll off the top of the voice
+/// band (10 ms frames lose 10 dB at 3.4 kHz).
+const NARROWBAND_FRAME_MS: usize = 20;
+
+/// Sample rate converter for captured audio, plugged in through
+/// `ResamplerFactory`. Input and output are interleaved at the channel
+/// count the converter was built for. Called from the delivery thread or
+/// task, so it must keep up with real time.
+pub trait Resample: Send {
+    /// Convert the next stretch of input. May return nothing while input
+    /// is being buffered.
+    fn process(&mut self, input: &[f32]) -> Result<Vec<f32>, AecError>;
+
+    /// Return whatever input is still buffered once capture ends, before a
+    /// rate change or across a pause, converted.
+    fn flush(&mut self) -> Result<Vec<f32>, AecError>;
 
-const FRAME_DURATION_MS: usize = 10;
+    /// Drop buffered input and filter history after `flush`, so audio
+    /// after a gap starts fresh.
+    fn reset(&mut self) {}
+
+    /// Worst-case delay added between input and output, counted into
+    /// `CaptureHandle::input_latency`.
+    fn latency(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+/// Builds the converter for `from_rate` to `to_rate` at `channels`
+type BuildFn = dyn Fn(u32, u32, u16) -> Result<Box<dyn Resample>, AecError> + Send + Sync;
+
+/// Constructor for a custom `Resample`, for `AecConfig::resampler`. Called
+/// with the device rate, the output rate and the interleaved channel count
+/// each time the capture rate conversion is set up: at open, on
+/// `set_output_sample_rate` and for each reconnect. Clones share the
+/// constructor.
+#[derive(Clone)]
+pub struct ResamplerFactory(Arc<BuildFn>);
+
+impl ResamplerFactory {
+    pub fn new(
+        build: impl Fn(u32, u32, u16) -> Result<Box<dyn Resample>, AecError> + Send + Sync + 'static,
+    ) -> Self {
+        Self(Arc::new(build))
+    }
+
+    pub(crate) fn build(
+        &self,
+        from_rate: u32,
+        to_rate: u32,
+        channels: u16,
+    ) -> Result<Box<dyn Resample>, AecError> {
+        (self.0)(from_rate, to_rate, channels)
+    }
+}
+
+impl std::fmt::Debug for ResamplerFactory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ResamplerFactory")
+    }
+}
 
 /// Wraps rubato for sample rate conversion when the OS backend
 /// uses a different rate than requested by the user.
 /// Handles variable-sized input by accumulating samples.
+/// Input and output are interleaved; rubato works on planar channels.
 pub(crate) struct Resampler {
     resampler: FftFixedIn<f32>,
     input_buffer: Vec<Vec<f32>>,
     output_buffer: Vec<Vec<f32>>,
     accumulator: Vec<f32>,
     chunk_size: usize,
+    channels: usize,
+    latency: Duration,
 }
 
 impl Resampler {
@@ -20,11 +94,54 @@ impl Resampler {
     ///
     /// - `from_rate`: Native sample rate from the backend
     /// - `to_rate`: Target sample rate requested by user
-    pub fn new(from_rate: u32, to_rate: u32) -> Result<Self, AecError> {
-        let chunk_size = (from_rate as usize * FRAME_DURATION_MS) / 1000;
+    /// - `channels`: Interleaved channels per frame
+    pub fn new(from_rate: u32, to_rate: u32, channels: u16) -> Result<Self, AecError> {
+        Self::with_frame_duration(from_rate, to_rate, channels, FRAME_DURATION_MS)
+    }
+
+    /// Like `new`, processing `frame_ms` of input at a time. Shorter frames
+    /// lower latency at the cost of more per-chunk overhead. Narrowband
+    /// output uses at least `NARROWBAND_FRAME_MS`, as shorter frames would
+    /// cut into the voice band.
+    pub fn with_frame_duration(
+        from_rate: u32,
+        to_rate: u32,
+        channels: u16,
+        frame_ms: usize,
+    ) -> Result<Self, AecError> {
+        let frame_ms = if to_rate <= NARROWBAND_RATE {
+            frame_ms.max(NARROWBAND_FRAME_MS)
+        } else {
+            frame_ms
+        };
+        let chunk_size = (from_rate as usize * frame_ms) / 1000;
+        let channels = channels.max(1) as usize;
+
+        if chunk_size == 0 {
+            return Err(AecError::InvalidConfig(format!(
+                "sample rate {from_rate} Hz is too low to resample ({frame_ms} ms chunks would be empty)"
+            )));
+        }
 
-        let resampler = FftFixedIn::new(from_rate as usize, to_rate as usize, chunk_size, 1, 1)
-            .map_err(|e| AecError::BackendError(format!("resampler init failed: {e}")))?;
+        // rubato converts whole blocks of `from_rate / gcd` frames and holds
+        // back the rest of a chunk, so round up to whole blocks: every chunk
+        // then yields its exact share of output and `latency` stays honest
+        let block = (from_rate / gcd(from_rate, to_rate)) as usize;
+        let chunk_size = chunk_size.next_multiple_of(block);
+
+        let resampler = FftFixedIn::new(
+            from_rate as usize,
+            to_rate as usize,
+            chunk_size,
+            1,
+            channels,
+        )
+        .map_err(|e| AecError::BackendError(format!("resampler init failed: {e}")))?;
+
+        // A full input chunk must accumulate, then the filter adds its delay
+        let latency = Duration::from_secs_f64(
+            chunk_size as f64 / from_rate as f64 + resampler.output_delay() as f64 / to_rate as f64,
+        );
 
         let input_buffer = resampler.input_buffer_allocate(true);
         let output_buffer = resampler.output_buffer_allocate(true);
@@ -33,11 +150,18 @@ impl Resampler {
             resampler,
             input_buffer,
             output_buffer,
-            accumulator: Vec::with_capacity(chunk_size * 2),
+            accumulator: Vec::with_capacity(chunk_size * channels * 2),
             chunk_size,
+            channels,
+            latency,
         })
     }
 
+    /// Worst-case delay the resampler adds between input and output.
+    pub fn latency(&self) -> Duration {
+        self.latency
+    }
+
     /// Process samples and return resampled output.
     /// Accumulates input until enough for a fixed chunk, then processes.
     /// May return empty Vec if not enough samples accumulated yet.
@@ -45,19 +169,276 @@ impl Resampler {
         self.accumulator.extend_from_slice(input);
 
         let mut output = Vec::new();
+        let chunk_len = self.chunk_size * self.channels;
 
-        while self.accumulator.len() >= self.chunk_size {
-            self.input_buffer[0].clear();
-            self.input_buffer[0].extend(self.accumulator.drain(..self.chunk_size));
+        // Drained below one chunk before returning, so the accumulator never
+        // holds more than one chunk plus the latest input
+        while self.accumulator.len() >= chunk_len {
+            dsp::deinterleave_into(&self.accumulator[..chunk_len], &mut self.input_buffer);
+            self.accumulator.drain(..chunk_len);
 
-            let (_, samples_out) = self
+            let (_, frames_out) = self
                 .resampler
                 .process_into_buffer(&self.input_buffer, &mut self.output_buffer, None)
                 .map_err(|e| AecError::BackendError(format!("resampling failed: {e}")))?;
 
-            output.extend_from_slice(&self.output_buffer[0][..samples_out]);
+            dsp::interleave_into(&self.output_buffer, frames_out, &mut output);
         }
 
         Ok(output)
     }
+
+    /// Discard pending input and the filter's delay line.
+    pub fn reset(&mut self) {
+        self.accumulator.clear();
+        self.resampler.reset();
+    }
+
+    /// Resample whatever is left in the accumulator, padded with silence.
+    /// Output is trimmed to the frames produced by real input plus the
+    /// resampler's delay, so the tail of the signal isn't cut off.
+    pub fn flush(&mut self) -> Result<Vec<f32>, AecError> {
+        let pending_frames = self.accumulator.len() / self.channels;
+        if pending_frames == 0 {
+            self.accumulator.clear();
+            return Ok(Vec::new());
+        }
+
+        self.accumulator
+            .resize(self.chunk_size * self.channels, 0.0);
+        let mut output = self.process(&[])?;
+
+        let frames_out = output.len() / self.channels;
+        let keep_frames =
+            (pending_frames * frames_out).div_ceil(self.chunk_size) + self.resampler.output_delay();
+        output.truncate(keep_frames.min(frames_out) * self.channels);
+        Ok(output)
+    }
+
+    /// Resample a whole clip in one go, tail included, e.g. a playback
+    /// request that nothing follows.
+    pub fn convert(
+        samples: &[f32],
+        from_rate: u32,
+        to_rate: u32,
+        channels: u16,
+    ) -> Result<Vec<f32>, AecError> {
+        let mut resampler = Self::new(from_rate, to_rate, channels)?;
+        let mut output = resampler.process(samples)?;
+        output.extend(resampler.flush()?);
+        Ok(output)
+    }
+}
+
+impl Resample for Resampler {
+    fn process(&mut self, input: &[f32]) -> Result<Vec<f32>, AecError> {
+        Resampler::process(self, input)
+    }
+
+    fn flush(&mut self) -> Result<Vec<f32>, AecError> {
+        Resampler::flush(self)
+    }
+
+    fn reset(&mut self) {
+        Resampler::reset(self)
+    }
+
+    fn latency(&self) -> Duration {
+        Resampler::latency(self)
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stereo_channels_stay_separate() {
+        let mut resampler = Resampler::new(48000, 16000, 2).unwrap();
+
+        // Left carries a 440 Hz tone, right is silent
+        let input: Vec<f32> = (0..4800)
+            .flat_map(|i| {
+                let t = i as f32 / 48000.0;
+                [(2.0 * std::f32::consts::PI * 440.0 * t).sin() * 0.5, 0.0]
+            })
+            .collect();
+
+        let output = resampler.process(&input).unwrap();
+        assert!(!output.is_empty());
+        assert_eq!(output.len() % 2, 0);
+
+        let left_peak = output.iter().step_by(2).fold(0.0f32, |m, s| m.max(s.abs()));
+        let right_peak = output
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .fold(0.0f32, |m, s| m.max(s.abs()));
+        assert!(left_peak > 0.4, "left tone lost: peak {left_peak}");
+        assert!(
+            right_peak < 1e-3,
+            "left leaked into right: peak {right_peak}"
+        );
+    }
+
+    #[test]
+    fn rejects_rate_too_low_for_a_chunk() {
+        assert!(matches!(
+            Resampler::new(50, 48000, 1),
+            Err(AecError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn shorter_frames_lower_latency() {
+        let default = Resampler::new(48000, 16000, 1).unwrap();
+        let short = Resampler::with_frame_duration(48000, 16000, 1, 2).unwrap();
+        assert!(short.latency() < default.latency());
+        assert!(default.latency() >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn leftover_stays_below_one_chunk() {
+        let mut resampler = Resampler::new(48000, 16000, 1).unwrap();
+        for _ in 0..100 {
+            resampler.process(&[0.0; 1234]).unwrap();
+            assert!(resampler.accumulator.len() < resampler.chunk_size);
+        }
+    }
+
+    #[test]
+    fn flush_returns_pending_tail() {
+        let mut resampler = Resampler::new(48000, 16000, 1).unwrap();
+        // Half a chunk: nothing comes out until flushed
+        assert!(resampler.process(&[0.5; 240]).unwrap().is_empty());
+
+        let tail = resampler.flush().unwrap();
+        assert!(
+            tail.len() >= 80,
+            "expected at least 80 frames, got {}",
+            tail.len()
+        );
+        assert!(resampler.accumulator.is_empty());
+        assert!(resampler.flush().unwrap().is_empty());
+    }
+
+    #[test]
+    fn convert_keeps_the_whole_clip() {
+        // Not a whole number of chunks, so `process` alone holds some back
+        let clip = vec![0.5; 1234];
+        let output = Resampler::convert(&clip, 16000, 48000, 1).unwrap();
+        assert!(
+            output.len() >= 3 * clip.len(),
+            "expected at least {} frames, got {}",
+            3 * clip.len(),
+            output.len()
+        );
+    }
+
+    /// Level in dB, relative to the input, of a 0.5 amplitude tone after
+    /// one second through `resampler`, skipping the filter's settling time
+    fn tone_gain_db(resampler: &mut Resampler, from_rate: u32, hz: f32) -> f32 {
+        let output = resampler.process(&sine(from_rate, hz)).unwrap();
+        let settled = &output[output.len() / 2..];
+        20.0 * (dsp::rms(settled) / (0.5 / 2f32.sqrt())).log10()
+    }
+
+    #[test]
+    fn narrowband_keeps_the_voice_band_and_rejects_aliases() {
+        // A latency target's short frames must not cut into the voice band
+        for frame_ms in [2, FRAME_DURATION_MS] {
+            for hz in [300.0, 1000.0, 3000.0, 3400.0] {
+                let mut resampler =
+                    Resampler::with_frame_duration(48000, 8000, 1, frame_ms).unwrap();
+                let gain = tone_gain_db(&mut resampler, 48000, hz);
+                assert!(gain.abs() < 1.0, "{hz} Hz at {frame_ms} ms: {gain:.1} dB");
+            }
+
+            // Above 4 kHz would fold back into the voice band
+            for hz in [5000.0, 7000.0, 12000.0] {
+                let mut resampler =
+                    Resampler::with_frame_duration(48000, 8000, 1, frame_ms).unwrap();
+                let gain = tone_gain_db(&mut resampler, 48000, hz);
+                assert!(gain < -60.0, "{hz} Hz at {frame_ms} ms: {gain:.1} dB");
+            }
+        }
+    }
+
+    /// One second of a 0.5 amplitude tone at `rate`
+    fn sine(rate: u32, hz: f32) -> Vec<f32> {
+        (0..rate)
+            .map(|i| (2.0 * std::f32::consts::PI * hz * i as f32 / rate as f32).sin() * 0.5)
+            .collect()
+    }
+
+    /// Frequency of a tone from its rising zero crossings
+    fn tone_hz(samples: &[f32], rate: u32) -> f32 {
+        let rising = samples.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0);
+        rising.count() as f32 * rate as f32 / samples.len() as f32
+    }
+
+    #[test]
+    fn mono_conversion_keeps_ratio_and_pitch_both_ways() {
+        for (from_rate, to_rate) in [(48000, 16000), (16000, 48000)] {
+            let mut resampler = Resampler::new(from_rate, to_rate, 1).unwrap();
+            let input = sine(from_rate, 1000.0);
+
+            // Uneven pieces exercise the accumulator across chunk boundaries
+            let mut output = Vec::new();
+            for piece in input.chunks(333) {
+                output.extend(resampler.process(piece).unwrap());
+            }
+            assert_eq!(output.len(), to_rate as usize, "{from_rate} -> {to_rate}");
+
+            let settled = &output[output.len() / 4..];
+            let hz = tone_hz(settled, to_rate);
+            assert!(
+                (hz - 1000.0).abs() < 10.0,
+                "{from_rate} -> {to_rate}: tone came out at {hz:.1} Hz"
+            );
+        }
+    }
+
+    #[test]
+    fn cd_rate_conversion_does_not_drift() {
+        // Latency targets' short frames included, which cut 44.1 kHz input
+        // into fractions of rubato's 147-frame block
+        for frame_ms in [2, 5, FRAME_DURATION_MS] {
+            for (from_rate, to_rate) in [(48000, 44100), (44100, 48000)] {
+                let mut resampler =
+                    Resampler::with_frame_duration(from_rate, to_rate, 1, frame_ms).unwrap();
+
+                // A minute in device-sized buffers, checked as it goes
+                let mut consumed = 0u64;
+                let mut produced = 0u64;
+                for _ in 0..(60 * from_rate / 512) {
+                    produced += resampler.process(&[0.1; 512]).unwrap().len() as u64;
+                    consumed += 512;
+                    let converted = consumed - resampler.accumulator.len() as u64;
+                    let expected = converted as f64 * to_rate as f64 / from_rate as f64;
+                    assert!(
+                        (produced as f64 - expected).abs() <= 1.0,
+                        "{from_rate} -> {to_rate} in {frame_ms} ms frames: \
+                         {produced} frames out after {converted} in"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stereo_output_length_follows_ratio() {
+        let mut resampler = Resampler::new(48000, 24000, 2).unwrap();
+        let output = resampler.process(&vec![0.1; 48000 * 2]).unwrap();
+        // Every full input chunk yields a fixed-size output chunk per channel
+        assert_eq!(output.len(), 24000 * 2);
+    }
 }
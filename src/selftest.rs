@@ -0,0 +1,161 @@
+//! Loopback diagnostic: play a tone through `play_audio` and measure how
+//! much of it comes back through the capture.
+
+use std::f32::consts::PI;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use crate::{dsp, AecError, CaptureHandle, SelfTestReport};
+
+/// Test tone, well inside the voice band every backend passes
+const TONE_HZ: f32 = 440.0;
+/// Loud enough to stand out from room noise without clipping the speaker
+const TONE_AMPLITUDE: f32 = 0.3;
+/// Rate the tone is generated at; backends resample it for output
+const TONE_RATE: u32 = 48000;
+/// Tone is queued in slices of this length...
+const SLICE: Duration = Duration::from_millis(100);
+/// ...keeping this much queued ahead of the capture so playback never starves
+const LEAD: Duration = Duration::from_millis(200);
+/// Capture ignored by the tone measurement while the canceller converges
+const SETTLE: Duration = Duration::from_millis(500);
+
+pub(crate) async fn run(
+    handle: &CaptureHandle,
+    duration: Duration,
+) -> Result<SelfTestReport, AecError> {
+    if handle.is_paused() {
+        return Err(AecError::InvalidConfig(
+            "self-test needs a running capture; call resume() first".to_string(),
+        ));
+    }
+
+    // Anything already queued was captured before the tone started
+    while handle.try_recv().is_some() {}
+
+    let rate = handle.sample_rate.load(Ordering::Relaxed);
+    let channels = handle.channels() as usize;
+    let total_frames = (duration.as_secs_f64() * rate as f64) as usize;
+
+    let mut tone = Tone::default();
+    let mut played = Duration::ZERO;
+    let mut captured: Vec<f32> = Vec::with_capacity(total_frames);
+    let mut first_chunk: Option<(Instant, usize)> = None;
+    let mut largest_chunk = 0;
+
+    while captured.len() < total_frames {
+        let captured_time = Duration::from_secs_f64(captured.len() as f64 / rate as f64);
+        while played < (captured_time + LEAD).min(duration) {
+            handle.play_audio(tone.next_slice(SLICE), TONE_RATE)?;
+            played += SLICE;
+        }
+
+        let chunk = match handle.recv().await {
+            Some(result) => dsp::downmix(&result?, channels),
+            None => {
+                return Err(AecError::BackendError(
+                    "capture stopped during self-test".to_string(),
+                ))
+            }
+        };
+        first_chunk.get_or_insert((Instant::now(), captured.len() + chunk.len()));
+        largest_chunk = largest_chunk.max(chunk.len());
+        captured.extend(chunk);
+    }
+
+    // Frames that should have arrived since the first chunk, going by the
+    // wall clock. Delivery jitter of up to a chunk is not a drop.
+    let dropped_frames = first_chunk.map_or(0, |(at, frames_then)| {
+        let expected = (at.elapsed().as_secs_f64() * rate as f64) as usize;
+        let received = captured.len() - frames_then;
+        let shortfall = expected.saturating_sub(received);
+        if shortfall > largest_chunk {
+            shortfall
+        } else {
+            0
+        }
+    });
+
+    let settle = ((SETTLE.as_secs_f64() * rate as f64) as usize).min(captured.len() / 2);
+    let level = tone_amplitude(&captured[settle..], rate);
+    let tone_attenuation_db = -20.0 * (level / TONE_AMPLITUDE).max(f32::EPSILON).log10();
+
+    let estimate = handle
+        .echo_meter
+        .lock()
+        .ok()
+        .and_then(|meter| meter.estimate());
+    let stats = handle.stats();
+
+    Ok(SelfTestReport {
+        backend: stats.backend,
+        aec_active: stats.aec_active,
+        duration,
+        tone_attenuation_db,
+        erle_db: estimate.map(|(erle, _)| erle),
+        round_trip_latency: estimate.map(|(_, delay)| delay),
+        input_latency: handle.input_latency(),
+        dropped_frames,
+    })
+}
+
+/// Phase-continuous sine generator.
+#[derive(Default)]
+struct Tone {
+    phase: f32,
+}
+
+impl Tone {
+    fn next_slice(&mut self, length: Duration) -> Vec<f32> {
+        let frames = (length.as_secs_f64() * TONE_RATE as f64) as usize;
+        let step = TONE_HZ / TONE_RATE as f32;
+        (0..frames)
+            .map(|_| {
+                let sample = (self.phase * 2.0 * PI).sin() * TONE_AMPLITUDE;
+                self.phase = (self.phase + step) % 1.0;
+                sample
+            })
+            .collect()
+    }
+}
+
+/// Peak amplitude of the `TONE_HZ` component, by the Goertzel algorithm.
+fn tone_amplitude(samples: &[f32], rate: u32) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let coefficient = 2.0 * (2.0 * PI * TONE_HZ / rate as f32).cos();
+    let (mut s1, mut s2) = (0.0f32, 0.0f32);
+    for &sample in samples {
+        let s0 = sample + coefficient * s1 - s2;
+        s2 = s1;
+        s1 = s0;
+    }
+    let power = s1 * s1 + s2 * s2 - coefficient * s1 * s2;
+    2.0 * power.max(0.0).sqrt() / samples.len() as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn goertzel_recovers_tone_amplitude() {
+        let mut tone = Tone::default();
+        let samples: Vec<f32> = tone
+            .next_slice(Duration::from_secs(1))
+            .iter()
+            .map(|s| s * 0.5)
+            .collect();
+        let level = tone_amplitude(&samples, TONE_RATE);
+        assert!((level - TONE_AMPLITUDE * 0.5).abs() < 1e-3, "level {level}");
+    }
+
+    #[test]
+    fn goertzel_ignores_other_frequencies() {
+        let samples: Vec<f32> = (0..TONE_RATE)
+            .map(|i| (2.0 * PI * 1000.0 * i as f32 / TONE_RATE as f32).sin())
+            .collect();
+        assert!(tone_amplitude(&samples, TONE_RATE) < 1e-3);
+    }
+}
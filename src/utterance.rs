@@ -0,0 +1,146 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::{AudioFrame, UtteranceConfig};
+
+/// Groups delivered chunks into utterances: from a pre-roll before the
+/// first speech chunk until `trailing_silence` passes without speech.
+pub(crate) struct Endpointer {
+    config: UtteranceConfig,
+    /// Latest audio before onset, at most `pre_roll` long
+    pre_roll: VecDeque<f32>,
+    /// Utterance in progress, None while waiting for speech
+    speech: Option<Vec<f32>>,
+    /// Non-speech since the last speech chunk
+    silence: Duration,
+    /// Rate and channel count of the buffered audio
+    format: (u32, u16),
+}
+
+impl Endpointer {
+    pub fn new(config: UtteranceConfig) -> Self {
+        Self {
+            config,
+            pre_roll: VecDeque::new(),
+            speech: None,
+            silence: Duration::ZERO,
+            format: (0, 0),
+        }
+    }
+
+    /// Add a chunk, returning the utterance it completes. A format change
+    /// ends the utterance in progress, as one can't mix rates.
+    pub fn push(&mut self, frame: AudioFrame) -> Option<AudioFrame> {
+        if frame.is_empty() {
+            return None;
+        }
+
+        let format = (frame.sample_rate(), frame.channels());
+        let mut finished = None;
+        if format != self.format {
+            finished = self.finish();
+            self.pre_roll.clear();
+            self.format = format;
+        }
+
+        let speaking = self.config.detector.is_speech(&frame);
+        match &mut self.speech {
+            Some(speech) => {
+                speech.extend_from_slice(&frame);
+                if speaking {
+                    self.silence = Duration::ZERO;
+                } else {
+                    self.silence += frame.duration();
+                    if self.silence >= self.config.trailing_silence {
+                        return self.finish();
+                    }
+                }
+            }
+            None if speaking => {
+                let mut speech: Vec<f32> = self.pre_roll.drain(..).collect();
+                speech.extend_from_slice(&frame);
+                self.speech = Some(speech);
+                self.silence = Duration::ZERO;
+            }
+            None => {
+                self.pre_roll.extend(frame.iter());
+                let (rate, channels) = self.format;
+                let keep =
+                    (self.config.pre_roll.as_secs_f64() * rate as f64) as usize * channels as usize;
+                let excess = self.pre_roll.len().saturating_sub(keep);
+                self.pre_roll.drain(..excess);
+            }
+        }
+        finished
+    }
+
+    /// The utterance in progress, if any, cut short by the stream ending.
+    pub fn finish(&mut self) -> Option<AudioFrame> {
+        self.silence = Duration::ZERO;
+        let (rate, channels) = self.format;
+        self.speech
+            .take()
+            .map(|speech| AudioFrame::new(speech, rate, channels))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VoiceDetector;
+
+    const RATE: u32 = 1000;
+
+    /// Endpointer that treats any non-zero chunk as speech
+    fn endpointer() -> Endpointer {
+        Endpointer::new(UtteranceConfig {
+            pre_roll: Duration::from_millis(20),
+            trailing_silence: Duration::from_millis(30),
+            detector: VoiceDetector::level(0.0),
+        })
+    }
+
+    fn chunk(value: f32) -> AudioFrame {
+        AudioFrame::new(vec![value; 10], RATE, 1)
+    }
+
+    #[test]
+    fn utterance_spans_pre_roll_to_trailing_silence() {
+        let mut endpointer = endpointer();
+        for _ in 0..5 {
+            assert_eq!(endpointer.push(chunk(0.0)), None);
+        }
+        assert_eq!(endpointer.push(chunk(0.5)), None);
+        assert_eq!(endpointer.push(chunk(0.0)), None);
+        assert_eq!(endpointer.push(chunk(0.5)), None);
+        assert_eq!(endpointer.push(chunk(0.0)), None);
+        assert_eq!(endpointer.push(chunk(0.0)), None);
+
+        let utterance = endpointer.push(chunk(0.0)).unwrap();
+        assert_eq!(utterance.sample_rate(), RATE);
+        // 20 ms pre-roll, then speech through the 30 ms of trailing silence
+        assert_eq!(utterance.len(), 20 + 60);
+        assert_eq!(&utterance[..20], &[0.0; 20]);
+        assert_eq!(&utterance[20..30], &[0.5; 10]);
+    }
+
+    #[test]
+    fn stream_end_flushes_the_utterance_in_progress() {
+        let mut endpointer = endpointer();
+        assert_eq!(endpointer.finish(), None);
+        endpointer.push(chunk(0.5));
+        assert_eq!(endpointer.finish().map(|u| u.len()), Some(10));
+        assert_eq!(endpointer.finish(), None);
+    }
+
+    #[test]
+    fn format_change_ends_the_utterance() {
+        let mut endpointer = endpointer();
+        endpointer.push(chunk(0.5));
+        let utterance = endpointer.push(AudioFrame::new(vec![0.0; 16], 1600, 1));
+        assert_eq!(
+            utterance.map(|u| (u.len(), u.sample_rate())),
+            Some((10, RATE))
+        );
+    }
+}
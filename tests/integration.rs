@@ -1,10 +1,85 @@
-use sys_voice::{AecConfig, AecError, CaptureHandle, Channels};
+#[cfg(feature = "backend-file")]
+use sys_voice::FileSource;
+use sys_voice::{aec_supported, AecConfig, AecError, CaptureHandle, Channels, RenderSource};
+
+/// WAV file in the temp directory, deleted when dropped so a failing
+/// test doesn't leave it behind.
+#[cfg(feature = "backend-file")]
+struct TempWav(std::path::PathBuf);
+
+#[cfg(feature = "backend-file")]
+impl TempWav {
+    /// Read as fast as the pipeline takes it, without faults
+    fn source(&self) -> FileSource {
+        FileSource {
+            path: self.0.clone(),
+            realtime: false,
+            fault: None,
+        }
+    }
+}
+
+#[cfg(feature = "backend-file")]
+impl Drop for TempWav {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Sample types a fixture can be written in
+#[cfg(feature = "backend-file")]
+trait WavSample: hound::Sample + Copy {
+    const BITS: u16;
+    const FORMAT: hound::SampleFormat;
+}
+
+#[cfg(feature = "backend-file")]
+impl WavSample for f32 {
+    const BITS: u16 = 32;
+    const FORMAT: hound::SampleFormat = hound::SampleFormat::Float;
+}
+
+#[cfg(feature = "backend-file")]
+impl WavSample for i16 {
+    const BITS: u16 = 16;
+    const FORMAT: hound::SampleFormat = hound::SampleFormat::Int;
+}
+
+/// Write interleaved `samples` to a WAV named after `tag`, unique to this
+/// test process.
+#[cfg(feature = "backend-file")]
+fn wav_fixture<S: WavSample>(tag: &str, channels: u16, sample_rate: u32, samples: &[S]) -> TempWav {
+    let path = std::env::temp_dir().join(format!("sys-voice-{tag}-{}.wav", std::process::id()));
+    let spec = hound::WavSpec {
+        channels,
+        sample_rate,
+        bits_per_sample: S::BITS,
+        sample_format: S::FORMAT,
+    };
+    let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+    for &sample in samples {
+        writer.write_sample(sample).unwrap();
+    }
+    writer.finalize().unwrap();
+    TempWav(path)
+}
+
+/// 16 kHz capture of `wav`
+#[cfg(feature = "backend-file")]
+fn file_config(wav: &TempWav) -> AecConfig {
+    AecConfig {
+        sample_rate: 16000,
+        input_file: Some(wav.source()),
+        ..Default::default()
+    }
+}
 
 #[test]
 fn test_aec_config_creation() {
     let config = AecConfig {
         sample_rate: 48000,
         channels: Channels::Mono,
+        ..Default::default()
     };
     assert_eq!(config.sample_rate, 48000);
     assert_eq!(config.channels, Channels::Mono);
@@ -15,10 +90,60 @@ fn test_aec_config_stereo() {
     let config = AecConfig {
         sample_rate: 48000,
         channels: Channels::Stereo,
+        ..Default::default()
     };
     assert_eq!(config.channels, Channels::Stereo);
 }
 
+#[test]
+#[cfg(target_os = "linux")]
+fn test_default_buffer_frames_is_known_before_opening() {
+    assert_eq!(sys_voice::default_buffer_frames(), 480);
+}
+
+#[test]
+fn test_config_for_output_rejects_a_zero_rate() {
+    assert!(matches!(
+        sys_voice::config_for_output(0, None),
+        Err(AecError::InvalidConfig(_))
+    ));
+}
+
+#[test]
+fn test_profiles_bundle_latency_knobs() {
+    use std::time::Duration;
+    use sys_voice::{CaptureProfile, SharingMode};
+
+    let low = AecConfig::from(CaptureProfile::LowLatency);
+    assert_eq!(low.latency_target, Some(Duration::from_millis(20)));
+    assert_eq!(low.sharing_mode, SharingMode::Exclusive);
+    assert!(low.dedicated_delivery_thread);
+
+    let balanced = AecConfig::from(CaptureProfile::Balanced);
+    assert_eq!(balanced.latency_target, None);
+    assert_eq!(balanced.sharing_mode, SharingMode::Shared);
+    assert!(!balanced.dedicated_delivery_thread);
+
+    let robust = AecConfig::from(CaptureProfile::Robust);
+    assert_eq!(robust.latency_target, None);
+    assert!(robust.dedicated_delivery_thread);
+    assert!(robust.auto_reconnect.is_some());
+
+    let raw = AecConfig::from(CaptureProfile::PassThrough);
+    assert_eq!(raw.processing, sys_voice::Processing::Raw);
+    assert!(!raw.resample);
+    assert_eq!(raw.channels, Channels::Native);
+    assert!(raw.auto_gain.is_none() && raw.output_chunk_frames.is_none());
+
+    // Fields set alongside a profile win
+    let config = AecConfig {
+        sample_rate: 16000,
+        ..CaptureProfile::LowLatency.into()
+    };
+    assert_eq!(config.sample_rate, 16000);
+    assert_eq!(config.latency_target, Some(Duration::from_millis(20)));
+}
+
 #[test]
 fn test_error_display() {
     let err = AecError::DeviceUnavailable;
@@ -40,6 +165,604 @@ fn test_error_display() {
     let err = AecError::BackendError("backend failed".to_string());
     let msg = format!("{err}");
     assert!(msg.contains("backend failed"));
+    assert_eq!(err.os_code(), None);
+
+    let err = AecError::OsError {
+        message: "AudioUnitInitialize failed".to_string(),
+        code: -10875,
+    };
+    let msg = format!("{err}");
+    assert!(msg.contains("AudioUnitInitialize failed") && msg.contains("-10875"));
+    assert_eq!(err.os_code(), Some(-10875));
+
+    let err = AecError::UnsupportedFormat {
+        bits: 64,
+        float: true,
+    };
+    assert_eq!(format!("{err}"), "unsupported sample format: 64-bit float");
+    assert_eq!(err.os_code(), None);
+}
+
+#[test]
+fn test_aec_supported_is_stable() {
+    // Probing has no side effects, so repeated calls agree
+    assert_eq!(aec_supported(), aec_supported());
+}
+
+#[test]
+fn test_new_outside_runtime_is_an_error() {
+    // No runtime to spawn on: fails cleanly before touching audio hardware
+    let result = CaptureHandle::new(AecConfig::default());
+    assert!(matches!(result, Err(AecError::BackendError(_))));
+}
+
+#[tokio::test]
+#[cfg(not(target_os = "android"))]
+async fn test_render_source_is_android_only() {
+    // Rejected before any device is opened
+    let config = AecConfig {
+        render_source: Some(RenderSource::new(|out: &mut [f32]| out.fill(0.0))),
+        ..Default::default()
+    };
+    let result = CaptureHandle::new(config);
+    assert!(matches!(result, Err(AecError::AecNotSupported)));
+}
+
+#[tokio::test]
+#[cfg(all(feature = "system-audio", not(target_os = "macos")))]
+async fn test_system_audio_is_macos_only() {
+    let config = AecConfig {
+        system_audio: sys_voice::SystemAudio::Mixed,
+        ..Default::default()
+    };
+    let result = CaptureHandle::new(config);
+    assert!(matches!(result, Err(AecError::AecNotSupported)));
+}
+
+#[tokio::test]
+#[cfg(feature = "backend-file")]
+async fn test_file_backend_round_trip() {
+    // Both channels equal, so the downmix reproduces the ramp exactly
+    let ramp = (0..1000)
+        .flat_map(|i| [i as f32 / 1000.0; 2])
+        .collect::<Vec<_>>();
+    let wav = wav_fixture("round-trip", 2, 16000, &ramp);
+
+    let handle = CaptureHandle::new(file_config(&wav)).unwrap();
+    assert_eq!(handle.backend_name(), "file");
+
+    let mut received = Vec::new();
+    while let Some(result) = handle.recv().await {
+        received.extend(result.unwrap());
+    }
+
+    let expected: Vec<f32> = (0..1000).map(|i| i as f32 / 1000.0).collect();
+    assert_eq!(received, expected);
+}
+
+#[tokio::test]
+#[cfg(feature = "backend-file")]
+async fn test_recv_into_reuses_the_buffer() {
+    let ramp: Vec<f32> = (0..48000).map(|i| i as f32 / 48000.0).collect();
+    let wav = wav_fixture("into", 1, 16000, &ramp);
+
+    let handle = CaptureHandle::new(AecConfig {
+        output_chunk_frames: Some(160),
+        ..file_config(&wav)
+    })
+    .unwrap();
+
+    let mut buf = Vec::with_capacity(160);
+    let buf_ptr = buf.as_ptr();
+    let mut received = Vec::new();
+    while let Some(result) = handle.recv_into(&mut buf).await {
+        let len = result.unwrap();
+        assert_eq!(len, buf.len());
+        assert_eq!(buf.as_ptr(), buf_ptr);
+        received.extend_from_slice(&buf);
+    }
+
+    assert_eq!(received, ramp);
+    // 300 chunks, cut into no more buffers than the 32-chunk queue and
+    // the few in hand at either end can hold
+    let allocated = handle.stats().buffers_allocated;
+    assert!((1..=40).contains(&allocated), "allocated {allocated}");
+}
+
+#[tokio::test]
+#[cfg(feature = "backend-file")]
+async fn test_pass_through_delivers_the_device_format_untouched() {
+    use sys_voice::CaptureProfile;
+
+    let written: Vec<f32> = (0..8820).map(|i| (i % 7) as f32 / 7.0 - 0.5).collect();
+    let wav = wav_fixture("raw", 2, 44100, &written);
+
+    let handle = CaptureHandle::new(AecConfig {
+        input_file: Some(wav.source()),
+        ..CaptureProfile::PassThrough.into()
+    })
+    .unwrap();
+    assert_eq!(handle.output_sample_rate(), 44100);
+    assert_eq!(handle.channels(), 2);
+
+    let mut received = Vec::new();
+    while let Some(result) = handle.recv().await {
+        let frame = result.unwrap();
+        assert_eq!((frame.sample_rate(), frame.channels()), (44100, 2));
+        received.extend(frame);
+    }
+    assert_eq!(received, written);
+}
+
+#[tokio::test]
+#[cfg(feature = "backend-file")]
+async fn test_record_to_capacity_stops_at_the_cap() {
+    use sys_voice::FileFault;
+
+    let written: Vec<f32> = (0..2000).map(|i| i as f32 / 2000.0).collect();
+    let wav = wav_fixture("cap", 2, 16000, &written);
+
+    let handle = CaptureHandle::new(AecConfig {
+        channels: Channels::Native,
+        ..file_config(&wav)
+    })
+    .unwrap();
+
+    // Not even one stereo frame
+    assert!(matches!(
+        handle.record_to_capacity(1).await,
+        Err(AecError::InvalidConfig(_))
+    ));
+
+    // Cut mid-chunk at a whole stereo frame
+    let first = handle.record_to_capacity(501).await.unwrap();
+    assert_eq!(first, written[..500]);
+    // The next call carries on from the cut...
+    let second = handle.record_to_capacity(300).await.unwrap();
+    assert_eq!(second, written[500..800]);
+    // ...and so does recv
+    let next = handle.recv().await.unwrap().unwrap();
+    assert!(!next.is_first());
+    assert_eq!(next[..], written[800..800 + next.len()]);
+    // The stream ends before this one fills, with nothing skipped
+    let rest = handle.record_to_capacity(10_000).await.unwrap();
+    assert_eq!(rest[..], written[800 + next.len()..]);
+
+    // A failure is told apart from the end, and keeps what came before it
+    let failing = CaptureHandle::new(AecConfig {
+        channels: Channels::Native,
+        input_file: Some(FileSource {
+            fault: Some(FileFault::Error {
+                at: std::time::Duration::from_millis(20),
+                message: "injected".to_string(),
+            }),
+            ..wav.source()
+        }),
+        ..file_config(&wav)
+    })
+    .unwrap();
+    assert!(matches!(
+        failing.record_to_capacity(10_000).await,
+        Err(AecError::BackendError(_))
+    ));
+    let kept = failing.record_to_capacity(10_000).await.unwrap();
+    assert_eq!(kept, written[..640]);
+}
+
+#[tokio::test]
+#[cfg(feature = "backend-file")]
+async fn test_record_to_capacity_stops_at_a_channel_change() {
+    // Both channels carry the frame's value, so the mono downmix does too.
+    // Longer than the queues hold, so the change lands mid-stream.
+    let value = |frame: usize| frame as f32 / 48000.0;
+    let written: Vec<f32> = (0..48000).flat_map(|i| [value(i); 2]).collect();
+    let wav = wav_fixture("cap_layout", 2, 16000, &written);
+
+    let handle = CaptureHandle::new(AecConfig {
+        channels: Channels::Native,
+        ..file_config(&wav)
+    })
+    .unwrap();
+
+    assert_eq!(
+        handle.record_to_capacity(400).await.unwrap(),
+        written[..400]
+    );
+    handle.set_channels(Channels::Mono).unwrap();
+
+    // What was already queued in stereo comes out on its own...
+    let stereo = handle.record_to_capacity(written.len()).await.unwrap();
+    assert_eq!(stereo[..], written[400..400 + stereo.len()]);
+    // ...and the mono recording picks up at the next frame
+    let resumed = (400 + stereo.len()) / 2;
+    let mono = handle.record_to_capacity(written.len()).await.unwrap();
+    let expected: Vec<f32> = (resumed..48000).map(value).collect();
+    assert_eq!(mono, expected);
+}
+
+#[tokio::test]
+#[cfg(feature = "backend-file")]
+async fn test_only_the_first_chunk_is_marked_first() {
+    let wav = wav_fixture("first", 1, 16000, &[0.5f32; 4000]);
+
+    let handle = CaptureHandle::new(AecConfig {
+        output_chunk_frames: Some(160),
+        ..file_config(&wav)
+    })
+    .unwrap();
+
+    let mut marks = Vec::new();
+    while let Some(result) = handle.recv().await {
+        marks.push(result.unwrap().is_first());
+    }
+
+    assert_eq!(marks.len(), 25);
+    assert!(marks[0]);
+    assert!(marks[1..].iter().all(|&first| !first));
+}
+
+#[tokio::test]
+#[cfg(feature = "backend-file")]
+async fn test_select_channel_out_of_range_is_rejected() {
+    use sys_voice::DownmixMode;
+
+    let wav = wav_fixture("select", 2, 16000, &[0i16; 200]);
+
+    let result = CaptureHandle::new(AecConfig {
+        downmix: DownmixMode::SelectChannel(2),
+        ..file_config(&wav)
+    });
+    assert!(matches!(result, Err(AecError::InvalidConfig(_))));
+}
+
+#[tokio::test]
+#[cfg(feature = "backend-file")]
+async fn test_join_reports_why_the_stream_closed() {
+    use sys_voice::CloseReason;
+
+    // Ten seconds, far longer than the realtime stream is left running
+    let wav = wav_fixture("join", 1, 16000, &[0i16; 160000]);
+    let open = |realtime| {
+        CaptureHandle::new(AecConfig {
+            input_file: Some(FileSource {
+                realtime,
+                ..wav.source()
+            }),
+            ..file_config(&wav)
+        })
+        .unwrap()
+    };
+
+    let running = open(true);
+    assert!(running.recv().await.is_some());
+    assert_eq!(running.join().await, CloseReason::Stopped);
+
+    let drained = open(false);
+    while drained.recv().await.is_some() {}
+    assert_eq!(drained.join().await, CloseReason::DeviceLost);
+}
+
+#[tokio::test]
+#[cfg(feature = "backend-file")]
+async fn test_file_faults_simulate_a_failing_device() {
+    use std::time::Duration;
+    use sys_voice::{CaptureStatus, CloseReason, FileFault, ReconnectConfig};
+
+    let ramp: Vec<f32> = (0..16000).map(|i| i as f32 / 16000.0).collect();
+    let wav = wav_fixture("fault", 1, 16000, &ramp);
+    let open = |fault| {
+        CaptureHandle::new(AecConfig {
+            input_file: Some(FileSource {
+                fault: Some(fault),
+                ..wav.source()
+            }),
+            auto_reconnect: Some(ReconnectConfig {
+                initial_delay: Duration::from_millis(1),
+                ..Default::default()
+            }),
+            ..file_config(&wav)
+        })
+        .unwrap()
+    };
+
+    // An error ends the stream after the audio before it
+    let failing = open(FileFault::Error {
+        at: Duration::from_millis(250),
+        message: "injected".to_string(),
+    });
+    let mut received = Vec::new();
+    let error = loop {
+        match failing.recv().await.unwrap() {
+            Ok(chunk) => received.extend(chunk),
+            Err(e) => break e,
+        }
+    };
+    assert!(matches!(error, AecError::BackendError(message) if message == "injected"));
+    assert_eq!(received, ramp[..4000]);
+    assert!(failing.recv().await.is_none());
+    assert_eq!(failing.join().await, CloseReason::Failed);
+
+    // A disconnect is reconnected once, and the reopened file plays through
+    let unplugged = open(FileFault::Disconnect(Duration::from_millis(250)));
+    let status = unplugged.status();
+    let mut received = Vec::new();
+    while let Some(result) = unplugged.recv().await {
+        received.extend(result.unwrap());
+    }
+    assert_eq!(received[..4000], ramp[..4000]);
+    assert_eq!(received[4000..], ramp[..]);
+    assert_eq!(
+        [status.try_recv(), status.try_recv(), status.try_recv()],
+        [
+            Some(CaptureStatus::Reconnecting { attempt: 1 }),
+            Some(CaptureStatus::Reconnected),
+            Some(CaptureStatus::DeviceLost),
+        ]
+    );
+    assert_eq!(unplugged.join().await, CloseReason::DeviceLost);
+}
+
+#[tokio::test]
+#[cfg(feature = "backend-file")]
+async fn test_events_follow_the_stream_lifecycle() {
+    use std::time::Duration;
+    use sys_voice::{CaptureEvent, CloseReason, FileFault, ReconnectConfig};
+
+    let wav = wav_fixture("events", 1, 16000, &[1000i16; 16000]);
+
+    let handle = CaptureHandle::new(AecConfig {
+        input_file: Some(FileSource {
+            fault: Some(FileFault::Disconnect(Duration::from_millis(250))),
+            ..wav.source()
+        }),
+        auto_reconnect: Some(ReconnectConfig {
+            initial_delay: Duration::from_millis(1),
+            ..Default::default()
+        }),
+        ..file_config(&wav)
+    })
+    .unwrap();
+    let events = handle.events();
+    while handle.recv().await.is_some() {}
+
+    let mut received = Vec::new();
+    while let Some(event) = events.recv().await {
+        received.push(event);
+    }
+    // File capture has no canceller, so the first audio is warmed up
+    assert_eq!(
+        received,
+        [
+            CaptureEvent::Started,
+            CaptureEvent::WarmedUp,
+            CaptureEvent::DeviceChanged,
+            CaptureEvent::Stopped(CloseReason::DeviceLost),
+        ]
+    );
+    // Closed independently of the audio, and not reported again
+    assert!(events.try_recv().is_none());
+    assert_eq!(handle.join().await, CloseReason::DeviceLost);
+}
+
+#[tokio::test]
+#[cfg(feature = "backend-file")]
+async fn test_snapshot_recent_keeps_the_newest_audio() {
+    use std::time::Duration;
+
+    let ramp: Vec<f32> = (0..32000).map(|i| i as f32 / 32000.0).collect();
+    let wav = wav_fixture("recent", 1, 16000, &ramp);
+
+    let handle = CaptureHandle::new(AecConfig {
+        retain_recent: Some(Duration::from_secs(1)),
+        ..file_config(&wav)
+    })
+    .unwrap();
+    while handle.recv().await.is_some() {}
+
+    assert_eq!(handle.snapshot_recent(), ramp[16000..]);
+}
+
+#[tokio::test]
+#[cfg(feature = "backend-file")]
+async fn test_file_input_has_no_hardware_controls() {
+    let wav = wav_fixture("gain", 1, 16000, &[0i16; 160]);
+
+    let handle = CaptureHandle::new(file_config(&wav)).unwrap();
+
+    assert!(matches!(
+        handle.hardware_gain(),
+        Err(AecError::AecNotSupported)
+    ));
+    assert!(matches!(
+        handle.set_hardware_gain(1.5),
+        Err(AecError::InvalidConfig(_))
+    ));
+    assert_eq!(handle.hardware_input_level(), None);
+    assert_eq!(handle.aec_reference_delay(), None);
+    assert!(matches!(
+        handle.set_aec_reference_enabled(false),
+        Err(AecError::AecNotSupported)
+    ));
+    assert!(!handle.is_aec_reference_enabled());
+    assert_eq!(handle.playback_sample_rate(), None);
+    // SAFETY: the closure doesn't touch the unit
+    let native = unsafe { handle.with_native_unit(|_| ()) };
+    assert!(matches!(native, Err(AecError::AecNotSupported)));
+}
+
+#[tokio::test]
+#[cfg(feature = "backend-file")]
+async fn test_play_stream_fails_without_an_output() {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    /// Yields its chunks, then ends
+    struct Chunks(Vec<Vec<f32>>);
+
+    impl futures_core::Stream for Chunks {
+        type Item = Vec<f32>;
+
+        fn poll_next(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Vec<f32>>> {
+            Poll::Ready((!self.0.is_empty()).then(|| self.0.remove(0)))
+        }
+    }
+
+    let wav = wav_fixture("play", 1, 16000, &[0i16; 160]);
+
+    let handle = CaptureHandle::new(file_config(&wav)).unwrap();
+
+    // Nothing to play finishes at once; file input has no output for the rest
+    assert!(handle.play_stream(Chunks(Vec::new()), 16000).await.is_ok());
+    let result = handle
+        .play_stream(Chunks(vec![vec![0.1; 160]; 3]), 16000)
+        .await;
+    assert!(matches!(result, Err(AecError::BackendError(_))));
+}
+
+#[tokio::test]
+#[cfg(feature = "backend-file")]
+async fn test_stereo_from_mono_file_reports_duplicated_channels() {
+    let wav = wav_fixture("layout", 1, 16000, &[0i16; 160]);
+
+    let handle = CaptureHandle::new(AecConfig {
+        channels: Channels::StereoTrue,
+        ..file_config(&wav)
+    })
+    .unwrap();
+
+    // Two channels delivered, but only one signal behind them
+    assert_eq!(handle.channels(), 2);
+    assert_eq!(handle.stats().distinct_channels, 1);
+
+    handle.set_channels(Channels::Mono).unwrap();
+    assert_eq!(handle.channels(), 1);
+    assert_eq!(handle.stats().distinct_channels, 1);
+}
+
+#[tokio::test]
+#[cfg(feature = "backend-file")]
+async fn test_file_speech_is_delivered_as_one_utterance() {
+    use std::time::Duration;
+    use sys_voice::{UtteranceConfig, VoiceDetector};
+
+    // 1 s of silence, 0.5 s of "speech", 1 s of silence
+    let samples: Vec<f32> = (0..40000)
+        .map(|i| {
+            if (16000..24000).contains(&i) {
+                0.5
+            } else {
+                0.0
+            }
+        })
+        .collect();
+    let wav = wav_fixture("utterance", 1, 16000, &samples);
+
+    let handle = CaptureHandle::new(AecConfig {
+        utterance: Some(UtteranceConfig {
+            pre_roll: Duration::from_millis(100),
+            trailing_silence: Duration::from_millis(300),
+            detector: VoiceDetector::level(0.1),
+        }),
+        ..file_config(&wav)
+    })
+    .unwrap();
+
+    let utterance = handle.recv_utterance().await.unwrap().unwrap();
+    assert_eq!(utterance.iter().filter(|&&s| s == 0.5).count(), 8000);
+    // Speech plus at least the pre-roll and the trailing silence
+    assert!(utterance.len() >= 8000 + 1600 + 4800, "{}", utterance.len());
+    assert!(utterance.len() < 24000, "{}", utterance.len());
+    assert!(handle.recv_utterance().await.is_none());
+}
+
+#[tokio::test]
+#[cfg(feature = "backend-file")]
+async fn test_new_async_resolves_once_audio_flows() {
+    let wav = wav_fixture("ready", 1, 16000, &[0i16; 16000]);
+
+    let handle = CaptureHandle::new_async(file_config(&wav)).await.unwrap();
+
+    // No AEC on file input, so only the first chunk was spent on readiness
+    let skipped = handle.stats().warmup_skipped_samples;
+    let mut remaining = 0;
+    while let Some(result) = handle.recv().await {
+        remaining += result.unwrap().len();
+    }
+    assert!(remaining > 0 && remaining < 16000, "{remaining}");
+    assert_eq!(skipped + remaining as u64, 16000);
+}
+
+#[tokio::test]
+#[cfg(feature = "backend-file")]
+async fn test_capture_for_returns_exactly_the_duration() {
+    use std::time::Duration;
+
+    let ramp: Vec<f32> = (0..16000).map(|i| i as f32 / 16000.0).collect();
+    let wav = wav_fixture("for", 1, 16000, &ramp);
+
+    let config = file_config(&wav);
+    let short = CaptureHandle::capture_for(config.clone(), Duration::from_millis(250))
+        .await
+        .unwrap();
+    // Longer than the file: capture ends with it
+    let whole = CaptureHandle::capture_for(config, Duration::from_secs(3))
+        .await
+        .unwrap();
+
+    assert_eq!(short, ramp[..4000]);
+    assert_eq!(whole, ramp);
+}
+
+#[tokio::test]
+#[cfg(feature = "backend-file")]
+async fn test_latency_target_sizes_file_chunks() {
+    use std::time::Duration;
+
+    let wav = wav_fixture("latency", 1, 48000, &[0.0f32; 4800]);
+
+    let handle = CaptureHandle::new(AecConfig {
+        latency_target: Some(Duration::from_millis(15)),
+        input_file: Some(wav.source()),
+        ..Default::default()
+    })
+    .unwrap();
+
+    // A third of the budget goes to the device buffer; no resampling at 48 kHz
+    assert_eq!(handle.buffer_size(), 240);
+    assert_eq!(handle.input_latency(), Duration::from_millis(5));
+
+    let first = handle.recv().await.unwrap().unwrap();
+    assert_eq!(first.len(), 240);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[cfg(feature = "backend-file")]
+async fn test_pause_discard_drops_queued_audio() {
+    let wav = wav_fixture("pause", 1, 16000, &[1000i16; 16000]);
+
+    let handle = CaptureHandle::new(AecConfig {
+        input_file: Some(FileSource {
+            realtime: true,
+            ..wav.source()
+        }),
+        pause_discard: true,
+        ..file_config(&wav)
+    })
+    .unwrap();
+
+    // Pause mid-file: nothing captured so far is delivered
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    handle.pause();
+    assert!(handle.is_paused());
+    assert!(handle.try_recv().is_none());
+
+    handle.resume();
+    let mut received = 0;
+    while let Some(result) = handle.recv().await {
+        received += result.unwrap().len();
+    }
+
+    assert!(received > 0 && received < 16000, "received {received}");
 }
 
 #[tokio::test]
@@ -49,6 +772,7 @@ async fn test_macos_stream_creation() {
     let config = AecConfig {
         sample_rate: 48000,
         channels: Channels::Mono,
+        ..Default::default()
     };
 
     let result = CaptureHandle::new(config);
@@ -60,6 +784,7 @@ async fn test_macos_stream_creation() {
         Err(AecError::PermissionDenied) => {}
         Err(AecError::DeviceUnavailable) => {}
         Err(AecError::BackendError(_)) => {}
+        Err(AecError::OsError { .. }) => {}
         Err(e) => {
             panic!("Unexpected error: {e:?}");
         }
@@ -73,6 +798,7 @@ async fn test_windows_stream_creation() {
     let config = AecConfig {
         sample_rate: 48000,
         channels: Channels::Mono,
+        ..Default::default()
     };
 
     let result = CaptureHandle::new(config);
@@ -90,6 +816,58 @@ async fn test_windows_stream_creation() {
     }
 }
 
+/// A stereo tone played through the crate comes back attenuated in the mono
+/// capture: the canceller's reference is the endpoint mix, both channels.
+#[tokio::test(flavor = "multi_thread")]
+#[cfg(target_os = "windows")]
+#[ignore] // Requires speakers the microphone can hear - run with: cargo test -- --ignored
+async fn test_windows_stereo_playback_is_cancelled() {
+    const RATE: u32 = 48000;
+    const AMPLITUDE: f32 = 0.3;
+
+    let config = AecConfig {
+        sample_rate: RATE,
+        ..Default::default()
+    };
+    let handle = match CaptureHandle::new_async(config).await {
+        Ok(handle) => handle,
+        Err(AecError::DeviceUnavailable) | Err(AecError::AecNotSupported) => return,
+        Err(e) => panic!("Unexpected error: {e:?}"),
+    };
+    if !handle.stats().aec_active {
+        return;
+    }
+
+    // 440 Hz left, 660 Hz right, for three seconds
+    let tone: Vec<f32> = (0..RATE * 3)
+        .flat_map(|i| {
+            let t = i as f32 / RATE as f32;
+            let phase = 2.0 * std::f32::consts::PI * t;
+            [
+                (phase * 440.0).sin() * AMPLITUDE,
+                (phase * 660.0).sin() * AMPLITUDE,
+            ]
+        })
+        .collect();
+    handle.play_audio_interleaved(tone, RATE, 2).unwrap();
+
+    let mut captured = Vec::new();
+    while captured.len() < RATE as usize * 3 {
+        match handle.recv().await {
+            Some(result) => captured.extend(result.unwrap()),
+            None => panic!("capture stopped during playback"),
+        }
+    }
+
+    // The first second is left for the canceller to converge
+    let level = sys_voice::dsp::rms(&captured[RATE as usize..]);
+    let attenuation_db = -20.0 * (level / (AMPLITUDE / 2f32.sqrt())).log10();
+    assert!(
+        attenuation_db > 20.0,
+        "stereo tone only attenuated by {attenuation_db:.1} dB"
+    );
+}
+
 #[tokio::test]
 #[cfg(target_os = "linux")]
 #[ignore] // Requires audio hardware - run locally with: cargo test -- --ignored
@@ -97,6 +875,7 @@ async fn test_linux_stream_creation() {
     let config = AecConfig {
         sample_rate: 48000,
         channels: Channels::Mono,
+        ..Default::default()
     };
 
     let result = CaptureHandle::new(config);
@@ -107,12 +886,21 @@ async fn test_linux_stream_creation() {
         }
         Err(AecError::DeviceUnavailable) => {}
         Err(AecError::BackendError(_)) => {}
+        Err(AecError::OsError { .. }) => {}
         Err(e) => {
             panic!("Unexpected error: {e:?}");
         }
     }
 }
 
+#[tokio::test]
+#[cfg(target_os = "linux")]
+async fn test_dual_capture_unsupported_on_linux() {
+    // Refused before any device is opened
+    let result = sys_voice::DualCapture::new(AecConfig::default());
+    assert!(matches!(result, Err(AecError::AecNotSupported)));
+}
+
 /// Test that the requested sample rate is honored.
 /// Bug: backends currently ignore config.sample_rate and use native rate instead.
 #[tokio::test]
@@ -122,6 +910,7 @@ async fn test_sample_rate_is_honored() {
     let config = AecConfig {
         sample_rate: 16000,
         channels: Channels::Mono,
+        ..Default::default()
     };
 
     let result = CaptureHandle::new(config);
@@ -137,6 +926,7 @@ async fn test_sample_rate_is_honored() {
         Err(AecError::PermissionDenied) => {}
         Err(AecError::DeviceUnavailable) => {}
         Err(AecError::BackendError(_)) => {}
+        Err(AecError::OsError { .. }) => {}
         Err(e) => {
             panic!("Unexpected error: {e:?}");
         }